@@ -0,0 +1,111 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+const SITES: usize = 1_000;
+
+/// Builds a config with `count` sites, each with the cache rules, headers and
+/// redirects that most sites have.
+fn synthetic_config(count: usize) -> String {
+    let mut config = String::new();
+
+    for i in 0..count {
+        write!(
+            config,
+            r#"
+[[sites]]
+domain = "site{i}.example.com"
+
+[[sites.cache_control]]
+mime = "text/css"
+value = "public, max-age=31536000, immutable"
+
+[[sites.cache_control]]
+mime = "text/html"
+value = "public, max-age=3600"
+
+[[sites.headers]]
+for = "= /"
+[sites.headers.values]
+Content-Security-Policy = "default-src 'self'"
+
+[[sites.redirects]]
+from = "/old"
+to = "https://site{i}.example.com/new"
+status_code = 301
+"#
+        )
+        .unwrap();
+    }
+
+    config
+}
+
+/// Parses `config` and removes the files of the previous iteration, which
+/// would otherwise be left unchanged rather than written again. It runs
+/// before each iteration, with `BatchSize::PerIteration`.
+fn setup(config: &str) -> nvhosts::UnverifiedConfig {
+    let _ = fs::remove_dir_all("sites-available");
+    toml::from_str(config).unwrap()
+}
+
+fn generate(c: &mut Criterion) {
+    let dir = env::temp_dir().join("nvhosts-bench");
+    fs::create_dir_all(&dir).unwrap();
+    env::set_current_dir(&dir).unwrap();
+
+    let config = synthetic_config(SITES);
+
+    c.bench_function("generate 1000 sites", |b| {
+        b.iter_batched(
+            || setup(&config),
+            |config| nvhosts::run(config, &nvhosts::Options::default()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Generates configs around the size from which sites are generated by a
+/// pool of threads, on one thread and on four, to check where the pool pays
+/// off.
+fn jobs(c: &mut Criterion) {
+    let dir = env::temp_dir().join("nvhosts-bench-jobs");
+    fs::create_dir_all(&dir).unwrap();
+    env::set_current_dir(&dir).unwrap();
+
+    let mut group = c.benchmark_group("jobs");
+    for sites in [64, 256] {
+        let config = synthetic_config(sites);
+        for jobs in [1, 4] {
+            let options = nvhosts::Options {
+                jobs: Some(jobs),
+                ..Default::default()
+            };
+            group.bench_with_input(
+                BenchmarkId::new(format!("{} jobs", jobs), sites),
+                &config,
+                |b, config| {
+                    b.iter_batched(
+                        || setup(config),
+                        |config| nvhosts::run(config, &options).unwrap(),
+                        BatchSize::PerIteration,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = generate, jobs
+}
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+//! A gzipped tarball of the generated files, for `--archive`, to ship them as
+//! a single artifact and unpack them under `/etc/nginx`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::{
+    section, templates, with_banner, write_atomically, NvhostsError, Options, Output,
+    UnverifiedConfig, CATCH_ALL_FILENAME, HTTP_FILENAME, MAIN_FILENAME, STREAM_FILENAME,
+};
+
+/// Directory of the files in the archive, the output directory relative to
+/// the nginx one.
+const ARCHIVE_DIR: &str = "sites-available";
+
+/// Mode of the files in the archive.
+const FILE_MODE: u32 = 0o644;
+
+/// Validates `config` and writes the files `run` would, rendered through
+/// `for_each_rendered`, to the gzipped tarball at `path`, under
+/// `sites-available/`. The tarball is built in memory then renamed over
+/// `path`, which is left as it was when anything fails. Returns the number
+/// of files archived.
+pub fn write(
+    config: UnverifiedConfig,
+    options: &Options,
+    path: &Path,
+) -> Result<usize, NvhostsError> {
+    let config = config.validate(options)?;
+    let mut tera = templates(options)?;
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut count = 0;
+    let mut append = |name: &str, content: &str| {
+        let content = with_banner(content);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(FILE_MODE);
+        header.set_mtime(mtime);
+        count += 1;
+        builder
+            .append_data(
+                &mut header,
+                Path::new(ARCHIVE_DIR).join(name),
+                content.as_bytes(),
+            )
+            .map_err(NvhostsError::io(path))
+    };
+
+    if config.main.is_some() {
+        append(MAIN_FILENAME, &config.render_main(&mut tera)?)?;
+    }
+    if !config.streams.is_empty() {
+        append(STREAM_FILENAME, &config.render_stream(&mut tera)?)?;
+    }
+    let mut sections = Vec::new();
+    if config.has_http() {
+        let http = config.render_http(&mut tera)?;
+        match &options.output {
+            Output::PerSite => append(HTTP_FILENAME, &http)?,
+            Output::Combined(_) => sections.push(section("http", &http)),
+        }
+    }
+    if config.catch_all.is_some() {
+        let catch_all = config.render_catch_all(&mut tera)?;
+        match &options.output {
+            Output::PerSite => append(CATCH_ALL_FILENAME, &catch_all)?,
+            Output::Combined(_) => sections.push(section("catch-all", &catch_all)),
+        }
+    }
+
+    let filenames: HashMap<&str, String> = config
+        .sites
+        .iter()
+        .map(|site| (site.domain.as_str(), site.filename()))
+        .collect();
+    config.for_each_rendered(options, |domain, content| match &options.output {
+        Output::PerSite => append(&filenames[domain], content),
+        Output::Combined(_) => {
+            sections.push(section(domain, content));
+            Ok(())
+        }
+    })?;
+    if let Output::Combined(filename) = &options.output {
+        append(filename, &sections.join("\n"))?;
+    }
+
+    let archive = builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map_err(NvhostsError::io(path))?;
+    write_atomically(path, &archive, None).map_err(NvhostsError::io(path))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let path =
+            std::env::temp_dir().join(format!("nvhosts-archive-{}.tar.gz", std::process::id()));
+        let config = r#"
+[[sites]]
+domain = "example.com"
+upstream = { url = "http://127.0.0.1:3000" }
+
+[[sites]]
+domain = "www.example.org"
+"#;
+        let options = Options::default();
+        let files = crate::dry_run(toml::from_str(config).unwrap(), &options).unwrap();
+        assert_eq!(
+            write(toml::from_str(config).unwrap(), &options, &path).unwrap(),
+            files.len()
+        );
+
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(&path).unwrap()));
+        let entries: Vec<(String, u32, String)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                (
+                    entry.path().unwrap().display().to_string(),
+                    entry.header().mode().unwrap(),
+                    content,
+                )
+            })
+            .collect();
+        let expected: Vec<(String, u32, String)> = files
+            .into_iter()
+            .map(|(name, content)| (format!("sites-available/{}", name), 0o644, content))
+            .collect();
+        assert_eq!(entries, expected);
+
+        // A failed run leaves the previous archive in place.
+        let invalid = toml::from_str("[[sites]]\ndomain = \"\"\n").unwrap();
+        assert!(write(invalid, &options, &path).is_err());
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(&path).unwrap()));
+        assert_eq!(archive.entries().unwrap().count(), expected.len());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Reports on the security posture of every site of a config, for `nvhosts
+//! audit`, as a table for reviewers or JSON for tooling.
+
+use serde::Serialize;
+
+use crate::lint::{certificate_mismatches, has_header};
+use crate::{NvhostsError, Options, UnverifiedConfig};
+
+/// The TLS posture of a site.
+#[derive(Debug, Serialize)]
+pub struct TlsAudit {
+    pub domain: String,
+    /// Whether the site is served over HTTPS, with `ssl_cert` or
+    /// `letsencrypt`.
+    pub https: bool,
+    /// Whether the responses have a `Strict-Transport-Security` header.
+    pub hsts: bool,
+    pub force_https: bool,
+    /// What the site lacks, empty when nothing.
+    pub issues: Vec<String>,
+}
+
+/// Validates `config` and audits the TLS of every site: the ones served over
+/// plain HTTP, and the HTTPS ones without HSTS, unless they only redirect,
+/// without `force_https`, or whose certificate doesn't look like it covers
+/// their names.
+pub fn tls(config: UnverifiedConfig, options: &Options) -> Result<Vec<TlsAudit>, NvhostsError> {
+    let config = config.validate(options)?;
+
+    Ok(config
+        .sites
+        .iter()
+        .map(|site| {
+            let https = site.ssl_cert.is_some();
+            let hsts = has_header(site, "Strict-Transport-Security");
+            let mut issues = Vec::new();
+            if !https {
+                issues.push(String::from(
+                    "not served over HTTPS, set ssl_cert and ssl_key or letsencrypt",
+                ));
+            } else {
+                if !hsts && site.redirect_to.is_none() {
+                    issues.push(String::from("no Strict-Transport-Security header"));
+                }
+                if !site.force_https {
+                    issues.push(String::from(
+                        "force_https is off, plain HTTP requests are served",
+                    ));
+                }
+                issues.extend(certificate_mismatches(site));
+            }
+            TlsAudit {
+                domain: site.domain.clone(),
+                https,
+                hsts,
+                force_https: site.force_https,
+                issues,
+            }
+        })
+        .collect())
+}
+
+/// `audits` as a table, one row per site, with a header row.
+pub fn table(audits: &[TlsAudit]) -> String {
+    let yes_no = |set: bool| if set { "yes" } else { "no" };
+    let mut rows = vec![[
+        String::from("DOMAIN"),
+        String::from("HTTPS"),
+        String::from("HSTS"),
+        String::from("FORCE_HTTPS"),
+        String::from("ISSUES"),
+    ]];
+    for audit in audits {
+        rows.push([
+            audit.domain.clone(),
+            yes_no(audit.https).to_string(),
+            yes_no(audit.hsts).to_string(),
+            yes_no(audit.force_https).to_string(),
+            match audit.issues.is_empty() {
+                true => String::from("-"),
+                false => audit.issues.join("; "),
+            },
+        ]);
+    }
+
+    let mut widths = [0; 4];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter().zip(row.iter()) {
+            table.push_str(&format!("{:<width$}  ", cell, width = width));
+        }
+        table.push_str(&row[4]);
+        table.push('\n');
+    }
+    table
+}
+
+/// `audits` as a pretty-printed JSON array.
+pub fn json(audits: &[TlsAudit]) -> String {
+    let mut json = serde_json::to_string_pretty(audits).expect("an audit serializes to JSON");
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+
+            [[sites.headers]]
+            for = "/*"
+            values = { Strict-Transport-Security = "max-age=63072000" }
+
+            [[sites]]
+            domain = "api.example.com"
+            letsencrypt = true
+
+            [[sites]]
+            domain = "legacy.example.com"
+            "#,
+        )
+        .unwrap();
+        let audits = tls(config, &Options::default()).unwrap();
+        assert!(audits[0].issues.is_empty());
+        assert!(audits[1].https);
+        assert_eq!(
+            audits[1].issues,
+            [
+                "no Strict-Transport-Security header",
+                "force_https is off, plain HTTP requests are served"
+            ]
+        );
+        assert!(!audits[2].https);
+
+        assert_eq!(
+            table(&audits),
+            "DOMAIN              HTTPS  HSTS  FORCE_HTTPS  ISSUES
+example.com         yes    yes   yes          -
+api.example.com     yes    no    no           no Strict-Transport-Security header; force_https is off, plain HTTP requests are served
+legacy.example.com  no     no    no           not served over HTTPS, set ssl_cert and ssl_key or letsencrypt
+"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&json(&audits)).unwrap();
+        assert_eq!(json[2]["domain"], "legacy.example.com");
+        assert_eq!(json[2]["https"], false);
+        assert_eq!(json[0]["issues"].as_array().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,180 @@
+//! Sites built in code rather than deserialized from TOML, for tests and
+//! programs embedding nvhosts:
+//!
+//! ```
+//! use nvhosts::builder::SiteBuilder;
+//! use nvhosts::UnverifiedConfig;
+//!
+//! let config = UnverifiedConfig::default().with_site(
+//!     SiteBuilder::new("example.com")
+//!         .proxy("http://127.0.0.1:3000")
+//!         .header("/*", "X-Frame-Options", "DENY")
+//!         .redirect("/old", "/new"),
+//! );
+//! ```
+//!
+//! The sites are validated like the ones of a config file.
+
+use crate::{default_redirect_status_code, Header, Redirect, Site, Upstream};
+
+/// A site, built one field at a time. Fields that aren't set keep the
+/// defaults of a config file.
+#[derive(Clone)]
+pub struct SiteBuilder {
+    site: Site,
+}
+
+impl SiteBuilder {
+    pub fn new(domain: &str) -> Self {
+        Self {
+            site: Site {
+                domain: domain.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Proxies the requests to `url`, e.g. `http://127.0.0.1:3000`.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.site.upstream = Some(Upstream {
+            url: url.to_string(),
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Serves the files of `dir` instead of an upstream.
+    pub fn root(mut self, dir: &str) -> Self {
+        self.site.root = Some(dir.to_string());
+        self
+    }
+
+    /// Serves the site over HTTPS with this certificate and key.
+    pub fn ssl(mut self, cert: &str, key: &str) -> Self {
+        self.site.ssl_cert = Some(cert.to_string());
+        self.site.ssl_key = Some(key.to_string());
+        self
+    }
+
+    /// Redirects plain HTTP requests to HTTPS.
+    pub fn force_https(mut self) -> Self {
+        self.site.force_https = true;
+        self
+    }
+
+    pub fn http2(mut self) -> Self {
+        self.site.http2 = true;
+        self
+    }
+
+    /// Adds a header to the responses of the paths of `for_path`, along with
+    /// the other headers of the same paths.
+    pub fn header(mut self, for_path: &str, name: &str, value: &str) -> Self {
+        let headers = self.site.headers.get_or_insert_with(Vec::new);
+        let header = match headers.iter().position(|h| h.for_field == for_path) {
+            Some(i) => &mut headers[i],
+            None => {
+                headers.push(Header {
+                    for_field: for_path.to_string(),
+                    ..Default::default()
+                });
+                headers.last_mut().expect("a header was just added")
+            }
+        };
+        header.values.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Permanently redirects the path `from` to `to`.
+    pub fn redirect(mut self, from: &str, to: &str) -> Self {
+        self.site
+            .redirects
+            .get_or_insert_with(Vec::new)
+            .push(Redirect {
+                from_field: from.to_string(),
+                to: to.to_string(),
+                status_code: default_redirect_status_code(),
+                regex: false,
+            });
+        self
+    }
+
+    /// Permanently redirects every request to `target` instead of serving
+    /// the site.
+    pub fn redirect_to(mut self, target: &str) -> Self {
+        self.site.redirect_to = Some(target.to_string());
+        self
+    }
+
+    /// Makes the site answer the requests for hosts no other site serves.
+    pub fn default_server(mut self) -> Self {
+        self.site.default_server = true;
+        self
+    }
+
+    pub(crate) fn build(self) -> Site {
+        self.site
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dry_run, Options, UnverifiedConfig};
+
+    #[test]
+    fn test_site_builder() {
+        let built = UnverifiedConfig::default()
+            .with_site(
+                SiteBuilder::new("example.com")
+                    .proxy("http://127.0.0.1:3000")
+                    .ssl("/etc/ssl/example.pem", "/etc/ssl/example.key")
+                    .force_https()
+                    .http2()
+                    .header("/*", "X-Frame-Options", "DENY")
+                    .header("/api", "Cache-Control", "no-store")
+                    .header("/*", "X-Content-Type-Options", "nosniff")
+                    .redirect("/old", "/new"),
+            )
+            .with_site(SiteBuilder::new("example.org").redirect_to("https://example.com"));
+        let parsed: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://127.0.0.1:3000" }
+            ssl_cert = "/etc/ssl/example.pem"
+            ssl_key = "/etc/ssl/example.key"
+            force_https = true
+            http2 = true
+            redirects = [{ from = "/old", to = "/new" }]
+
+            [[sites.headers]]
+            for = "/*"
+            values = { X-Frame-Options = "DENY", X-Content-Type-Options = "nosniff" }
+
+            [[sites.headers]]
+            for = "/api"
+            values = { Cache-Control = "no-store" }
+
+            [[sites]]
+            domain = "example.org"
+            redirect_to = "https://example.com"
+            "#,
+        )
+        .unwrap();
+
+        let options = Options::default();
+        assert_eq!(
+            dry_run(built.clone(), &options).unwrap(),
+            dry_run(parsed, &options).unwrap()
+        );
+        assert_eq!(built.to_toml().unwrap().matches("[[sites]]").count(), 2);
+
+        let site = SiteBuilder::new("_")
+            .default_server()
+            .root("/var/www")
+            .build();
+        assert!(site.default_server);
+        assert_eq!(site.root.as_deref(), Some("/var/www"));
+    }
+}
@@ -0,0 +1,213 @@
+//! The canonical form of a config file, printed by `nvhosts fmt`, so that
+//! configs edited by several people stay consistent and diff well.
+
+use toml::Value;
+
+use crate::UnverifiedConfig;
+
+/// Rewrites the config file `content` in canonical form: sites sorted by
+/// domain, keys in the order of the fields of the config, and the layout of
+/// the TOML serializer. Only the keys of the file are kept, with their values
+/// as written, e.g. `${file:<path>}` references aren't read; comments are
+/// lost. With `strip_trailing_slashes`, the `from` paths of the redirects
+/// that aren't regular expressions and the `for` paths of the headers lose
+/// their trailing slash; the `to` targets of the redirects are kept.
+pub fn format(content: &str, strip_trailing_slashes: bool) -> Result<String, toml::de::Error> {
+    let mut config: UnverifiedConfig = toml::from_str(content)?;
+    let mut original: Value = toml::from_str(content)?;
+
+    if strip_trailing_slashes {
+        for site in original
+            .get_mut("sites")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten()
+        {
+            strip_path_slashes(site);
+            let overrides = site.get_mut("overrides").and_then(Value::as_table_mut);
+            for overridden in overrides
+                .into_iter()
+                .flat_map(|table| table.iter_mut().map(|(_, value)| value))
+            {
+                strip_path_slashes(overridden);
+            }
+        }
+    }
+
+    // Both sorts are stable and use the same keys, so the sites stay paired.
+    config.sites.sort_by(|a, b| a.domain.cmp(&b.domain));
+    if let Some(sites) = original.get_mut("sites").and_then(Value::as_array_mut) {
+        sites.sort_by(|a, b| domain(a).cmp(domain(b)));
+    }
+
+    let order = Value::try_from(&config).expect("a config serializes to TOML");
+    let formatted = reorder(original, &order);
+    Ok(toml::to_string_pretty(&formatted).expect("a config serializes to TOML"))
+}
+
+/// Strips the trailing slash of the paths matched by the `redirects` and the
+/// `headers` of `table`: the `from` of the redirects that aren't regular
+/// expressions and the `for` of the headers. The `to` of a redirect is where
+/// it points, so it's left as written.
+fn strip_path_slashes(table: &mut Value) {
+    let redirects = table.get_mut("redirects").and_then(Value::as_array_mut);
+    for redirect in redirects.into_iter().flatten() {
+        if redirect.get("regex").and_then(Value::as_bool) != Some(true) {
+            strip_slash(redirect, "from");
+        }
+    }
+    let headers = table.get_mut("headers").and_then(Value::as_array_mut);
+    for header in headers.into_iter().flatten() {
+        strip_slash(header, "for");
+    }
+}
+
+/// Strips the trailing slash of the path at `key` of `table`, keeping `/`
+/// itself, URLs and paths with a query.
+fn strip_slash(table: &mut Value, key: &str) {
+    if let Some(Value::String(path)) = table.get_mut(key) {
+        if path.starts_with('/') && !path.contains('?') && path.len() > 1 {
+            let stripped = path.trim_end_matches('/');
+            *path = match stripped {
+                "" => String::from("/"),
+                stripped => stripped.to_string(),
+            };
+        }
+    }
+}
+
+fn domain(site: &Value) -> &str {
+    site.get("domain")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+/// `value` with its keys in the order of `order`, the same config serialized
+/// from its fields. Keys unknown to `order` come last, sorted.
+fn reorder(value: Value, order: &Value) -> Value {
+    match (value, order) {
+        (Value::Table(mut table), Value::Table(order)) => {
+            let mut reordered = toml::value::Table::new();
+            for (key, order) in order.iter() {
+                if let Some(value) = table.remove(key) {
+                    reordered.insert(key.clone(), reorder(value, order));
+                }
+            }
+            let mut rest: Vec<_> = table.into_iter().collect();
+            rest.sort_by(|(a, _), (b, _)| a.cmp(b));
+            reordered.extend(rest);
+            Value::Table(reordered)
+        }
+        (Value::Array(values), Value::Array(order)) if values.len() == order.len() => Value::Array(
+            values
+                .into_iter()
+                .zip(order.iter())
+                .map(|(value, order)| reorder(value, order))
+                .collect(),
+        ),
+        (value, _) => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let formatted = format(
+            r#"
+[[sites]]
+redirects = [{ to = "/new", from = "/old" }]
+domain = "www.example.com"
+server_tokens   =   false
+
+[[sites]]
+force_https = true
+domain = "api.example.com"
+ssl_key = "${file:key.path}"
+ssl_cert = "/etc/ssl/cert.pem"
+  [sites.overrides.prod]
+  http2 = true
+  [sites.overrides.dev]
+  force_https = false
+  [[sites.headers]]
+  for = "/*"
+  values = { X-Frame-Options = "DENY", Content-Security-Policy = "default-src 'self'" }
+
+[resolver]
+addresses = ["127.0.0.11"]
+"#,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            formatted,
+            r#"[[sites]]
+domain = 'api.example.com'
+ssl_cert = '/etc/ssl/cert.pem'
+ssl_key = '${file:key.path}'
+force_https = true
+
+[[sites.headers]]
+for = '/*'
+
+[sites.headers.values]
+Content-Security-Policy = "default-src 'self'"
+X-Frame-Options = 'DENY'
+[sites.overrides.dev]
+force_https = false
+
+[sites.overrides.prod]
+http2 = true
+
+[[sites]]
+domain = 'www.example.com'
+server_tokens = false
+
+[[sites.redirects]]
+from = '/old'
+to = '/new'
+
+[resolver]
+addresses = ['127.0.0.11']
+"#
+        );
+        assert_eq!(format(&formatted, false).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_strip_trailing_slashes() {
+        let content = r#"
+[[sites]]
+domain = "example.com"
+redirects = [
+    { from = "/old/", to = "/new/" },
+    { from = "^/blog/(.*)/$", to = "/posts/$1/", regex = true },
+    { from = "/shop//", to = "https://shop.example.com/" },
+    { from = "/search/", to = "/find/?q=" },
+]
+headers = [
+    { for = "/api/", values = { X-Robots-Tag = "noindex" } },
+    { for = "/", values = { X-Frame-Options = "DENY" } },
+]
+
+[sites.overrides.dev]
+redirects = [{ from = "/dev/", to = "/" }]
+"#;
+        let formatted = format(content, true).unwrap();
+        assert!(formatted.contains("from = '/old'\nto = '/new/'\n"));
+        assert!(formatted.contains("from = '^/blog/(.*)/$'\nto = '/posts/$1/'\n"));
+        assert!(formatted.contains("from = '/shop'\nto = 'https://shop.example.com/'\n"));
+        assert!(formatted.contains("from = '/search'\nto = '/find/?q='\n"));
+        assert!(formatted.contains("for = '/api'\n"));
+        assert!(formatted.contains("for = '/'\n"));
+        assert!(
+            formatted.contains("from = '/dev'\nto = '/'\n"),
+            "{}",
+            formatted
+        );
+        assert!(format(content, false).unwrap().contains("from = '/old/'"));
+    }
+}
@@ -0,0 +1,338 @@
+//! Checks rendered configs with `nginx -t`, in a temporary directory rather
+//! than the real config tree: a single site, or every file of a dry run.
+//! Also reloads the running nginx, for `--watch --reload`.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::{
+    command, templates, NvhostsError, Options, Syntax, UnverifiedConfig, COMMAND_TIMEOUT,
+    HTTP_FILENAME, MAIN_FILENAME, OUTPUT_DIR, STREAM_FILENAME,
+};
+
+/// The result of `nginx -t` on a single site.
+pub struct SiteCheck {
+    pub passed: bool,
+    /// The rendered site, along with the blocks it needs in the http context.
+    pub rendered: String,
+    /// What nginx printed.
+    pub output: String,
+}
+
+/// The result of `nginx -t` on the files of a dry run.
+pub struct FilesCheck {
+    pub passed: bool,
+    /// The file nginx complained about, as generated in the output directory,
+    /// if it named one.
+    pub file: Option<String>,
+    /// What nginx printed, with the paths of the temporary directory replaced
+    /// by the ones of the output directory.
+    pub output: String,
+}
+
+/// Renders the site of `domain` alone and runs `nginx -t` on it, using the
+/// `nginx` binary.
+pub fn check_site(
+    mut config: UnverifiedConfig,
+    options: &Options,
+    domain: &str,
+    nginx: &str,
+) -> Result<SiteCheck, NvhostsError> {
+    config.sites.retain(|site| site.domain == domain);
+    let config = config.validate(options)?;
+    let site = config
+        .sites
+        .first()
+        .ok_or_else(|| NvhostsError::UnknownSite {
+            domain: domain.to_string(),
+        })?;
+
+    let mut tera = templates(options)?;
+    let http = config.render_http(&mut tera)?;
+    let rendered = site.render(
+        &mut tera,
+        Syntax::for_version(&config.nginx_version),
+        &config.vars,
+    )?;
+
+    let dir = temp_dir();
+    let files = [
+        (String::from(HTTP_FILENAME), http.clone()),
+        (site.filename(), rendered.clone()),
+    ];
+    let result = run_nginx(
+        &dir,
+        &files,
+        nginx,
+        options.timeout.unwrap_or(COMMAND_TIMEOUT),
+    );
+    let _ = fs::remove_dir_all(&dir);
+    let (passed, output) = result?;
+
+    Ok(SiteCheck {
+        passed,
+        rendered: format!("{}\n{}", http, rendered).trim_start().to_string(),
+        output,
+    })
+}
+
+/// Runs `nginx -t` on `files`, the result of `dry_run`, written to a
+/// temporary directory, using the `nginx` binary, killed after `timeout`.
+pub fn check_files(
+    files: &[(String, String)],
+    nginx: &str,
+    timeout: Duration,
+) -> Result<FilesCheck, NvhostsError> {
+    let dir = temp_dir();
+    let result = run_nginx(&dir, files, nginx, timeout);
+    let _ = fs::remove_dir_all(&dir);
+    let (passed, output) = result?;
+
+    let output_dir = OUTPUT_DIR.trim_start_matches("./");
+    let output = output.replace(&format!("{}/", dir.display()), &format!("{}/", output_dir));
+    let file = files
+        .iter()
+        .map(|(path, _)| path)
+        .find(|path| output.contains(&format!("{}/{}:", output_dir, path)))
+        .cloned();
+    Ok(FilesCheck {
+        passed,
+        file,
+        output,
+    })
+}
+
+/// The result of `nginx -s reload`.
+pub struct Reload {
+    pub reloaded: bool,
+    /// How many times nginx was run.
+    pub attempts: u32,
+    /// What nginx printed the last time.
+    pub output: String,
+}
+
+/// Tells the running nginx to reload its config, using the `nginx` binary,
+/// killed after `timeout`. A reload failing for a reason that may be
+/// transient, such as nginx timing out or not running while it restarts, is
+/// tried again up to `retries` times, after `delay` then twice as long each
+/// time; one failing on an invalid config isn't.
+pub fn reload(
+    nginx: &str,
+    timeout: Duration,
+    retries: u32,
+    mut delay: Duration,
+) -> Result<Reload, NvhostsError> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let result = command::nginx(nginx, &["-s", "reload"], timeout);
+        let reason = match &result {
+            Ok((false, output)) if !is_config_error(output) => {
+                output.lines().next().unwrap_or_default().to_string()
+            }
+            Err(err @ NvhostsError::NginxTimeout { .. }) => err.to_string(),
+            _ => String::new(),
+        };
+        if reason.is_empty() || attempts > retries {
+            return result.map(|(reloaded, output)| Reload {
+                reloaded,
+                attempts,
+                output,
+            });
+        }
+        warn!(
+            "failed to reload nginx: {}, retrying in {:?}",
+            reason, delay
+        );
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+/// Whether nginx failed because of its config, which trying again doesn't
+/// fix, rather than e.g. a missing pid file.
+fn is_config_error(output: &str) -> bool {
+    output.contains("[emerg]")
+}
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("nvhosts-check-{}", process::id()))
+}
+
+/// Writes `files` to `dir`, along with a minimal nginx config including them,
+/// and tests it. The main tuning of `MAIN_FILENAME` and the streams of
+/// `STREAM_FILENAME` go in the main context, the other files in the http one.
+fn run_nginx(
+    dir: &Path,
+    files: &[(String, String)],
+    nginx: &str,
+    timeout: Duration,
+) -> Result<(bool, String), NvhostsError> {
+    let mut main = None;
+    let mut stream = None;
+    let mut includes = Vec::new();
+    for (path, content) in files.iter() {
+        let path_buf = dir.join(path);
+        if let Some(dir) = path_buf.parent() {
+            fs::create_dir_all(dir).map_err(NvhostsError::io(dir))?;
+        }
+        fs::write(&path_buf, content).map_err(NvhostsError::io(&path_buf))?;
+        if path == MAIN_FILENAME {
+            main = Some(path_buf);
+        } else if path == STREAM_FILENAME {
+            stream = Some(path_buf);
+        } else {
+            includes.push(path_buf);
+        }
+    }
+
+    let config = dir.join("nginx.conf");
+    fs::write(
+        &config,
+        main_config(main.as_deref(), stream.as_deref(), &includes),
+    )
+    .map_err(NvhostsError::io(&config))?;
+
+    command::nginx(
+        nginx,
+        &[
+            OsStr::new("-t"),
+            OsStr::new("-p"),
+            dir.as_os_str(),
+            OsStr::new("-c"),
+            config.as_os_str(),
+        ],
+        timeout,
+    )
+}
+
+/// A main config with just enough for `nginx -t` to check the `includes`,
+/// logging to stderr and keeping its pid file in the prefix directory. `main`
+/// replaces its empty `events` block, and `stream` is included next to it.
+fn main_config(main: Option<&Path>, stream: Option<&Path>, includes: &[PathBuf]) -> String {
+    let mut config = String::from("pid nginx.pid;\nerror_log stderr;\n\n");
+    match main {
+        Some(main) => config.push_str(&format!("include {};\n", main.display())),
+        None => config.push_str("events {}\n"),
+    }
+    if let Some(stream) = stream {
+        config.push_str(&format!("include {};\n", stream.display()));
+    }
+    config.push_str("\nhttp {\n");
+    for include in includes {
+        config.push_str(&format!("    include {};\n", include.display()));
+    }
+    config.push_str("}\n");
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_main_config() {
+        assert_eq!(
+            main_config(
+                None,
+                None,
+                &[
+                    PathBuf::from("/tmp/check/nvhosts-http.conf"),
+                    PathBuf::from("/tmp/check/example.com.conf"),
+                ]
+            ),
+            "pid nginx.pid;
+error_log stderr;
+
+events {}
+
+http {
+    include /tmp/check/nvhosts-http.conf;
+    include /tmp/check/example.com.conf;
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_main_config_main() {
+        let config = main_config(
+            Some(Path::new("/tmp/check/nvhosts-main.conf")),
+            None,
+            &[PathBuf::from("/tmp/check/example.com.conf")],
+        );
+        assert!(config.contains("\ninclude /tmp/check/nvhosts-main.conf;\n\nhttp {\n"));
+        assert!(!config.contains("events"));
+
+        let config = main_config(
+            None,
+            Some(Path::new("/tmp/check/nvhosts-stream.conf")),
+            &[PathBuf::from("/tmp/check/example.com.conf")],
+        );
+        assert!(config.contains("\nevents {}\ninclude /tmp/check/nvhosts-stream.conf;\n\nhttp {\n"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-reload-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Fails once as if nginx weren't running, then succeeds.
+        let flaky = dir.join("flaky-nginx");
+        fs::write(
+            &flaky,
+            format!(
+                "#!/bin/sh\nif [ -e {0}/ran ]; then exit 0; fi\ntouch {0}/ran\necho 'nginx: [error] open() \"/run/nginx.pid\" failed' >&2\nexit 1\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        let invalid = dir.join("invalid-nginx");
+        fs::write(
+            &invalid,
+            "#!/bin/sh\necho 'nginx: [emerg] unknown directive \"x\"' >&2\nexit 1\n",
+        )
+        .unwrap();
+        for path in [&flaky, &invalid] {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let delay = Duration::from_millis(1);
+        let reload = super::reload(flaky.to_str().unwrap(), COMMAND_TIMEOUT, 0, delay).unwrap();
+        assert!(!reload.reloaded);
+        assert!(reload.output.contains("nginx.pid"));
+        fs::remove_file(dir.join("ran")).unwrap();
+        let reload = super::reload(flaky.to_str().unwrap(), COMMAND_TIMEOUT, 2, delay).unwrap();
+        assert!(reload.reloaded);
+        assert_eq!(reload.attempts, 2);
+
+        let reload = super::reload(invalid.to_str().unwrap(), COMMAND_TIMEOUT, 2, delay).unwrap();
+        assert!(!reload.reloaded);
+        assert_eq!(reload.attempts, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_unknown_site() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+
+        match check_site(config, &Options::default(), "example.org", "nginx") {
+            Err(NvhostsError::UnknownSite { domain }) => assert_eq!(domain, "example.org"),
+            _ => panic!("expected an unknown site error"),
+        }
+    }
+}
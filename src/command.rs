@@ -0,0 +1,144 @@
+//! External commands, nginx and the hooks, run with a time limit so that one
+//! that hangs, e.g. on a prompt or the network, doesn't hang nvhosts too.
+
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::NvhostsError;
+
+/// How often a running command is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why a command didn't complete.
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// It couldn't be started, or waited for.
+    Io(io::Error),
+    /// It ran longer than the timeout, and was killed.
+    TimedOut,
+}
+
+/// What a completed command printed, when its output is piped.
+#[derive(Debug)]
+pub(crate) struct Completed {
+    pub(crate) status: ExitStatus,
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+}
+
+/// Runs `command`, reading its stdout and stderr if they are piped, and kills
+/// it once it runs longer than `timeout`.
+pub(crate) fn run(command: &mut Command, timeout: Duration) -> Result<Completed, Error> {
+    let mut child = command.spawn().map_err(Error::Io)?;
+    // Read on their own threads, so that a command filling a pipe doesn't
+    // block before it exits.
+    let stdout = child.stdout.take().map(read_all);
+    let stderr = child.stderr.take().map(read_all);
+
+    let status = match wait(&mut child, timeout) {
+        Ok(status) => status,
+        Err(err) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            // The readers are left behind: a process the command started
+            // may still hold its pipes.
+            return Err(err);
+        }
+    };
+    let join = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+        reader.map_or_else(Vec::new, |reader| reader.join().unwrap_or_default())
+    };
+    Ok(Completed {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+/// Runs the `nginx` binary with `args`, and returns whether it succeeded and
+/// what it printed, on stderr then stdout.
+pub(crate) fn nginx<S: AsRef<OsStr>>(
+    nginx: &str,
+    args: &[S],
+    timeout: Duration,
+) -> Result<(bool, String), NvhostsError> {
+    let completed = run(
+        Command::new(nginx)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        timeout,
+    )
+    .map_err(|err| match err {
+        Error::Io(source) => NvhostsError::Nginx {
+            nginx: nginx.to_string(),
+            source,
+        },
+        Error::TimedOut => NvhostsError::NginxTimeout {
+            nginx: nginx.to_string(),
+            timeout,
+        },
+    })?;
+
+    let mut printed = String::from_utf8_lossy(&completed.stderr).into_owned();
+    printed.push_str(&String::from_utf8_lossy(&completed.stdout));
+    Ok((completed.status.success(), printed))
+}
+
+fn wait(child: &mut Child, timeout: Duration) -> Result<ExitStatus, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().map_err(Error::Io)? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::TimedOut);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn read_all<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut content = Vec::new();
+        let _ = pipe.read_to_end(&mut content);
+        content
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::COMMAND_TIMEOUT;
+
+    #[test]
+    fn test_run() {
+        let completed = run(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo out; echo err >&2; exit 3")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            COMMAND_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(completed.status.code(), Some(3));
+        assert_eq!(completed.stdout, b"out\n");
+        assert_eq!(completed.stderr, b"err\n");
+
+        let started = Instant::now();
+        let err = run(Command::new("sleep").arg("10"), Duration::from_millis(100))
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::TimedOut));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let err = nginx("/nonexistent/nginx", &["-t"], COMMAND_TIMEOUT)
+            .err()
+            .unwrap();
+        assert!(matches!(err, NvhostsError::Nginx { .. }));
+    }
+}
@@ -0,0 +1,437 @@
+//! Shell completion scripts, generated from the help of the command line so
+//! that they follow its options and subcommands.
+
+use std::str::FromStr;
+
+/// A shell `completions` writes a script for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            _ => Err(format!("expected bash, zsh or fish, got {:?}", s)),
+        }
+    }
+}
+
+/// An option of a command, e.g. `-c, --config <config>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flag {
+    /// Its short name, without the dash.
+    pub short: Option<char>,
+    /// Its long name, without the dashes.
+    pub long: String,
+    /// Whether it is followed by a value.
+    pub value: bool,
+    pub description: String,
+}
+
+/// A command of the command line, with its options and subcommands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub name: String,
+    pub description: String,
+    pub flags: Vec<Flag>,
+    pub commands: Vec<CommandSpec>,
+}
+
+/// Reads the options of a command and the names and descriptions of its
+/// subcommands from its `--help`, as printed by argh.
+pub fn parse_help(help: &str) -> (Vec<Flag>, Vec<(String, String)>) {
+    let usage = help
+        .lines()
+        .find_map(|line| line.strip_prefix("Usage: "))
+        .unwrap_or_default();
+
+    let mut flags: Vec<Flag> = Vec::new();
+    let mut commands: Vec<(String, String)> = Vec::new();
+    let mut section = "";
+    for line in help.lines() {
+        if !line.starts_with(' ') {
+            section = line.trim_end_matches(':');
+            continue;
+        }
+        let (names, description) = match line.trim_start().split_once("  ") {
+            Some((names, description)) => (names, description.trim()),
+            None => (line.trim(), ""),
+        };
+        // Continuation lines of a description are indented past the names.
+        if line.starts_with("   ") {
+            let last = match section {
+                "Options" => flags.last_mut().map(|flag| &mut flag.description),
+                "Commands" => commands.last_mut().map(|(_, description)| description),
+                _ => None,
+            };
+            if let Some(last) = last {
+                // Long names have their description on the next line.
+                if !last.is_empty() {
+                    last.push(' ');
+                }
+                last.push_str(line.trim());
+            }
+            continue;
+        }
+        match section {
+            "Options" => {
+                let mut short = None;
+                let mut long = None;
+                for name in names.split(", ") {
+                    if let Some(name) = name.strip_prefix("--") {
+                        long = Some(name.to_string());
+                    } else if let Some(name) = name.strip_prefix('-') {
+                        short = name.chars().next();
+                    }
+                }
+                let Some(long) = long else { continue };
+                if long == "help" {
+                    continue;
+                }
+                // Options taking a value are followed by it in the usage,
+                // under their short name when they have one.
+                let name = match short {
+                    Some(short) => format!("-{} <", short),
+                    None => format!("--{} <", long),
+                };
+                let value = [" ", "["]
+                    .iter()
+                    .any(|before| usage.contains(&format!("{}{}", before, name)));
+                flags.push(Flag {
+                    short,
+                    value,
+                    long,
+                    description: description.to_string(),
+                });
+            }
+            "Commands" => commands.push((names.to_string(), description.to_string())),
+            _ => {}
+        }
+    }
+    (flags, commands)
+}
+
+/// The completion script of `command` for `shell`.
+pub fn script(shell: Shell, command: &CommandSpec) -> String {
+    let mut paths = Vec::new();
+    collect(command, &mut Vec::new(), &mut paths);
+    match shell {
+        Shell::Bash => bash(&command.name, &paths),
+        Shell::Zsh => zsh(&command.name, &paths),
+        Shell::Fish => fish(&command.name, &paths),
+    }
+}
+
+/// Lists `command` and its subcommands, each with the names leading to it.
+fn collect<'a>(
+    command: &'a CommandSpec,
+    path: &mut Vec<&'a str>,
+    paths: &mut Vec<(String, &'a CommandSpec)>,
+) {
+    path.push(&command.name);
+    paths.push((path.join(" "), command));
+    for subcommand in command.commands.iter() {
+        collect(subcommand, path, paths);
+    }
+    path.pop();
+}
+
+/// The words completing the options of `command`, then its subcommands.
+fn words(command: &CommandSpec) -> (Vec<String>, Vec<String>) {
+    let mut options = Vec::new();
+    let mut values = Vec::new();
+    for flag in command.flags.iter() {
+        let mut names = vec![format!("--{}", flag.long)];
+        if let Some(short) = flag.short {
+            names.insert(0, format!("-{}", short));
+        }
+        if flag.value {
+            values.extend(names.iter().cloned());
+        }
+        options.extend(names);
+    }
+    options.push(String::from("--help"));
+    (options, values)
+}
+
+fn subcommands(command: &CommandSpec) -> String {
+    command
+        .commands
+        .iter()
+        .map(|command| command.name.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn function_name(name: &str) -> String {
+    format!("_{}", name.replace('-', "_"))
+}
+
+fn bash(name: &str, paths: &[(String, &CommandSpec)]) -> String {
+    let function = function_name(name);
+    let mut script = format!(
+        "{function}() {{
+    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+    local command=\"{name}\" i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case \"$command ${{COMP_WORDS[i]}}\" in
+"
+    );
+    let nested: Vec<&str> = paths
+        .iter()
+        .skip(1)
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !nested.is_empty() {
+        script.push_str(&format!(
+            "            \"{}\") command=\"$command ${{COMP_WORDS[i]}}\" ;;\n",
+            nested.join("\" | \"")
+        ));
+    }
+    script.push_str(
+        "        esac
+    done
+
+    local options values commands
+    case \"$command\" in
+",
+    );
+    for (path, command) in paths {
+        let (options, values) = words(command);
+        script.push_str(&format!(
+            "        \"{}\")\n            options=\"{}\"\n            values=\"{}\"\n            commands=\"{}\"\n            ;;\n",
+            path,
+            options.join(" "),
+            values.join(" "),
+            subcommands(command)
+        ));
+    }
+    script.push_str(&format!(
+        "    esac
+
+    if [[ \" $values \" == *\" $prev \"* ]]; then
+        COMPREPLY=($(compgen -f -- \"$cur\"))
+    elif [[ \"$cur\" == -* ]]; then
+        COMPREPLY=($(compgen -W \"$options\" -- \"$cur\"))
+    elif [[ -n \"$commands\" ]]; then
+        COMPREPLY=($(compgen -W \"$commands\" -- \"$cur\"))
+    else
+        COMPREPLY=($(compgen -f -- \"$cur\"))
+    fi
+}}
+
+complete -o filenames -F {function} {name}
+"
+    ));
+    script
+}
+
+fn zsh(name: &str, paths: &[(String, &CommandSpec)]) -> String {
+    let function = function_name(name);
+    let mut script = format!(
+        "#compdef {name}
+
+{function}() {{
+    local command=\"{name}\" i
+    for ((i = 2; i < CURRENT; i++)); do
+        case \"$command ${{words[i]}}\" in
+"
+    );
+    let nested: Vec<&str> = paths
+        .iter()
+        .skip(1)
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !nested.is_empty() {
+        script.push_str(&format!(
+            "            \"{}\") command=\"$command ${{words[i]}}\" ;;\n",
+            nested.join("\" | \"")
+        ));
+    }
+    script.push_str(
+        "        esac
+    done
+
+    local -a options values commands
+    case \"$command\" in
+",
+    );
+    for (path, command) in paths {
+        let (options, values) = words(command);
+        let commands: Vec<String> = command
+            .commands
+            .iter()
+            .map(|command| format!("'{}:{}'", command.name, quote(&command.description)))
+            .collect();
+        script.push_str(&format!(
+            "        \"{}\")\n            options=({})\n            values=({})\n            commands=({})\n            ;;\n",
+            path,
+            options.join(" "),
+            values.join(" "),
+            commands.join(" ")
+        ));
+    }
+    script.push_str(&format!(
+        "    esac
+
+    if (( ${{values[(Ie)${{words[CURRENT-1]}}]}} )); then
+        _files
+    elif [[ \"$PREFIX\" == -* ]]; then
+        compadd -a options
+    elif (( ${{#commands}} )); then
+        _describe command commands
+    else
+        _files
+    fi
+}}
+
+if [ \"$funcstack[1]\" = \"{function}\" ]; then
+    {function} \"$@\"
+else
+    compdef {function} {name}
+fi
+"
+    ));
+    script
+}
+
+fn fish(name: &str, paths: &[(String, &CommandSpec)]) -> String {
+    let mut script = format!("complete -c {} -f\n", name);
+    for (path, command) in paths {
+        // The command is the last one seen when its names, but none of its
+        // subcommands, were.
+        let names: Vec<&str> = path.split(' ').skip(1).collect();
+        let mut conditions: Vec<String> = names
+            .iter()
+            .map(|name| format!("__fish_seen_subcommand_from {}", name))
+            .collect();
+        if !command.commands.is_empty() {
+            conditions.push(format!(
+                "not __fish_seen_subcommand_from {}",
+                subcommands(command)
+            ));
+        }
+        let condition = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" -n '{}'", conditions.join("; and ")),
+        };
+        for flag in command.flags.iter() {
+            let mut line = format!("complete -c {}{}", name, condition);
+            if let Some(short) = flag.short {
+                line.push_str(&format!(" -s {}", short));
+            }
+            line.push_str(&format!(" -l {}", flag.long));
+            if flag.value {
+                line.push_str(" -r -F");
+            }
+            line.push_str(&format!(" -d '{}'\n", quote(&flag.description)));
+            script.push_str(&line);
+        }
+        for subcommand in command.commands.iter() {
+            script.push_str(&format!(
+                "complete -c {}{} -a {} -d '{}'\n",
+                name,
+                condition,
+                subcommand.name,
+                quote(&subcommand.description)
+            ));
+        }
+        if command.commands.is_empty() && !names.is_empty() {
+            script.push_str(&format!("complete -c {}{} -F\n", name, condition));
+        }
+    }
+    script
+}
+
+/// Escapes `description` for single quotes, where zsh and fish both take
+/// `'\''` for a quote.
+fn quote(description: &str) -> String {
+    description.replace('\'', "'\\''")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_help() {
+        let help = "Usage: nvhosts [-c <config>] [--dry-run] [--nginx <nginx>] [<command>] [<args>]
+
+Generate nginx vhosts from a configuration file
+
+Options:
+  -c, --config      path to config file to use, or http(s) URL to fetch it from;
+                    defaults to nvhosts.toml
+  --dry-run         render the files without writing them
+  --nginx           nginx binary run by --check
+  --help, help      display usage information
+
+Commands:
+  lint              Print advice on risky or redundant parts of the config
+";
+        let (flags, commands) = parse_help(help);
+        assert_eq!(
+            flags,
+            [
+                Flag {
+                    short: Some('c'),
+                    long: String::from("config"),
+                    value: true,
+                    description: String::from(
+                        "path to config file to use, or http(s) URL to fetch it from; defaults to nvhosts.toml"
+                    ),
+                },
+                Flag {
+                    short: None,
+                    long: String::from("dry-run"),
+                    value: false,
+                    description: String::from("render the files without writing them"),
+                },
+                Flag {
+                    short: None,
+                    long: String::from("nginx"),
+                    value: true,
+                    description: String::from("nginx binary run by --check"),
+                },
+            ]
+        );
+        assert_eq!(
+            commands,
+            [(
+                String::from("lint"),
+                String::from("Print advice on risky or redundant parts of the config")
+            )]
+        );
+
+        let command = CommandSpec {
+            name: String::from("nvhosts"),
+            description: String::new(),
+            flags,
+            commands: vec![CommandSpec {
+                name: String::from("lint"),
+                description: commands[0].1.clone(),
+                flags: Vec::new(),
+                commands: Vec::new(),
+            }],
+        };
+        let bash = script(Shell::Bash, &command);
+        assert!(bash.contains("            \"nvhosts lint\") command="));
+        assert!(bash.contains(
+            "options=\"-c --config --dry-run --nginx --help\"\n            values=\"-c --config --nginx\"\n            commands=\"lint\""
+        ));
+        assert!(bash.ends_with("complete -o filenames -F _nvhosts nvhosts\n"));
+        assert!(script(Shell::Zsh, &command)
+            .contains("commands=('lint:Print advice on risky or redundant parts of the config')"));
+        assert!(script(Shell::Fish, &command).contains(
+            "complete -c nvhosts -n 'not __fish_seen_subcommand_from lint' -s c -l config -r -F -d"
+        ));
+    }
+}
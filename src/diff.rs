@@ -0,0 +1,181 @@
+//! Unified diffs between the files on disk and the ones a run would write,
+//! for `--diff`.
+
+/// Lines of context around the changes of a hunk.
+const CONTEXT: usize = 3;
+
+/// Above this many cells of the table of common lines, the changed lines are
+/// shown as removed then added as a whole, rather than matched.
+const MAX_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// The unified diff turning `old` into `new`, with `path` in its headers, or
+/// an empty string when they are the same.
+pub fn unified(path: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let ops = ops(&old, &new);
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", path, path);
+    // Positions in `old` and `new` before each op.
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut o, mut n) = (0, 0);
+    for (op, _) in ops.iter() {
+        positions.push((o, n));
+        match op {
+            Op::Equal => (o, n) = (o + 1, n + 1),
+            Op::Delete => o += 1,
+            Op::Insert => n += 1,
+        }
+    }
+    positions.push((o, n));
+
+    let changes: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].0 != Op::Equal).collect();
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes closer than twice the context share a hunk.
+        let mut last = i;
+        while last + 1 < changes.len() && changes[last + 1] - changes[last] <= 2 * CONTEXT {
+            last += 1;
+        }
+        let start = changes[i].saturating_sub(CONTEXT);
+        let end = (changes[last] + CONTEXT + 1).min(ops.len());
+
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        ));
+        for (op, line) in ops[start..end].iter() {
+            let prefix = match op {
+                Op::Equal => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            };
+            diff.push_str(&format!("{}{}\n", prefix, line));
+        }
+        i = last + 1;
+    }
+    diff
+}
+
+/// The `start,count` of a hunk, 1-based, or after the line before an empty
+/// range.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+/// The lines of `old` and `new` as kept, removed or added, keeping the
+/// longest run of common lines.
+fn ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(Op, &str)> = old[..prefix]
+        .iter()
+        .map(|line| (Op::Equal, *line))
+        .collect();
+    if (old_middle.len() + 1) * (new_middle.len() + 1) > MAX_CELLS {
+        ops.extend(old_middle.iter().map(|line| (Op::Delete, *line)));
+        ops.extend(new_middle.iter().map(|line| (Op::Insert, *line)));
+    } else {
+        // lengths[i][j] is the length of the longest common subsequence of
+        // old_middle[i..] and new_middle[j..].
+        let width = new_middle.len() + 1;
+        let mut lengths = vec![0u32; (old_middle.len() + 1) * width];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                lengths[i * width + j] = if old_middle[i] == new_middle[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() || j < new_middle.len() {
+            if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+                ops.push((Op::Equal, old_middle[i]));
+                (i, j) = (i + 1, j + 1);
+            } else if j == new_middle.len()
+                || (i < old_middle.len()
+                    && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+            {
+                ops.push((Op::Delete, old_middle[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Insert, new_middle[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| (Op::Equal, *line)),
+    );
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified() {
+        assert_eq!(unified("a.conf", "x\n", "x\n"), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n11\n12\n13\n";
+        assert_eq!(
+            unified("a.conf", old, new),
+            "--- a/a.conf
++++ b/a.conf
+@@ -2,7 +2,7 @@
+ 2
+ 3
+ 4
+-5
++five
+ 6
+ 7
+ 8
+@@ -10,3 +10,4 @@
+ 10
+ 11
+ 12
++13
+"
+        );
+
+        assert_eq!(
+            unified("new.conf", "", "server {}\n"),
+            "--- a/new.conf\n+++ b/new.conf\n@@ -0,0 +1,1 @@\n+server {}\n"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Errors returned by validation and generation.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum NvhostsError {
+    /// Validation found problems in the config, all of them listed.
+    #[error("invalid config:{}", list(.0))]
+    Invalid(Vec<ValidationError>),
+    /// The config file isn't valid TOML, or doesn't fit the fields of the
+    /// config. `site` is the entry of `sites` at fault, if any.
+    #[error("{}{message}", match site { Some(site) => format!("{}: ", site), None => String::new() })]
+    Parse {
+        site: Option<String>,
+        message: String,
+    },
+    /// The overrides of a profile don't fit the fields they replace.
+    #[error("{domain:?}: invalid overrides for profile {profile:?}: {message}")]
+    Profile {
+        domain: String,
+        profile: String,
+        message: String,
+    },
+    /// The `include` of a site names a fragment that is missing, invalid, or
+    /// includes itself.
+    #[error("{domain:?}: invalid include: {message}")]
+    Include { domain: String, message: String },
+    /// Rendering a template failed, e.g. a header value using an unknown
+    /// variable. `domain` is the site being rendered, if any.
+    #[error("{}couldn't render {name}: {}", site(domain), chain(source))]
+    Template {
+        domain: Option<String>,
+        name: String,
+        #[source]
+        source: tera::Error,
+    },
+    /// A template of `Options::template_dir` is invalid, e.g. it doesn't
+    /// parse or extends an unknown template.
+    #[error("couldn't load the templates of {}: {}", dir.display(), chain(source))]
+    TemplateDir {
+        dir: PathBuf,
+        #[source]
+        source: tera::Error,
+    },
+    /// No site of the config, in the selected profile, has this domain.
+    #[error("{domain:?}: no such site")]
+    UnknownSite { domain: String },
+    /// A `${file:<path>}` reference of a config value can't be read.
+    #[error("{field}: couldn't read {}: {source}", path.display())]
+    FileReference {
+        field: String,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// A remote config can't be fetched.
+    #[error("couldn't fetch {url}: {message}")]
+    Fetch { url: String, message: String },
+    /// A file of the config's `sites_dir`, or the directory itself, can't be
+    /// read or parsed.
+    #[error("{}: {message}", path.display())]
+    SitesFile { path: PathBuf, message: String },
+    /// A file of template variables can't be read, or isn't a table of
+    /// strings, numbers and booleans.
+    #[error("{}: {message}", path.display())]
+    VarsFile { path: PathBuf, message: String },
+    /// A `${VAR}` reference names an environment variable that isn't set.
+    #[error("{field}: environment variable {name} isn't set")]
+    EnvVar { field: String, name: String },
+    /// The output directory holds files that weren't generated by nvhosts.
+    #[error("{} holds files not generated by nvhosts:{}", dir.display(), list_paths(paths))]
+    Unmanaged { dir: PathBuf, paths: Vec<PathBuf> },
+    /// The nginx binary couldn't be run.
+    #[error("couldn't run {nginx}: {source}")]
+    Nginx {
+        nginx: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The nginx binary ran longer than `Options::timeout`, and was killed.
+    #[error("{nginx} didn't complete within {}s, killed", timeout.as_secs_f64())]
+    NginxTimeout { nginx: String, timeout: Duration },
+    /// A command of the config's `hooks` couldn't be run.
+    #[error("couldn't run hook {command:?}: {source}")]
+    Hook {
+        command: String,
+        #[source]
+        source: io::Error,
+    },
+    /// A command of the config's `hooks` exited with a failure.
+    #[error("hook {command:?} failed: {status}")]
+    HookFailed { command: String, status: ExitStatus },
+    /// A command of the config's `hooks` ran longer than `Options::timeout`,
+    /// and was killed.
+    #[error("hook {command:?} didn't complete within {}s, killed", timeout.as_secs_f64())]
+    HookTimeout { command: String, timeout: Duration },
+    /// Sites failed with `Options::render_errors_inline`, and were written
+    /// as placeholders commenting out their errors.
+    #[error("{} written as placeholders:{}", sites(.0.len()), failures(.0))]
+    Placeholders(Vec<NvhostsError>),
+    #[error("couldn't write {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// A problem found by validation.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ValidationError {
+    #[error("{domain:?}: invalid domain")]
+    InvalidDomain { domain: String },
+    /// Several sites have the same domain, and would be written to the same
+    /// file.
+    #[error("{domain:?}: defined more than once")]
+    DuplicateDomain { domain: String },
+    /// A field of a site has an invalid value, or contradicts another one.
+    #[error("{domain:?}: {message}")]
+    Site {
+        domain: String,
+        field: &'static str,
+        message: String,
+    },
+    /// A field outside of the sites has an invalid value, e.g. a map.
+    #[error("{message}")]
+    Config {
+        field: &'static str,
+        message: String,
+    },
+    /// A warning, turned into an error by `Options::strict`.
+    #[error("{0}")]
+    Warning(Warning),
+    /// Stands for the errors left out by `Options::max_errors`.
+    #[error("and {0} more")]
+    Omitted(usize),
+    /// Stands for the errors of `Options::quiet_errors`, or the warnings
+    /// turned into errors under `Options::no_warn`.
+    #[error("{} not shown", counts(*errors, *warnings))]
+    Counted { errors: usize, warnings: usize },
+}
+
+impl ValidationError {
+    /// `errors` counted by a single `ValidationError::Counted`, the warnings
+    /// turned into errors apart from the others.
+    pub fn counted(errors: &[ValidationError]) -> Self {
+        let (mut count, mut warnings) = (0, 0);
+        for error in errors {
+            match error {
+                ValidationError::Warning(_) => warnings += 1,
+                ValidationError::Counted {
+                    errors: e,
+                    warnings: w,
+                } => {
+                    count += e;
+                    warnings += w;
+                }
+                _ => count += 1,
+            }
+        }
+        ValidationError::Counted {
+            errors: count,
+            warnings,
+        }
+    }
+}
+
+/// What a validation warning is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// A field is set, but has no effect with the rest of the config.
+    NoEffect,
+    /// A field repeats what another one already does.
+    Redundant,
+    /// A value was fixed up to what it likely meant, e.g. a missing slash.
+    Corrected,
+    /// A feature needs a module nginx wasn't built with.
+    MissingModule,
+    /// A field isn't supported on this platform, and is ignored.
+    Unsupported,
+    /// The upstream of a site doesn't accept connections, and the site is
+    /// left out, with `Options::skip_unreachable`.
+    Unreachable,
+}
+
+/// A problem found by validation that doesn't prevent generation, unless
+/// `Options::strict` is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// The site at fault, or `None` for the fields outside of the sites.
+    pub domain: Option<String>,
+    pub message: String,
+}
+
+impl Warning {
+    pub(crate) fn site(kind: WarningKind, domain: &str, message: String) -> Self {
+        Self {
+            kind,
+            domain: Some(domain.to_string()),
+            message,
+        }
+    }
+
+    pub(crate) fn config(kind: WarningKind, message: &str) -> Self {
+        Self {
+            kind,
+            domain: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.domain {
+            Some(domain) => write!(f, "{:?}: {}", domain, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl ValidationError {
+    pub(crate) fn site(domain: &str, field: &'static str, message: String) -> Self {
+        Self::Site {
+            domain: domain.to_string(),
+            field,
+            message,
+        }
+    }
+}
+
+impl NvhostsError {
+    pub(crate) fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| Self::Io { path, source }
+    }
+}
+
+fn counts(errors: usize, warnings: usize) -> String {
+    let plural = |count: usize, thing: &str| match count {
+        1 => format!("1 {}", thing),
+        _ => format!("{} {}s", count, thing),
+    };
+    match (errors, warnings) {
+        (_, 0) => plural(errors, "error"),
+        (0, _) => plural(warnings, "warning"),
+        _ => format!(
+            "{} and {}",
+            plural(errors, "error"),
+            plural(warnings, "warning")
+        ),
+    }
+}
+
+fn list(errors: &[ValidationError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("\n  {}", error))
+        .collect()
+}
+
+fn sites(count: usize) -> String {
+    match count {
+        1 => String::from("1 site"),
+        _ => format!("{} sites", count),
+    }
+}
+
+/// The errors of `Placeholders`, the ones of validation one per line.
+fn failures(errors: &[NvhostsError]) -> String {
+    errors
+        .iter()
+        .map(|error| match error {
+            NvhostsError::Invalid(errors) => list(errors),
+            error => format!("\n  {}", error),
+        })
+        .collect()
+}
+
+fn list_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| format!("\n  {}", path.display()))
+        .collect()
+}
+
+fn site(domain: &Option<String>) -> String {
+    match domain {
+        Some(domain) => format!("{:?}: ", domain),
+        None => String::new(),
+    }
+}
+
+/// Tera puts the actual reason of a failure in the sources of its errors,
+/// wrapped in errors that only repeat the template being rendered: these are
+/// left out, along with the name Tera gives to one-off templates such as
+/// header values.
+pub(crate) fn chain(error: &tera::Error) -> String {
+    let mut messages = Vec::new();
+    let mut source: Option<&dyn std::error::Error> = Some(error);
+    while let Some(error) = source {
+        let message = error.to_string();
+        source = error.source();
+        if !(message.starts_with("Failed to render '") && source.is_some()) {
+            messages.push(message.replace(&format!(" while rendering '{}'", ONE_OFF), ""));
+        }
+    }
+    messages.join(": ")
+}
+
+/// The name of the templates rendered by `Tera::render_str`.
+const ONE_OFF: &str = "__tera_one_off";
@@ -0,0 +1,444 @@
+//! A plain English summary of what the generated config of a site does, for
+//! reviewers who'd rather not read nginx directives.
+
+use crate::{
+    is_server_location, BackendType, MtlsVerify, NvhostsError, Options, PathMode, Site,
+    UnverifiedConfig,
+};
+
+/// Validates `config`, with the profile and file references applied like for
+/// generation, and describes the site of `domain`, one feature per line.
+pub fn explain(
+    config: UnverifiedConfig,
+    options: &Options,
+    domain: &str,
+) -> Result<String, NvhostsError> {
+    let config = config.validate(options)?;
+    let site = config
+        .sites
+        .iter()
+        .find(|site| site.domain == domain)
+        .ok_or_else(|| NvhostsError::UnknownSite {
+            domain: domain.to_string(),
+        })?;
+
+    let mut explanation = format!("{}:\n", site.domain);
+    if let Some(description) = &site.description {
+        for line in description.lines() {
+            explanation.push_str(&format!("  # {}\n", line));
+        }
+    }
+    for feature in features(site) {
+        explanation.push_str(&format!("  - {}\n", feature));
+    }
+    Ok(explanation)
+}
+
+/// Count of `things`, e.g. `3 headers`, or `1 header`.
+fn plural(count: usize, thing: &str) -> String {
+    match count {
+        1 => format!("1 {}", thing),
+        _ => format!("{} {}s", count, thing),
+    }
+}
+
+fn features(site: &Site) -> Vec<String> {
+    let mut features = Vec::new();
+
+    if let Some(names) = &site.server_names {
+        let names: Vec<String> = names
+            .iter()
+            .map(|name| match name.regex {
+                true => format!("hosts matching {}", name.name),
+                false => name.name.clone(),
+            })
+            .collect();
+        features.push(format!("also answers {}", names.join(", ")));
+    }
+    if let Some(listen) = &site.listen {
+        features.push(format!("listens on {} only", listen.join(", ")));
+    }
+    match (&site.ssl_cert, site.force_https) {
+        (Some(cert), force_https) => {
+            let source = if site.letsencrypt {
+                "the Let's Encrypt certificate"
+            } else {
+                "the certificate"
+            };
+            features.push(format!("serves HTTPS with {} {}", source, cert));
+            if force_https {
+                features.push(String::from(
+                    "HTTPS enforced: plain HTTP requests are redirected (301)",
+                ));
+            }
+        }
+        (None, _) => features.push(String::from("serves plain HTTP only")),
+    }
+    if let Some(exclude) = &site.https_redirect_exclude {
+        features.push(format!(
+            "still serves {} over plain HTTP",
+            exclude.join(", ")
+        ));
+    }
+    if site.default_server {
+        features.push(String::from(
+            "default server: also answers the requests for hosts no other site serves",
+        ));
+    }
+    if site.http2 {
+        features.push(String::from("HTTP/2 enabled"));
+    }
+    if site.http3 {
+        features.push(String::from("HTTP/3 enabled"));
+    }
+    if let Some(mtls) = &site.mtls {
+        let verify = match mtls.verify {
+            MtlsVerify::On => "requires",
+            MtlsVerify::Optional => "asks for, without requiring,",
+        };
+        features.push(format!(
+            "{} client certificates signed by {}",
+            verify, mtls.client_ca
+        ));
+    }
+
+    if let Some(redirect_to) = &site.redirect_to {
+        let path = match site.path_mode.unwrap_or_default() {
+            PathMode::Preserve => String::from("keeping the path"),
+            PathMode::Drop => String::from("dropping the path"),
+            PathMode::Rewrite => format!(
+                "mapping paths with {}",
+                plural(site.path_rewrites.iter().flatten().count(), "rewrite")
+            ),
+        };
+        features.push(format!(
+            "redirects every request to {}, {} (301)",
+            redirect_to, path
+        ));
+    } else if site.maintenance {
+        let allowed = site.maintenance_allow.iter().flatten().count();
+        let mut feature = String::from("in maintenance: answers every request with a 503");
+        if allowed > 0 {
+            feature.push_str(&format!(
+                ", except for {}",
+                plural(allowed, "allowed address")
+            ));
+        }
+        features.push(feature);
+    }
+    for error_page in site.error_pages.iter().flatten() {
+        let codes: Vec<String> = error_page.codes.iter().map(u16::to_string).collect();
+        let mut feature = format!(
+            "serves {} in place of the {} errors",
+            error_page.page,
+            codes.join(", ")
+        );
+        if site.proxy_intercept_errors && site.root.is_none() {
+            feature.push_str(", the upstream's included");
+        }
+        features.push(feature);
+    }
+    match (&site.upstream, &site.root) {
+        _ if site.redirect_to.is_some() => {}
+        _ if site.redirects_only => features.push(String::from(
+            "answers the paths it doesn't redirect with a 404",
+        )),
+        (Some(upstream), _) => {
+            let mut feature = match upstream.backend_type {
+                BackendType::Proxy => format!("proxies to {}", upstream.url),
+                BackendType::Fastcgi => format!("passes requests to FastCGI at {}", upstream.url),
+                BackendType::Uwsgi => format!("passes requests to uWSGI at {}", upstream.url),
+            };
+            if let Some(servers) = &upstream.servers {
+                feature.push_str(&format!(
+                    ", balanced between {}",
+                    plural(servers.len(), "server")
+                ));
+                if upstream.health_check.is_some() {
+                    feature.push_str(" checked periodically");
+                }
+            }
+            if upstream.dynamic {
+                feature.push_str(", resolved at request time");
+            }
+            if let Some(split) = &site.split {
+                let targets: Vec<String> = split
+                    .targets
+                    .iter()
+                    .map(|target| format!("{} to {}", target.percent, target.url))
+                    .collect();
+                feature.push_str(&format!(
+                    ", except for {} of the traffic, by {}",
+                    targets.join(" and "),
+                    split.key
+                ));
+            }
+            features.push(feature);
+        }
+        (None, Some(root)) => {
+            let mut feature = format!("serves the files of {}", root);
+            let precompressed: Vec<&str> = [(".gz", site.gzip_static), (".br", site.brotli_static)]
+                .iter()
+                .filter(|(_, set)| *set)
+                .map(|(extension, _)| *extension)
+                .collect();
+            if !precompressed.is_empty() {
+                feature.push_str(&format!(
+                    ", preferring the precompressed {} files",
+                    precompressed.join(" and ")
+                ));
+            }
+            if site.static_optimize {
+                feature.push_str(", with sendfile and an open file cache");
+            }
+            if site.etag == Some(false) {
+                feature.push_str(", without ETags");
+            }
+            if let Some(if_modified_since) = site.if_modified_since {
+                feature.push_str(&format!(", if_modified_since {}", if_modified_since));
+            }
+            features.push(feature);
+        }
+        (None, None) => features.push(String::from("proxies to the Clever Cloud Cellar bucket")),
+    }
+    match site.charset.as_deref() {
+        Some("off") => features.push(String::from(
+            "doesn't add a charset to the Content-Type of the responses",
+        )),
+        Some(charset) => features.push(format!(
+            "adds charset={} to the Content-Type of the responses",
+            charset
+        )),
+        None => {}
+    }
+    if site.proxy_buffering == Some(false) {
+        features.push(String::from("streams the responses without buffering them"));
+    }
+    if let Some(proxy_ssl) = &site.proxy_ssl {
+        if proxy_ssl.verify {
+            features.push(String::from("verifies the certificate of the upstream"));
+        }
+    }
+    if let Some(cache) = &site.proxy_cache {
+        features.push(format!("caches the responses in the zone {}", cache.zone));
+    }
+
+    if let Some(aliases) = &site.aliases {
+        features.push(format!(
+            "redirects {} to {} (301)",
+            aliases.join(", "),
+            site.canonical.as_deref().unwrap_or(&site.domain)
+        ));
+    }
+    if let Some(www) = site.www_domain() {
+        features.push(format!("redirects {} to {} (301)", www, site.domain));
+    }
+    for redirect in site.redirects.iter().flatten() {
+        let from = match redirect.regex {
+            true => format!("paths matching {}", redirect.from_field),
+            false => redirect.from_field.clone(),
+        };
+        features.push(format!(
+            "redirects {} to {} ({})",
+            from, redirect.to, redirect.status_code
+        ));
+    }
+    for variable in site.geo_deny.iter().flatten() {
+        features.push(format!(
+            "denies the clients for which the geo ${} is set (403)",
+            variable
+        ));
+    }
+    if let Some(rate_limit) = &site.rate_limit {
+        let mut feature = format!("limits the requests of each client to {}", rate_limit.rate);
+        if let Some(burst) = rate_limit.burst {
+            feature.push_str(&format!(", with bursts of {}", burst));
+        }
+        if rate_limit.dry_run {
+            feature.push_str(", only logging the excess");
+        }
+        features.push(feature);
+    }
+    if let Some(patterns) = &site.block_user_agents {
+        features.push(format!(
+            "blocks the user agents matching {} ({})",
+            plural(patterns.len(), "pattern"),
+            site.block_user_agents_status.unwrap_or(444)
+        ));
+    }
+    if let Some(cors) = &site.cors {
+        let origins = match cors.origins.iter().any(|origin| origin == "*") {
+            true => String::from("any origin"),
+            false => plural(cors.origins.len(), "origin"),
+        };
+        features.push(format!(
+            "allows cross-origin requests from {}, answering their preflights (204)",
+            origins
+        ));
+    }
+    for map_redirect in site.map_redirects.iter().flatten() {
+        features.push(format!(
+            "redirects the paths of the map ${} ({})",
+            map_redirect.map, map_redirect.status_code
+        ));
+    }
+    for rewrite in site.rewrites.iter().flatten() {
+        features.push(format!(
+            "rewrites paths matching {} to {}",
+            rewrite.pattern, rewrite.replacement
+        ));
+    }
+
+    if let Some(acl) = &site.allow_acl {
+        features.push(format!("only serves the addresses of the ACL {}", acl));
+    }
+    if let Some(methods) = &site.limit_except {
+        features.push(format!(
+            "only serves the methods {}, others get a 403",
+            methods.join(", ")
+        ));
+    }
+    for header in site.headers.iter().flatten() {
+        let headers = plural(header.values.len(), "header");
+        match is_server_location(&header.for_field) {
+            true => features.push(format!("sets {} on every response", headers)),
+            false => features.push(format!("sets {} in location {}", headers, header.for_field)),
+        }
+    }
+    if let Some(cache_control) = &site.cache_control {
+        features.push(format!(
+            "sets Cache-Control for {}",
+            plural(cache_control.len(), "MIME type")
+        ));
+    }
+    if let Some(pattern) = &site.immutable_assets {
+        features.push(format!(
+            "lets browsers cache the assets of {} for a year without revalidating",
+            pattern
+        ));
+    }
+    if let Some(hints) = &site.early_hints {
+        features.push(format!("preloads {}", plural(hints.len(), "resource")));
+    }
+
+    if let Some(healthcheck) = &site.healthcheck {
+        features.push(format!(
+            "answers health checks on {} with a {}",
+            healthcheck.path, healthcheck.status
+        ));
+    }
+    if let Some(webroot) = &site.acme_webroot {
+        features.push(format!("serves ACME challenges from {}", webroot));
+    }
+    if let Some(access_log) = &site.access_log {
+        let mut feature = match &access_log.sample {
+            Some(sample) => format!("logs {} of the requests to {}", sample, access_log.path),
+            None => format!("logs requests to {}", access_log.path),
+        };
+        if !access_log.skip_paths.is_empty() {
+            feature.push_str(&format!(
+                ", except the ones of {}",
+                access_log.skip_paths.join(", ")
+            ));
+        }
+        if let Some(condition) = &access_log.condition {
+            feature.push_str(&format!(" when {} is set", condition));
+        }
+        features.push(feature);
+    }
+    if let Some(error_log) = &site.error_log {
+        features.push(match site.error_log_level {
+            Some(level) => format!("logs errors from the {} level to {}", level, error_log),
+            None => format!("logs errors to {}", error_log),
+        });
+    }
+    if site.header_buffers.is_some() {
+        features.push(String::from(
+            "accepts larger request headers than nginx's defaults",
+        ));
+    }
+    if site.lua.is_some() || site.headers.iter().flatten().any(|h| h.lua.is_some()) {
+        features.push(String::from("runs Lua code"));
+    }
+    let extra: Vec<&str> = [
+        ("extra", site.extra.is_some()),
+        ("extra_http", site.extra_http.is_some()),
+        ("extra_location", site.extra_location.is_some()),
+    ]
+    .iter()
+    .filter(|(_, set)| *set)
+    .map(|(field, _)| *field)
+    .collect();
+    if !extra.is_empty() {
+        features.push(format!("adds the directives of {} as is", extra.join(", ")));
+    }
+
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            description = "The public website."
+            ssl_cert = "/etc/ssl/example.pem"
+            ssl_key = "/etc/ssl/example.key"
+            force_https = true
+            upstream = { url = "http://127.0.0.1:3000" }
+
+            [[sites.redirects]]
+            from = "/old"
+            to = "/new"
+            status_code = 301
+
+            [[sites.headers]]
+            for = "/*"
+            values = { X-Frame-Options = "DENY", X-Content-Type-Options = "nosniff", Referrer-Policy = "no-referrer" }
+
+            [[sites]]
+            domain = "old.example.org"
+            redirect_to = "https://example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            explain(config, &Options::default(), "example.com").unwrap(),
+            "example.com:
+  # The public website.
+  - serves HTTPS with the certificate /etc/ssl/example.pem
+  - HTTPS enforced: plain HTTP requests are redirected (301)
+  - proxies to http://127.0.0.1:3000
+  - redirects www.example.com to example.com (301)
+  - redirects /old to /new (301)
+  - sets 3 headers on every response
+"
+        );
+
+        let config: UnverifiedConfig = toml::from_str(
+            "[[sites]]\ndomain = \"old.example.org\"\nredirect_to = \"https://example.com\"\npath_mode = \"drop\"",
+        )
+        .unwrap();
+        assert_eq!(
+            explain(config, &Options::default(), "old.example.org").unwrap(),
+            "old.example.org:
+  - serves plain HTTP only
+  - redirects every request to https://example.com, dropping the path (301)
+"
+        );
+
+        // The redirect of an apex domain has no www redirect server.
+        let config: UnverifiedConfig = toml::from_str(
+            "[[sites]]\ndomain = \"example.net\"\nredirect_to = \"https://example.com\"",
+        )
+        .unwrap();
+        assert!(!explain(config, &Options::default(), "example.net")
+            .unwrap()
+            .contains("www."));
+    }
+}
@@ -0,0 +1,152 @@
+//! Normalization of the rendered configs, so that their layout doesn't
+//! depend on the whitespace left by the template loops.
+
+const INDENT: &str = "    ";
+
+/// Start and end of the output of the `verbatim` filter, which `normalize`
+/// leaves as is.
+pub(crate) const VERBATIM_START: char = '\u{E000}';
+pub(crate) const VERBATIM_END: char = '\u{E001}';
+
+/// Reindents `content` by block level, strips trailing whitespace, collapses
+/// runs of blank lines, and drops blank lines at the start and end of blocks
+/// and of the file, which then ends with a single newline. The text marked by
+/// the `verbatim` filter, such as `extra`, is kept as written: only its first
+/// line moves, along with the line it starts on.
+pub(crate) fn normalize(content: &str) -> String {
+    // The verbatim texts are set aside, behind placeholders without braces
+    // nor whitespace, and put back once the rest is normalized.
+    let mut verbatim = Vec::new();
+    let mut marked = String::new();
+    let mut rest = content;
+    while let Some((before, after)) = rest.split_once(VERBATIM_START) {
+        let (text, after) = after.split_once(VERBATIM_END).unwrap_or((after, ""));
+        marked.push_str(before);
+        marked.push_str(&placeholder(verbatim.len()));
+        verbatim.push(text);
+        rest = after;
+    }
+    marked.push_str(rest);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut depth = 0usize;
+
+    for line in marked.lines().map(str::trim) {
+        if line.is_empty() {
+            if lines
+                .last()
+                .is_some_and(|last| !last.is_empty() && !last.ends_with('{'))
+            {
+                lines.push(String::new());
+            }
+            continue;
+        }
+
+        let (leading, opened, closed) = braces(line);
+        if leading > 0 && lines.last().is_some_and(String::is_empty) {
+            lines.pop();
+        }
+
+        depth = depth.saturating_sub(leading);
+        lines.push(format!("{}{}", INDENT.repeat(depth), line));
+        depth = (depth + opened).saturating_sub(closed - leading);
+    }
+
+    if lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+
+    let mut normalized = lines.join("\n");
+    normalized.push('\n');
+    for (index, text) in verbatim.into_iter().enumerate() {
+        normalized = normalized.replacen(&placeholder(index), text, 1);
+    }
+    normalized
+}
+
+fn placeholder(index: usize) -> String {
+    format!("{}{}{}", VERBATIM_START, index, VERBATIM_END)
+}
+
+/// Counts the braces of a line that open and close blocks, leaving out the
+/// ones in quotes and comments: the closing braces starting the line, then
+/// every opening and closing brace.
+fn braces(line: &str) -> (usize, usize, usize) {
+    let leading = line.chars().take_while(|c| *c == '}').count();
+    let (mut opened, mut closed) = (0, 0);
+
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for c in line.chars() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '#' if previous.is_whitespace() => break,
+                '{' => opened += 1,
+                '}' => closed += 1,
+                _ => {}
+            },
+        }
+        previous = c;
+    }
+
+    (leading, opened, closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let rendered = "
+server {
+  listen 8080;
+
+
+        location / {
+
+            return 200 \"{ ok }\"; # } {
+
+        }
+
+}";
+        assert_eq!(
+            normalize(rendered),
+            "server {
+    listen 8080;
+
+    location / {
+        return 200 \"{ ok }\"; # } {
+    }
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_normalize_verbatim() {
+        let extra = "\n  location /x {\n\n\n      return 204;  \n}";
+        let rendered = format!(
+            "server {{\n  listen 8080;\n    deny all;{}{}{}\n}}",
+            VERBATIM_START, extra, VERBATIM_END
+        );
+        assert_eq!(
+            normalize(&rendered),
+            "server {\n    listen 8080;\n    deny all;\n  location /x {\n\n\n      return 204;  \n}\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_comments() {
+        assert_eq!(
+            normalize("# a\n\n\n\n# b\nmap $a $b {}"),
+            "# a\n\n# b\nmap $a $b {}\n"
+        );
+    }
+}
@@ -0,0 +1,427 @@
+//! An HAProxy configuration, as an alternative to the nginx configs for an
+//! edge layer running HAProxy. Only host routing, redirects and proxied
+//! upstreams are mapped; the other fields of the sites are reported as
+//! ignored, except for access controls, whose sites are left out rather than
+//! exposed without them.
+
+use url::Url;
+
+use crate::{
+    unix_socket, BackendType, NvhostsError, Options, PathMode, Site, UnverifiedConfig, Upstream,
+};
+
+const FRONTEND: &str = "nvhosts";
+
+/// Status codes of `http-request redirect`.
+const REDIRECT_CODES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// The `frontend` routing every site, its `backend` sections, and what
+/// couldn't be mapped to them.
+pub struct Haproxy {
+    pub config: String,
+    pub warnings: Vec<String>,
+}
+
+/// The `http-request` rules and `backend` section of a site.
+struct Section {
+    acls: Vec<String>,
+    rules: Vec<String>,
+    backend: Option<(String, String)>,
+}
+
+/// Validates `config` and maps every site to ACLs on the `Host` header of a
+/// single frontend. Sites that can't be routed to a `server`, or whose access
+/// controls HAProxy can't enforce, are left out, with a warning.
+pub fn haproxy(config: UnverifiedConfig, options: &Options) -> Result<Haproxy, NvhostsError> {
+    let config = config.validate(options)?;
+
+    let mut warnings = Vec::new();
+    let sections: Vec<Section> = config
+        .sites
+        .iter()
+        .filter_map(|site| site_section(site, &mut warnings))
+        .collect();
+
+    let mut frontend = format!("frontend {}\n    mode http\n    bind :80\n", FRONTEND);
+    let certs: Vec<&str> = config
+        .sites
+        .iter()
+        .filter_map(|site| site.ssl_cert.as_deref())
+        .collect();
+    if !certs.is_empty() {
+        frontend.push_str("    bind :443 ssl");
+        for cert in certs {
+            frontend.push_str(&format!(" crt {}", cert));
+        }
+        frontend.push('\n');
+    }
+    // HAProxy applies the http-request rules before use_backend, so they are
+    // written in that order.
+    for section in sections.iter() {
+        for acl in section.acls.iter() {
+            frontend.push_str(&format!("    acl {}\n", acl));
+        }
+    }
+    for section in sections.iter() {
+        for rule in section.rules.iter() {
+            frontend.push_str(&format!("    http-request {}\n", rule));
+        }
+    }
+    for section in sections.iter() {
+        if let Some((name, _)) = &section.backend {
+            frontend.push_str(&format!("    use_backend {} if host_{}\n", name, name));
+        }
+    }
+
+    let mut config = frontend;
+    for section in sections.iter() {
+        if let Some((name, server)) = &section.backend {
+            config.push_str(&format!(
+                "\nbackend {}\n    mode http\n    server {} {}\n",
+                name, name, server
+            ));
+        }
+    }
+
+    Ok(Haproxy { config, warnings })
+}
+
+fn site_section(site: &Site, warnings: &mut Vec<String>) -> Option<Section> {
+    let mut warn = |message: String| warnings.push(format!("{:?}: {}", site.domain, message));
+
+    let name = site.domain.replace(['.', '-'], "_");
+    let host = format!("host_{}", name);
+    let mut section = Section {
+        acls: vec![format!(
+            "{} req.hdr(host),field(1,:) -i {}",
+            host, site.domain
+        )],
+        rules: Vec::new(),
+        backend: None,
+    };
+
+    if let Some(aliases) = &site.aliases {
+        let scheme = if site.ssl_cert.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let canonical = site.canonical.as_deref().unwrap_or(&site.domain);
+        section.acls.push(format!(
+            "alias_{} req.hdr(host),field(1,:) -i {}",
+            name,
+            aliases.join(" ")
+        ));
+        section.rules.push(format!(
+            "redirect prefix {}://{} code 301 if alias_{}",
+            scheme, canonical, name
+        ));
+    }
+
+    if let Some(redirect_to) = &site.redirect_to {
+        let rule = match site.path_mode.unwrap_or_default() {
+            PathMode::Preserve => format!(
+                "redirect prefix {} code 301 if {}",
+                redirect_to
+                    .trim_end_matches("$request_uri")
+                    .trim_end_matches('/'),
+                host
+            ),
+            PathMode::Drop | PathMode::Rewrite => {
+                format!("redirect location {} code 301 if {}", redirect_to, host)
+            }
+        };
+        if site.path_rewrites.is_some() {
+            warn(String::from(
+                "path_rewrites isn't supported by the HAProxy output, ignored",
+            ));
+        }
+        section.rules.push(rule);
+        return Some(section);
+    }
+    if site.root.is_some() {
+        warn(String::from("root has no server to route to, skipped"));
+        return None;
+    }
+    let upstream = match &site.upstream {
+        Some(upstream) => upstream,
+        None => {
+            warn(String::from("the default bucket isn't supported, skipped"));
+            return None;
+        }
+    };
+    let server = match server(upstream) {
+        Ok(server) => server,
+        Err(reason) => {
+            warn(format!("upstream {:?}: {}, skipped", upstream.url, reason));
+            return None;
+        }
+    };
+
+    // Routing these sites would serve what their access controls protect.
+    let controls = [
+        ("allow_acl", site.allow_acl.is_some()),
+        ("mtls", site.mtls.is_some()),
+        (
+            "method_allow",
+            site.method_allow.is_some() || site.method_deny.is_some(),
+        ),
+        ("geo_deny", site.geo_deny.is_some()),
+        ("block_user_agents", site.block_user_agents.is_some()),
+        ("rate_limit", site.rate_limit.is_some()),
+        ("maintenance", site.maintenance),
+    ];
+    let controls: Vec<&str> = controls
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(field, _)| *field)
+        .collect();
+    if !controls.is_empty() {
+        warn(format!(
+            "{} can't be enforced by the HAProxy output, skipped",
+            controls.join(", ")
+        ));
+        return None;
+    }
+
+    let ignored = [
+        ("listen", site.listen.is_some()),
+        (
+            "tls_profile",
+            site.tls_profile.is_some()
+                || site.ssl_protocols.is_some()
+                || site.ssl_ciphers.is_some(),
+        ),
+        ("www_redirect", site.www_redirect == Some(true)),
+        ("acme_webroot", site.acme_webroot.is_some()),
+        (
+            "https_redirect_exclude",
+            site.https_redirect_exclude.is_some(),
+        ),
+        ("healthcheck", site.healthcheck.is_some()),
+        ("headers", site.headers.is_some()),
+        (
+            "lua",
+            site.lua.is_some() || site.headers.iter().flatten().any(|h| h.lua.is_some()),
+        ),
+        ("access_log", site.access_log.is_some()),
+        (
+            "error_log",
+            site.error_log.is_some() || site.error_log_level.is_some(),
+        ),
+        ("real_ip", site.real_ip.is_some()),
+        (
+            "cache_control",
+            site.cache_control.is_some() || site.cache_control_default.is_some(),
+        ),
+        ("immutable_assets", site.immutable_assets.is_some()),
+        ("default_server", site.default_server),
+        ("server_names", site.server_names.is_some()),
+        ("split", site.split.is_some()),
+        ("map_redirects", site.map_redirects.is_some()),
+        ("cors", site.cors.is_some()),
+        ("charset", site.charset.is_some()),
+        ("error_pages", site.error_pages.is_some()),
+        ("proxy_intercept_errors", site.proxy_intercept_errors),
+        ("rewrites", site.rewrites.is_some()),
+        ("early_hints", site.early_hints.is_some()),
+        ("http3", site.http3),
+        ("proxy_buffering", site.proxy_buffering.is_some()),
+        ("proxy_ssl", site.proxy_ssl.is_some()),
+        ("proxy_buffer_size", site.proxy_buffer_size.is_some()),
+        ("proxy_buffers", site.proxy_buffers.is_some()),
+        ("header_buffers", site.header_buffers.is_some()),
+        ("proxy_cache", site.proxy_cache.is_some()),
+        ("extra", site.extra.is_some()),
+        ("extra_http", site.extra_http.is_some()),
+        ("extra_location", site.extra_location.is_some()),
+    ];
+    for (field, _) in ignored.iter().filter(|(_, set)| *set) {
+        warn(format!(
+            "{} isn't supported by the HAProxy output, ignored",
+            field
+        ));
+    }
+
+    if site.force_https {
+        section.rules.push(format!(
+            "redirect scheme https code 301 if {} !{{ ssl_fc }}",
+            host
+        ));
+    }
+    for redirect in site.redirects.iter().flatten() {
+        if !REDIRECT_CODES.contains(&redirect.status_code) {
+            warn(format!(
+                "redirect from {:?}: status {} isn't supported by HAProxy, ignored",
+                redirect.from_field, redirect.status_code
+            ));
+            continue;
+        }
+        section.rules.push(format!(
+            "redirect location {} code {} if {} {{ {} {} }}",
+            redirect.to,
+            redirect.status_code,
+            host,
+            if redirect.regex { "path_reg" } else { "path" },
+            redirect.from_field
+        ));
+    }
+
+    section.backend = Some((name, server));
+    Some(section)
+}
+
+/// The address and options of the `server` line of `upstream`.
+fn server(upstream: &Upstream) -> Result<String, String> {
+    if upstream.servers.is_some() {
+        return Err(String::from(
+            "servers aren't supported by the HAProxy output",
+        ));
+    }
+    match upstream.backend_type {
+        BackendType::Proxy => {}
+        BackendType::Fastcgi => {
+            return Err(String::from(
+                "fastcgi isn't supported by the HAProxy output",
+            ))
+        }
+        BackendType::Uwsgi => {
+            return Err(String::from("uwsgi isn't supported by the HAProxy output"))
+        }
+    }
+    if let Some(socket) = unix_socket(&upstream.url) {
+        return match socket.split_once(':') {
+            Some((_, uri)) if uri != "/" => {
+                Err(String::from("HAProxy can't replace the path of requests"))
+            }
+            Some((path, _)) => Ok(path.to_string()),
+            None => Ok(socket.to_string()),
+        };
+    }
+
+    let url = Url::parse(&upstream.url).map_err(|err| err.to_string())?;
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    if url.path() != "/" {
+        return Err(String::from("HAProxy can't replace the path of requests"));
+    }
+    let mut server = format!("{}:{}", host, port);
+    if url.scheme() == "https" {
+        // Like proxy_pass, which doesn't verify upstream certificates by
+        // default.
+        server.push_str(" ssl verify none");
+    }
+    if upstream.dynamic {
+        server.push_str(" init-addr last,libc,none");
+    }
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn haproxy_config(config: &str) -> Haproxy {
+        let config: UnverifiedConfig = toml::from_str(config).unwrap();
+        haproxy(config, &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn test_haproxy() {
+        let output = haproxy_config(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/example.pem"
+            ssl_key = "/etc/ssl/example.key"
+            force_https = true
+            upstream = { url = "http://10.0.0.5:8080" }
+
+            [[sites.redirects]]
+            from = "/old"
+            to = "/new"
+
+            [[sites.redirects]]
+            from = "^/blog/(.*)$"
+            to = "/posts"
+            status_code = 301
+            regex = true
+
+            [[sites]]
+            domain = "api.example.com"
+            upstream = { url = "https://api.internal" }
+
+            [[sites]]
+            domain = "old.example.com"
+            redirect_to = "https://example.com"
+            "#,
+        );
+
+        assert!(output.warnings.is_empty(), "{:?}", output.warnings);
+        assert_eq!(
+            output.config,
+            r#"frontend nvhosts
+    mode http
+    bind :80
+    bind :443 ssl crt /etc/ssl/example.pem
+    acl host_example_com req.hdr(host),field(1,:) -i example.com
+    acl host_api_example_com req.hdr(host),field(1,:) -i api.example.com
+    acl host_old_example_com req.hdr(host),field(1,:) -i old.example.com
+    http-request redirect scheme https code 301 if host_example_com !{ ssl_fc }
+    http-request redirect location /new code 302 if host_example_com { path /old }
+    http-request redirect location /posts code 301 if host_example_com { path_reg ^/blog/(.*)$ }
+    http-request redirect prefix https://example.com code 301 if host_old_example_com
+    use_backend example_com if host_example_com
+    use_backend api_example_com if host_api_example_com
+
+backend example_com
+    mode http
+    server example_com 10.0.0.5:8080
+
+backend api_example_com
+    mode http
+    server api_example_com api.internal:443 ssl verify none
+"#
+        );
+    }
+
+    #[test]
+    fn test_haproxy_skipped() {
+        let output = haproxy_config(
+            r#"
+            [[sites]]
+            domain = "bucket.example.com"
+
+            [[sites]]
+            domain = "php.example.com"
+            upstream = { url = "php:9000", backend_type = "fastcgi" }
+
+            [[sites]]
+            domain = "app.example.com"
+            upstream = { url = "http://unix:/run/app.sock" }
+            healthcheck = {}
+
+            [[sites]]
+            domain = "admin.example.com"
+            upstream = { url = "http://10.0.0.5:8080" }
+            rate_limit = { rate = "10r/s" }
+            method_allow = ["GET", "HEAD"]
+            "#,
+        );
+
+        assert!(output
+            .config
+            .contains("    server app_example_com /run/app.sock\n"));
+        assert!(!output.config.contains("backend php_example_com"));
+        assert!(!output.config.contains("admin.example.com"));
+        assert_eq!(
+            output.warnings,
+            vec![
+                "\"bucket.example.com\": the default bucket isn't supported, skipped",
+                "\"php.example.com\": upstream \"php:9000\": fastcgi isn't supported by the HAProxy output, skipped",
+                "\"app.example.com\": healthcheck isn't supported by the HAProxy output, ignored",
+                "\"admin.example.com\": method_allow, rate_limit can't be enforced by the HAProxy output, skipped",
+            ]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! nginx syntax highlighting of the generated files, for `nvhosts show`:
+//! directives, strings, variables and comments are colored when stdout
+//! supports colors.
+
+use owo_colors::{OwoColorize, Stream};
+
+/// What a token of an nginx config is, for its color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// The first word of a statement or block.
+    Directive,
+    /// A quoted parameter, with its quotes.
+    String,
+    /// A `$variable` or `${variable}`.
+    Variable,
+    /// From `#` to the end of the line.
+    Comment,
+    /// Other parameters, whitespace and punctuation.
+    Other,
+}
+
+/// `config` with its tokens colored, unless stdout isn't a terminal, colors
+/// are disabled with `NO_COLOR`, or `--color never` is given.
+pub fn nginx(config: &str) -> String {
+    let mut highlighted = String::with_capacity(config.len());
+    for (kind, token) in tokens(config) {
+        let colored = match kind {
+            Kind::Directive => token
+                .if_supports_color(Stream::Stdout, |t| t.cyan())
+                .to_string(),
+            Kind::String => token
+                .if_supports_color(Stream::Stdout, |t| t.green())
+                .to_string(),
+            Kind::Variable => token
+                .if_supports_color(Stream::Stdout, |t| t.yellow())
+                .to_string(),
+            Kind::Comment => token
+                .if_supports_color(Stream::Stdout, |t| t.dimmed())
+                .to_string(),
+            Kind::Other => token.to_string(),
+        };
+        highlighted.push_str(&colored);
+    }
+    highlighted
+}
+
+/// Splits `config` into tokens, which put back together give `config`.
+fn tokens(config: &str) -> Vec<(Kind, &str)> {
+    let mut tokens = Vec::new();
+    // Whether the next word starts a statement, and is a directive.
+    let mut statement = true;
+    let mut rest = config;
+    while let Some(c) = rest.chars().next() {
+        let (kind, len) = match c {
+            c if c.is_whitespace() => (
+                Kind::Other,
+                rest.find(|c: char| !c.is_whitespace())
+                    .unwrap_or(rest.len()),
+            ),
+            '#' => (Kind::Comment, rest.find('\n').unwrap_or(rest.len())),
+            ';' | '{' | '}' => {
+                statement = true;
+                (Kind::Other, 1)
+            }
+            '"' | '\'' => {
+                statement = false;
+                (Kind::String, quoted(rest, c))
+            }
+            '$' => {
+                statement = false;
+                (Kind::Variable, variable(rest))
+            }
+            _ => {
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || matches!(c, ';' | '{' | '}' | '$'))
+                    .unwrap_or(rest.len());
+                let kind = match statement {
+                    true => Kind::Directive,
+                    false => Kind::Other,
+                };
+                statement = false;
+                (kind, len)
+            }
+        };
+        tokens.push((kind, &rest[..len]));
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// Length of the string starting `rest` with the `quote`, up to the closing
+/// one, or to the end when it isn't closed.
+fn quoted(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return i + 1,
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+/// Length of the variable starting `rest`, `$name` or `${name}`.
+fn variable(rest: &str) -> usize {
+    if rest[1..].starts_with('{') {
+        return rest.find('}').map_or(rest.len(), |end| end + 1);
+    }
+    1 + rest[1..]
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(rest.len() - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let config = "# Generated\nserver {\n    return 301 https://$host${request_uri};\n    add_header X-Frame-Options \"DENY\" always; # inline\n    rewrite '^/a;b' /$1;\n}\n";
+        let tokens = tokens(config);
+        assert_eq!(
+            tokens.iter().map(|(_, token)| *token).collect::<String>(),
+            config
+        );
+
+        let of = |kind: Kind| -> Vec<&str> {
+            tokens
+                .iter()
+                .filter(|(k, _)| *k == kind)
+                .map(|(_, token)| *token)
+                .collect()
+        };
+        assert_eq!(
+            of(Kind::Directive),
+            ["server", "return", "add_header", "rewrite"]
+        );
+        assert_eq!(of(Kind::String), ["\"DENY\"", "'^/a;b'"]);
+        assert_eq!(of(Kind::Variable), ["$host", "${request_uri}", "$1"]);
+        assert_eq!(of(Kind::Comment), ["# Generated", "# inline"]);
+    }
+}
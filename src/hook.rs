@@ -0,0 +1,151 @@
+//! Commands run after a successful generation, e.g. to reload nginx or copy
+//! the files to another host.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{command, NvhostsError};
+
+/// A command run by the shell once the files are generated, with the paths
+/// of the files that changed in `NVHOSTS_CHANGED_FILES`, one per line.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct Hook {
+    pub(crate) command: String,
+    /// Also run the command when no file changed.
+    #[serde(default)]
+    pub(crate) always: bool,
+}
+
+/// Runs `hooks` in order, skipping the ones that don't apply when `changed`
+/// is empty, and stops at the first one that fails or runs longer than
+/// `timeout`.
+pub(crate) fn run_hooks(
+    hooks: &[Hook],
+    changed: &[PathBuf],
+    timeout: Duration,
+) -> Result<(), NvhostsError> {
+    let changed_files = changed
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    for hook in hooks.iter() {
+        if changed.is_empty() && !hook.always {
+            continue;
+        }
+        info!("running hook {:?}", hook.command);
+
+        let completed = command::run(
+            shell(&hook.command).env("NVHOSTS_CHANGED_FILES", &changed_files),
+            timeout,
+        )
+        .map_err(|err| match err {
+            command::Error::Io(source) => NvhostsError::Hook {
+                command: hook.command.clone(),
+                source,
+            },
+            command::Error::TimedOut => NvhostsError::HookTimeout {
+                command: hook.command.clone(),
+                timeout,
+            },
+        })?;
+        if !completed.status.success() {
+            return Err(NvhostsError::HookFailed {
+                command: hook.command.clone(),
+                status: completed.status,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::COMMAND_TIMEOUT;
+
+    fn hook(command: &str, always: bool) -> Hook {
+        Hook {
+            command: command.to_string(),
+            always,
+        }
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-hooks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("log");
+        let append = format!(
+            "printf '%s;' \"$NVHOSTS_CHANGED_FILES\" >> {}",
+            log.display()
+        );
+
+        let changed = [PathBuf::from("a.conf"), PathBuf::from("b.conf")];
+        run_hooks(
+            &[hook(&append, false), hook(&append, true)],
+            &changed,
+            COMMAND_TIMEOUT,
+        )
+        .unwrap();
+        run_hooks(
+            &[hook(&append, false), hook(&append, true)],
+            &[],
+            COMMAND_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "a.conf\nb.conf;a.conf\nb.conf;;"
+        );
+
+        let err = run_hooks(
+            &[hook("exit 3", false), hook(&append, false)],
+            &changed,
+            COMMAND_TIMEOUT,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "hook \"exit 3\" failed: exit status: 3");
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "a.conf\nb.conf;a.conf\nb.conf;;"
+        );
+
+        let err = run_hooks(
+            &[hook("sleep 10", true)],
+            &changed,
+            Duration::from_millis(100),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "hook \"sleep 10\" didn't complete within 0.1s, killed"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,623 @@
+//! Best-effort conversion of hand-written nginx vhosts into a config.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{Header, Redirect, Site, UnverifiedConfig};
+
+/// Directives that are implied by the generated config and dropped silently.
+const IMPLIED: &[&str] = &["listen", "server_name"];
+
+/// The result of an import: the config and a warning per lossy conversion.
+pub struct Import {
+    pub config: UnverifiedConfig,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Word {
+    value: String,
+    quoted: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(Word),
+    Semicolon,
+    Open,
+    Close,
+    /// A comment, `inline` when it follows something on the same line.
+    Comment {
+        text: String,
+        inline: bool,
+    },
+}
+
+#[derive(Debug)]
+struct Directive {
+    name: String,
+    args: Vec<Word>,
+    block: Option<Vec<Directive>>,
+    /// The comments on the lines above the directive or at the end of its
+    /// line.
+    comments: Vec<String>,
+}
+
+/// Imports every file of `dir`, in file name order.
+pub fn import_dir(dir: &Path) -> Result<Import> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("couldn't read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut import = Import {
+        config: UnverifiedConfig::default(),
+        warnings: Vec::new(),
+    };
+
+    for path in paths {
+        let input = fs::read_to_string(&path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        let directives =
+            parse(&input).with_context(|| format!("couldn't parse {}", path.display()))?;
+        import_directives(&directives, &path.display().to_string(), &mut import);
+    }
+
+    Ok(import)
+}
+
+/// Imports the `server` blocks found at the top level or in an `http` block.
+fn import_directives(directives: &[Directive], origin: &str, import: &mut Import) {
+    for directive in directives {
+        match (directive.name.as_str(), &directive.block) {
+            ("server", Some(block)) => import_server(block, &directive.comments, origin, import),
+            ("http", Some(block)) => import_directives(block, origin, import),
+            _ => import.warnings.push(format!(
+                "{}: ignored {:?} outside of a server block",
+                origin, directive.name
+            )),
+        }
+    }
+}
+
+/// Imports a server block, its `comments` becoming the site description.
+fn import_server(block: &[Directive], comments: &[String], origin: &str, import: &mut Import) {
+    let names: Vec<&str> = block
+        .iter()
+        .filter(|directive| directive.name == "server_name")
+        .flat_map(|directive| directive.args.iter().map(|arg| arg.value.as_str()))
+        .collect();
+
+    let domain = match names.first() {
+        Some(domain) => domain.trim_start_matches('.').to_string(),
+        None => {
+            import.warnings.push(format!(
+                "{}: skipped a server block without server_name",
+                origin
+            ));
+            return;
+        }
+    };
+
+    let mut server = Server {
+        origin,
+        site: Site {
+            domain,
+            ..Default::default()
+        },
+        warnings: &mut import.warnings,
+    };
+    server.describe(comments);
+
+    if is_redirect_only(block) {
+        server.warn(String::from("skipped, it only redirects to another host"));
+        return;
+    }
+
+    if names.len() > 1 {
+        server.warn(format!("other server names {:?} dropped", &names[1..]));
+    }
+
+    for directive in block {
+        match directive.name.as_str() {
+            name if IMPLIED.contains(&name) => server.describe(&directive.comments),
+            "location" => server.location(directive),
+            "add_header" => server.header(directive, "/"),
+            "rewrite" => match rewrite_redirect(directive) {
+                Some(redirect) => {
+                    server.describe(&directive.comments);
+                    server
+                        .site
+                        .redirects
+                        .get_or_insert_with(Vec::new)
+                        .push(redirect)
+                }
+                None => {
+                    server.warn(format!("rewrite {:?} kept in extra", args(directive)));
+                    server.extra(serialize(directive, 2));
+                }
+            },
+            name => {
+                if name == "proxy_pass" {
+                    server.warn(String::from("proxy_pass kept in extra"));
+                }
+                server.extra(serialize(directive, 2));
+            }
+        }
+    }
+
+    let site = server.site;
+    import.config.sites.push(site);
+}
+
+/// The site being imported from a server block.
+struct Server<'a> {
+    origin: &'a str,
+    site: Site,
+    warnings: &'a mut Vec<String>,
+}
+
+impl Server<'_> {
+    fn warn(&mut self, message: String) {
+        self.warnings.push(format!(
+            "{}: {:?}: {}",
+            self.origin, self.site.domain, message
+        ));
+    }
+
+    /// Appends the comments of a directive that isn't kept in `extra` to the
+    /// site description, so that they survive the import.
+    fn describe(&mut self, comments: &[String]) {
+        if comments.is_empty() {
+            return;
+        }
+        let description = self.site.description.get_or_insert_with(String::new);
+        for comment in comments {
+            if !description.is_empty() {
+                description.push('\n');
+            }
+            description.push_str(comment);
+        }
+    }
+
+    fn extra(&mut self, directives: String) {
+        self.site
+            .extra
+            .get_or_insert_with(String::new)
+            .push_str(&directives);
+    }
+
+    fn location(&mut self, directive: &Directive) {
+        let block = match &directive.block {
+            Some(block) => block,
+            None => return,
+        };
+        let path = args(directive);
+
+        // `extra` is emitted in `location /`, so its directives can be kept
+        // as is and the nested locations imported like top-level ones.
+        if path == "/" {
+            self.describe(&directive.comments);
+            for inner in block {
+                match inner.name.as_str() {
+                    "location" => self.location(inner),
+                    "add_header" => self.header(inner, "/"),
+                    _ => self.extra(serialize(inner, 2)),
+                }
+            }
+            return;
+        }
+
+        if let ([modifier, from], [only]) = (&directive.args[..], &block[..]) {
+            if modifier.value == "=" && only.name == "return" && only.args.len() == 2 {
+                if let Ok(status_code) = only.args[0].value.parse() {
+                    self.describe(&directive.comments);
+                    self.describe(&only.comments);
+                    self.site
+                        .redirects
+                        .get_or_insert_with(Vec::new)
+                        .push(Redirect {
+                            from_field: from.value.clone(),
+                            to: only.args[1].value.clone(),
+                            status_code,
+                            regex: false,
+                        });
+                    return;
+                }
+            }
+        }
+
+        let (header_lines, rest): (Vec<&Directive>, Vec<&Directive>) =
+            block.iter().partition(|inner| inner.name == "add_header");
+
+        for line in header_lines {
+            self.header(line, &path);
+        }
+
+        if rest.is_empty() {
+            self.describe(&directive.comments);
+        } else {
+            self.warn(format!("location {:?} kept in extra", path));
+            let inner: Vec<String> = rest.iter().map(|inner| serialize(inner, 3)).collect();
+            self.extra(format!(
+                "{}\n        location {} {{{}\n        }}",
+                serialize_comments(&directive.comments, 2),
+                path,
+                inner.concat()
+            ));
+        }
+    }
+
+    fn header(&mut self, directive: &Directive, path: &str) {
+        self.describe(&directive.comments);
+        let (name, value) = match &directive.args[..] {
+            [name, value, rest @ ..] => {
+                if !rest.is_empty() {
+                    self.warn(format!(
+                        "dropped {:?} from header {}",
+                        rest.iter()
+                            .map(|arg| arg.value.as_str())
+                            .collect::<Vec<_>>(),
+                        name.value
+                    ));
+                }
+                (name.value.clone(), value.value.clone())
+            }
+            _ => return,
+        };
+
+        let headers = self.site.headers.get_or_insert_with(Vec::new);
+        match headers.iter_mut().find(|header| header.for_field == path) {
+            Some(header) => {
+                header.values.insert(name, value);
+            }
+            None => headers.push(Header {
+                for_field: path.to_string(),
+                values: BTreeMap::from([(name, value)]),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Converts `rewrite ^/old$ /new permanent;` into a redirect. Rewrites with an
+/// actual pattern can't be expressed as a redirect.
+fn rewrite_redirect(directive: &Directive) -> Option<Redirect> {
+    let (pattern, to, flag) = match &directive.args[..] {
+        [pattern, to, flag] => (&pattern.value, &to.value, &flag.value),
+        _ => return None,
+    };
+    let status_code = match flag.as_str() {
+        "permanent" => 301,
+        "redirect" => 302,
+        _ => return None,
+    };
+    let from = pattern.strip_prefix('^')?.strip_suffix('$')?;
+    if !from.starts_with('/') || from.contains(|c: char| "\\.*+?()[]{}|".contains(c)) {
+        return None;
+    }
+
+    Some(Redirect {
+        from_field: from.to_string(),
+        to: to.clone(),
+        status_code,
+        regex: false,
+    })
+}
+
+/// Whether the server block does nothing but `return` to another location,
+/// like the www redirection servers.
+fn is_redirect_only(block: &[Directive]) -> bool {
+    let rest: Vec<&Directive> = block
+        .iter()
+        .filter(|directive| !IMPLIED.contains(&directive.name.as_str()))
+        .collect();
+
+    match &rest[..] {
+        [only] if only.name == "return" => true,
+        [only] if only.name == "location" && args(only) == "/" => match &only.block {
+            Some(inner) => matches!(&inner[..], [only] if only.name == "return"),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn args(directive: &Directive) -> String {
+    directive
+        .args
+        .iter()
+        .map(quote)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn quote(word: &Word) -> String {
+    if word.quoted {
+        format!("\"{}\"", word.value.replace('"', "\\\""))
+    } else {
+        word.value.clone()
+    }
+}
+
+/// Writes comments back as nginx config, like `serialize`.
+fn serialize_comments(comments: &[String], depth: usize) -> String {
+    let indent = "    ".repeat(depth);
+    comments
+        .iter()
+        .map(|comment| format!("\n{}# {}", indent, comment))
+        .collect()
+}
+
+/// Writes a directive back as nginx config, each line preceded by a newline
+/// and `depth` levels of indentation, as expected by `extra`.
+fn serialize(directive: &Directive, depth: usize) -> String {
+    let indent = "    ".repeat(depth);
+    let mut head = format!(
+        "{}\n{}{}",
+        serialize_comments(&directive.comments, depth),
+        indent,
+        directive.name
+    );
+    if !directive.args.is_empty() {
+        head.push(' ');
+        head.push_str(&args(directive));
+    }
+
+    match &directive.block {
+        None => format!("{};", head),
+        Some(block) => {
+            let inner: Vec<String> = block.iter().map(|d| serialize(d, depth + 1)).collect();
+            format!("{} {{{}\n{}}}", head, inner.concat(), indent)
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    // Whether a token was already read on the current line.
+    let mut inline = false;
+
+    while let Some(&c) = chars.peek() {
+        if c != '#' && !c.is_whitespace() {
+            inline = true;
+        }
+        match c {
+            '\n' => {
+                chars.next();
+                inline = false;
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '#' => {
+                let mut text = String::new();
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                    text.push(c);
+                }
+                tokens.push(Token::Comment {
+                    text: text.trim_start_matches('#').trim().to_string(),
+                    inline,
+                });
+                inline = false;
+            }
+            ';' | '{' | '}' => {
+                chars.next();
+                tokens.push(match c {
+                    ';' => Token::Semicolon,
+                    '{' => Token::Open,
+                    _ => Token::Close,
+                });
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            if let Some(escaped) = chars.next() {
+                                if escaped != c {
+                                    value.push('\\');
+                                }
+                                value.push(escaped);
+                            }
+                        }
+                        Some(end) if end == c => break,
+                        Some(other) => value.push(other),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(Token::Word(Word {
+                    value,
+                    quoted: true,
+                }));
+            }
+            _ => {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || ";{}".contains(c) {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(Word {
+                    value,
+                    quoted: false,
+                }));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse(input: &str) -> Result<Vec<Directive>> {
+    let tokens = tokenize(input)?;
+    let mut tokens = tokens.into_iter();
+    let directives = parse_block(&mut tokens, false)?;
+    Ok(directives)
+}
+
+fn parse_block(tokens: &mut impl Iterator<Item = Token>, nested: bool) -> Result<Vec<Directive>> {
+    let mut directives: Vec<Directive> = Vec::new();
+    let mut comments = Vec::new();
+
+    loop {
+        let name = match tokens.next() {
+            Some(Token::Word(word)) => word.value,
+            Some(Token::Comment { text, inline }) => {
+                match directives.last_mut() {
+                    Some(previous) if inline => previous.comments.push(text),
+                    _ => comments.push(text),
+                }
+                continue;
+            }
+            Some(Token::Close) if nested => return Ok(directives),
+            None if !nested => return Ok(directives),
+            None => bail!("unexpected end of file, missing '}}'"),
+            Some(token) => bail!("unexpected {:?}", token),
+        };
+
+        let mut args = Vec::new();
+        let block = loop {
+            match tokens.next() {
+                Some(Token::Word(word)) => args.push(word),
+                Some(Token::Semicolon) => break None,
+                Some(Token::Open) => break Some(parse_block(tokens, true)?),
+                Some(Token::Comment { text, .. }) => comments.push(text),
+                Some(Token::Close) => bail!("unexpected '}}' in {:?}", name),
+                None => bail!("unexpected end of file in {:?}", name),
+            }
+        };
+
+        directives.push(Directive {
+            name,
+            args,
+            block,
+            comments: std::mem::take(&mut comments),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_server() {
+        let directives = parse(
+            r#"
+            server {
+                listen 80;
+                server_name .www.example.com;
+                return 301 https://example.com$request_uri;
+            }
+
+            server {
+                listen 80;
+                server_name example.com;
+
+                add_header X-Frame-Options "DENY" always;
+                rewrite ^/old$ /new permanent;
+
+                location = /example {
+                    return 302 http://example.com;
+                }
+
+                location ~* \.css$ {
+                    add_header Content-Type "text/css";
+                }
+
+                location /api {
+                    proxy_pass http://127.0.0.1:3000;
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut import = Import {
+            config: UnverifiedConfig::default(),
+            warnings: Vec::new(),
+        };
+        import_directives(&directives, "test.conf", &mut import);
+
+        assert_eq!(import.config.sites.len(), 1);
+        let site = &import.config.sites[0];
+        assert_eq!(site.domain, "example.com");
+
+        let redirects = site.redirects.as_ref().unwrap();
+        assert_eq!(redirects.len(), 2);
+        assert_eq!(redirects[0].from_field, "/old");
+        assert_eq!(redirects[0].status_code, 301);
+        assert_eq!(redirects[1].from_field, "/example");
+
+        let headers = site.headers.as_ref().unwrap();
+        assert_eq!(headers[0].for_field, "/");
+        assert_eq!(headers[0].values["X-Frame-Options"], "DENY");
+        assert_eq!(headers[1].for_field, "~* \\.css$");
+
+        assert_eq!(
+            site.extra.as_deref().unwrap(),
+            "\n        location /api {\n            proxy_pass http://127.0.0.1:3000;\n        }"
+        );
+        assert_eq!(import.warnings.len(), 3);
+    }
+
+    #[test]
+    fn test_import_comments() {
+        let directives = parse(
+            r#"
+            # Owner: web team
+            # Ticket: OPS-42
+            server {
+                server_name example.com; # main domain
+
+                # Used by the mobile app
+                add_header X-App "1";
+
+                # Legacy assets
+                location /static {
+                    root /var/www; # on the NFS mount
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut import = Import {
+            config: UnverifiedConfig::default(),
+            warnings: Vec::new(),
+        };
+        import_directives(&directives, "test.conf", &mut import);
+
+        let site = &import.config.sites[0];
+        assert_eq!(
+            site.description.as_deref().unwrap(),
+            "Owner: web team\nTicket: OPS-42\nmain domain\nUsed by the mobile app"
+        );
+        assert_eq!(
+            site.extra.as_deref().unwrap(),
+            "\n        # Legacy assets\n        location /static {\n            # on the NFS mount\n            root /var/www;\n        }"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("server {").is_err());
+        assert!(parse("server { listen 80 }").is_err());
+        assert!(parse("add_header X \"unterminated;").is_err());
+    }
+}
@@ -0,0 +1,406 @@
+//! Kubernetes `Ingress` manifests for the ingress-nginx controller, as an
+//! alternative to the nginx configs. Only host routing, TLS, headers and
+//! redirects are mapped; the other fields of the sites are reported as
+//! ignored.
+
+use url::Url;
+
+use crate::{
+    is_server_location, unix_socket, BackendType, NvhostsError, Options, Site, UnverifiedConfig,
+    Upstream,
+};
+
+const ANNOTATION_PREFIX: &str = "nginx.ingress.kubernetes.io/";
+
+/// The YAML documents of the `Ingress` resources, one per site, and what
+/// couldn't be mapped to them.
+pub struct Manifest {
+    pub yaml: String,
+    pub warnings: Vec<String>,
+}
+
+/// Validates `config` and maps every site to an `Ingress`. Sites that can't
+/// be routed to a Kubernetes `Service` are left out, with a warning.
+pub fn ingress(config: UnverifiedConfig, options: &Options) -> Result<Manifest, NvhostsError> {
+    let config = config.validate(options)?;
+
+    let mut warnings = Vec::new();
+    let documents: Vec<String> = config
+        .sites
+        .iter()
+        .filter_map(|site| site_ingress(site, &mut warnings))
+        .collect();
+
+    Ok(Manifest {
+        yaml: documents.join("---\n"),
+        warnings,
+    })
+}
+
+fn site_ingress(site: &Site, warnings: &mut Vec<String>) -> Option<String> {
+    let mut warn = |message: String| warnings.push(format!("{:?}: {}", site.domain, message));
+
+    if site.redirect_to.is_some() {
+        warn(String::from(
+            "redirect_to has no Service to route to, skipped",
+        ));
+        return None;
+    }
+    if site.root.is_some() {
+        warn(String::from("root has no Service to route to, skipped"));
+        return None;
+    }
+    let upstream = match &site.upstream {
+        Some(upstream) => upstream,
+        None => {
+            warn(String::from("the default bucket isn't a Service, skipped"));
+            return None;
+        }
+    };
+    let (service, port, protocol) = match service(upstream) {
+        Ok(service) => service,
+        Err(reason) => {
+            warn(format!("upstream {:?}: {}, skipped", upstream.url, reason));
+            return None;
+        }
+    };
+
+    let ignored = [
+        ("listen", site.listen.is_some()),
+        ("aliases", site.aliases.is_some()),
+        ("mtls", site.mtls.is_some()),
+        (
+            "tls_profile",
+            site.tls_profile.is_some()
+                || site.ssl_protocols.is_some()
+                || site.ssl_ciphers.is_some(),
+        ),
+        ("acme_webroot", site.acme_webroot.is_some()),
+        (
+            "https_redirect_exclude",
+            site.https_redirect_exclude.is_some(),
+        ),
+        ("healthcheck", site.healthcheck.is_some()),
+        ("maintenance", site.maintenance),
+        (
+            "lua",
+            site.lua.is_some() || site.headers.iter().flatten().any(|h| h.lua.is_some()),
+        ),
+        ("access_log", site.access_log.is_some()),
+        (
+            "error_log",
+            site.error_log.is_some() || site.error_log_level.is_some(),
+        ),
+        ("real_ip", site.real_ip.is_some()),
+        (
+            "cache_control",
+            site.cache_control.is_some() || site.cache_control_default.is_some(),
+        ),
+        ("immutable_assets", site.immutable_assets.is_some()),
+        ("default_server", site.default_server),
+        ("server_names", site.server_names.is_some()),
+        ("split", site.split.is_some()),
+        ("map_redirects", site.map_redirects.is_some()),
+        ("geo_deny", site.geo_deny.is_some()),
+        ("rate_limit", site.rate_limit.is_some()),
+        ("block_user_agents", site.block_user_agents.is_some()),
+        ("cors", site.cors.is_some()),
+        ("charset", site.charset.is_some()),
+        ("error_pages", site.error_pages.is_some()),
+        ("proxy_intercept_errors", site.proxy_intercept_errors),
+        ("rewrites", site.rewrites.is_some()),
+        ("allow_acl", site.allow_acl.is_some()),
+        (
+            "method_allow",
+            site.method_allow.is_some() || site.method_deny.is_some(),
+        ),
+        ("early_hints", site.early_hints.is_some()),
+        ("http3", site.http3),
+        ("proxy_buffering", site.proxy_buffering.is_some()),
+        ("proxy_ssl", site.proxy_ssl.is_some()),
+        ("proxy_buffer_size", site.proxy_buffer_size.is_some()),
+        ("proxy_buffers", site.proxy_buffers.is_some()),
+        ("header_buffers", site.header_buffers.is_some()),
+        ("proxy_cache", site.proxy_cache.is_some()),
+        ("extra_http", site.extra_http.is_some()),
+        ("extra_location", site.extra_location.is_some()),
+    ];
+    for (field, _) in ignored.iter().filter(|(_, set)| *set) {
+        warn(format!(
+            "{} isn't supported by the Ingress output, ignored",
+            field
+        ));
+    }
+
+    let mut annotations = Vec::new();
+    if let Some(protocol) = protocol {
+        annotations.push(("backend-protocol", String::from(protocol)));
+    }
+    if site.force_https {
+        let key = match site.ssl_cert {
+            Some(_) => "ssl-redirect",
+            None => "force-ssl-redirect",
+        };
+        annotations.push((key, String::from("true")));
+    }
+
+    let mut location_snippet = String::new();
+    for header in site.headers.iter().flatten() {
+        if !is_server_location(&header.for_field) {
+            warn(format!(
+                "headers for {:?} can't be limited to some paths, ignored",
+                header.for_field
+            ));
+            continue;
+        }
+        for (name, value) in header.values.iter() {
+            let header = format!("{}: {}", name, value);
+            location_snippet.push_str(&format!("more_set_headers {};\n", quote(&header)));
+        }
+    }
+    if let Some(extra) = &site.extra {
+        location_snippet.push_str(extra.trim());
+        location_snippet.push('\n');
+    }
+    if !location_snippet.is_empty() {
+        annotations.push(("configuration-snippet", location_snippet));
+    }
+
+    let mut server_snippet = String::new();
+    for redirect in site.redirects.iter().flatten() {
+        server_snippet.push_str(&format!(
+            "location {} {} {{ return {} {}; }}\n",
+            if redirect.regex { "~" } else { "=" },
+            redirect.from_field,
+            redirect.status_code,
+            redirect.to
+        ));
+    }
+    if !server_snippet.is_empty() {
+        annotations.push(("server-snippet", server_snippet));
+    }
+
+    let mut yaml = String::from("apiVersion: networking.k8s.io/v1\nkind: Ingress\n");
+    yaml.push_str(&format!("metadata:\n  name: {}\n", quote(&site.domain)));
+    if !annotations.is_empty() {
+        yaml.push_str("  annotations:\n");
+        for (key, value) in annotations.iter() {
+            yaml.push_str(&format!(
+                "    {}{}: {}\n",
+                ANNOTATION_PREFIX,
+                key,
+                scalar(value, 6)
+            ));
+        }
+    }
+    yaml.push_str("spec:\n  ingressClassName: nginx\n");
+    if site.ssl_cert.is_some() {
+        yaml.push_str(&format!(
+            "  tls:\n    - hosts:\n        - {}\n      secretName: {}\n",
+            quote(&site.domain),
+            quote(&format!("{}-tls", site.domain))
+        ));
+    }
+    yaml.push_str(&format!(
+        "  rules:
+    - host: {}
+      http:
+        paths:
+          - path: /
+            pathType: Prefix
+            backend:
+              service:
+                name: {}
+                port:
+                  number: {}
+",
+        quote(&site.domain),
+        quote(&service),
+        port
+    ));
+
+    Some(yaml)
+}
+
+/// The `Service` name and port of `upstream`, from the first label of its
+/// host, and the `backend-protocol` it needs, if not plain HTTP.
+fn service(upstream: &Upstream) -> Result<(String, u16, Option<&'static str>), String> {
+    if upstream.servers.is_some() {
+        return Err(String::from("servers aren't a Service"));
+    }
+    let (host, port, protocol) = match upstream.backend_type {
+        BackendType::Proxy if unix_socket(&upstream.url).is_some() => {
+            return Err(String::from(
+                "unix sockets aren't reachable from an Ingress",
+            ));
+        }
+        BackendType::Proxy => {
+            let url = Url::parse(&upstream.url).map_err(|err| err.to_string())?;
+            let host = url.host_str().unwrap_or_default().to_string();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let protocol = match url.scheme() {
+                "https" => Some("HTTPS"),
+                _ => None,
+            };
+            (host, port, protocol)
+        }
+        BackendType::Fastcgi => match upstream.url.rsplit_once(':') {
+            Some((host, port)) if host != "unix" => (
+                host.to_string(),
+                port.parse().map_err(|_| String::from("invalid port"))?,
+                Some("FCGI"),
+            ),
+            _ => {
+                return Err(String::from(
+                    "unix sockets aren't reachable from an Ingress",
+                ))
+            }
+        },
+        BackendType::Uwsgi => {
+            return Err(String::from("uwsgi isn't supported by ingress-nginx"));
+        }
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let service = host.split('.').next().unwrap_or_default().to_string();
+    if service.is_empty() || host.parse::<std::net::IpAddr>().is_ok() {
+        return Err(String::from("the host isn't the name of a Service"));
+    }
+    Ok((service, port, protocol))
+}
+
+/// `value` as a YAML scalar: a literal block, indented by `indent` spaces,
+/// when it spans several lines, and a double-quoted string otherwise.
+fn scalar(value: &str, indent: usize) -> String {
+    if !value.contains('\n') {
+        return quote(value);
+    }
+
+    let mut block = String::from("|");
+    for line in value.trim_end().lines() {
+        block.push('\n');
+        if !line.is_empty() {
+            block.push_str(&" ".repeat(indent));
+            block.push_str(line);
+        }
+    }
+    block
+}
+
+/// `value` as a YAML double-quoted string.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(config: &str) -> Manifest {
+        let config: UnverifiedConfig = toml::from_str(config).unwrap();
+        ingress(config, &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn test_ingress() {
+        let manifest = manifest(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            upstream = { url = "http://web.default.svc.cluster.local:8080" }
+
+            [[sites.headers]]
+            for = "/*"
+
+            [sites.headers.values]
+            X-Frame-Options = "DENY"
+            Content-Security-Policy = "default-src 'self'"
+
+            [[sites.redirects]]
+            from = "/old"
+            to = "/new"
+            "#,
+        );
+
+        assert!(manifest.warnings.is_empty(), "{:?}", manifest.warnings);
+        assert_eq!(
+            manifest.yaml,
+            r#"apiVersion: networking.k8s.io/v1
+kind: Ingress
+metadata:
+  name: "example.com"
+  annotations:
+    nginx.ingress.kubernetes.io/ssl-redirect: "true"
+    nginx.ingress.kubernetes.io/configuration-snippet: |
+      more_set_headers "Content-Security-Policy: default-src 'self'";
+      more_set_headers "X-Frame-Options: DENY";
+    nginx.ingress.kubernetes.io/server-snippet: |
+      location = /old { return 302 /new; }
+spec:
+  ingressClassName: nginx
+  tls:
+    - hosts:
+        - "example.com"
+      secretName: "example.com-tls"
+  rules:
+    - host: "example.com"
+      http:
+        paths:
+          - path: /
+            pathType: Prefix
+            backend:
+              service:
+                name: "web"
+                port:
+                  number: 8080
+"#
+        );
+    }
+
+    #[test]
+    fn test_ingress_skipped() {
+        let manifest = manifest(
+            r#"
+            [[sites]]
+            domain = "bucket.example.com"
+
+            [[sites]]
+            domain = "php.example.com"
+            upstream = { url = "php:9000", backend_type = "fastcgi" }
+            healthcheck = {}
+
+            [[sites]]
+            domain = "ip.example.com"
+            upstream = { url = "http://127.0.0.1:8000" }
+            "#,
+        );
+
+        assert_eq!(manifest.yaml.matches("kind: Ingress").count(), 1);
+        assert!(manifest
+            .yaml
+            .contains("nginx.ingress.kubernetes.io/backend-protocol: \"FCGI\""));
+        assert_eq!(
+            manifest.warnings,
+            vec![
+                "\"bucket.example.com\": the default bucket isn't a Service, skipped",
+                "\"php.example.com\": healthcheck isn't supported by the Ingress output, ignored",
+                "\"ip.example.com\": upstream \"http://127.0.0.1:8000\": the host isn't the name of a Service, skipped",
+            ]
+        );
+    }
+}
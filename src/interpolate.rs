@@ -0,0 +1,220 @@
+//! `${file:<path>}` references in string values, replaced by the content of
+//! the file when the config is loaded, and `${VAR}` references in paths given
+//! on the command line, replaced by the value of the environment variable.
+
+use std::fs;
+use std::path::Path;
+
+use regex::{Captures, Regex};
+use toml::Value;
+
+use crate::NvhostsError;
+
+/// Replaces the file references of every string of `value`, reading relative
+/// paths from `dir`. `field` is the path to `value` in the config, used to
+/// name the field of a reference that can't be read.
+pub(crate) fn read_files(value: &mut Value, dir: &Path, field: &str) -> Result<(), NvhostsError> {
+    match value {
+        Value::String(s) => {
+            if let Some(replaced) = replace(s, dir, field)? {
+                *s = replaced;
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                read_files(value, dir, &format!("{}[{}]", field, i))?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let field = match field {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", field, key),
+                };
+                read_files(value, dir, &field)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The fields of `value` with a file reference, named as in `read_files`.
+pub(crate) fn file_references(value: &Value, field: &str) -> Vec<String> {
+    match value {
+        Value::String(s) if file_re().is_match(s) => vec![field.to_string()],
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .flat_map(|(i, value)| file_references(value, &format!("{}[{}]", field, i)))
+            .collect(),
+        Value::Table(table) => table
+            .iter()
+            .flat_map(|(key, value)| match field {
+                "" => file_references(value, key),
+                _ => file_references(value, &format!("{}.{}", field, key)),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn file_re() -> Regex {
+    Regex::new(r"\$\{file:([^}]+)\}").expect("valid regex")
+}
+
+/// `s` with its file references replaced, or `None` when it has none.
+fn replace(s: &str, dir: &Path, field: &str) -> Result<Option<String>, NvhostsError> {
+    let re = file_re();
+    if !re.is_match(s) {
+        return Ok(None);
+    }
+
+    let mut error = None;
+    let replaced = re.replace_all(s, |captures: &Captures| {
+        let path = dir.join(captures[1].trim());
+        match fs::read_to_string(&path) {
+            // Files usually end with a newline that isn't part of the value.
+            Ok(content) => content
+                .strip_suffix('\n')
+                .map(|content| content.strip_suffix('\r').unwrap_or(content))
+                .unwrap_or(&content)
+                .to_string(),
+            Err(source) => {
+                error.get_or_insert(NvhostsError::FileReference {
+                    field: field.to_string(),
+                    path,
+                    source,
+                });
+                String::new()
+            }
+        }
+    });
+
+    match error {
+        Some(error) => Err(error),
+        None => Ok(Some(replaced.into_owned())),
+    }
+}
+
+/// `s` with its `${VAR}` references replaced by the value of the environment
+/// variable `VAR`. `field` names where `s` comes from, for the error of a
+/// variable that isn't set.
+pub fn expand_env(s: &str, field: &str) -> Result<String, NvhostsError> {
+    expand(s, field, |name| std::env::var(name).ok())
+}
+
+fn expand(
+    s: &str,
+    field: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, NvhostsError> {
+    let re = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("valid regex");
+    let mut error = None;
+    let expanded = re.replace_all(s, |captures: &Captures| {
+        lookup(&captures[1]).unwrap_or_else(|| {
+            error.get_or_insert(NvhostsError::EnvVar {
+                field: field.to_string(),
+                name: captures[1].to_string(),
+            });
+            String::new()
+        })
+    });
+
+    match error {
+        Some(error) => Err(error),
+        None => Ok(expanded.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_files() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("csp.txt"), "default-src 'self'\n").unwrap();
+
+        let mut value: Value = toml::from_str(
+            r##"
+            [[sites]]
+            domain = "example.com"
+            extra = "# ${file:csp.txt}, ${ file:csp.txt }"
+
+            [[sites.headers]]
+            for = "/*"
+
+            [sites.headers.values]
+            Content-Security-Policy = "${file:csp.txt}"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(
+            file_references(&value, ""),
+            [
+                "sites[0].extra",
+                "sites[0].headers[0].values.Content-Security-Policy"
+            ]
+        );
+        read_files(&mut value, &dir, "").unwrap();
+        let site = &value["sites"][0];
+        assert_eq!(
+            site["headers"][0]["values"]["Content-Security-Policy"].as_str(),
+            Some("default-src 'self'")
+        );
+        assert_eq!(
+            site["extra"].as_str(),
+            Some("# default-src 'self', ${ file:csp.txt }")
+        );
+
+        let mut value: Value = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            extra = "${file:missing.conf}"
+            "#,
+        )
+        .unwrap();
+        match read_files(&mut value, &dir, "") {
+            Err(NvhostsError::FileReference { field, path, .. }) => {
+                assert_eq!(field, "sites[0].extra");
+                assert_eq!(path, dir.join("missing.conf"));
+            }
+            _ => panic!("expected a file reference error"),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| match name {
+            "HOSTNAME" => Some(String::from("web-1")),
+            "ENV" => Some(String::from("prod")),
+            _ => None,
+        };
+        assert_eq!(
+            expand(
+                "/srv/${HOSTNAME}/${ENV}/sites-available",
+                "--output-dir",
+                lookup
+            )
+            .unwrap(),
+            "/srv/web-1/prod/sites-available"
+        );
+        // Only braced names are variables, and file references are left alone.
+        assert_eq!(
+            expand("$HOSTNAME/${file:x}/${1}", "--output-dir", lookup).unwrap(),
+            "$HOSTNAME/${file:x}/${1}"
+        );
+        assert_eq!(
+            expand("/srv/${MISSING}", "--output-dir", lookup)
+                .err()
+                .unwrap()
+                .to_string(),
+            "--output-dir: environment variable MISSING isn't set"
+        );
+    }
+}
@@ -1,30 +1,175 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
-use anyhow::{anyhow, bail, Error, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use ipnet::IpNet;
+use log::{debug, info, warn};
 use regex::Regex;
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use tera::{to_value, try_get_value, Context, Tera, Value};
+use url::Url;
 
+use hook::Hook;
+
+pub use error::{NvhostsError, ValidationError, Warning, WarningKind};
+pub use interpolate::expand_env;
+
+pub mod archive;
+pub mod audit;
+pub mod builder;
+pub mod canonical;
+pub mod check;
+mod command;
+pub mod completions;
+pub mod diff;
+mod error;
+pub mod explain;
+mod format;
+pub mod haproxy;
+pub mod highlight;
+mod hook;
+pub mod import;
+pub mod ingress;
+mod interpolate;
+pub mod lint;
+mod manifest;
+pub mod minimize;
+pub mod modules;
+mod reachable;
+pub mod remote;
+pub mod split;
+mod state;
 pub mod verbose;
+pub mod watch;
 
 const OUTPUT_DIR: &str = "./sites-available";
 const TEMPLATE: &str = include_str!("vhost.template");
+const HTTP_TEMPLATE: &str = include_str!("http.template");
+const MAIN_TEMPLATE: &str = include_str!("main.template");
+const CATCH_ALL_TEMPLATE: &str = include_str!("catch_all.template");
+const STREAM_TEMPLATE: &str = include_str!("stream.template");
+const MACROS_TEMPLATE: &str = include_str!("macros.template");
+/// Names of the templates rendered for each site, for the http context and
+/// for the main one.
+/// The built-in ones are also registered as `base/<name>`, for the templates
+/// of `Options::template_dir` to extend.
+const TEMPLATE_NAME: &str = "vhost.template";
+const HTTP_TEMPLATE_NAME: &str = "http.template";
+const MAIN_TEMPLATE_NAME: &str = "main.template";
+const CATCH_ALL_TEMPLATE_NAME: &str = "catch_all.template";
+const STREAM_TEMPLATE_NAME: &str = "stream.template";
+/// Macros shared by the server blocks of `vhost.template`, which imports them.
+const MACROS_TEMPLATE_NAME: &str = "macros.template";
+/// First line of the generated files, telling them apart from the ones
+/// written by hand.
+const BANNER: &str = "# Generated by nvhosts, changes are overwritten on the next run.";
+/// File holding the http-context blocks shared by every site, such as `map`s.
+const HTTP_FILENAME: &str = "nvhosts-http.conf";
+/// File holding the server of `catch_all`.
+const CATCH_ALL_FILENAME: &str = "nvhosts-catch-all.conf";
+/// File holding the tuning of `main`, included in the main context of
+/// nginx.conf rather than in the http one.
+const MAIN_FILENAME: &str = "nvhosts-main.conf";
+/// File holding the `stream` block of `streams`, included in the main context
+/// of nginx.conf as well.
+const STREAM_FILENAME: &str = "nvhosts-stream.conf";
+/// Variables the templates are rendered with, which `Options::vars` can't
+/// replace.
+pub const TEMPLATE_GLOBALS: [&str; 11] = [
+    "site",
+    "nginx",
+    "maps",
+    "geos",
+    "proxy_caches",
+    "resolver",
+    "real_ip",
+    "log_formats",
+    "main",
+    "catch_all",
+    "streams",
+];
+/// Extension of the files generated for the sites, unless `extension` is set.
+const DEFAULT_EXTENSION: &str = "conf";
+
+/// Number of sites from which they are generated by a pool of threads: below
+/// it, starting the threads and copying the templates for each of them costs
+/// more than it saves. On a single CPU, the `jobs` group of `cargo bench`
+/// generates 64 sites in 44ms on one thread and 57ms on four, and 256 sites in
+/// 106ms and 124ms: the pool costs about 13ms, and each site about 0.32ms,
+/// three quarters of which four CPUs save. The pool pays off from about 55
+/// sites, which 64 rounds up.
+const PARALLEL_SITES: usize = 64;
+
+/// How long nginx and the hooks may run when `Options::timeout` isn't set,
+/// before they are killed.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `--skip-unreachable` waits for an upstream to accept a
+/// connection.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// nginx version assumed when the config doesn't set `nginx_version`.
+const DEFAULT_NGINX_VERSION: Version = Version::new(1, 28, 0);
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 struct Header {
     #[serde(rename = "for")]
     for_field: String,
-    values: HashMap<String, String>,
+    /// Sorted by name, so that the `add_header` lines come in the same order
+    /// on every run.
+    values: BTreeMap<String, String>,
+    /// Lua code run in the location of the headers.
+    lua: Option<Vec<Lua>>,
+    /// Sends the values of the entry along with the ones the other entries
+    /// of the location set for the same headers, e.g. several `Set-Cookie`,
+    /// rather than failing validation.
+    #[serde(default)]
+    allow_duplicates: bool,
+    /// Filled in by validation: the values of the later entries of the
+    /// location for the headers this one sets too.
+    #[serde(skip_deserializing)]
+    duplicates: Vec<(String, String)>,
+    /// Filled in by validation: the entry is merged into an earlier one of
+    /// the same location.
+    #[serde(skip_deserializing)]
+    merged: bool,
+    /// Filled in by validation: the names of `values` in lowercase, for the
+    /// template to leave out the headers of the whole site the location sets
+    /// too, whatever their case.
+    #[serde(skip_deserializing)]
+    names: Vec<String>,
+}
+
+/// Lua code run by OpenResty in a phase of a location, rendered as a
+/// `<phase>_by_lua_block`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Lua {
+    phase: LuaPhase,
+    code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+enum LuaPhase {
+    Rewrite,
+    Access,
+    /// Generates the response, instead of the upstream.
+    Content,
+    HeaderFilter,
+    BodyFilter,
+    Log,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 struct CacheControl {
+    /// A MIME type, e.g. `text/html`, or all those of a type, e.g. `image/*`.
     mime: String,
     value: String,
 }
@@ -36,54 +181,1508 @@ struct Redirect {
     to: String,
     #[serde(default = "default_redirect_status_code")]
     status_code: u16,
+    /// Match `from` as a case-sensitive regular expression instead of an
+    /// exact path.
+    #[serde(default)]
+    regex: bool,
+}
+
+/// A name of a site besides its domain, e.g. `*.example.com`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ServerName {
+    name: String,
+    /// Match `name` as a regular expression, emitted as `~name`; its named
+    /// captures become variables, e.g. `$sub` for `(?<sub>.+)`.
+    #[serde(default)]
+    regex: bool,
+}
+
+/// A `rewrite` of the server block, changing the URI of the requests that
+/// match `pattern`, in order.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Rewrite {
+    /// A case-sensitive regular expression.
+    pattern: String,
+    /// Can refer to the groups of `pattern`, e.g. `/posts?id=$1`.
+    replacement: String,
+    /// Without it, the next rewrites are tried with the new URI.
+    flag: Option<RewriteFlag>,
+}
+
+/// What a `redirect_to` site does with the path of the requests.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PathMode {
+    /// Appends the path and query to the target, to move a whole site.
+    #[default]
+    Preserve,
+    /// Redirects every request to the target itself, e.g. a homepage.
+    Drop,
+    /// Maps paths with `path_rewrites`, the others going to the target.
+    Rewrite,
+}
+
+/// A path of a `redirect_to` site redirected to another path of the target.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PathRewrite {
+    /// A case-sensitive regular expression.
+    pattern: String,
+    /// A path, appended to the target, that can refer to the groups of
+    /// `pattern`, e.g. `/posts/$1`.
+    replacement: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RewriteFlag {
+    /// Stop rewriting and search the location of the new URI.
+    Last,
+    /// Stop rewriting and stay in the current location.
+    Break,
+    /// Return a temporary redirect to the new URI.
+    Redirect,
+    /// Return a permanent redirect to the new URI.
+    Permanent,
+}
+
+/// A resource the browser is told to fetch early with a `Link` preload
+/// header, and that nginx versions with server push also push.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct EarlyHint {
+    href: String,
+    #[serde(rename = "as")]
+    as_field: PreloadType,
+}
+
+/// The destinations of preloads, from the `as` attribute of `<link>`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PreloadType {
+    Script,
+    Style,
+    /// Also preloaded with `crossorigin`, without which browsers ignore it.
+    Font,
+    Image,
+    Fetch,
+    Document,
 }
 
 const fn default_redirect_status_code() -> u16 {
     302
 }
 
+/// A `map` block emitted once in the http context.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct Map {
+    /// Name of the variable being defined, without the leading `$`.
+    variable: String,
+    /// Variable the map matches on, e.g. `$http_user_agent`.
+    source: String,
+    default: Option<String>,
+    #[serde(default)]
+    entries: Vec<MapEntry>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct MapEntry {
+    #[serde(rename = "match")]
+    match_field: String,
+    value: String,
+}
+
+/// A variable set by the address of the client, emitted as a `geo` block in
+/// the http context.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct Geo {
+    /// Name of the variable being defined, without the leading `$`.
+    variable: String,
+    /// Variable holding the address matched, `$remote_addr` by default, which
+    /// `real_ip` sets to the one of the client.
+    source: Option<String>,
+    /// Value of the addresses no range matches, empty by default.
+    default: Option<String>,
+    #[serde(default)]
+    ranges: Vec<GeoRange>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct GeoRange {
+    /// An IP address or a CIDR range.
+    cidr: String,
+    value: String,
+}
+
+/// A `log_format` emitted once in the http context. Without `format`, `name`
+/// must be the one of a preset: `json`.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct LogFormat {
+    name: String,
+    format: Option<String>,
+    /// Escaping of the variables, e.g. `json`.
+    escape: Option<String>,
+}
+
+const JSON_LOG_FORMAT: &str = concat!(
+    r#"{"time":"$time_iso8601","remote_addr":"$remote_addr","host":"$host","#,
+    r#""request":"$request","status":$status,"body_bytes_sent":$body_bytes_sent,"#,
+    r#""request_time":$request_time,"http_referer":"$http_referer","#,
+    r#""http_user_agent":"$http_user_agent"}"#
+);
+
+/// A TLS configuration recommended by Mozilla, see
+/// https://ssl-config.mozilla.org/.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TlsProfile {
+    /// TLS 1.3 only, for clients from 2019 on.
+    Modern,
+    /// TLS 1.2 and 1.3, for almost every client.
+    Intermediate,
+    /// Down to TLS 1.0, for very old clients.
+    Old,
+}
+
+const INTERMEDIATE_CIPHERS: &str = concat!(
+    "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:",
+    "ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:",
+    "ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:",
+    "DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384:DHE-RSA-CHACHA20-POLY1305"
+);
+
+const OLD_CIPHERS: &str = concat!(
+    "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:",
+    "ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:",
+    "ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:",
+    "DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384:DHE-RSA-CHACHA20-POLY1305:",
+    "ECDHE-ECDSA-AES128-SHA256:ECDHE-RSA-AES128-SHA256:ECDHE-ECDSA-AES128-SHA:",
+    "ECDHE-RSA-AES128-SHA:ECDHE-ECDSA-AES256-SHA384:ECDHE-RSA-AES256-SHA384:",
+    "ECDHE-ECDSA-AES256-SHA:ECDHE-RSA-AES256-SHA:DHE-RSA-AES128-SHA256:",
+    "DHE-RSA-AES256-SHA256:AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA256:",
+    "AES256-SHA256:AES128-SHA:AES256-SHA:DES-CBC3-SHA"
+);
+
+/// Protocols accepted by `ssl_protocols`.
+const TLS_PROTOCOLS: &[&str] = &["SSLv2", "SSLv3", "TLSv1", "TLSv1.1", "TLSv1.2", "TLSv1.3"];
+
+/// Where certbot links the current certificates of each domain.
+const LETSENCRYPT_LIVE: &str = "/etc/letsencrypt/live";
+
+/// Methods that `limit_except` knows, in the order nginx lists them.
+const HTTP_METHODS: &[&str] = &[
+    "GET",
+    "HEAD",
+    "POST",
+    "PUT",
+    "DELETE",
+    "MKCOL",
+    "COPY",
+    "MOVE",
+    "OPTIONS",
+    "PROPFIND",
+    "PROPPATCH",
+    "LOCK",
+    "UNLOCK",
+    "PATCH",
+];
+
+/// Ports of the generated servers, which `listen` entries must use.
+const HTTP_PORT: u16 = 8080;
+const HTTPS_PORT: u16 = 443;
+
+/// The `listen` entries of a site split by port, replacing the wildcard
+/// addresses of the servers on that port when not empty. Exposed to the
+/// template as `site.listen_on`.
+#[derive(Default, Debug, Serialize, Clone)]
+struct ListenOn {
+    http: Vec<String>,
+    https: Vec<String>,
+}
+
+/// The TLS directives of a site, from its profile and its own
+/// `ssl_protocols` and `ssl_ciphers`. Exposed to the template as `site.tls`.
+#[derive(Default, Debug, Serialize, Clone)]
+struct Tls {
+    protocols: Option<String>,
+    ciphers: Option<String>,
+    prefer_server_ciphers: Option<bool>,
+    /// Emit the session settings of the profiles.
+    session: bool,
+    dhparam: Option<String>,
+}
+
+impl Tls {
+    fn new(profile: Option<TlsProfile>, dhparam: Option<&String>) -> Self {
+        let protocols = |protocols: &str| Some(String::from(protocols));
+        match profile {
+            None => Self::default(),
+            Some(TlsProfile::Modern) => Self {
+                protocols: protocols("TLSv1.3"),
+                prefer_server_ciphers: Some(false),
+                session: true,
+                ..Default::default()
+            },
+            Some(TlsProfile::Intermediate) => Self {
+                protocols: protocols("TLSv1.2 TLSv1.3"),
+                ciphers: Some(String::from(INTERMEDIATE_CIPHERS)),
+                prefer_server_ciphers: Some(false),
+                session: true,
+                dhparam: dhparam.cloned(),
+            },
+            Some(TlsProfile::Old) => Self {
+                protocols: protocols("TLSv1 TLSv1.1 TLSv1.2 TLSv1.3"),
+                ciphers: Some(String::from(OLD_CIPHERS)),
+                prefer_server_ciphers: Some(true),
+                session: true,
+                dhparam: dhparam.cloned(),
+            },
+        }
+    }
+}
+
+/// Client certificate verification of an HTTPS site.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct Mtls {
+    /// CA certificates trusted to sign client certificates.
+    client_ca: String,
+    #[serde(default)]
+    verify: MtlsVerify,
+    verify_depth: Option<u8>,
+}
+
+/// Whether requests without a valid client certificate are rejected.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MtlsVerify {
+    #[default]
+    On,
+    /// Accept them, leaving the check of `$ssl_client_verify` to the
+    /// upstream.
+    Optional,
+}
+
+/// How nginx connects to an `https://` upstream.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ProxySsl {
+    /// Sends the name of the upstream with SNI, which most HTTPS servers
+    /// hosting several names need. On by default, unlike in nginx.
+    #[serde(default = "default_proxy_ssl_server_name")]
+    server_name: bool,
+    /// Name sent with SNI and verified in the certificate, the host of the
+    /// upstream by default.
+    name: Option<String>,
+    /// Verifies the certificate of the upstream, against
+    /// `trusted_certificate`.
+    #[serde(default)]
+    verify: bool,
+    /// CA certificates trusted to sign the certificate of the upstream.
+    trusted_certificate: Option<String>,
+}
+
+const fn default_proxy_ssl_server_name() -> bool {
+    true
+}
+
+/// Limits the rate of the requests of each client of a site, with a
+/// `limit_req_zone` of its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RateLimit {
+    /// Requests per second or minute, e.g. `"10r/s"`.
+    rate: String,
+    /// Requests above the rate that are delayed rather than rejected.
+    burst: Option<u32>,
+    /// Serves the requests of `burst` at once rather than at the rate.
+    #[serde(default)]
+    nodelay: bool,
+    /// What the requests are counted by, the client address by default.
+    #[serde(default = "default_rate_limit_key")]
+    key: String,
+    /// Size of the shared memory zone of the counters.
+    #[serde(default = "default_rate_limit_zone_size")]
+    zone_size: String,
+    /// Status of the rejected requests, 503 by default in nginx.
+    status: Option<u16>,
+    /// Only logs the requests that would be rejected, to tune `rate` before
+    /// enforcing it. Requires nginx 1.17.1.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+fn default_rate_limit_key() -> String {
+    String::from("$binary_remote_addr")
+}
+
+fn default_rate_limit_zone_size() -> String {
+    String::from("10m")
+}
+
+/// A page served in place of the errors of some statuses.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ErrorPage {
+    /// Statuses of the errors, e.g. `[502, 503, 504]`.
+    codes: Vec<u16>,
+    /// Absolute path of the HTML page, served with the status of the error.
+    page: String,
+}
+
+/// Cross-origin requests allowed to a site: their preflights are answered
+/// with a 204, and the other responses get the `Access-Control-Allow-*`
+/// headers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Cors {
+    /// Origins allowed, e.g. `["https://app.example.com"]`, or `["*"]` for
+    /// any.
+    origins: Vec<String>,
+    /// Methods allowed by the preflights.
+    #[serde(default = "default_cors_methods")]
+    methods: Vec<String>,
+    /// Request headers allowed by the preflights, e.g. `["Authorization"]`.
+    #[serde(default)]
+    headers: Vec<String>,
+    /// Response headers readable by scripts, beyond the safelisted ones.
+    #[serde(default)]
+    expose_headers: Vec<String>,
+    /// How long browsers may cache a preflight, in seconds.
+    max_age: Option<u32>,
+    /// Allows cookies and HTTP authentication, which requires listed
+    /// `origins`.
+    #[serde(default)]
+    credentials: bool,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec![
+        String::from("GET"),
+        String::from("HEAD"),
+        String::from("POST"),
+    ]
+}
+
+/// First nginx version with `limit_req_dry_run`.
+const LIMIT_REQ_DRY_RUN_VERSION: Version = Version::new(1, 17, 1);
+
+/// First nginx version with QUIC, for `http3`.
+const QUIC_VERSION: Version = Version::new(1, 25, 0);
+
+/// Where the requests of a site are logged.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct AccessLog {
+    path: String,
+    /// One of the config's `log_formats`, or nginx's `combined`.
+    format: Option<String>,
+    /// Only log this percentage of the requests, picked by `$request_id`,
+    /// e.g. `"10%"`.
+    sample: Option<String>,
+    /// Paths whose requests aren't logged, matched exactly against `$uri`.
+    #[serde(default)]
+    skip_paths: Vec<String>,
+    /// Don't log the requests of the usual health check paths either, those
+    /// of `HEALTHCHECK_PATHS`, e.g. when the upstream answers them.
+    #[serde(default)]
+    skip_healthchecks: bool,
+    /// Only log the requests for which this variable of the config's `maps`
+    /// is neither empty nor `"0"`, e.g. `"$loggable"`.
+    condition: Option<String>,
+}
+
+/// Paths of health checks commonly answered by upstreams, not logged with
+/// `skip_healthchecks`.
+const HEALTHCHECK_PATHS: [&str; 5] = ["/health", "/healthz", "/livez", "/readyz", "/ping"];
+
+/// A `proxy_cache_path` emitted once in the http context.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct ProxyCache {
+    path: String,
+    /// `name:size`, e.g. `static:10m`.
+    keys_zone: String,
+    levels: Option<String>,
+    max_size: Option<String>,
+    inactive: Option<String>,
+}
+
+impl ProxyCache {
+    fn zone(&self) -> &str {
+        self.keys_zone.split(':').next().unwrap_or_default()
+    }
+}
+
+/// Caches upstream responses of a site in one of the config's `proxy_caches`.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct SiteProxyCache {
+    zone: String,
+    /// `proxy_cache_valid` rules, e.g. `200 302 10m`.
+    #[serde(default)]
+    valid: Vec<String>,
+}
+
+/// Buffers reading the request headers, for clients sending large cookies or
+/// tokens, which nginx otherwise rejects with a 400.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct HeaderBuffers {
+    /// `client_header_buffer_size`, e.g. `4k`.
+    size: Option<String>,
+    /// Number and size of the `large_client_header_buffers`, e.g. `4 32k`.
+    large: Option<String>,
+}
+
+/// The `resolver` emitted once in the http context, needed by dynamic
+/// upstreams.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct Resolver {
+    /// IP addresses of the DNS servers, with an optional port, e.g.
+    /// `"127.0.0.11"` or `"[2001:db8::53]:5353"`.
+    addresses: Vec<String>,
+    /// How long answers are cached, e.g. `30s`. Defaults to the record TTL.
+    valid: Option<String>,
+    /// Whether IPv6 addresses are looked up too, as nginx does by default.
+    ipv6: Option<bool>,
+}
+
+/// Takes the client address from a header set by trusted proxies, such as a
+/// CDN, instead of the address of the connection.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct RealIp {
+    /// Addresses or CIDR ranges of the trusted proxies.
+    #[serde(default)]
+    from: Vec<String>,
+    /// Defaults to nginx's `X-Real-IP`, or `CF-Connecting-IP` with the
+    /// cloudflare preset.
+    header: Option<String>,
+    /// Skip the trusted addresses of a header listing several of them, such
+    /// as `X-Forwarded-For`.
+    #[serde(default)]
+    recursive: bool,
+    /// Adds the published ranges of a CDN to `from`.
+    preset: Option<RealIpPreset>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RealIpPreset {
+    Cloudflare,
+}
+
+/// The ranges listed on https://www.cloudflare.com/ips/.
+const CLOUDFLARE_RANGES: &[&str] = &[
+    "173.245.48.0/20",
+    "103.21.244.0/22",
+    "103.22.200.0/22",
+    "103.31.4.0/22",
+    "141.101.64.0/18",
+    "108.162.192.0/18",
+    "190.93.240.0/20",
+    "188.114.96.0/20",
+    "197.234.240.0/22",
+    "198.41.128.0/17",
+    "162.158.0.0/15",
+    "104.16.0.0/13",
+    "104.24.0.0/14",
+    "172.64.0.0/13",
+    "131.0.72.0/22",
+    "2400:cb00::/32",
+    "2606:4700::/32",
+    "2803:f800::/32",
+    "2405:b500::/32",
+    "2405:8100::/32",
+    "2a06:98c0::/29",
+    "2c0f:f248::/32",
+];
+
+impl RealIp {
+    /// Fills in the preset, if any, and explains what is wrong with the
+    /// result.
+    fn apply_preset(&mut self) -> Vec<String> {
+        if self.preset == Some(RealIpPreset::Cloudflare) {
+            self.from
+                .extend(CLOUDFLARE_RANGES.iter().map(|range| range.to_string()));
+            self.header
+                .get_or_insert_with(|| String::from("CF-Connecting-IP"));
+        }
+
+        let mut problems = Vec::new();
+        if self.from.is_empty() {
+            problems.push(String::from(
+                "real_ip: at least one trusted address is required in from",
+            ));
+        }
+        for address in self.from.iter() {
+            if !address.starts_with("unix:") && !is_address_range(address) {
+                problems.push(format!("real_ip: invalid address {:?}", address));
+            }
+        }
+        problems
+    }
+}
+
+/// Tuning of the workers, for users who let nvhosts manage more than the
+/// vhosts. The `events` block is rendered to its own file, the other
+/// directives, only valid in the http context, along with the maps.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct Main {
+    worker_connections: Option<u32>,
+    multi_accept: Option<bool>,
+    sendfile: Option<bool>,
+    /// Only used by nginx along with `sendfile`.
+    tcp_nopush: Option<bool>,
+}
+
+/// The default server on port 443, closing the connections for the hosts
+/// that no site serves, rather than answering with the certificate of the
+/// first site.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct CatchAll {
+    /// A self-signed certificate, such as the snakeoil one of Debian.
+    ssl_cert: String,
+    ssl_key: String,
+}
+
+/// A TCP or UDP proxy of the `stream` context, for services that don't speak
+/// HTTP, such as databases or SMTP.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StreamProxy {
+    /// Port listened on, on every IPv4 and IPv6 address.
+    listen: u16,
+    #[serde(default)]
+    protocol: Protocol,
+    /// Where the connections are proxied to: a `url` of the form `host:port`
+    /// or `unix:/path`, or the name of the `upstream` block of its
+    /// `servers`, as for fastcgi upstreams.
+    upstream: Upstream,
+    /// Closes the connections idle for this long, e.g. `"1h"`; 10 minutes by
+    /// default.
+    proxy_timeout: Option<String>,
+}
+
+/// The transport protocol of a `StreamProxy`.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// The protocol spoken to an upstream, which picks the `*_pass` directive.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum BackendType {
+    #[default]
+    Proxy,
+    Fastcgi,
+    Uwsgi,
+}
+
+/// How the `headers` and `redirects` of a site combine with the ones of the
+/// fragments it includes. Its other lists replace the ones of the fragments.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MergeStrategy {
+    /// The entries of the site come after the ones of the fragments.
+    #[default]
+    Append,
+    /// The entries of the site come before the ones of the fragments.
+    Prepend,
+    /// The list of the site replaces the one of the fragments.
+    Replace,
+}
+
+/// How nginx compares the `If-Modified-Since` of a request with the
+/// modification time of a file, the values of `if_modified_since`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum IfModifiedSince {
+    /// Always sends the file.
+    Off,
+    /// Answers a 304 when the times are the same, nginx's default.
+    Exact,
+    /// Answers a 304 when the file wasn't modified after the time of the
+    /// request.
+    Before,
+}
+
+impl std::fmt::Display for IfModifiedSince {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            IfModifiedSince::Off => "off",
+            IfModifiedSince::Exact => "exact",
+            IfModifiedSince::Before => "before",
+        })
+    }
+}
+
+/// Severity from which nginx logs the errors of a site, the levels of its
+/// `error_log` directive.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ErrorLogLevel {
+    /// Only logged by an nginx built `--with-debug`.
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Error,
+    Crit,
+    Alert,
+    Emerg,
+}
+
+impl std::fmt::Display for ErrorLogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorLogLevel::Debug => "debug",
+            ErrorLogLevel::Info => "info",
+            ErrorLogLevel::Notice => "notice",
+            ErrorLogLevel::Warn => "warn",
+            ErrorLogLevel::Error => "error",
+            ErrorLogLevel::Crit => "crit",
+            ErrorLogLevel::Alert => "alert",
+            ErrorLogLevel::Emerg => "emerg",
+        })
+    }
+}
+
+/// Where the errors of a site are logged when it sets `error_log_level`
+/// without `error_log`.
+const DEFAULT_ERROR_LOG: &str = "/var/log/nginx/error.log";
+
+/// Where a site proxies its requests to.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct Upstream {
+    /// An http(s) URL for `proxy`, `host:port` or `unix:/path` otherwise.
+    url: String,
+    #[serde(default)]
+    backend_type: BackendType,
+    /// Resolve the host of `url` at request time rather than once at startup,
+    /// for hosts whose address changes (containers, external APIs).
+    #[serde(default)]
+    dynamic: bool,
+    /// Servers the requests are balanced between, emitted as an `upstream`
+    /// block named after the host of `url`, e.g. `http://app`, or `url`
+    /// itself for `fastcgi` and `uwsgi`.
+    servers: Option<Vec<UpstreamServer>>,
+    /// Checks the `servers` periodically, with `active_health_checks = true`.
+    health_check: Option<HealthCheck>,
+}
+
+/// A `server` of the `upstream` block of a site.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct UpstreamServer {
+    /// `host:port` or `unix:/path`.
+    address: String,
+    /// Failed attempts within `fail_timeout` after which the server is left
+    /// out for `fail_timeout`, 1 by default in nginx, 0 never.
+    max_fails: Option<u32>,
+    /// e.g. `"30s"`, 10 seconds by default in nginx.
+    fail_timeout: Option<String>,
+}
+
+/// The `health_check` directive of nginx Plus, probing the servers of an
+/// upstream without waiting for requests to fail.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct HealthCheck {
+    /// Path requested, `/` by default.
+    uri: Option<String>,
+    /// Time between two checks, e.g. `"5s"`.
+    interval: Option<String>,
+    /// Failed checks after which a server is left out.
+    fails: Option<u32>,
+    /// Passed checks after which a server is back.
+    passes: Option<u32>,
+}
+
+/// Shares of the traffic of a site sent to other upstreams than its own,
+/// which gets the rest.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Split {
+    /// What the share of a request is picked by, `$remote_addr` by default so
+    /// that a client always reaches the same upstream.
+    #[serde(default = "default_split_key")]
+    key: String,
+    targets: Vec<SplitTarget>,
+}
+
+fn default_split_key() -> String {
+    String::from("$remote_addr")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SplitTarget {
+    /// Share of the traffic, e.g. `"10%"`.
+    percent: String,
+    /// An http(s) URL without a path, or `http://unix:/path`.
+    url: String,
+}
+
+/// A cheap location answering load balancer health checks, served over
+/// plain HTTP even when `force_https` is set.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Healthcheck {
+    #[serde(default = "default_healthcheck_path")]
+    path: String,
+    #[serde(default = "default_healthcheck_status")]
+    status: u16,
+    #[serde(default = "default_healthcheck_body")]
+    body: String,
+}
+
+fn default_healthcheck_path() -> String {
+    String::from("/healthz")
+}
+
+const fn default_healthcheck_status() -> u16 {
+    200
+}
+
+fn default_healthcheck_body() -> String {
+    String::from("ok")
+}
+
+/// Redirects to the value of a map variable whenever it is not empty.
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct MapRedirect {
+    map: String,
+    #[serde(default = "default_redirect_status_code")]
+    status_code: u16,
+}
+
+const fn default_nginx_version() -> Version {
+    DEFAULT_NGINX_VERSION
+}
+
+/// Syntax switches derived from the targeted nginx version, exposed to the
+/// template as `nginx`.
+#[derive(Debug, Serialize, Clone, Copy)]
+struct Syntax {
+    /// `http2 on;` replaced the `http2` parameter of `listen` in 1.25.1.
+    http2_directive: bool,
+    /// Server push, e.g. from the `Link` preload headers, was removed in
+    /// 1.25.1.
+    http2_push: bool,
+    /// The `quic` parameter of `listen`, for HTTP/3.
+    quic: bool,
+}
+
+impl Syntax {
+    fn for_version(version: &Version) -> Self {
+        Self {
+            http2_directive: *version >= Version::new(1, 25, 1),
+            http2_push: *version < Version::new(1, 25, 1),
+            quic: *version >= QUIC_VERSION,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, Clone)]
 struct Site {
     domain: String,
+    /// More names the server of the site answers to, in its `server_name`
+    /// after the domain.
+    server_names: Option<Vec<ServerName>>,
+    /// Filled in by validation: the names of the `server_name` directive of
+    /// the server of the site.
+    #[serde(skip_deserializing)]
+    server_name: String,
+    /// Free-form note rendered as comments at the top of the generated file.
+    description: Option<String>,
+    #[serde(default)]
+    http2: bool,
+    /// Also serves the site over HTTP/3, with `quic` listens on the HTTPS
+    /// addresses next to the `ssl` ones.
+    #[serde(default)]
+    http3: bool,
+    /// Filled in by validation: the HTTPS addresses whose `quic` listen
+    /// takes `reuseport`, which nginx accepts once per address.
+    #[serde(skip_deserializing)]
+    quic_reuseport: Vec<String>,
+    /// Filled in by validation: the ports of the `quic` listens, advertised
+    /// by `Alt-Svc`.
+    #[serde(skip_deserializing)]
+    quic_ports: Vec<u16>,
+    /// `address:port` entries binding the servers to specific IPs, e.g.
+    /// `"10.0.0.5:443"` or `"[2001:db8::5]:8080"`, instead of all of them.
+    listen: Option<Vec<String>>,
+    /// Filled in by validation from `listen`.
+    #[serde(skip_deserializing)]
+    listen_on: Option<ListenOn>,
+    /// Filled in by validation: whether the site is rendered to the minimal
+    /// server of `lean_redirects`.
+    #[serde(skip_deserializing)]
+    redirects_only: bool,
+    /// Marks the listens of the site as `default_server`, to serve the
+    /// requests whose host no server matches.
+    #[serde(default)]
+    default_server: bool,
+    ssl_cert: Option<String>,
+    ssl_key: Option<String>,
+    /// Fills `ssl_cert` and `ssl_key` from the certbot layout,
+    /// `/etc/letsencrypt/live/<domain>/`.
+    #[serde(default)]
+    letsencrypt: bool,
+    mtls: Option<Mtls>,
+    /// Replaces the config's `tls_profile`.
+    tls_profile: Option<TlsProfile>,
+    /// Replaces the protocols of the TLS profile, e.g. `"TLSv1.2 TLSv1.3"`.
+    ssl_protocols: Option<String>,
+    /// Replaces the ciphers of the TLS profile.
+    ssl_ciphers: Option<String>,
+    /// Filled in by validation for HTTPS sites.
+    #[serde(skip_deserializing)]
+    tls: Option<Tls>,
+    /// Redirect plain HTTP requests to HTTPS.
+    #[serde(default)]
+    force_https: bool,
+    /// Serves `/.well-known/acme-challenge/` from this directory over plain
+    /// HTTP, even when `force_https` is set.
+    acme_webroot: Option<String>,
+    /// Locations still served over plain HTTP when `force_https` is set.
+    https_redirect_exclude: Option<Vec<String>>,
+    /// Defaults to the Clever Cloud Cellar bucket.
+    upstream: Option<Upstream>,
+    /// Filled in by validation: the name of the `upstream` block of the
+    /// `servers` of `upstream`.
+    #[serde(skip_deserializing)]
+    upstream_group: Option<String>,
+    /// Sends a share of the traffic to other upstreams, for canary deploys.
+    split: Option<Split>,
+    /// Serves the files of this directory instead of an upstream.
+    root: Option<String>,
+    /// Emits the usual directives for serving files from `root`: `sendfile`,
+    /// `tcp_nopush`, `tcp_nodelay` and `open_file_cache`.
+    #[serde(default)]
+    static_optimize: bool,
+    /// Serves `<file>.gz` instead of a requested file when it exists and the
+    /// client accepts gzip, from `root`.
+    #[serde(default)]
+    gzip_static: bool,
+    /// Serves `<file>.br` likewise for brotli; requires the ngx_brotli module.
+    #[serde(default)]
+    brotli_static: bool,
+    /// `false` sends the files of `root` without an `ETag`, e.g. behind a CDN
+    /// handling the conditional requests. Defaults to nginx's `on`.
+    etag: Option<bool>,
+    /// How the `If-Modified-Since` of the requests is compared with the
+    /// files of `root`, `exact` by default in nginx.
+    if_modified_since: Option<IfModifiedSince>,
+    /// The only HTTP methods served, e.g. `["GET", "POST"]`; others get a 403.
+    method_allow: Option<Vec<String>>,
+    /// HTTP methods refused with a 403, the others being served.
+    method_deny: Option<Vec<String>>,
+    /// Filled in by validation from `method_allow` or `method_deny`, for
+    /// `limit_except`.
+    #[serde(skip_deserializing)]
+    limit_except: Option<Vec<String>>,
+    /// Other hosts of the site, all permanently redirected by a single server
+    /// to `canonical`, keeping the path.
+    aliases: Option<Vec<String>>,
+    /// Host the `aliases` redirect to. Defaults to the domain.
+    canonical: Option<String>,
+    /// Permanently redirect every request to this target, e.g.
+    /// `https://new.example.com`, instead of serving the site.
+    redirect_to: Option<String>,
+    /// Defaults to `preserve`.
+    path_mode: Option<PathMode>,
+    /// With `path_mode = "rewrite"`, tried in order.
+    path_rewrites: Option<Vec<PathRewrite>>,
+    /// Filled in by validation: the target of the `return` of `redirect_to`.
+    #[serde(skip_deserializing)]
+    redirect_return: Option<String>,
+    /// Redirect `www.<domain>`, or the apex of a `www.` domain, to the site.
+    /// By default, only apexes of two labels get a `www.` redirect.
+    www_redirect: Option<bool>,
+    healthcheck: Option<Healthcheck>,
+    /// Answer every request with a 503, except for `maintenance_allow`.
+    #[serde(default)]
+    maintenance: bool,
+    /// Absolute path of the HTML page served with the 503 of `maintenance`.
+    /// Defaults to nginx's error page.
+    maintenance_page: Option<String>,
+    /// Addresses or CIDR ranges still served normally during maintenance.
+    maintenance_allow: Option<Vec<String>>,
+    error_pages: Option<Vec<ErrorPage>>,
+    /// Serve `error_pages` in place of the errors of the upstream too, rather
+    /// than passing its responses as they are.
+    #[serde(default)]
+    proxy_intercept_errors: bool,
+    /// Name of one of the config's `acls`: requests from other addresses are
+    /// denied, except for the healthcheck and ACME challenges.
+    allow_acl: Option<String>,
+    /// Addresses of `allow_acl`, filled in by validation.
+    #[serde(skip_deserializing)]
+    allow: Option<Vec<String>>,
+    /// Overrides the config's `server_tokens`.
+    server_tokens: Option<bool>,
+    /// Charset added to the `Content-Type` of the responses, e.g. `"utf-8"`,
+    /// or `"off"`.
+    charset: Option<String>,
+    /// Replaces the config's `real_ip` for the site.
+    real_ip: Option<RealIp>,
+    access_log: Option<AccessLog>,
+    /// File the errors of the site are logged to, `DEFAULT_ERROR_LOG` when
+    /// only `error_log_level` is set.
+    error_log: Option<String>,
+    /// Logs the errors of the site from this level, e.g. `"debug"` while
+    /// troubleshooting it, rather than the one of the http context.
+    error_log_level: Option<ErrorLogLevel>,
     cache_control: Option<Vec<CacheControl>>,
+    /// Cache-Control of the responses whose type `cache_control` doesn't
+    /// list, `public` by default. Empty, they get no Cache-Control.
+    cache_control_default: Option<String>,
+    /// Location of content-hashed assets, e.g. `/assets/`, cached for a year
+    /// by browsers and proxies without being revalidated.
+    immutable_assets: Option<String>,
     headers: Option<Vec<Header>>,
+    /// Filled in when rendering from the values of the headers for `/` and
+    /// `/*`, which apply to the whole site: nginx drops the `add_header` of
+    /// `location /` in the locations that have their own, so they are
+    /// repeated there.
+    #[serde(skip_deserializing)]
+    server_headers: BTreeMap<String, String>,
+    /// Filled in along `server_headers`: the values of headers they already
+    /// set, with `allow_duplicates`.
+    #[serde(skip_deserializing)]
+    server_duplicates: Vec<(String, String)>,
     redirects: Option<Vec<Redirect>>,
+    map_redirects: Option<Vec<MapRedirect>>,
+    /// Variables of the config's `geos` denying the clients for which they
+    /// are set, neither empty nor `0`, with a 403.
+    geo_deny: Option<Vec<String>>,
+    rate_limit: Option<RateLimit>,
+    /// Case-insensitive regular expressions of the `User-Agent` of the
+    /// clients denied with `block_user_agents_status`, e.g. `"AhrefsBot"`.
+    block_user_agents: Option<Vec<String>>,
+    /// Status of the requests of blocked user agents, 444 by default, which
+    /// closes the connection without a response.
+    block_user_agents_status: Option<u16>,
+    cors: Option<Cors>,
+    /// Filled in by validation from `cors`: the headers of the answers to
+    /// the preflights.
+    #[serde(skip_deserializing)]
+    cors_preflight: BTreeMap<String, String>,
+    /// Filled in by validation from `cors`: the headers of the other
+    /// responses.
+    #[serde(skip_deserializing)]
+    cors_headers: BTreeMap<String, String>,
+    rewrites: Option<Vec<Rewrite>>,
+    /// Resources preloaded by the responses of `location /`. Requires
+    /// `http2`.
+    early_hints: Option<Vec<EarlyHint>>,
+    proxy_cache: Option<SiteProxyCache>,
+    /// `false` streams the responses of the upstream, e.g. server-sent
+    /// events, instead of buffering them. Defaults to nginx's `on`.
+    proxy_buffering: Option<bool>,
+    /// Size of the buffer of the response headers, e.g. `16k`.
+    proxy_buffer_size: Option<String>,
+    /// Number and size of the buffers of a response, e.g. `8 16k`.
+    proxy_buffers: Option<String>,
+    /// TLS towards an `https://` upstream.
+    proxy_ssl: Option<ProxySsl>,
+    header_buffers: Option<HeaderBuffers>,
+    /// Free-form directives rendered in `location /`.
     extra: Option<String>,
+    /// Lua code run in `location /`, with `openresty = true`.
+    lua: Option<Vec<Lua>>,
+    /// Free-form directives rendered in the http context, before the server
+    /// blocks, e.g. a `limit_req_zone` or a `geo` block.
+    extra_http: Option<String>,
+    /// Free-form directives rendered in every location passing requests to
+    /// the upstream.
+    extra_location: Option<String>,
+    /// Profiles the site is generated for. Sites without profiles are
+    /// generated whatever the profile.
+    profiles: Option<Vec<String>>,
+    /// Field values replacing the site's ones, by profile.
+    #[serde(serialize_with = "sorted_option")]
+    overrides: Option<HashMap<String, toml::value::Table>>,
+    /// Names of the config's `fragments` merged into the site, in order.
+    include: Option<Vec<String>>,
+    /// How the `headers` and `redirects` of the site combine with the ones of
+    /// its fragments.
+    merge_strategy: Option<MergeStrategy>,
+    /// Extension of the generated file, instead of the config's `extension`.
+    extension: Option<String>,
+    /// Order of the site among the others, lower first, e.g. in the combined
+    /// output or as the prefix of a templated `filename`. Sites of the same
+    /// priority are ordered by domain. 0 by default.
+    #[serde(default)]
+    priority: i32,
+    /// Path of the generated file, rendered from the config's `filename`.
+    #[serde(skip)]
+    file: Option<String>,
+    /// The validation errors of the site, with
+    /// `Options::render_errors_inline`.
+    #[serde(skip)]
+    inline_errors: Option<Vec<ValidationError>>,
+    /// The table the site was parsed from, to tell the fields it sets from
+    /// the ones it leaves to their default when merging its fragments.
+    #[serde(skip)]
+    written: Option<toml::value::Table>,
+}
+
+/// Serializes `map` with its keys in order, so that the serialized configs
+/// don't change from one run to the next.
+fn sorted<S: serde::Serializer, V: Serialize>(
+    map: &HashMap<String, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+fn sorted_option<S: serde::Serializer, V: Serialize>(
+    map: &Option<HashMap<String, V>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.as_ref()
+        .map(|map| map.iter().collect::<BTreeMap<_, _>>())
+        .serialize(serializer)
 }
 
 impl Site {
-    fn generate(
+    fn render(&self, tera: &mut Tera, syntax: Syntax, vars: &Vars) -> Result<String, NvhostsError> {
+        if let Some(errors) = &self.inline_errors {
+            return Err(NvhostsError::Invalid(errors.clone()));
+        }
+        let context = self.context(tera, syntax, vars)?;
+        match tera.render(TEMPLATE_NAME, &context) {
+            Ok(x) => Ok(format::normalize(&x)),
+            Err(source) => Err(NvhostsError::Template {
+                domain: Some(self.domain.clone()),
+                name: String::from(TEMPLATE_NAME),
+                source,
+            }),
+        }
+    }
+
+    /// The context the template of the site is rendered with.
+    fn context(
         &self,
-        mut tera: MutexGuard<Tera>,
-        writer: &mut impl std::io::Write,
-    ) -> Result<(), Error> {
-        let mut context = Context::new();
-        context.insert("site", &self);
+        tera: &mut Tera,
+        syntax: Syntax,
+        vars: &Vars,
+    ) -> Result<Context, NvhostsError> {
+        let site = self.render_header_values(tera, vars)?;
 
-        let content = match tera.render_str(TEMPLATE, &context) {
-            Ok(x) => x,
-            Err(x) => bail!("{:?}", x),
-        };
+        let mut context = vars_context(vars);
+        context.insert("site", &site);
+        context.insert("nginx", &syntax);
+        Ok(context)
+    }
 
-        writer.write(content.as_bytes())?;
+    /// Renders the header values using Tera syntax, e.g. `{{ site.domain }}`,
+    /// with the site as context. Other values, including ones with literal
+    /// braces, are kept as is.
+    fn render_header_values(&self, tera: &mut Tera, vars: &Vars) -> Result<Site, NvhostsError> {
+        let mut context = vars_context(vars);
+        context.insert("site", &self);
 
-        Ok(())
+        let mut site = self.clone();
+        for header in site.headers.iter_mut().flatten() {
+            let duplicates = header
+                .duplicates
+                .iter_mut()
+                .map(|(name, value)| (&*name, value));
+            for (name, value) in header.values.iter_mut().chain(duplicates) {
+                if !["{{", "{%", "{#"].iter().any(|open| value.contains(open)) {
+                    continue;
+                }
+                *value = match tera.render_str(value, &context) {
+                    Ok(x) => x,
+                    Err(source) => {
+                        return Err(NvhostsError::Template {
+                            domain: Some(self.domain.clone()),
+                            name: format!("header {} for {:?}", name, header.for_field),
+                            source,
+                        })
+                    }
+                };
+            }
+        }
+        for header in site.headers.iter().flatten() {
+            if is_server_location(&header.for_field) {
+                site.server_headers.extend(header.values.clone());
+                site.server_duplicates.extend(header.duplicates.clone());
+            }
+        }
+
+        Ok(site)
     }
 
+    /// Path of the generated file, relative to the output directory.
     fn filename(&self) -> String {
-        format!("{}.conf", &self.domain)
+        match (&self.file, self.extension()) {
+            (Some(file), _) => file.clone(),
+            (None, "") => self.domain.clone(),
+            (None, extension) => format!("{}.{}", &self.domain, extension),
+        }
+    }
+
+    /// Extension of the generated file, without the leading dot.
+    fn extension(&self) -> &str {
+        self.extension.as_deref().unwrap_or(DEFAULT_EXTENSION)
+    }
+
+    /// Whether the site has redirects and nothing else to serve, for
+    /// `lean_redirects`.
+    fn only_redirects(&self) -> bool {
+        let serving = [
+            self.upstream.is_some(),
+            self.root.is_some(),
+            self.redirect_to.is_some(),
+            self.headers.is_some(),
+            self.cache_control.is_some() || self.cache_control_default.is_some(),
+            self.immutable_assets.is_some(),
+            self.rewrites.is_some(),
+            self.rate_limit.is_some(),
+            self.block_user_agents.is_some(),
+            self.cors.is_some(),
+            self.charset.is_some(),
+            self.error_pages.is_some(),
+            self.early_hints.is_some(),
+            self.proxy_buffering.is_some()
+                || self.proxy_buffer_size.is_some()
+                || self.proxy_buffers.is_some(),
+            self.proxy_cache.is_some(),
+            self.header_buffers.is_some(),
+            self.method_allow.is_some() || self.method_deny.is_some(),
+            self.https_redirect_exclude.is_some(),
+            self.extra.is_some(),
+            self.extra_location.is_some(),
+            self.maintenance,
+            self.lua.is_some(),
+        ];
+        (self.redirects.is_some() || self.map_redirects.is_some())
+            && !serving.iter().any(|set| *set)
+    }
+
+    /// The host the www redirect server redirects to the domain, none for a
+    /// site redirecting everything with `redirect_to`.
+    fn www_domain(&self) -> Option<String> {
+        if self.redirect_to.is_some() || self.www_redirect == Some(false) {
+            return None;
+        }
+        www_counterpart(&self.domain, self.www_redirect == Some(true))
+    }
+
+    /// The addresses of the `listen` directives of the server of the site,
+    /// as written in them.
+    fn listen_addresses(&self) -> Vec<String> {
+        let listen_on = self.listen_on.clone().unwrap_or_default();
+        let mut addresses = or_wildcards(listen_on.http, HTTP_PORT);
+        addresses.extend(self.https_addresses());
+        addresses
+    }
+
+    /// The addresses of the `ssl` listens of the server of the site, none
+    /// without `ssl_cert`.
+    fn https_addresses(&self) -> Vec<String> {
+        match self.ssl_cert {
+            Some(_) => or_wildcards(self.listen_on.clone().unwrap_or_default().https, HTTPS_PORT),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Settings of a run that are given on the command line rather than in the
+/// config file.
+#[derive(Default, Debug, Clone)]
+pub struct Options {
+    /// Turn validation warnings into errors.
+    pub strict: bool,
+    /// Show a progress bar on stderr while sites are generated. It is hidden
+    /// when stderr isn't a terminal.
+    pub progress: bool,
+    pub output: Output,
+    /// Copy files about to be overwritten to `<file>.bak` first.
+    pub backup: bool,
+    /// Overrides the config's `enabled_dir`.
+    pub enabled_dir: Option<PathBuf>,
+    /// Only generate the sites of this profile, with its overrides. Without
+    /// it, only the sites without profiles are generated.
+    pub profile: Option<String>,
+    /// Directory of `.template` files replacing the built-in templates of the
+    /// same name, e.g. `vhost.template`.
+    pub template_dir: Option<PathBuf>,
+    /// Fail after generation when the output directory holds files with the
+    /// extension of the sites that weren't generated by nvhosts.
+    pub verify_clean: bool,
+    /// Remove the files the last run wrote and this one doesn't, along with
+    /// their links, as long as they still start with the banner.
+    pub clean: bool,
+    /// Skip generation when the config, the options and the templates are
+    /// the same as in the last incremental run.
+    pub incremental: bool,
+    /// Generate even when `incremental` would skip it.
+    pub force: bool,
+    /// How many times writing a file is tried again when it fails with an
+    /// error that may be transient, e.g. `EINTR` or `EAGAIN` on a network
+    /// filesystem. Other errors fail at once.
+    pub write_retries: u32,
+    /// Print validation errors as warnings instead of failing. Errors that
+    /// leave nothing to generate, such as invalid overrides, still fail.
+    pub no_validate: bool,
+    /// Report at most this many validation errors, the others being counted
+    /// by a last `ValidationError::Omitted`. All of them by default.
+    pub max_errors: Option<usize>,
+    /// Don't print the validation warnings. Under `strict`, the warnings
+    /// turned into errors are counted by a last `ValidationError::Counted`
+    /// rather than listed.
+    pub no_warn: bool,
+    /// Report the validation errors as a single `ValidationError::Counted`.
+    pub quiet_errors: bool,
+    /// Number of threads generating the sites, the number of CPUs by
+    /// default. Configs of fewer than `PARALLEL_SITES` sites are always
+    /// generated on the calling thread.
+    pub jobs: Option<usize>,
+    /// Modules of the nginx the config is generated for: validation warns
+    /// about the features needing one it lacks.
+    pub modules: Option<modules::Modules>,
+    /// How long nginx and the hooks may run before they are killed and the
+    /// run fails, `COMMAND_TIMEOUT` by default.
+    pub timeout: Option<Duration>,
+    /// Tera filters available to the templates on top of the ones of nvhosts.
+    pub filters: Filters,
+    /// Logs the errors of the site of this domain from the `debug` level,
+    /// whatever its `error_log_level`, for this run only.
+    pub debug_site: Option<String>,
+    /// Variables given to every template next to the config, e.g.
+    /// `environment` for `{{ environment }}`. They can't be named like the
+    /// ones of nvhosts, listed in `TEMPLATE_GLOBALS`.
+    pub vars: Vars,
+    /// Directory the files are generated in, `./sites-available` by default.
+    pub output_dir: Option<PathBuf>,
+    /// Leaves out the proxied sites whose upstream doesn't accept a
+    /// connection within this time, with a warning each.
+    pub skip_unreachable: Option<Duration>,
+    /// Writes the sites that fail validation or rendering as a placeholder
+    /// file commenting out their errors, rather than failing before writing
+    /// anything. The run still fails, with `NvhostsError::Placeholders`,
+    /// before the files are enabled and the hooks run.
+    pub render_errors_inline: bool,
+}
+
+impl Options {
+    fn output_dir(&self) -> &Path {
+        self.output_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new(OUTPUT_DIR))
+    }
+}
+
+/// Variables given to the templates by the program, by name.
+pub type Vars = BTreeMap<String, String>;
+
+/// Generated files, by path relative to the output directory, with their
+/// content.
+type Files = Vec<(String, String)>;
+
+/// A context holding `vars`, for a template to add its own variables to.
+fn vars_context(vars: &Vars) -> Context {
+    let mut context = Context::new();
+    for (name, value) in vars.iter() {
+        context.insert(name, value);
+    }
+    context
+}
+
+/// Reads the template variables of the file at `path`, a table of TOML, or
+/// an object of JSON when its extension is `.json`. Numbers and booleans are
+/// given to the templates as strings.
+pub fn read_vars(path: &Path) -> Result<Vars, NvhostsError> {
+    let invalid = |message: String| NvhostsError::VarsFile {
+        path: path.to_path_buf(),
+        message,
+    };
+    let content = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let values: BTreeMap<String, serde_json::Value> =
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&content).map_err(|err| invalid(err.to_string()))?
+        } else {
+            toml::from_str(&content).map_err(|err| invalid(err.to_string()))?
+        };
+
+    let mut vars = Vars::new();
+    for (name, value) in values {
+        let value = match value {
+            serde_json::Value::String(value) => value,
+            serde_json::Value::Number(value) => value.to_string(),
+            serde_json::Value::Bool(value) => value.to_string(),
+            _ => {
+                return Err(invalid(format!(
+                    "variable {:?} must be a string, a number or a boolean",
+                    name
+                )))
+            }
+        };
+        vars.insert(name, value);
     }
+    info!(
+        "{}: variables {}",
+        path.display(),
+        vars.keys().cloned().collect::<Vec<_>>().join(", ")
+    );
+    Ok(vars)
+}
+
+/// How the generated files are laid out in the output directory.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// One `<domain>.conf` file per site, plus the http context blocks in
+    /// their own file.
+    #[default]
+    PerSite,
+    /// A single file of the given name holding the http context blocks and
+    /// every site, each preceded by a banner comment.
+    Combined(String),
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct UnverifiedConfig {
+    #[serde(default = "default_nginx_version")]
+    nginx_version: Version,
+    #[serde(default)]
+    maps: Vec<Map>,
+    #[serde(default)]
+    geos: Vec<Geo>,
+    #[serde(default)]
+    proxy_caches: Vec<ProxyCache>,
+    #[serde(default)]
+    log_formats: Vec<LogFormat>,
+    resolver: Option<Resolver>,
+    /// Emitted in the http context, for every site.
+    real_ip: Option<RealIp>,
+    /// TLS settings of the HTTPS sites, unless they set their own.
+    tls_profile: Option<TlsProfile>,
+    /// Diffie-Hellman parameters, e.g. generated by `openssl dhparam`, used
+    /// by the intermediate and old TLS profiles.
+    dhparam: Option<String>,
+    /// Lists of addresses or CIDR ranges, by name, that sites allow with
+    /// `allow_acl`.
+    #[serde(default, serialize_with = "sorted")]
+    acls: HashMap<String, Vec<String>>,
+    /// Emits `server_tokens on|off;` in every server block when set; `false`
+    /// hides the nginx version from responses.
+    server_tokens: Option<bool>,
+    /// Directory where the generated files are symlinked to enable them,
+    /// e.g. `/etc/nginx/sites-enabled`.
+    enabled_dir: Option<PathBuf>,
+    /// Tera template of the path of the file generated for each site,
+    /// relative to the output directory, with the site as `site`, e.g.
+    /// `"10-{{ site.domain }}.conf"`. Defaults to `<domain>.<extension>`.
+    filename: Option<String>,
+    /// Extension of the files generated for the sites, without the leading
+    /// dot, e.g. `"vhost"`, or `""` for none. Defaults to `conf`. The shared
+    /// `nvhosts-*.conf` files keep theirs.
+    extension: Option<String>,
+    /// Octal permissions of the generated files, e.g. `"0644"`, instead of
+    /// the ones given by the umask. Only applied on Unix.
+    file_mode: Option<String>,
+    /// Commands run in order after a successful generation.
+    #[serde(default)]
+    hooks: Vec<Hook>,
+    /// Targets OpenResty, allowing the `lua` fields of the sites.
+    #[serde(default)]
+    openresty: bool,
+    /// Targets nginx Plus, allowing the `health_check` of the upstreams of
+    /// the sites.
+    #[serde(default)]
+    active_health_checks: bool,
+    /// Renders the sites that only redirect, with `redirects` or
+    /// `map_redirects` and nothing to serve, to a minimal server answering
+    /// other paths with a 404, rather than one proxying them.
+    #[serde(default)]
+    lean_redirects: bool,
+    /// Rendered to `nvhosts-main.conf` when set.
+    main: Option<Main>,
+    catch_all: Option<CatchAll>,
+    /// Rendered to `nvhosts-stream.conf` when not empty.
+    #[serde(default)]
+    streams: Vec<StreamProxy>,
+    /// Bundles of site fields, by name, that sites pull in with `include`.
+    #[serde(default, serialize_with = "sorted")]
+    fragments: HashMap<String, toml::value::Table>,
+    /// Directory of `.toml` files holding more `[[sites]]`, relative to the
+    /// directory of the config file, e.g. `"conf.d"`.
+    sites_dir: Option<String>,
+    #[serde(default)]
     sites: Vec<Site>,
 }
 
+impl Default for UnverifiedConfig {
+    fn default() -> Self {
+        Self {
+            nginx_version: DEFAULT_NGINX_VERSION,
+            maps: Vec::new(),
+            geos: Vec::new(),
+            proxy_caches: Vec::new(),
+            log_formats: Vec::new(),
+            resolver: None,
+            real_ip: None,
+            tls_profile: None,
+            dhparam: None,
+            acls: HashMap::new(),
+            server_tokens: None,
+            enabled_dir: None,
+            filename: None,
+            extension: None,
+            file_mode: None,
+            hooks: Vec::new(),
+            openresty: false,
+            active_health_checks: false,
+            lean_redirects: false,
+            main: None,
+            catch_all: None,
+            streams: Vec::new(),
+            fragments: HashMap::new(),
+            sites_dir: None,
+            sites: Vec::new(),
+        }
+    }
+}
+
+impl std::str::FromStr for UnverifiedConfig {
+    type Err = NvhostsError;
+
+    /// Parses a config file. When a site doesn't fit the fields of a site,
+    /// the error names it by index and domain.
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let err = match toml::from_str::<Self>(content) {
+            Ok(config) => {
+                let value = toml::from_str(content).expect("a parsed config is a TOML value");
+                return Ok(config.keep_written(&value));
+            }
+            Err(err) => err,
+        };
+
+        let sites = toml::from_str::<toml::Value>(content)
+            .ok()
+            .and_then(|value| value.get("sites")?.as_array().cloned())
+            .unwrap_or_default();
+        let invalid = sites
+            .into_iter()
+            .enumerate()
+            .find(|(_, site)| site.clone().try_into::<Site>().is_err());
+        Err(match invalid {
+            Some((i, site)) => NvhostsError::Parse {
+                site: Some(format!(
+                    "sites[{}] {:?}",
+                    i,
+                    site.get("domain")
+                        .and_then(toml::Value::as_str)
+                        .unwrap_or_default()
+                )),
+                message: err
+                    .to_string()
+                    .replace("for key `sites.", &format!("for key `sites[{}].", i)),
+            },
+            None => NvhostsError::Parse {
+                site: None,
+                message: err.to_string(),
+            },
+        })
+    }
+}
+
 impl UnverifiedConfig {
     pub fn example() -> Self {
-        let mut values = HashMap::new();
-        values.insert(String::from("Cache-Control"), String::from("public"));
+        let mut values = BTreeMap::new();
         values.insert(
             String::from("Referrer-Policy"),
             String::from("strict-origin-when-cross-origin"),
@@ -95,154 +1694,9276 @@ impl UnverifiedConfig {
 
         let h = Header {
             for_field: String::from("/*"),
-            values: values,
+            values,
+            ..Default::default()
         };
 
         let r = Redirect {
             from_field: String::from("/example"),
             to: String::from("http://example.com"),
             status_code: 301,
+            regex: false,
+        };
+
+        let c = CacheControl {
+            mime: String::from("text/css"),
+            value: String::from("public, max-age=31536000, immutable"),
         };
 
         let example_site = Site {
             domain: String::from("example.com"),
+            description: Some(String::from("Example site")),
+            cache_control: Some(vec![c]),
             headers: Some(vec![h]),
             redirects: Some(vec![r]),
             ..Default::default()
         };
 
         Self {
+            nginx_version: DEFAULT_NGINX_VERSION,
             sites: vec![example_site],
+            ..Default::default()
         }
     }
 
-    fn validate(self) -> Result<Config, Error> {
-        let mut errors = Vec::<Error>::new();
+    /// Adds the site of `site` after the others.
+    pub fn with_site(mut self, site: builder::SiteBuilder) -> Self {
+        self.sites.push(site.build());
+        self
+    }
 
-        let re = Regex::new(r"\b([a-z0-9]+(-[a-z0-9]+)*\.)+[a-z]{2,}\b")?;
+    /// Serializes the config to TOML.
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        // Going through a `toml::Value` emits plain values before tables,
+        // whatever the order of the struct fields.
+        let value = toml::Value::try_from(self)?;
+        toml::to_string_pretty(&value)
+    }
 
-        for site in self.sites.iter() {
-            if !re.is_match(&site.domain) {
-                errors.push(anyhow!("{:?} ", site.domain));
+    /// Adds the sites of the `.toml` files of `sites_dir`, in the order of
+    /// their names, after the sites of the config. Relative paths are read
+    /// from `dir`, usually the directory of the config file.
+    pub fn read_sites_dir(mut self, dir: &Path) -> Result<Self, NvhostsError> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct SitesFile {
+            #[serde(default)]
+            sites: Vec<Site>,
+        }
+
+        let sites_dir = match self.sites_dir.take() {
+            Some(sites_dir) => dir.join(sites_dir),
+            None => return Ok(self),
+        };
+        let invalid = |path: &Path, message: String| NvhostsError::SitesFile {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        let entries =
+            fs::read_dir(&sites_dir).map_err(|err| invalid(&sites_dir, err.to_string()))?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|err| invalid(&sites_dir, err.to_string()))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+                paths.push(path);
             }
+        }
+        paths.sort();
 
-            if site.headers.is_some() {
-                let cache_control_headers: Vec<Header> = site
+        for path in paths {
+            let content =
+                fs::read_to_string(&path).map_err(|err| invalid(&path, err.to_string()))?;
+            let file: SitesFile =
+                toml::from_str(&content).map_err(|err| invalid(&path, err.to_string()))?;
+            let value = toml::from_str(&content).expect("a parsed file is a TOML value");
+            self.sites.extend(written(file.sites, &value));
+        }
+        Ok(self)
+    }
+
+    /// Replaces the `${file:<path>}` references of every value with the
+    /// content of the file, without its final newline. Relative paths are
+    /// read from `dir`, usually the directory of the config file.
+    pub fn read_files(self, dir: &Path) -> Result<Self, NvhostsError> {
+        let written: Vec<_> = self.sites.iter().map(|site| site.written.clone()).collect();
+        let mut value = toml::Value::try_from(&self).expect("a config serializes to TOML");
+        interpolate::read_files(&mut value, dir, "")?;
+        let mut config: Self = value
+            .try_into()
+            .expect("strings replaced by strings still deserialize");
+        for (site, written) in config.sites.iter_mut().zip(written) {
+            site.written = written;
+        }
+        Ok(config)
+    }
+
+    /// The fields of the config that reach out of it: `hooks`, which run
+    /// commands, and `sites_dir` and the `${file:<path>}` references, which
+    /// read local files. Empty for a config that only describes sites.
+    pub fn local_access(&self) -> Vec<String> {
+        let mut fields = Vec::new();
+        if !self.hooks.is_empty() {
+            fields.push(String::from("hooks"));
+        }
+        if self.sites_dir.is_some() {
+            fields.push(String::from("sites_dir"));
+        }
+        let value = toml::Value::try_from(self).expect("a config serializes to TOML");
+        fields.extend(interpolate::file_references(&value, ""));
+        fields
+    }
+
+    /// Keeps the tables of the sites of `value`, the config parsed into this
+    /// one, for `apply_includes`.
+    pub(crate) fn keep_written(mut self, value: &toml::Value) -> Self {
+        self.sites = written(self.sites, value);
+        self
+    }
+
+    /// Keeps the sites of `profile` and applies its overrides.
+    fn select_profile(&mut self, profile: Option<&str>) -> Result<(), NvhostsError> {
+        let sites = std::mem::take(&mut self.sites);
+
+        for site in sites {
+            let selected = match (&site.profiles, profile) {
+                (None, _) => true,
+                (Some(profiles), Some(profile)) => profiles.iter().any(|p| p == profile),
+                (Some(_), None) => false,
+            };
+            if !selected {
+                continue;
+            }
+
+            let overrides = profile.and_then(|profile| site.overrides.as_ref()?.get(profile));
+            let overrides = match overrides {
+                Some(overrides) => overrides.clone(),
+                None => {
+                    self.sites.push(site);
+                    continue;
+                }
+            };
+
+            let invalid = |message: String| NvhostsError::Profile {
+                domain: site.domain.clone(),
+                profile: profile.unwrap_or_default().to_string(),
+                message,
+            };
+            let mut table = match toml::Value::try_from(&site) {
+                Ok(toml::Value::Table(table)) => table,
+                _ => unreachable!("a site serializes to a table"),
+            };
+            for (key, value) in overrides {
+                if key == "profiles" || key == "overrides" {
+                    return Err(invalid(format!("{} can't be overridden", key)));
+                }
+                table.insert(key, value);
+            }
+            let site: Site = toml::Value::Table(table)
+                .try_into()
+                .map_err(|err: toml::de::Error| invalid(err.to_string()))?;
+            self.sites.push(site);
+        }
+
+        Ok(())
+    }
+
+    /// Merges the fragments of its `include` into every site. Fragments are
+    /// merged in order, nested tables key by key, lists by the site's
+    /// `merge_strategy`, and the fields the site sets win, even set to their
+    /// default value. Of a site built rather than parsed, the fields left to
+    /// their default value take the one of the fragments.
+    fn apply_includes(&mut self) -> Result<(), NvhostsError> {
+        let defaults = match toml::Value::try_from(Site::default()) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => unreachable!("a site serializes to a table"),
+        };
+
+        for site in self.sites.iter_mut() {
+            let include = match site.include.take() {
+                Some(include) => include,
+                None => continue,
+            };
+            let invalid = |message: String| NvhostsError::Include {
+                domain: site.domain.clone(),
+                message,
+            };
+
+            let strategy = site.merge_strategy.unwrap_or_default();
+            let mut merged = toml::value::Table::new();
+            for name in include.iter() {
+                let fragment =
+                    fragment(&self.fragments, name, &mut Vec::new(), strategy).map_err(invalid)?;
+                merge(&mut merged, fragment, strategy);
+            }
+            let own = match toml::Value::try_from(&*site) {
+                Ok(toml::Value::Table(table)) => table,
+                _ => unreachable!("a site serializes to a table"),
+            };
+            let own = match &site.written {
+                Some(written) => set_fields(own, written),
+                None => own
+                    .into_iter()
+                    .filter(|(key, value)| defaults.get(key) != Some(value))
+                    .collect(),
+            };
+            merge(&mut merged, own, strategy);
+
+            *site = toml::Value::Table(merged)
+                .try_into()
+                .map_err(|err: toml::de::Error| invalid(err.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Validates the config, logging the warnings.
+    fn validate(self, options: &Options) -> Result<Config, NvhostsError> {
+        let (config, warnings) = self.validate_with_warnings(options)?;
+        for warning in warnings.iter().filter(|_| !options.no_warn) {
+            warn!("{}", warning);
+        }
+        Ok(config)
+    }
+
+    /// Validates the config, and returns its warnings along with it. Under
+    /// `Options::strict`, the warnings are errors instead.
+    fn validate_with_warnings(
+        mut self,
+        options: &Options,
+    ) -> Result<(Config, Vec<Warning>), NvhostsError> {
+        self.apply_includes()?;
+        self.select_profile(options.profile.as_deref())?;
+        if let Some(domain) = &options.debug_site {
+            let site = self
+                .sites
+                .iter_mut()
+                .find(|site| &site.domain == domain)
+                .ok_or_else(|| NvhostsError::UnknownSite {
+                    domain: domain.clone(),
+                })?;
+            site.error_log_level = Some(ErrorLogLevel::Debug);
+        }
+
+        let mut errors = Vec::<ValidationError>::new();
+        let mut warnings = Vec::<Warning>::new();
+
+        let re = Regex::new(r"^([a-z0-9]+(-[a-z0-9]+)*\.)+[a-z]{2,}$").expect("valid regex");
+        let variable_re = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").expect("valid regex");
+        let size_re = Regex::new(r"^[0-9]+[kKmM]?$").expect("valid regex");
+        let rate_re = Regex::new(r"^[1-9][0-9]*r/[sm]$").expect("valid regex");
+        let mime_re =
+            Regex::new(r"^[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]*/([A-Za-z0-9][A-Za-z0-9!#$&^_.+-]*|\*)$")
+                .expect("valid regex");
+
+        for name in options.vars.keys() {
+            let message = if !variable_re.is_match(name) {
+                "invalid variable name"
+            } else if TEMPLATE_GLOBALS.contains(&name.as_str()) {
+                "already given to the templates by nvhosts"
+            } else {
+                continue;
+            };
+            errors.push(ValidationError::Config {
+                field: "vars",
+                message: format!("variable {:?}: {}", name, message),
+            });
+        }
+
+        let mut variables = HashSet::new();
+        for map in self.maps.iter() {
+            if !variable_re.is_match(&map.variable) {
+                errors.push(ValidationError::Config {
+                    field: "maps",
+                    message: format!("map {:?}: invalid variable name", map.variable),
+                });
+            }
+            if !map.source.starts_with('$') {
+                errors.push(ValidationError::Config {
+                    field: "maps",
+                    message: format!(
+                        "map {:?}: source {:?} must be a variable",
+                        map.variable, map.source
+                    ),
+                });
+            }
+            if !variables.insert(map.variable.as_str()) {
+                errors.push(ValidationError::Config {
+                    field: "maps",
+                    message: format!("map {:?}: defined more than once", map.variable),
+                });
+            }
+        }
+
+        for geo in self.geos.iter() {
+            let mut invalid = |message: String| {
+                errors.push(ValidationError::Config {
+                    field: "geos",
+                    message: format!("geo {:?}: {}", geo.variable, message),
+                })
+            };
+            if !variable_re.is_match(&geo.variable) {
+                invalid(String::from("invalid variable name"));
+            }
+            if !variables.insert(geo.variable.as_str()) {
+                invalid(String::from("defined more than once, in maps or geos"));
+            }
+            if let Some(source) = &geo.source {
+                if !source.starts_with('$') || !variable_re.is_match(&source[1..]) {
+                    invalid(format!("source {:?} must be a variable", source));
+                }
+            }
+            let mut cidrs = HashSet::new();
+            for range in geo.ranges.iter() {
+                if !is_address_range(&range.cidr) {
+                    invalid(format!("invalid address range {:?}", range.cidr));
+                } else if !cidrs.insert(range.cidr.as_str()) {
+                    invalid(format!("range {:?} listed more than once", range.cidr));
+                }
+            }
+            let values = geo.ranges.iter().map(|range| &range.value);
+            for value in values.chain(geo.default.iter()) {
+                if value.contains(['"', '\\']) {
+                    invalid(format!(
+                        "value {:?} can't contain double quotes or backslashes",
+                        value
+                    ));
+                }
+            }
+        }
+
+        let mut zones = HashSet::new();
+        for cache in self.proxy_caches.iter() {
+            match cache.keys_zone.split_once(':') {
+                Some((name, size)) if !name.is_empty() && !size.is_empty() => {}
+                _ => errors.push(ValidationError::Config {
+                    field: "proxy_caches",
+                    message: format!(
+                        "proxy cache {:?}: keys_zone {:?} must be formatted as name:size",
+                        cache.path, cache.keys_zone
+                    ),
+                }),
+            }
+            if !zones.insert(cache.zone()) {
+                errors.push(ValidationError::Config {
+                    field: "proxy_caches",
+                    message: format!(
+                        "proxy cache zone {:?}: defined more than once",
+                        cache.zone()
+                    ),
+                });
+            }
+        }
+
+        let mut log_formats = HashSet::new();
+        for log_format in self.log_formats.iter_mut() {
+            let mut invalid = |message: &str| {
+                errors.push(ValidationError::Config {
+                    field: "log_formats",
+                    message: format!("log format {:?}: {}", log_format.name, message),
+                })
+            };
+            if !variable_re.is_match(&log_format.name) {
+                invalid("invalid name");
+            }
+            if log_format.name == "combined" {
+                invalid("already defined by nginx");
+            }
+            if !log_formats.insert(log_format.name.clone()) {
+                invalid("defined more than once");
+            }
+            match &log_format.format {
+                Some(format) if format.contains('\'') => {
+                    invalid("format must not contain single quotes")
+                }
+                Some(_) => {}
+                None if log_format.name == "json" => {
+                    log_format.format = Some(String::from(JSON_LOG_FORMAT));
+                    log_format
+                        .escape
+                        .get_or_insert_with(|| String::from("json"));
+                }
+                None => invalid("format is required, except for the json preset"),
+            }
+        }
+
+        if let Some(resolver) = &self.resolver {
+            if resolver.addresses.is_empty() {
+                errors.push(ValidationError::Config {
+                    field: "resolver",
+                    message: String::from("resolver: at least one address is required"),
+                });
+            }
+            for address in resolver.addresses.iter() {
+                // IPv6 addresses are bracketed, with or without a port.
+                let host = address.trim_start_matches('[').trim_end_matches(']');
+                if host.parse::<std::net::IpAddr>().is_err()
+                    && address.parse::<std::net::SocketAddr>().is_err()
+                {
+                    errors.push(ValidationError::Config {
+                        field: "resolver",
+                        message: format!(
+                            "resolver: {:?} isn't an IP address, with an optional port",
+                            address
+                        ),
+                    });
+                }
+            }
+        }
+
+        if let Some(real_ip) = &mut self.real_ip {
+            for message in real_ip.apply_preset() {
+                errors.push(ValidationError::Config {
+                    field: "real_ip",
+                    message,
+                });
+            }
+        }
+
+        let mut acls = self.acls.iter().collect::<Vec<_>>();
+        acls.sort();
+        for (name, addresses) in acls {
+            if addresses.is_empty() {
+                errors.push(ValidationError::Config {
+                    field: "acls",
+                    message: format!("acl {:?}: at least one address is required", name),
+                });
+            }
+            for address in addresses.iter() {
+                if !is_address_range(address) {
+                    errors.push(ValidationError::Config {
+                        field: "acls",
+                        message: format!("acl {:?}: invalid address {:?}", name, address),
+                    });
+                }
+            }
+        }
+
+        // What the http context defines across files, and who defines it.
+        let mut definitions = HashMap::new();
+        for map in self.maps.iter() {
+            definitions.insert(format!("variable ${}", map.variable), String::from("maps"));
+        }
+        for geo in self.geos.iter() {
+            definitions.insert(format!("variable ${}", geo.variable), String::from("geos"));
+        }
+        for zone in zones.iter() {
+            definitions.insert(format!("zone {}", zone), String::from("proxy_caches"));
+        }
+
+        let geo_variables: HashSet<&str> =
+            self.geos.iter().map(|geo| geo.variable.as_str()).collect();
+        let site_domains: HashSet<String> =
+            self.sites.iter().map(|site| site.domain.clone()).collect();
+        let duration_re = Regex::new(r"^[0-9]+(ms|s|m|h|d)?$").expect("valid regex");
+        let mut aliases = HashSet::new();
+        let mut domains = HashSet::new();
+        let mut server_names = HashMap::new();
+        for site in self.sites.iter_mut() {
+            site.server_tokens = site.server_tokens.or(self.server_tokens);
+            site.redirects_only = self.lean_redirects && site.only_redirects();
+
+            if !re.is_match(&site.domain) && !is_special_server_name(&site.domain) {
+                errors.push(ValidationError::InvalidDomain {
+                    domain: site.domain.clone(),
+                });
+            }
+            if !domains.insert(site.domain.clone()) {
+                errors.push(ValidationError::DuplicateDomain {
+                    domain: site.domain.clone(),
+                });
+            }
+
+            let mut server_name = vec![site.domain.clone()];
+            let mut captures = Vec::new();
+            for name in site.server_names.iter().flatten() {
+                let invalid = if name.regex {
+                    match Regex::new(&name.name) {
+                        Err(err) if !pcre_only(&name.name) => {
+                            Some(format!("isn't a valid regex: {}", err))
+                        }
+                        _ if name.name.starts_with('~') => Some(String::from(
+                            "starts with ~, which regex = true already adds",
+                        )),
+                        _ if name.name.contains(|c: char| c.is_whitespace() || c == '"') => {
+                            Some(String::from("can't contain whitespace nor double quotes"))
+                        }
+                        // nginx compiles them with PCRE, which knows more
+                        // than the regex crate.
+                        Err(_) => {
+                            captures.extend(capture_names(&name.name));
+                            None
+                        }
+                        Ok(regex) => {
+                            captures.extend(regex.capture_names().flatten().map(String::from));
+                            None
+                        }
+                    }
+                } else {
+                    let domain = name
+                        .name
+                        .strip_prefix("*.")
+                        .or_else(|| name.name.strip_prefix('.'))
+                        .or_else(|| name.name.strip_suffix(".*"))
+                        .unwrap_or(&name.name);
+                    (!re.is_match(domain) || domain.contains(['*', ' ', ';']))
+                        .then(|| String::from("isn't a domain, a wildcard name, nor a regex"))
+                };
+                match invalid {
+                    Some(reason) => errors.push(ValidationError::site(
+                        &site.domain,
+                        "server_names",
+                        format!("server name {:?} {}", name.name, reason),
+                    )),
+                    None if name.regex => server_name.push(format!("\"~{}\"", name.name)),
+                    None => server_name.push(name.name.clone()),
+                }
+            }
+            site.server_name = server_name.join(" ");
+            // nginx serves a name from the first server that has it, and
+            // ignores it in the others.
+            let names = site
+                .server_names
+                .iter()
+                .flatten()
+                .map(|name| match name.regex {
+                    true => format!("~{}", name.name),
+                    false => name.name.clone(),
+                });
+            for name in std::iter::once(site.domain.clone()).chain(names) {
+                match server_names.get(&name) {
+                    Some(other) if other != &site.domain => errors.push(ValidationError::site(
+                        &site.domain,
+                        "server_names",
+                        format!(
+                            "server name {:?} is also one of {:?}, nginx would only serve it from the first",
+                            name, other
+                        ),
+                    )),
+                    Some(_) => {}
+                    None => {
+                        server_names.insert(name, site.domain.clone());
+                    }
+                }
+            }
+            if let Some(upstream) = site.upstream.as_ref().filter(|upstream| !upstream.dynamic) {
+                if let Some(capture) = captures
+                    .iter()
+                    .find(|capture| references(&upstream.url, capture))
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "upstream",
+                        format!(
+                            "upstream {:?} uses the capture ${} of a server name, which requires dynamic = true",
+                            upstream.url, capture
+                        ),
+                    ));
+                }
+            }
+
+            let canonical = site.canonical.as_ref().unwrap_or(&site.domain);
+            if site.canonical.is_some() && !re.is_match(canonical) {
+                errors.push(ValidationError::site(
+                    &site.domain,
+                    "canonical",
+                    format!("canonical {:?} isn't a domain", canonical),
+                ));
+            }
+            match &site.aliases {
+                Some(list) => {
+                    for alias in list {
+                        let invalid = if !re.is_match(alias) {
+                            Some(String::from("isn't a domain"))
+                        } else if alias == canonical {
+                            Some(String::from("is the canonical host it redirects to"))
+                        } else if site_domains.contains(alias) {
+                            Some(String::from("is the domain of a site"))
+                        } else if !aliases.insert(alias.clone()) {
+                            Some(String::from("is listed more than once"))
+                        } else {
+                            None
+                        };
+                        if let Some(reason) = invalid {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "aliases",
+                                format!("alias {:?} {}", alias, reason),
+                            ));
+                        }
+                    }
+                }
+                None if site.canonical.is_some() => {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "canonical",
+                        String::from("canonical has no effect without aliases"),
+                    ));
+                }
+                None => {}
+            }
+
+            for header in site.headers.iter().flatten() {
+                if is_server_location(&header.for_field) && header.lua.is_some() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "headers",
+                        format!(
+                            "headers for {:?} apply to the whole site, their lua goes in the lua of the site",
+                            header.for_field
+                        ),
+                    ));
+                }
+                if let Err(reason) = validate_location(&header.for_field) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "headers",
+                        format!("headers for {:?}: {}", header.for_field, reason),
+                    ));
+                }
+            }
+
+            // The entries of a location are merged into its first one, `/`
+            // and `/*` being the same, since nginx refuses two locations with
+            // the same pattern.
+            let headers = site.headers.as_deref_mut().unwrap_or_default();
+            let location = |header: &Header| match is_server_location(&header.for_field) {
+                true => String::from("/"),
+                false => header.for_field.clone(),
+            };
+            for later in 1..headers.len() {
+                let Some(first) = (0..later).find(|&i| {
+                    !headers[i].merged && location(&headers[i]) == location(&headers[later])
+                }) else {
+                    continue;
+                };
+                let (before, after) = headers.split_at_mut(later);
+                let (first, header) = (&mut before[first], &mut after[0]);
+                for (name, value) in std::mem::take(&mut header.values) {
+                    let set = first
+                        .values
+                        .keys()
+                        .find(|key| key.eq_ignore_ascii_case(&name))
+                        .cloned();
+                    match set {
+                        None => {
+                            first.values.insert(name, value);
+                        }
+                        Some(set) => {
+                            if !first.allow_duplicates || !header.allow_duplicates {
+                                errors.push(ValidationError::site(
+                                    &site.domain,
+                                    "headers",
+                                    format!(
+                                        "headers for {:?} set {} in more than one entry, set allow_duplicates = true on each of them to send every value, e.g. for Set-Cookie",
+                                        header.for_field, set
+                                    ),
+                                ));
+                            }
+                            first.duplicates.push((name, value));
+                        }
+                    }
+                }
+                match (&first.lua, header.lua.take()) {
+                    (_, None) => {}
+                    (None, lua) => first.lua = lua,
+                    (Some(_), Some(_)) => errors.push(ValidationError::site(
+                        &site.domain,
+                        "headers",
+                        format!(
+                            "headers for {:?} have lua in more than one entry, move it to a single one",
+                            header.for_field
+                        ),
+                    )),
+                }
+                header.merged = true;
+            }
+            for header in headers.iter_mut() {
+                header.names = header
+                    .values
+                    .keys()
+                    .map(|name| name.to_ascii_lowercase())
+                    .collect();
+            }
+
+            if let Some(pattern) = &site.immutable_assets {
+                if let Err(reason) = validate_location(pattern) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "immutable_assets",
+                        format!("immutable_assets {:?}: {}", pattern, reason),
+                    ));
+                } else if is_server_location(pattern) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "immutable_assets",
+                        format!(
+                            "immutable_assets {:?} would cache the whole site forever, use the location of the hashed assets",
+                            pattern
+                        ),
+                    ));
+                }
+                if site
+                    .headers
+                    .iter()
+                    .flatten()
+                    .any(|header| &header.for_field == pattern)
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "immutable_assets",
+                        format!(
+                            "immutable_assets and headers both have a location {:?}, move the headers into a less specific one",
+                            pattern
+                        ),
+                    ));
+                }
+            }
+
+            let mut mimes = HashSet::new();
+            for cache_control in site.cache_control.iter().flatten() {
+                if !mime_re.is_match(&cache_control.mime) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "cache_control",
+                        format!(
+                            "cache_control for {:?}: not a MIME type like text/html or image/*",
+                            cache_control.mime
+                        ),
+                    ));
+                } else if !mimes.insert(cache_control.mime.to_lowercase()) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "cache_control",
+                        format!(
+                            "cache_control for {:?} is listed more than once",
+                            cache_control.mime
+                        ),
+                    ));
+                }
+                if cache_control.value.trim().is_empty() || cache_control.value.contains('"') {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "cache_control",
+                        format!(
+                            "cache_control for {:?}: the value must be non-empty, without double quotes",
+                            cache_control.mime
+                        ),
+                    ));
+                }
+            }
+            if let Some(default) = &site.cache_control_default {
+                if default.contains('"') {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "cache_control_default",
+                        String::from("cache_control_default can't contain double quotes"),
+                    ));
+                }
+            }
+
+            if site.headers.is_some() {
+                let cache_control_headers: Vec<Header> = site
                     .headers
                     .clone()
                     .unwrap()
                     .iter()
-                    .cloned()
                     .filter(|header| header.values.contains_key("Cache-Control"))
+                    .cloned()
                     .collect();
 
                 cache_control_headers.iter().for_each(|header| {
-                    errors.push(anyhow!("{:?} ", header));
+                    warnings.push(Warning::site(
+                        WarningKind::Redundant,
+                        &site.domain,
+                        format!(
+                            "Cache-Control in headers for {:?} is sent along the one set from cache_control",
+                            header.for_field
+                        ),
+                    ));
                 });
             }
-        }
 
-        if errors.len() > 0 {
-            return Err(anyhow!("{:?} ", errors));
-        }
+            // nginx picks a server by its names or as the default one, never
+            // by the `_` of the usual nameless server.
+            if site.domain == "_" {
+                if !site.default_server {
+                    warnings.push(Warning::site(
+                        WarningKind::NoEffect,
+                        &site.domain,
+                        String::from(
+                            "the domain \"_\" matches no host, set default_server to answer the hosts no other site serves",
+                        ),
+                    ));
+                }
+                if site.letsencrypt {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "letsencrypt",
+                        String::from(
+                            "letsencrypt can't issue a certificate for \"_\", set ssl_cert and ssl_key",
+                        ),
+                    ));
+                }
+                if site.www_redirect == Some(true) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "www_redirect",
+                        String::from("www_redirect needs a domain, \"_\" has no www host"),
+                    ));
+                }
+            }
 
-        Ok(Config { sites: self.sites })
-    }
-}
+            if site.letsencrypt {
+                if site.ssl_cert.is_some() || site.ssl_key.is_some() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "letsencrypt",
+                        String::from("letsencrypt conflicts with ssl_cert and ssl_key"),
+                    ));
+                } else {
+                    let live = format!("{}/{}", LETSENCRYPT_LIVE, site.domain);
+                    site.ssl_cert = Some(format!("{}/fullchain.pem", live));
+                    site.ssl_key = Some(format!("{}/privkey.pem", live));
+                }
+            }
 
-fn redirect_domain(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
-    let mut s = try_get_value!("redirect_domain", "value", String, value);
+            if site.ssl_cert.is_some() != site.ssl_key.is_some() {
+                errors.push(ValidationError::site(
+                    &site.domain,
+                    "ssl_cert",
+                    String::from("ssl_cert and ssl_key must be set together"),
+                ));
+            }
 
-    s = if s.starts_with("www.") {
-        s.replace("www.", "")
-    } else {
-        format!("www.{}", s)
-    };
+            if site.force_https && site.ssl_cert.is_none() {
+                errors.push(ValidationError::site(
+                    &site.domain,
+                    "force_https",
+                    String::from("force_https requires ssl_cert and ssl_key"),
+                ));
+            }
 
-    Ok(to_value(&s).unwrap())
-}
+            if let Some(mtls) = &site.mtls {
+                if site.ssl_cert.is_none() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "mtls",
+                        String::from("mtls requires ssl_cert and ssl_key"),
+                    ));
+                }
+                if mtls.client_ca.trim().is_empty() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "mtls",
+                        String::from("mtls requires a client_ca"),
+                    ));
+                }
+                // Client certificates are only checked on the HTTPS listeners,
+                // plain HTTP would serve the same site without one.
+                if site.ssl_cert.is_some() && !site.force_https {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "mtls",
+                        String::from("mtls requires force_https"),
+                    ));
+                }
+                if site.https_redirect_exclude.is_some() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "mtls",
+                        String::from(
+                            "mtls conflicts with https_redirect_exclude, whose paths are served over HTTP without a client certificate",
+                        ),
+                    ));
+                }
+            }
 
-fn pad_right(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
-    let s = try_get_value!("pad_right", "value", String, value);
+            let methods = match (&site.method_allow, &site.method_deny) {
+                (Some(_), Some(_)) => {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "method_allow",
+                        String::from(
+                            "method_allow and method_deny conflict: set the methods to allow or the ones to deny",
+                        ),
+                    ));
+                    None
+                }
+                (Some(methods), None) => Some(("method_allow", methods)),
+                (None, Some(methods)) => Some(("method_deny", methods)),
+                (None, None) => None,
+            };
+            if let Some((field, methods)) = methods {
+                let unknown: Vec<&String> = methods
+                    .iter()
+                    .filter(|method| !HTTP_METHODS.contains(&method.as_str()))
+                    .collect();
+                // limit_except allows HEAD along with GET.
+                let allowed: Vec<String> = HTTP_METHODS
+                    .iter()
+                    .filter(|method| {
+                        methods.iter().any(|m| m == *method) == (field == "method_allow")
+                    })
+                    .map(|method| method.to_string())
+                    .collect();
+                let head_only =
+                    allowed.iter().any(|m| m == "GET") && !allowed.iter().any(|m| m == "HEAD");
+                if !unknown.is_empty() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        field,
+                        format!(
+                            "unknown methods {:?} in {}, expected some of {}",
+                            unknown,
+                            field,
+                            HTTP_METHODS.join(", ")
+                        ),
+                    ));
+                } else if allowed.is_empty() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        field,
+                        format!("{} leaves no method to serve", field),
+                    ));
+                } else if head_only {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        field,
+                        String::from(
+                            "HEAD can't be denied while GET is allowed, nginx allows both together",
+                        ),
+                    ));
+                } else {
+                    site.limit_except = Some(allowed);
+                }
+            }
 
-    let width = match args.get("width") {
-        Some(val) => try_get_value!("pad_right", "plural", usize, val),
-        None => 35,
-    };
+            if let Some(root) = &site.root {
+                if !root.starts_with('/') || root.contains(|c: char| c.is_whitespace() || c == ';')
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "root",
+                        format!(
+                            "root {:?} must be an absolute path without whitespace nor ';'",
+                            root
+                        ),
+                    ));
+                }
+            }
 
-    Ok(to_value(format!("{:width$}", s, width = width)).unwrap())
-}
+            if let Some(listen) = &site.listen {
+                let mut listen_on = ListenOn::default();
+                for entry in listen {
+                    let address = match entry.parse::<std::net::SocketAddr>() {
+                        Ok(address) => address,
+                        Err(_) => {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "listen",
+                                format!(
+                                    "{:?} isn't an IP address and port, e.g. \"10.0.0.5:443\" or \"[2001:db8::5]:443\"",
+                                    entry
+                                ),
+                            ));
+                            continue;
+                        }
+                    };
+                    let ports = match address.port() {
+                        HTTP_PORT => &mut listen_on.http,
+                        HTTPS_PORT if site.ssl_cert.is_some() => &mut listen_on.https,
+                        HTTPS_PORT => {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "listen",
+                                format!("{:?} listens for HTTPS without ssl_cert", entry),
+                            ));
+                            continue;
+                        }
+                        port => {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "listen",
+                                format!(
+                                    "port {} of {:?} isn't served, use {} for HTTP or {} for HTTPS",
+                                    port, entry, HTTP_PORT, HTTPS_PORT
+                                ),
+                            ));
+                            continue;
+                        }
+                    };
+                    if ports.contains(&address.to_string()) {
+                        errors.push(ValidationError::site(
+                            &site.domain,
+                            "listen",
+                            format!("{:?} is listed twice", entry),
+                        ));
+                        continue;
+                    }
+                    ports.push(address.to_string());
+                }
+                site.listen_on = Some(listen_on);
+            }
 
-#[derive(Serialize, Deserialize, Default)]
-pub struct Config {
-    sites: Vec<Site>,
-}
+            if site.ssl_cert.is_some() {
+                site.tls_profile = site.tls_profile.or(self.tls_profile);
+                let mut tls = Tls::new(site.tls_profile, self.dhparam.as_ref());
+                if let Some(protocols) = &site.ssl_protocols {
+                    for protocol in protocols.split_whitespace() {
+                        if !TLS_PROTOCOLS.contains(&protocol) {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "ssl_protocols",
+                                format!("unknown TLS protocol {:?} in ssl_protocols", protocol),
+                            ));
+                        }
+                    }
+                    tls.protocols = Some(protocols.clone());
+                }
+                if let Some(ciphers) = &site.ssl_ciphers {
+                    tls.ciphers = Some(ciphers.clone());
+                }
+                if tls.protocols.is_some() || tls.ciphers.is_some() {
+                    site.tls = Some(tls);
+                }
+            } else if site.tls_profile.is_some()
+                || site.ssl_protocols.is_some()
+                || site.ssl_ciphers.is_some()
+            {
+                warnings.push(Warning::site(
+                    WarningKind::NoEffect,
+                    &site.domain,
+                    String::from("TLS settings have no effect without ssl_cert"),
+                ));
+            }
 
-impl Config {
-    fn generate(self) -> Result<()> {
-        fs::create_dir_all(OUTPUT_DIR)?;
+            let lua_locations = std::iter::once(("/", &site.lua)).chain(
+                site.headers
+                    .iter()
+                    .flatten()
+                    .map(|header| (header.for_field.as_str(), &header.lua)),
+            );
+            for (location, lua) in lua_locations {
+                let lua = match lua {
+                    Some(lua) => lua,
+                    None => continue,
+                };
+                if !self.openresty {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "lua",
+                        format!("lua of location {:?} requires openresty = true", location),
+                    ));
+                }
+                let mut phases = HashSet::new();
+                for block in lua.iter() {
+                    if !phases.insert(block.phase) {
+                        errors.push(ValidationError::site(
+                            &site.domain,
+                            "lua",
+                            format!(
+                                "location {:?} has more than one lua block for phase {:?}",
+                                location,
+                                format!("{:?}", block.phase).to_lowercase()
+                            ),
+                        ));
+                    }
+                }
+            }
 
-        let mut tera = Tera::default();
-        tera.register_filter("redirect_domain", redirect_domain);
-        tera.register_filter("pad_right", pad_right);
+            if let Some(real_ip) = &mut site.real_ip {
+                for message in real_ip.apply_preset() {
+                    errors.push(ValidationError::site(&site.domain, "real_ip", message));
+                }
+            }
 
-        let tera = Arc::new(Mutex::new(tera));
-        let mut handles = vec![];
+            if let Some(acl) = &site.allow_acl {
+                match self.acls.get(acl) {
+                    Some(addresses) => site.allow = Some(addresses.clone()),
+                    None => errors.push(ValidationError::site(
+                        &site.domain,
+                        "allow_acl",
+                        format!("acl {:?} is not defined", acl),
+                    )),
+                }
+            }
 
-        self.sites
-            .iter()
-            .cloned()
-            .enumerate()
-            .for_each(|(_, site)| {
-                let tera = Arc::clone(&tera);
+            if let Some(page) = &site.maintenance_page {
+                if !page.starts_with('/') || page.ends_with('/') {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "maintenance_page",
+                        format!(
+                            "maintenance_page {:?} must be the absolute path of a file",
+                            page
+                        ),
+                    ));
+                }
+            }
+            let mut error_codes = HashSet::new();
+            for error_page in site.error_pages.iter().flatten() {
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "error_pages", message))
+                };
+                if error_page.codes.is_empty() {
+                    invalid(format!("error page {:?} must have codes", error_page.page));
+                }
+                for code in error_page.codes.iter() {
+                    if !(300..=599).contains(code) {
+                        invalid(format!(
+                            "error page code {} must be between 300 and 599",
+                            code
+                        ));
+                    } else if !error_codes.insert(*code) {
+                        invalid(format!("error page code {} is given more than once", code));
+                    } else if *code == 503 && site.maintenance_page.is_some() {
+                        invalid(String::from(
+                            "error page code 503 conflicts with maintenance_page, remove one of them",
+                        ));
+                    }
+                }
+                let page = &error_page.page;
+                if !page.starts_with('/')
+                    || page.ends_with('/')
+                    || page.contains(|c: char| c.is_whitespace() || matches!(c, ';' | '"' | '\''))
+                {
+                    invalid(format!(
+                        "error page {:?} must be the absolute path of a file",
+                        page
+                    ));
+                }
+            }
+            if site.proxy_intercept_errors {
+                if site.error_pages.is_none() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "proxy_intercept_errors",
+                        String::from(
+                            "proxy_intercept_errors requires error_pages, the pages served in place of the errors of the upstream",
+                        ),
+                    ));
+                } else if site.root.is_some() {
+                    warnings.push(Warning::site(
+                        WarningKind::NoEffect,
+                        &site.domain,
+                        String::from(
+                            "proxy_intercept_errors has no effect with root, which isn't proxied",
+                        ),
+                    ));
+                }
+            }
+
+            for address in site.maintenance_allow.iter().flatten() {
+                if !is_address_range(address) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "maintenance_allow",
+                        format!("invalid maintenance_allow address {:?}", address),
+                    ));
+                }
+            }
+
+            for path in site.https_redirect_exclude.iter().flatten() {
+                if !path.starts_with('/') {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "https_redirect_exclude",
+                        format!(
+                            "https_redirect_exclude path {:?} must start with a slash",
+                            path
+                        ),
+                    ));
+                }
+            }
+            if site.https_redirect_exclude.is_some() && !site.force_https {
+                warnings.push(Warning::site(
+                    WarningKind::NoEffect,
+                    &site.domain,
+                    String::from("https_redirect_exclude has no effect without force_https"),
+                ));
+            }
+
+            if let Some(target) = &site.redirect_to {
+                if let Err(reason) = check_url(target) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "redirect_to",
+                        format!("invalid redirect_to {:?}: {}", target, reason),
+                    ));
+                }
+                let path_mode = site.path_mode.unwrap_or_default();
+                let keeps_uri = target.contains("$request_uri");
+                site.redirect_return = Some(match path_mode {
+                    // Targets already ending with the path are kept as they
+                    // are.
+                    PathMode::Preserve if keeps_uri => target.clone(),
+                    PathMode::Preserve => format!("{}$request_uri", target.trim_end_matches('/')),
+                    PathMode::Drop | PathMode::Rewrite => target.clone(),
+                });
+                if path_mode != PathMode::Preserve && keeps_uri {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "path_mode",
+                        format!(
+                            "redirect_to {:?} keeps the path with $request_uri, remove it or set path_mode = \"preserve\"",
+                            target
+                        ),
+                    ));
+                }
+                match (path_mode, &site.path_rewrites) {
+                    (PathMode::Rewrite, None) => errors.push(ValidationError::site(
+                        &site.domain,
+                        "path_rewrites",
+                        String::from("path_mode = \"rewrite\" requires path_rewrites"),
+                    )),
+                    (PathMode::Rewrite, Some(_)) => {}
+                    (_, Some(_)) => errors.push(ValidationError::site(
+                        &site.domain,
+                        "path_rewrites",
+                        String::from("path_rewrites only apply with path_mode = \"rewrite\""),
+                    )),
+                    (_, None) => {}
+                }
+                for rewrite in site.path_rewrites.iter().flatten() {
+                    if let Err(err) = Regex::new(&rewrite.pattern) {
+                        errors.push(ValidationError::site(
+                            &site.domain,
+                            "path_rewrites",
+                            format!(
+                                "path rewrite pattern {:?} is not a valid regex: {}",
+                                rewrite.pattern, err
+                            ),
+                        ));
+                    }
+                    if !rewrite.replacement.starts_with('/') {
+                        errors.push(ValidationError::site(
+                            &site.domain,
+                            "path_rewrites",
+                            format!(
+                                "path rewrite replacement {:?} must be a path, starting with /",
+                                rewrite.replacement
+                            ),
+                        ));
+                    }
+                    for value in [&rewrite.pattern, &rewrite.replacement] {
+                        if value.contains(|c: char| c.is_whitespace() || c == '"') {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "path_rewrites",
+                                format!(
+                                    "path rewrite {:?} can't contain spaces or double quotes",
+                                    value
+                                ),
+                            ));
+                        }
+                    }
+                }
+            } else if site.path_mode.is_some() || site.path_rewrites.is_some() {
+                errors.push(ValidationError::site(
+                    &site.domain,
+                    "path_mode",
+                    String::from("path_mode and path_rewrites only apply with redirect_to"),
+                ));
+            }
+
+            errors.extend(conflicts(site));
+
+            if let Some(healthcheck) = &site.healthcheck {
+                if !healthcheck.path.starts_with('/') {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "healthcheck",
+                        format!(
+                            "healthcheck path {:?} must start with a slash",
+                            healthcheck.path
+                        ),
+                    ));
+                }
+                if !(200..600).contains(&healthcheck.status) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "healthcheck",
+                        format!(
+                            "healthcheck status {} is not an HTTP status",
+                            healthcheck.status
+                        ),
+                    ));
+                }
+                if healthcheck.body.contains(['"', '\\', '\n']) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "healthcheck",
+                        format!(
+                            "healthcheck body {:?} can't contain quotes, backslashes or newlines",
+                            healthcheck.body
+                        ),
+                    ));
+                }
+            }
+
+            for redirect in site.redirects.iter_mut().flatten() {
+                if !redirect.regex && !redirect.from_field.starts_with('/') {
+                    let from = format!("/{}", redirect.from_field);
+                    warnings.push(Warning::site(
+                        WarningKind::Corrected,
+                        &site.domain,
+                        format!(
+                            "redirect from {:?} doesn't start with a slash, using {:?}",
+                            redirect.from_field, from
+                        ),
+                    ));
+                    redirect.from_field = from;
+                }
+                if redirect.regex {
+                    if let Err(err) = Regex::new(&redirect.from_field) {
+                        errors.push(ValidationError::site(
+                            &site.domain,
+                            "redirects",
+                            format!(
+                                "redirect from {:?} is not a valid regex: {}",
+                                redirect.from_field, err
+                            ),
+                        ));
+                    }
+                }
+                if let Err(reason) = check_url(&redirect.to) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "redirects",
+                        format!(
+                            "redirect from {:?} has an invalid target {:?}: {}",
+                            redirect.from_field, redirect.to, reason
+                        ),
+                    ));
+                }
+            }
+
+            if let Some(size) = &site.proxy_buffer_size {
+                if !size_re.is_match(size) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "proxy_buffer_size",
+                        format!("proxy_buffer_size {:?} must be a size, e.g. \"16k\"", size),
+                    ));
+                }
+            }
+            let buffers_valid = |buffers: &str| match buffers.split_once(' ') {
+                Some((number, size)) => {
+                    number.parse::<u32>().is_ok_and(|n| n > 0) && size_re.is_match(size)
+                }
+                None => false,
+            };
+            if let Some(buffers) = &site.proxy_buffers {
+                if !buffers_valid(buffers) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "proxy_buffers",
+                        format!(
+                            "proxy_buffers {:?} must be a number and a size, e.g. \"8 16k\"",
+                            buffers
+                        ),
+                    ));
+                }
+            }
+
+            if let Some(header_buffers) = &site.header_buffers {
+                if header_buffers.size.is_none() && header_buffers.large.is_none() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "header_buffers",
+                        String::from("header_buffers requires size, large or both"),
+                    ));
+                }
+                if let Some(size) = header_buffers
+                    .size
+                    .as_ref()
+                    .filter(|size| !size_re.is_match(size))
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "header_buffers",
+                        format!("header_buffers size {:?} must be a size, e.g. \"4k\"", size),
+                    ));
+                }
+                if let Some(large) = header_buffers
+                    .large
+                    .as_ref()
+                    .filter(|large| !buffers_valid(large))
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "header_buffers",
+                        format!(
+                            "header_buffers large {:?} must be a number and a size, e.g. \"4 32k\"",
+                            large
+                        ),
+                    ));
+                }
+            }
 
-                let handle = thread::spawn(move || {
-                    let path = Path::new(OUTPUT_DIR).join(site.filename());
-                    let display = path.display();
+            if site.early_hints.is_some() && !site.http2 {
+                errors.push(ValidationError::site(
+                    &site.domain,
+                    "early_hints",
+                    String::from("early_hints requires http2 = true"),
+                ));
+            }
+            if site.http3 {
+                if site.ssl_cert.is_none() {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "http3",
+                        String::from("http3 requires ssl_cert, QUIC being always encrypted"),
+                    ));
+                }
+                if self.nginx_version < QUIC_VERSION {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "http3",
+                        format!(
+                            "http3 requires nginx {} or later, nginx_version is {}",
+                            QUIC_VERSION, self.nginx_version
+                        ),
+                    ));
+                }
+            }
+            for hint in site.early_hints.iter().flatten() {
+                let valid = hint.href.starts_with('/')
+                    || Url::parse(&hint.href).is_ok_and(|url| url.scheme() == "https");
+                if !valid || hint.href.contains(['"', '>', ' ']) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "early_hints",
+                        format!("early hint {:?} must be a path or an https URL", hint.href),
+                    ));
+                }
+            }
+
+            for rewrite in site.rewrites.iter().flatten() {
+                if let Err(err) = Regex::new(&rewrite.pattern) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "rewrites",
+                        format!(
+                            "rewrite pattern {:?} is not a valid regex: {}",
+                            rewrite.pattern, err
+                        ),
+                    ));
+                }
+                for value in [&rewrite.pattern, &rewrite.replacement] {
+                    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+                        errors.push(ValidationError::site(
+                            &site.domain,
+                            "rewrites",
+                            format!(
+                                "rewrite {:?} can't be empty or contain spaces or double quotes",
+                                value
+                            ),
+                        ));
+                    }
+                }
+            }
+
+            if let Some(upstream) = &site.upstream {
+                let checked = match upstream.backend_type {
+                    BackendType::Proxy => check_upstream(&upstream.url),
+                    // The name of the upstream block of the servers.
+                    BackendType::Fastcgi | BackendType::Uwsgi if upstream.servers.is_some() => {
+                        Ok(())
+                    }
+                    BackendType::Fastcgi | BackendType::Uwsgi => check_address(&upstream.url),
+                };
+                if let Err(reason) = checked {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "upstream",
+                        format!("invalid upstream {:?}: {}", upstream.url, reason),
+                    ));
+                }
+                if upstream.dynamic
+                    && self.resolver.is_none()
+                    && unix_socket(&upstream.url).is_none()
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "upstream",
+                        format!("dynamic upstream {:?} requires a resolver", upstream.url),
+                    ));
+                }
+                if upstream.dynamic
+                    && upstream.backend_type == BackendType::Proxy
+                    && has_uri(&upstream.url)
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "upstream",
+                        format!(
+                            "dynamic upstream {:?} can't have a path, which nginx would send in place of the URI of the requests",
+                            upstream.url
+                        ),
+                    ));
+                }
 
-                    let mut file = match File::create(&path) {
-                        Err(why) => bail!("couldn't create {}: {}", display, why),
-                        Ok(file) => file,
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "upstream", message))
+                };
+                if let Some(servers) = &upstream.servers {
+                    let group = match upstream.backend_type {
+                        BackendType::Proxy if unix_socket(&upstream.url).is_none() => {
+                            Url::parse(&upstream.url)
+                                .ok()
+                                .filter(|url| url.port().is_none())
+                                .and_then(|url| url.host_str().map(String::from))
+                        }
+                        BackendType::Proxy => None,
+                        BackendType::Fastcgi | BackendType::Uwsgi => Some(upstream.url.clone()),
                     };
+                    match group.filter(|group| {
+                        !group.is_empty()
+                            && group
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                    }) {
+                        Some(group) => {
+                            let owner = format!("the upstream of {:?}", site.domain);
+                            if let Some(other) = definitions.get(&format!("upstream {}", group)) {
+                                invalid(format!(
+                                    "the servers of upstream {:?} are already those of {}",
+                                    upstream.url, other
+                                ));
+                            } else {
+                                definitions.insert(format!("upstream {}", group), owner.clone());
+                            }
+                            if upstream.health_check.is_some() {
+                                definitions.insert(format!("zone {}", group), owner);
+                            }
+                            site.upstream_group = Some(group);
+                        }
+                        None => invalid(format!(
+                            "with servers, upstream {:?} must name their group, e.g. \"http://app\", or \"app\" for fastcgi and uwsgi, without a port",
+                            upstream.url
+                        )),
+                    }
+                    if servers.is_empty() {
+                        invalid(String::from("servers can't be empty"));
+                    }
+                    if upstream.dynamic {
+                        invalid(String::from(
+                            "servers and dynamic conflict: the addresses of servers are resolved once at startup",
+                        ));
+                    }
+                    for server in servers.iter() {
+                        if let Err(reason) = check_address(&server.address) {
+                            invalid(format!("server {:?}: {}", server.address, reason));
+                        }
+                        if let Some(fail_timeout) = &server.fail_timeout {
+                            if !duration_re.is_match(fail_timeout) {
+                                invalid(format!(
+                                    "server {:?}: fail_timeout {:?} must be a duration, e.g. \"30s\"",
+                                    server.address, fail_timeout
+                                ));
+                            }
+                        }
+                    }
+                }
+                if let Some(health_check) = &upstream.health_check {
+                    if !self.active_health_checks {
+                        invalid(String::from(
+                            "health_check requires active_health_checks = true, for nginx Plus",
+                        ));
+                    }
+                    if upstream.servers.is_none() || upstream.backend_type != BackendType::Proxy {
+                        invalid(String::from(
+                            "health_check requires servers, with the proxy backend_type",
+                        ));
+                    }
+                    if site.split.is_some() {
+                        invalid(String::from(
+                            "health_check and split conflict: split proxies to a variable, which nginx can't check",
+                        ));
+                    }
+                    if let Some(uri) = &health_check.uri {
+                        if !uri.starts_with('/')
+                            || uri.contains(|c: char| c.is_whitespace() || c == ';' || c == '"')
+                        {
+                            invalid(format!(
+                                "health_check uri {:?} must be a path, without spaces, ';' or '\"'",
+                                uri
+                            ));
+                        }
+                    }
+                    if let Some(interval) = &health_check.interval {
+                        if !duration_re.is_match(interval) {
+                            invalid(format!(
+                                "health_check interval {:?} must be a duration, e.g. \"5s\"",
+                                interval
+                            ));
+                        }
+                    }
+                    if health_check.fails == Some(0) || health_check.passes == Some(0) {
+                        invalid(String::from(
+                            "health_check fails and passes must be at least 1",
+                        ));
+                    }
+                }
+            }
 
-                    site.generate(tera.lock().unwrap(), file.by_ref())?;
+            if let Some(split) = &site.split {
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "split", message))
+                };
+                match &site.upstream {
+                    Some(upstream) if upstream.backend_type == BackendType::Proxy => {
+                        if upstream.dynamic {
+                            invalid(String::from(
+                                "split and a dynamic upstream conflict: split already resolves its upstreams at request time",
+                            ));
+                        }
+                    }
+                    _ => invalid(String::from(
+                        "requires a proxied upstream, which gets the rest of the traffic",
+                    )),
+                }
+                if split.targets.is_empty() {
+                    invalid(String::from("at least one target is required"));
+                }
+                if !split.key.contains('$') || split.key.contains(['"', ';', '\\']) {
+                    invalid(format!(
+                        "key {:?} must contain a variable, and can't contain quotes, semicolons or backslashes",
+                        split.key
+                    ));
+                }
 
-                    if verbose::is_enabled() {
-                        println!("{}", display)
+                let mut total = 0.0;
+                for target in split.targets.iter() {
+                    match percentage(&target.percent) {
+                        Some(percentage) if percentage > 0.0 => total += percentage,
+                        _ => invalid(format!(
+                            "percent {:?} of {:?} must be a percentage above 0 with at most two decimals, e.g. \"10%\" or \"0.25%\"",
+                            target.percent, target.url
+                        )),
                     }
+                }
+                if total >= 100.0 {
+                    invalid(format!(
+                        "the targets get {}% of the traffic, leaving none to the upstream",
+                        total
+                    ));
+                }
 
-                    Ok(())
-                });
-                handles.push(handle);
-            });
+                let urls = split.targets.iter().map(|target| &target.url);
+                let mut hostnames = Vec::new();
+                for url in site
+                    .upstream
+                    .iter()
+                    .map(|upstream| &upstream.url)
+                    .chain(urls)
+                {
+                    match check_split_url(url) {
+                        // The upstream block of servers is found by name.
+                        Ok(Some(host)) if site.upstream_group.as_ref() != Some(&host) => {
+                            hostnames.push(host)
+                        }
+                        Ok(_) => {}
+                        Err(reason) => invalid(format!("invalid upstream {:?}: {}", url, reason)),
+                    }
+                }
+                if self.resolver.is_none() {
+                    if let Some(host) = hostnames.first() {
+                        invalid(format!(
+                            "upstream host {:?} requires a resolver, as the upstream is picked at request time",
+                            host
+                        ));
+                    }
+                }
+            }
 
-        for handle in handles {
-            handle.join().unwrap()?;
-        }
+            if let Some(proxy_ssl) = &site.proxy_ssl {
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "proxy_ssl", message))
+                };
+                let https = site
+                    .upstream
+                    .iter()
+                    .filter(|upstream| upstream.backend_type == BackendType::Proxy)
+                    .map(|upstream| &upstream.url)
+                    .chain(
+                        site.split
+                            .iter()
+                            .flat_map(|split| split.targets.iter().map(|target| &target.url)),
+                    )
+                    .any(|url| url.starts_with("https://"));
+                if !https {
+                    invalid(String::from("proxy_ssl requires an https:// upstream"));
+                }
+                if proxy_ssl.verify && proxy_ssl.trusted_certificate.is_none() {
+                    invalid(String::from(
+                        "verify requires a trusted_certificate, the CA certificates signing the one of the upstream",
+                    ));
+                }
+                if proxy_ssl
+                    .trusted_certificate
+                    .as_ref()
+                    .is_some_and(|path| path.trim().is_empty())
+                {
+                    invalid(String::from("trusted_certificate can't be empty"));
+                }
+                if let Some(name) = &proxy_ssl.name {
+                    if !proxy_ssl.server_name {
+                        invalid(String::from(
+                            "name requires server_name, which sends it with SNI",
+                        ));
+                    }
+                    if name.is_empty()
+                        || name.contains(|c: char| c.is_whitespace() || c == ';' || c == '"')
+                    {
+                        invalid(format!(
+                            "name {:?} can't be empty or contain whitespace, ';' or '\"'",
+                            name
+                        ));
+                    }
+                }
+                if proxy_ssl.trusted_certificate.is_some() && !proxy_ssl.verify {
+                    warnings.push(Warning::site(
+                        WarningKind::NoEffect,
+                        &site.domain,
+                        String::from(
+                            "proxy_ssl trusted_certificate has no effect without verify = true",
+                        ),
+                    ));
+                }
+            }
 
-        Ok(())
-    }
-}
+            if let Some(error_log) = &site.error_log {
+                if error_log.is_empty()
+                    || error_log.contains(|c: char| c.is_whitespace() || c == ';')
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "error_log",
+                        format!(
+                            "error_log {:?} must be a path, without whitespace or semicolons",
+                            error_log
+                        ),
+                    ));
+                }
+            } else if site.error_log_level.is_some() {
+                site.error_log = Some(String::from(DEFAULT_ERROR_LOG));
+            }
 
-pub fn run(config: UnverifiedConfig) -> Result<()> {
-    config.validate()?.generate()
-}
+            if let Some(access_log) = site.access_log.as_mut() {
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "access_log", message))
+                };
+                if let Some(format) = &access_log.format {
+                    if format != "combined" && !log_formats.contains(format) {
+                        invalid(format!("log format {:?} is not defined", format));
+                    }
+                }
+                if let Some(sample) = &access_log.sample {
+                    if !percentage(sample)
+                        .is_some_and(|percentage| percentage > 0.0 && percentage < 100.0)
+                    {
+                        invalid(format!(
+                            "sample {:?} must be a percentage between 0 and 100 with at most two decimals, e.g. \"10%\" or \"0.25%\"",
+                            sample
+                        ));
+                    }
+                }
+                for path in access_log.skip_paths.iter() {
+                    if !path.starts_with('/')
+                        || path.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+                    {
+                        invalid(format!(
+                            "skip path {:?} must start with / and can't contain whitespace, quotes or backslashes",
+                            path
+                        ));
+                    }
+                }
+                if access_log.skip_healthchecks {
+                    for path in HEALTHCHECK_PATHS {
+                        if !access_log.skip_paths.iter().any(|skipped| skipped == path) {
+                            access_log.skip_paths.push(path.to_string());
+                        }
+                    }
+                }
+                if let Some(condition) = &access_log.condition {
+                    let defined = condition
+                        .strip_prefix('$')
+                        .is_some_and(|variable| variables.contains(variable));
+                    if !defined {
+                        invalid(format!(
+                            "condition {:?} must be the variable of one of maps, e.g. \"$loggable\"",
+                            condition
+                        ));
+                    }
+                    if access_log.sample.is_some() || !access_log.skip_paths.is_empty() {
+                        invalid(String::from(
+                            "condition can't be combined with sample, skip_paths or skip_healthchecks, set them in its map instead",
+                        ));
+                    }
+                }
+            }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            if let Some(proxy_cache) = &site.proxy_cache {
+                if !zones.contains(proxy_cache.zone.as_str()) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "proxy_cache",
+                        format!("proxy cache zone {:?} is not defined", proxy_cache.zone),
+                    ));
+                }
+            }
 
-    #[test]
-    fn test_redirect_domain() {
-        use serde_json::json;
+            for map_redirect in site.map_redirects.iter().flatten() {
+                if !variables.contains(map_redirect.map.as_str()) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "map_redirects",
+                        format!("map {:?} is not defined", map_redirect.map),
+                    ));
+                }
+            }
 
-        let value = redirect_domain(&json!("www.mlcdf.fr"), &HashMap::<String, Value>::new());
-        assert_eq!(value.unwrap().to_string(), "\"mlcdf.fr\"");
+            for variable in site.geo_deny.iter().flatten() {
+                if !geo_variables.contains(variable.as_str()) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "geo_deny",
+                        format!("geo {:?} is not defined", variable),
+                    ));
+                }
+            }
 
-        let value = redirect_domain(&json!("mlcdf.fr"), &HashMap::<String, Value>::new());
-        assert_eq!(value.unwrap().to_string(), "\"www.mlcdf.fr\"");
+            if let Some(rate_limit) = &site.rate_limit {
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "rate_limit", message))
+                };
+                if !rate_re.is_match(&rate_limit.rate) {
+                    invalid(format!(
+                        "rate {:?} must be requests per second or minute, e.g. \"10r/s\"",
+                        rate_limit.rate
+                    ));
+                }
+                if !rate_limit.key.contains('$') || rate_limit.key.contains(['"', ';', '\\']) {
+                    invalid(format!(
+                        "key {:?} must contain a variable, and can't contain quotes, semicolons or backslashes",
+                        rate_limit.key
+                    ));
+                }
+                if !size_re.is_match(&rate_limit.zone_size) {
+                    invalid(format!(
+                        "zone_size {:?} must be a size, e.g. \"10m\"",
+                        rate_limit.zone_size
+                    ));
+                }
+                if let Some(status) = rate_limit.status {
+                    if !(400..=599).contains(&status) {
+                        invalid(format!("status {} must be between 400 and 599", status));
+                    }
+                }
+                if rate_limit.dry_run && self.nginx_version < LIMIT_REQ_DRY_RUN_VERSION {
+                    invalid(format!(
+                        "dry_run requires nginx {} or later, nginx_version is {}",
+                        LIMIT_REQ_DRY_RUN_VERSION, self.nginx_version
+                    ));
+                }
+                define(
+                    &mut definitions,
+                    &mut errors,
+                    &site.domain,
+                    "rate_limit",
+                    format!("zone rate_limit_{}", site.domain.replace(['.', '-'], "_")),
+                );
+            }
 
-        let value = redirect_domain(&json!("dev.www.mlcdf.fr"), &HashMap::<String, Value>::new());
-        assert_eq!(value.unwrap().to_string(), "\"www.dev.www.mlcdf.fr\"");
+            for pattern in site.block_user_agents.iter().flatten() {
+                let reason = if pattern.is_empty() {
+                    Some(String::from("can't be empty"))
+                } else if pattern.contains(|c: char| c == '"' || c.is_control()) {
+                    Some(String::from(
+                        "can't contain double quotes or control characters",
+                    ))
+                } else {
+                    Regex::new(pattern)
+                        .err()
+                        .map(|err| format!("is not a valid regex: {}", err))
+                };
+                if let Some(reason) = reason {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "block_user_agents",
+                        format!("user agent pattern {:?} {}", pattern, reason),
+                    ));
+                }
+            }
+            if let Some(status) = site.block_user_agents_status {
+                if !(400..=599).contains(&status) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "block_user_agents_status",
+                        format!(
+                            "block_user_agents_status {} must be between 400 and 599",
+                            status
+                        ),
+                    ));
+                }
+                if site.block_user_agents.is_none() {
+                    warnings.push(Warning::site(
+                        WarningKind::NoEffect,
+                        &site.domain,
+                        String::from(
+                            "block_user_agents_status has no effect without block_user_agents",
+                        ),
+                    ));
+                }
+            }
+            if site.block_user_agents.is_some() {
+                define(
+                    &mut definitions,
+                    &mut errors,
+                    &site.domain,
+                    "block_user_agents",
+                    format!(
+                        "variable $blocked_user_agent_{}",
+                        site.domain.replace(['.', '-'], "_")
+                    ),
+                );
+            }
+
+            if let Some(cors) = &site.cors {
+                let site_id = site.domain.replace(['.', '-'], "_");
+                let mut invalid = |message: String| {
+                    errors.push(ValidationError::site(&site.domain, "cors", message));
+                };
+                let any_origin = cors.origins.iter().any(|origin| origin == "*");
+                if cors.origins.is_empty() {
+                    invalid(String::from("origins can't be empty"));
+                } else if any_origin && cors.origins.len() > 1 {
+                    invalid(String::from(
+                        "origins can't list \"*\" along with other origins",
+                    ));
+                }
+                for origin in cors.origins.iter().filter(|origin| *origin != "*") {
+                    let serialized = Url::parse(origin)
+                        .ok()
+                        .filter(|url| ["http", "https"].contains(&url.scheme()))
+                        .map(|url| url.origin().ascii_serialization());
+                    if serialized.as_deref() != Some(origin.as_str()) {
+                        invalid(format!(
+                            "origin {:?} must be a scheme and a host, e.g. \"https://app.example.com\", without a path",
+                            origin
+                        ));
+                    }
+                }
+                if any_origin && cors.credentials {
+                    invalid(String::from(
+                        "credentials require listed origins, browsers refuse them from \"*\"",
+                    ));
+                }
+                let unknown: Vec<&String> = cors
+                    .methods
+                    .iter()
+                    .filter(|method| !HTTP_METHODS.contains(&method.as_str()))
+                    .collect();
+                if cors.methods.is_empty() {
+                    invalid(String::from("methods can't be empty"));
+                } else if !unknown.is_empty() {
+                    invalid(format!(
+                        "unknown methods {:?}, expected some of {}",
+                        unknown,
+                        HTTP_METHODS.join(", ")
+                    ));
+                }
+                for name in cors.headers.iter().chain(cors.expose_headers.iter()) {
+                    if name.is_empty()
+                        || !name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    {
+                        invalid(format!(
+                            "header {:?} must be a header name, e.g. \"Authorization\"",
+                            name
+                        ));
+                    }
+                }
+                // With cors, the Access-Control headers of headers would be
+                // sent twice, as would Vary with listed origins.
+                for header in site.headers.iter().flatten() {
+                    for name in header.values.keys() {
+                        if name.to_ascii_lowercase().starts_with("access-control-")
+                            || (!any_origin && name.eq_ignore_ascii_case("vary"))
+                        {
+                            errors.push(ValidationError::site(
+                                &site.domain,
+                                "headers",
+                                format!(
+                                    "headers for {:?} set {}, which cors sets",
+                                    header.for_field, name
+                                ),
+                            ));
+                        }
+                    }
+                }
+
+                let origin = match any_origin {
+                    true => String::from("*"),
+                    false => format!("$cors_origin_{}", site_id),
+                };
+                let mut headers = BTreeMap::new();
+                headers.insert(String::from("Access-Control-Allow-Origin"), origin);
+                if cors.credentials {
+                    headers.insert(
+                        String::from("Access-Control-Allow-Credentials"),
+                        String::from("true"),
+                    );
+                }
+                // The origin allowed depends on the request's.
+                if !any_origin {
+                    headers.insert(String::from("Vary"), String::from("Origin"));
+                }
+                let mut preflight = headers.clone();
+                preflight.insert(
+                    String::from("Access-Control-Allow-Methods"),
+                    cors.methods.join(", "),
+                );
+                if !cors.headers.is_empty() {
+                    preflight.insert(
+                        String::from("Access-Control-Allow-Headers"),
+                        cors.headers.join(", "),
+                    );
+                }
+                if let Some(max_age) = cors.max_age {
+                    preflight.insert(String::from("Access-Control-Max-Age"), max_age.to_string());
+                }
+                if !cors.expose_headers.is_empty() {
+                    headers.insert(
+                        String::from("Access-Control-Expose-Headers"),
+                        cors.expose_headers.join(", "),
+                    );
+                }
+                if !any_origin {
+                    define(
+                        &mut definitions,
+                        &mut errors,
+                        &site.domain,
+                        "cors",
+                        format!("variable $cors_origin_{}", site_id),
+                    );
+                }
+                site.cors_preflight = preflight;
+                site.cors_headers = headers;
+            }
+
+            if let Some(charset) = &site.charset {
+                if !charset.starts_with(|c: char| c.is_ascii_alphanumeric())
+                    || !charset
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.:+".contains(c))
+                {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "charset",
+                        format!(
+                            "charset {:?} must be a charset name, e.g. \"utf-8\", or \"off\"",
+                            charset
+                        ),
+                    ));
+                }
+            }
+
+            for definition in http_definitions(site.extra_http.as_deref().unwrap_or_default()) {
+                if let Some(owner) = definitions.get(&definition) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "extra_http",
+                        format!(
+                            "extra_http defines {}, already defined by {}",
+                            definition, owner
+                        ),
+                    ));
+                } else {
+                    definitions.insert(definition, format!("{:?}", site.domain));
+                }
+            }
+        }
+
+        // nginx passes to the upstream block named like the host of a URL
+        // without a port, whichever site defines it.
+        for site in self.sites.iter() {
+            let own = [
+                format!("the upstream of {:?}", site.domain),
+                format!("{:?}", site.domain),
+            ];
+            let upstream = site
+                .upstream
+                .iter()
+                .filter(|upstream| upstream.servers.is_none())
+                .map(|upstream| (&upstream.url, upstream.backend_type));
+            let targets = site
+                .split
+                .iter()
+                .flat_map(|split| split.targets.iter())
+                .map(|target| (&target.url, BackendType::Proxy));
+            for (url, backend_type) in upstream.chain(targets) {
+                let host = match backend_type {
+                    BackendType::Proxy => Url::parse(url)
+                        .ok()
+                        .filter(|url| url.port().is_none())
+                        .and_then(|url| url.host_str().map(String::from)),
+                    BackendType::Fastcgi | BackendType::Uwsgi => Some(url.clone()),
+                };
+                let owner = host.and_then(|host| definitions.get(&format!("upstream {}", host)));
+                if let Some(owner) = owner.filter(|owner| !own.contains(owner)) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "upstream",
+                        format!(
+                            "upstream {:?} would pass to the servers of {}, named alike",
+                            url, owner
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if let Some(extension) = &self.extension {
+            if let Err(message) = check_extension(extension) {
+                errors.push(ValidationError::Config {
+                    field: "extension",
+                    message,
+                });
+            }
+        }
+        for site in self.sites.iter_mut() {
+            if let Some(extension) = &site.extension {
+                if let Err(message) = check_extension(extension) {
+                    errors.push(ValidationError::site(&site.domain, "extension", message));
+                }
+            } else {
+                site.extension = self.extension.clone();
+            }
+        }
+
+        if let Some(template) = &self.filename {
+            let mut tera = tera();
+            let mut files = HashMap::new();
+            for site in self.sites.iter_mut() {
+                let file = match site_filename(&mut tera, template, site) {
+                    Ok(file) => file,
+                    Err(message) => {
+                        errors.push(ValidationError::site(&site.domain, "filename", message));
+                        continue;
+                    }
+                };
+                if let Some(other) = files.insert(file.clone(), site.domain.clone()) {
+                    errors.push(ValidationError::site(
+                        &site.domain,
+                        "filename",
+                        format!("filename {:?} is also the one of {:?}", file, other),
+                    ));
+                }
+                site.file = Some(file);
+            }
+        }
+
+        // nginx refuses to start when two servers are the default one of the
+        // same address and port, even in separate files.
+        let mut default_servers: HashMap<std::net::SocketAddr, String> = HashMap::new();
+        if self.catch_all.is_some() {
+            for address in ["443", "[::]:443"] {
+                default_servers.insert(listen_socket(address), String::from("catch_all"));
+            }
+        }
+        for site in self.sites.iter().filter(|site| site.default_server) {
+            let mut conflicts: Vec<(String, Vec<String>)> = Vec::new();
+            for address in site.listen_addresses() {
+                match default_servers.get(&listen_socket(&address)) {
+                    Some(owner) => match conflicts.iter_mut().find(|(other, _)| other == owner) {
+                        Some((_, addresses)) => addresses.push(address),
+                        None => conflicts.push((owner.clone(), vec![address])),
+                    },
+                    None => {
+                        default_servers
+                            .insert(listen_socket(&address), format!("{:?}", site.domain));
+                    }
+                }
+            }
+            for (owner, addresses) in conflicts {
+                errors.push(ValidationError::site(
+                    &site.domain,
+                    "default_server",
+                    format!(
+                        "default_server on {} is also set by {}, nginx would refuse to start",
+                        addresses.join(", "),
+                        owner
+                    ),
+                ));
+            }
+        }
+
+        // QUIC needs reuseport to work with several workers, which nginx only
+        // accepts on one listen of an address: the first site with http3 on
+        // it gets it.
+        let mut quic_sockets = HashSet::new();
+        for site in self.sites.iter_mut().filter(|site| site.http3) {
+            site.quic_reuseport = site
+                .https_addresses()
+                .into_iter()
+                .filter(|address| quic_sockets.insert(listen_socket(address)))
+                .collect();
+            let ports: BTreeSet<u16> = site
+                .https_addresses()
+                .iter()
+                .map(|address| listen_socket(address).port())
+                .collect();
+            site.quic_ports = ports.into_iter().collect();
+        }
+        let quic_ports: HashSet<u16> = quic_sockets.iter().map(|socket| socket.port()).collect();
+
+        // The ports of the servers of the sites and of catch_all, which TCP
+        // streams can't listen on too.
+        let mut site_ports: HashSet<u16> = self
+            .sites
+            .iter()
+            .flat_map(|site| site.listen_addresses())
+            .filter_map(|address| {
+                let port = address.rsplit_once(':').map_or(&*address, |(_, port)| port);
+                port.parse().ok()
+            })
+            .collect();
+        if self.catch_all.is_some() {
+            site_ports.insert(443);
+        }
+        let mut stream_ports = HashSet::new();
+        let mut stream_groups = HashMap::new();
+        for stream in self.streams.iter() {
+            let protocol = match stream.protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            };
+            let mut invalid = |message: String| {
+                errors.push(ValidationError::Config {
+                    field: "streams",
+                    message: format!("streams: port {}/{}: {}", stream.listen, protocol, message),
+                })
+            };
+            if stream.listen == 0 {
+                invalid(String::from("listen must be a port between 1 and 65535"));
+            }
+            if !stream_ports.insert((stream.listen, protocol)) {
+                invalid(String::from("another stream listens on it"));
+            }
+            if stream.protocol == Protocol::Tcp && site_ports.contains(&stream.listen) {
+                invalid(String::from(
+                    "the servers of the sites listen on it, nginx would refuse to start",
+                ));
+            }
+            if stream.protocol == Protocol::Udp && quic_ports.contains(&stream.listen) {
+                invalid(String::from(
+                    "the servers of the sites listen on it for http3, nginx would refuse to start",
+                ));
+            }
+            let upstream = &stream.upstream;
+            match &upstream.servers {
+                Some(servers) => {
+                    let group = &upstream.url;
+                    if group.is_empty()
+                        || !group
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                    {
+                        invalid(format!(
+                            "with servers, upstream {:?} must name their group, e.g. \"db\", without a port",
+                            group
+                        ));
+                    } else if let Some(other) = stream_groups.insert(group, stream.listen) {
+                        invalid(format!(
+                            "the servers of upstream {:?} are already those of the stream on port {}",
+                            group, other
+                        ));
+                    }
+                    if servers.is_empty() {
+                        invalid(String::from("servers can't be empty"));
+                    }
+                    for server in servers.iter() {
+                        if let Err(reason) = check_address(&server.address) {
+                            invalid(format!("server {:?}: {}", server.address, reason));
+                        }
+                        if let Some(fail_timeout) = &server.fail_timeout {
+                            if !duration_re.is_match(fail_timeout) {
+                                invalid(format!(
+                                    "server {:?}: fail_timeout {:?} must be a duration, e.g. \"30s\"",
+                                    server.address, fail_timeout
+                                ));
+                            }
+                        }
+                    }
+                }
+                None => {
+                    if let Err(reason) = check_address(&upstream.url) {
+                        invalid(format!("invalid upstream {:?}: {}", upstream.url, reason));
+                    }
+                }
+            }
+            if upstream.backend_type != BackendType::Proxy {
+                invalid(String::from(
+                    "backend_type doesn't apply to streams, which proxy the connections as they come",
+                ));
+            }
+            if upstream.dynamic {
+                invalid(String::from(
+                    "dynamic doesn't apply to streams, which resolve their upstream once at startup",
+                ));
+            }
+            if upstream.health_check.is_some() {
+                invalid(String::from(
+                    "health_check doesn't apply to streams, which nvhosts only checks over HTTP",
+                ));
+            }
+            if let Some(timeout) = stream
+                .proxy_timeout
+                .as_ref()
+                .filter(|timeout| !duration_re.is_match(timeout))
+            {
+                invalid(format!(
+                    "proxy_timeout {:?} must be a duration, e.g. \"10m\"",
+                    timeout
+                ));
+            }
+        }
+
+        if let Some(modules) = &options.modules {
+            warnings.extend(modules::missing(&self, modules));
+        }
+
+        let mut file_mode = None;
+        if let Some(mode) = &self.file_mode {
+            match parse_file_mode(mode) {
+                Ok(_) if !cfg!(unix) => warnings.push(Warning::config(
+                    WarningKind::Unsupported,
+                    "file_mode is ignored on this platform",
+                )),
+                Ok(mode) => file_mode = Some(mode),
+                Err(reason) => errors.push(ValidationError::Config {
+                    field: "file_mode",
+                    message: format!("invalid file_mode {:?}: {}", mode, reason),
+                }),
+            }
+        }
+
+        if self.dhparam.is_some()
+            && !self
+                .sites
+                .iter()
+                .any(|site| site.tls.as_ref().is_some_and(|tls| tls.dhparam.is_some()))
+        {
+            warnings.push(Warning::config(
+                WarningKind::NoEffect,
+                "dhparam is only used by the intermediate and old TLS profiles",
+            ));
+        }
+
+        if let Some(catch_all) = &self.catch_all {
+            if catch_all.ssl_cert.trim().is_empty() || catch_all.ssl_key.trim().is_empty() {
+                errors.push(ValidationError::Config {
+                    field: "catch_all",
+                    message: String::from("catch_all: ssl_cert and ssl_key are required"),
+                });
+            }
+            if domains.contains("_") {
+                errors.push(ValidationError::Config {
+                    field: "catch_all",
+                    message: String::from(
+                        "catch_all and the site \"_\" conflict: both catch every host, remove one of them",
+                    ),
+                });
+            }
+        }
+
+        if let Some(main) = &self.main {
+            if main.worker_connections == Some(0) {
+                errors.push(ValidationError::Config {
+                    field: "main",
+                    message: String::from("main: worker_connections must be positive"),
+                });
+            }
+            if main.tcp_nopush == Some(true) && main.sendfile != Some(true) {
+                warnings.push(Warning::config(
+                    WarningKind::NoEffect,
+                    "main: tcp_nopush has no effect without sendfile = true",
+                ));
+            }
+        }
+
+        for hook in self.hooks.iter() {
+            if hook.command.trim().is_empty() {
+                errors.push(ValidationError::Config {
+                    field: "hooks",
+                    message: String::from("hooks must have a command"),
+                });
+            }
+        }
+
+        if let Some(timeout) = options.skip_unreachable.filter(|_| errors.is_empty()) {
+            let unreachable = reachable::unreachable(&self.sites, options, timeout);
+            let mut unreachable = unreachable.into_iter();
+            self.sites
+                .retain(|site| match unreachable.next().flatten() {
+                    Some(reason) => {
+                        warnings.push(Warning::site(
+                            WarningKind::Unreachable,
+                            &site.domain,
+                            format!("skipped, {}", reason),
+                        ));
+                        false
+                    }
+                    None => true,
+                });
+        }
+
+        if options.strict && options.no_warn && !warnings.is_empty() {
+            errors.push(ValidationError::Counted {
+                errors: 0,
+                warnings: warnings.len(),
+            });
+            warnings.clear();
+        } else if options.strict {
+            errors.extend(warnings.drain(..).map(ValidationError::Warning));
+        }
+
+        // Without any priority, the sites keep the order of the config.
+        if self.sites.iter().any(|site| site.priority != 0) {
+            self.sites
+                .sort_by(|a, b| (a.priority, &a.domain).cmp(&(b.priority, &b.domain)));
+        }
+
+        // When every error is about a single site, and not about the path of
+        // its file, the sites at fault are left to placeholders.
+        let inline_site = |error: &ValidationError| {
+            let domain = match error {
+                ValidationError::Site { domain, field, .. }
+                    if !["filename", "extension"].contains(field) =>
+                {
+                    domain
+                }
+                ValidationError::Warning(Warning {
+                    domain: Some(domain),
+                    ..
+                }) => domain,
+                _ => return None,
+            };
+            match self
+                .sites
+                .iter()
+                .filter(|site| &site.domain == domain)
+                .count()
+            {
+                1 => Some(domain.clone()),
+                _ => None,
+            }
+        };
+        if options.render_errors_inline
+            && !options.no_validate
+            && errors.iter().all(|error| inline_site(error).is_some())
+        {
+            let mut inline: HashMap<String, Vec<ValidationError>> = HashMap::new();
+            for error in errors.drain(..) {
+                if let Some(domain) = inline_site(&error) {
+                    inline.entry(domain).or_default().push(error);
+                }
+            }
+            for site in self.sites.iter_mut() {
+                site.inline_errors = inline.remove(&site.domain);
+            }
+        }
+
+        // Sites written to the same file would overwrite each other, so the
+        // errors on where a site is written stop the run even under
+        // `Options::no_validate`.
+        let misplaced = |error: &ValidationError| match error {
+            ValidationError::DuplicateDomain { .. } => true,
+            ValidationError::Site { field, .. } => ["filename", "extension"].contains(field),
+            _ => false,
+        };
+        if !errors.is_empty() && (!options.no_validate || errors.iter().any(misplaced)) {
+            if options.no_validate {
+                errors.retain(misplaced);
+            }
+            if options.quiet_errors {
+                errors = vec![ValidationError::counted(&errors)];
+            } else if let Some(max) = options.max_errors.filter(|max| errors.len() > *max) {
+                let omitted = errors.len() - max;
+                errors.truncate(max);
+                errors.push(ValidationError::Omitted(omitted));
+            }
+            return Err(NvhostsError::Invalid(errors));
+        }
+        for error in errors.iter() {
+            warn!("{} (ignored by --no-validate)", error);
+        }
+
+        let config = Config {
+            nginx_version: self.nginx_version,
+            maps: self.maps,
+            geos: self.geos,
+            proxy_caches: self.proxy_caches,
+            log_formats: self.log_formats,
+            resolver: self.resolver,
+            real_ip: self.real_ip,
+            enabled_dir: self.enabled_dir,
+            file_mode,
+            hooks: self.hooks,
+            openresty: self.openresty,
+            main: self.main,
+            catch_all: self.catch_all,
+            streams: self.streams,
+            sites: self.sites,
+            vars: options.vars.clone(),
+        };
+        Ok((config, warnings))
+    }
+}
+
+/// Whether `pattern` uses PCRE syntax that the regex crate doesn't know:
+/// lookarounds, atomic groups, possessive quantifiers, backreferences,
+/// recursion or conditionals.
+fn pcre_only(pattern: &str) -> bool {
+    let re = Regex::new(r"\(\?(<?[=!]|>|P=|R\)|[0-9]+\)|\()|\\([1-9]|[gkKG])|[*+?}]\+")
+        .expect("valid regex");
+    re.is_match(pattern)
+}
+
+/// The names of the named groups of a PCRE `pattern`, `(?<name>...)`,
+/// `(?'name'...)` or `(?P<name>...)`.
+fn capture_names(pattern: &str) -> Vec<String> {
+    let re = Regex::new(r"\(\?P?(?:<([A-Za-z_][A-Za-z0-9_]*)>|'([A-Za-z_][A-Za-z0-9_]*)')")
+        .expect("valid regex");
+    re.captures_iter(pattern)
+        .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+        .map(|name| name.as_str().to_string())
+        .collect()
+}
+
+/// The `listen` entries of a site for `port`, or the wildcard addresses the
+/// servers listen on without them.
+fn or_wildcards(addresses: Vec<String>, port: u16) -> Vec<String> {
+    match addresses.is_empty() {
+        true => vec![port.to_string(), format!("[::]:{}", port)],
+        false => addresses,
+    }
+}
+
+/// The address and port of the `listen_addresses` of a site, nginx listening
+/// on all the IPv4 addresses alike for `443`, `*:443` and `0.0.0.0:443`.
+fn listen_socket(address: &str) -> std::net::SocketAddr {
+    let address = address.strip_prefix("*:").unwrap_or(address);
+    match address.parse::<u16>() {
+        Ok(port) => std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port)),
+        Err(_) => address
+            .parse()
+            .expect("the listen addresses are validated socket addresses"),
+    }
+}
+
+/// The fields of the fragment `name`, with the fragments it includes merged
+/// first. `chain` holds the fragments being resolved, to report cycles.
+fn fragment(
+    fragments: &HashMap<String, toml::value::Table>,
+    name: &str,
+    chain: &mut Vec<String>,
+    strategy: MergeStrategy,
+) -> Result<toml::value::Table, String> {
+    chain.push(name.to_string());
+    if chain[..chain.len() - 1].iter().any(|n| n == name) {
+        return Err(format!(
+            "fragments include each other: {}",
+            chain.join(" -> ")
+        ));
+    }
+    let mut own = fragments
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("fragment {:?} is not defined", name))?;
+    if let Some(key) = ["domain", "profiles", "overrides", "merge_strategy"]
+        .iter()
+        .find(|key| own.contains_key(**key))
+    {
+        return Err(format!("fragment {:?} can't set {}", name, key));
+    }
+
+    let mut merged = toml::value::Table::new();
+    if let Some(include) = own.remove("include") {
+        let include: Vec<String> = include
+            .try_into()
+            .map_err(|_| format!("fragment {:?}: include must be a list of names", name))?;
+        for name in include.iter() {
+            merge(
+                &mut merged,
+                fragment(fragments, name, chain, strategy)?,
+                strategy,
+            );
+        }
+    }
+    merge(&mut merged, own, strategy);
+    chain.pop();
+    Ok(merged)
+}
+
+/// The `sites` parsed from `value`, holding the table each one was parsed
+/// from.
+fn written(mut sites: Vec<Site>, value: &toml::Value) -> Vec<Site> {
+    let tables = value.get("sites").and_then(toml::Value::as_array);
+    for (site, table) in sites.iter_mut().zip(tables.into_iter().flatten()) {
+        site.written = table.as_table().cloned();
+    }
+    sites
+}
+
+/// The fields of `table` that `written` sets, nested tables key by key.
+fn set_fields(table: toml::value::Table, written: &toml::value::Table) -> toml::value::Table {
+    table
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = match (value, written.get(&key)?) {
+                (toml::Value::Table(table), toml::Value::Table(written)) => {
+                    toml::Value::Table(set_fields(table, written))
+                }
+                (value, _) => value,
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+/// Merges `table` into `into`: nested tables are merged key by key, the
+/// `headers` and `redirects` lists are combined by `strategy`, and other
+/// values of `table`, other lists included, replace the ones of `into`.
+fn merge(into: &mut toml::value::Table, table: toml::value::Table, strategy: MergeStrategy) {
+    for (key, value) in table {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table)) => {
+                merge(existing, table, MergeStrategy::Replace)
+            }
+            (Some(toml::Value::Array(existing)), toml::Value::Array(entries))
+                if strategy != MergeStrategy::Replace
+                    && ["headers", "redirects"].contains(&key.as_str()) =>
+            {
+                combine(&key, existing, entries, strategy)
+            }
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Combines the `entries` of the list `key` with the `existing` ones, before
+/// or after them by `strategy`. An existing entry equal to one of `entries`,
+/// or a header for the same location, `/` and `/*` being the same, or a
+/// redirect from the same path, is dropped: headers are merged into the new
+/// one, their values key by key whatever the case of their names, and
+/// redirects replaced by it.
+fn combine(
+    key: &str,
+    existing: &mut Vec<toml::Value>,
+    entries: Vec<toml::Value>,
+    strategy: MergeStrategy,
+) {
+    fn identity<'a>(key: &str, entry: &'a toml::Value) -> Option<&'a str> {
+        match key {
+            "headers" => entry
+                .get("for")
+                .and_then(|pattern| pattern.as_str())
+                .map(|pattern| match is_server_location(pattern) {
+                    true => "/",
+                    false => pattern,
+                }),
+            "redirects" => entry.get("from").and_then(|path| path.as_str()),
+            _ => None,
+        }
+    }
+    let same = |a: &toml::Value, b: &toml::Value| {
+        a == b || identity(key, a).is_some_and(|id| identity(key, b) == Some(id))
+    };
+
+    let mut added = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let entry = match existing.iter().position(|old| same(old, &entry)) {
+            Some(index) => match (existing.remove(index), entry) {
+                (toml::Value::Table(mut old), toml::Value::Table(new)) if key == "headers" => {
+                    // The new value of a header replaces the old one whatever
+                    // the case of their names.
+                    if let (Some(toml::Value::Table(values)), Some(toml::Value::Table(new))) =
+                        (old.get_mut("values"), new.get("values"))
+                    {
+                        let replaced: Vec<String> = values
+                            .keys()
+                            .filter(|name| new.keys().any(|key| key.eq_ignore_ascii_case(name)))
+                            .cloned()
+                            .collect();
+                        for name in replaced {
+                            values.remove(&name);
+                        }
+                    }
+                    merge(&mut old, new, strategy);
+                    toml::Value::Table(old)
+                }
+                (_, entry) => entry,
+            },
+            None => entry,
+        };
+        added.push(entry);
+    }
+    match strategy {
+        MergeStrategy::Prepend => {
+            added.append(existing);
+            *existing = added;
+        }
+        _ => existing.append(&mut added),
+    }
+}
+
+/// Explains the options of `site` that contradict each other, and which one
+/// to keep.
+fn conflicts(site: &Site) -> Vec<ValidationError> {
+    let mut conflicts = Vec::new();
+
+    if site.redirect_to.is_some() {
+        let serving = [
+            (
+                "cache_control",
+                site.cache_control.is_some() || site.cache_control_default.is_some(),
+            ),
+            ("headers", site.headers.is_some()),
+            ("immutable_assets", site.immutable_assets.is_some()),
+            ("redirects", site.redirects.is_some()),
+            ("map_redirects", site.map_redirects.is_some()),
+            ("geo_deny", site.geo_deny.is_some()),
+            ("rate_limit", site.rate_limit.is_some()),
+            ("block_user_agents", site.block_user_agents.is_some()),
+            ("cors", site.cors.is_some()),
+            ("error_pages", site.error_pages.is_some()),
+            ("proxy_intercept_errors", site.proxy_intercept_errors),
+            ("rewrites", site.rewrites.is_some()),
+            ("allow_acl", site.allow_acl.is_some()),
+            ("early_hints", site.early_hints.is_some()),
+            ("charset", site.charset.is_some()),
+            ("proxy_buffering", site.proxy_buffering.is_some()),
+            ("proxy_buffer_size", site.proxy_buffer_size.is_some()),
+            ("proxy_buffers", site.proxy_buffers.is_some()),
+            ("proxy_ssl", site.proxy_ssl.is_some()),
+            ("proxy_cache", site.proxy_cache.is_some()),
+            ("header_buffers", site.header_buffers.is_some()),
+            ("upstream", site.upstream.is_some()),
+            ("aliases", site.aliases.is_some()),
+            (
+                "method_allow",
+                site.method_allow.is_some() || site.method_deny.is_some(),
+            ),
+            ("root", site.root.is_some()),
+            ("static_optimize", site.static_optimize),
+            ("gzip_static", site.gzip_static),
+            ("brotli_static", site.brotli_static),
+            ("etag", site.etag.is_some()),
+            ("if_modified_since", site.if_modified_since.is_some()),
+            ("healthcheck", site.healthcheck.is_some()),
+            (
+                "https_redirect_exclude",
+                site.https_redirect_exclude.is_some(),
+            ),
+            ("extra", site.extra.is_some()),
+            ("extra_location", site.extra_location.is_some()),
+            ("mtls", site.mtls.is_some()),
+            ("maintenance", site.maintenance),
+            ("lua", site.lua.is_some()),
+        ];
+        for (field, _) in serving.iter().filter(|(_, set)| *set) {
+            conflicts.push(ValidationError::site(&site.domain, field, format!(
+                "redirect_to and {} conflict: a site with redirect_to only redirects, remove {} or serve the site by removing redirect_to",
+                field, field
+            )));
+        }
+        if site.force_https {
+            conflicts.push(ValidationError::site(&site.domain, "force_https", String::from(
+                "redirect_to and force_https conflict: requests are already redirected to redirect_to, remove force_https",
+            )));
+        }
+    }
+
+    if site.root.is_some() {
+        let proxied = [
+            ("upstream", site.upstream.is_some()),
+            ("proxy_cache", site.proxy_cache.is_some()),
+            ("proxy_buffering", site.proxy_buffering.is_some()),
+            ("proxy_buffer_size", site.proxy_buffer_size.is_some()),
+            ("proxy_buffers", site.proxy_buffers.is_some()),
+            ("proxy_ssl", site.proxy_ssl.is_some()),
+        ];
+        for (field, _) in proxied.iter().filter(|(_, set)| *set) {
+            conflicts.push(ValidationError::site(&site.domain, field, format!(
+                "root and {} conflict: a site with root serves files rather than an upstream, remove {} or root",
+                field, field
+            )));
+        }
+    } else if site.redirect_to.is_none() {
+        let serving_files = [
+            ("static_optimize", site.static_optimize),
+            ("gzip_static", site.gzip_static),
+            ("brotli_static", site.brotli_static),
+            ("etag", site.etag.is_some()),
+            ("if_modified_since", site.if_modified_since.is_some()),
+        ];
+        for (field, _) in serving_files.iter().filter(|(_, set)| *set) {
+            conflicts.push(ValidationError::site(
+                &site.domain,
+                field,
+                format!(
+                    "{} only applies to sites serving files, set root or remove {}",
+                    field, field
+                ),
+            ));
+        }
+    }
+
+    if let Some(upstream) = &site.upstream {
+        let backend_type = format!("{:?}", upstream.backend_type).to_lowercase();
+        if upstream.backend_type != BackendType::Proxy && site.proxy_cache.is_some() {
+            conflicts.push(ValidationError::site(&site.domain, "proxy_cache", format!(
+                "proxy_cache and backend_type \"{}\" conflict: proxy_cache only caches proxied upstreams, remove proxy_cache or use backend_type \"proxy\"",
+                backend_type
+            )));
+        }
+        let buffering = [
+            ("proxy_buffering", site.proxy_buffering.is_some()),
+            ("proxy_buffer_size", site.proxy_buffer_size.is_some()),
+            ("proxy_buffers", site.proxy_buffers.is_some()),
+            ("proxy_ssl", site.proxy_ssl.is_some()),
+        ];
+        if upstream.backend_type != BackendType::Proxy {
+            for (field, _) in buffering.iter().filter(|(_, set)| *set) {
+                conflicts.push(ValidationError::site(&site.domain, field, format!(
+                    "{} and backend_type \"{}\" conflict: {} only applies to proxied upstreams, remove it or use backend_type \"proxy\"",
+                    field, backend_type, field
+                )));
+            }
+        }
+    }
+
+    conflicts
+}
+
+/// Whether headers for `pattern` apply to the whole site rather than to a
+/// location of it.
+pub(crate) fn is_server_location(pattern: &str) -> bool {
+    pattern == "/" || pattern == "/*"
+}
+
+/// Checks that `pattern` can follow `location`: a path starting with `/`, a
+/// named location starting with `@`, or a pattern after one of the modifiers
+/// `=`, `~`, `~*` and `^~`, which may be written apart.
+fn validate_location(pattern: &str) -> Result<(), String> {
+    const MODIFIERS: &[&str] = &["=", "~", "~*", "^~"];
+
+    let words: Vec<&str> = pattern.split_whitespace().collect();
+    let path = match words.as_slice() {
+        [] => return Err(String::from("the location is empty")),
+        [modifier, path] if MODIFIERS.contains(modifier) => path,
+        [path] if path.starts_with(['/', '=', '~', '@']) || path.starts_with("^~") => path,
+        [_] => {
+            return Err(String::from(
+                "a location starts with /, @, or a modifier among =, ~, ~* and ^~",
+            ))
+        }
+        _ => {
+            return Err(String::from(
+                "a location can't contain whitespace, except after its modifier",
+            ))
+        }
+    };
+    if path.contains(['{', '}', ';']) {
+        return Err(String::from("a location can't contain '{', '}' nor ';'"));
+    }
+    Ok(())
+}
+
+/// Whether `value` references the nginx variable `$name`, as `$name` or
+/// `${name}`.
+fn references(value: &str, name: &str) -> bool {
+    value.contains(&format!("${{{}}}", name))
+        || value
+            .match_indices(&format!("${}", name))
+            .any(|(i, variable)| {
+                !value[i + variable.len()..]
+                    .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            })
+}
+
+/// Whether `name` is a `server_name` that isn't a domain but is still
+/// useful: nginx's catch-all `_`, `localhost` and IPv4 addresses, for
+/// development and default vhosts.
+fn is_special_server_name(name: &str) -> bool {
+    name == "_" || name == "localhost" || name.parse::<std::net::Ipv4Addr>().is_ok()
+}
+
+/// Whether `address` is an IP address or a CIDR range, as accepted by `geo`.
+fn is_address_range(address: &str) -> bool {
+    address.parse::<IpNet>().is_ok() || address.parse::<std::net::IpAddr>().is_ok()
+}
+
+/// Checks that `target` is either a path or an absolute http(s) URL. Targets
+/// starting with an nginx variable can't be checked and are accepted as is,
+/// as long as they hold no whitespace nor `;`, which would split the
+/// directive.
+fn check_url(target: &str) -> Result<(), String> {
+    if target.trim().is_empty() {
+        return Err(String::from("it is empty"));
+    }
+    if target.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return Err(String::from(
+            "whitespace and control characters aren't allowed, encode them, e.g. %20",
+        ));
+    }
+    if target.contains(';') {
+        return Err(String::from("';' isn't allowed, encode it as %3B"));
+    }
+    if target.starts_with('/') || target.starts_with('$') {
+        return Ok(());
+    }
+
+    let url = Url::parse(target).map_err(|err| err.to_string())?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("unsupported scheme {:?}", scheme)),
+    }
+}
+
+/// The part of `url` after `http://unix:`, when it targets a Unix socket.
+fn unix_socket(url: &str) -> Option<&str> {
+    url.strip_prefix("http://unix:")
+        .or_else(|| url.strip_prefix("https://unix:"))
+}
+
+/// Checks that `url` is an absolute http(s) URL that `proxy_pass` accepts,
+/// or a Unix socket, e.g. `http://unix:/run/app.sock` or
+/// `http://unix:/run/app.sock:/api/`, which the URL parser rejects. The URL
+/// is written as is in the directive, so it can't hold whitespace, `;` nor
+/// `"`.
+fn check_upstream(url: &str) -> Result<(), String> {
+    if url.contains(|c: char| c.is_whitespace() || c == ';' || c == '"') {
+        return Err(String::from("whitespace, ';' and '\"' aren't allowed"));
+    }
+    if let Some(socket) = unix_socket(url) {
+        let (path, uri) = match socket.split_once(':') {
+            Some((path, uri)) => (path, Some(uri)),
+            None => (socket, None),
+        };
+        if path.len() < 2 || !path.starts_with('/') {
+            return Err(String::from("the socket path must be absolute"));
+        }
+        if uri.is_some_and(|uri| !uri.starts_with('/')) {
+            return Err(String::from(
+                "the URI after the socket path must start with /",
+            ));
+        }
+        return Ok(());
+    }
+
+    let url = Url::parse(url).map_err(|err| err.to_string())?;
+    match url.scheme() {
+        "http" | "https" if url.has_host() => Ok(()),
+        "http" | "https" => Err(String::from("missing host")),
+        scheme => Err(format!("unsupported scheme {:?}", scheme)),
+    }
+}
+
+/// A number of percents with at most two decimals, which nginx rejects
+/// beyond, e.g. `"10%"` or `"0.25%"`.
+fn percentage(value: &str) -> Option<f64> {
+    let number = value.strip_suffix('%')?;
+    let (integer, decimals) = number.split_once('.').unwrap_or((number, ""));
+    let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if integer.is_empty() || !digits(integer) || !digits(decimals) || decimals.len() > 2 {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// Checks an upstream of a `split`, whose URI is the request's, and returns
+/// its hostname, if it isn't an IP address or a socket.
+fn check_split_url(url: &str) -> Result<Option<String>, String> {
+    check_upstream(url)?;
+    if unix_socket(url).is_some() {
+        if has_uri(url) {
+            return Err(String::from("a split upstream can't have a URI"));
+        }
+        return Ok(None);
+    }
+
+    let parsed = Url::parse(url).map_err(|err| err.to_string())?;
+    if has_uri(url) {
+        return Err(String::from("a split upstream can't have a path"));
+    }
+    Ok(match parsed.host() {
+        Some(url::Host::Domain(host)) => Some(host.to_string()),
+        _ => None,
+    })
+}
+
+/// Whether the proxied `url` has a URI, even `/`, after its host or socket.
+/// A `proxy_pass` with a variable sends it as is, in place of the URI of the
+/// request, so only the root without a trailing slash passes the request on.
+fn has_uri(url: &str) -> bool {
+    match unix_socket(url) {
+        Some(socket) => socket.contains(':'),
+        None => {
+            let authority_end = url.find("://").map_or(0, |scheme| scheme + 3);
+            url[authority_end..].contains(['/', '?', '#'])
+        }
+    }
+}
+
+/// Renders the `filename` template of the config for `site`, and checks that
+/// the result is a file with the extension of the site within the output
+/// directory.
+fn site_filename(tera: &mut Tera, template: &str, site: &Site) -> Result<String, String> {
+    let mut context = Context::new();
+    context.insert("site", site);
+    let file = tera
+        .render_str(template, &context)
+        .map_err(|err| format!("couldn't render filename: {}", error::chain(&err)))?;
+
+    let path = Path::new(&file);
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!(
+            "filename {:?} must be a relative path without ..",
+            file
+        ));
+    }
+    let extension = site.extension();
+    if !extension.is_empty() && path.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+        return Err(format!("filename {:?} must end with .{}", file, extension));
+    }
+    if [
+        HTTP_FILENAME,
+        MAIN_FILENAME,
+        CATCH_ALL_FILENAME,
+        STREAM_FILENAME,
+    ]
+    .contains(&file.as_str())
+    {
+        return Err(format!("filename {:?} is reserved by nvhosts", file));
+    }
+    Ok(file)
+}
+
+/// Checks that `extension` is empty, or letters, digits, `-` and `_`, without
+/// the leading dot.
+fn check_extension(extension: &str) -> Result<(), String> {
+    if extension.starts_with('.') {
+        return Err(format!(
+            "extension {:?} must not start with a dot, e.g. {:?}",
+            extension,
+            extension.trim_start_matches('.')
+        ));
+    }
+    if !extension
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "extension {:?} must only have letters, digits, - and _",
+            extension
+        ));
+    }
+    Ok(())
+}
+
+/// Parses permissions written in octal, with or without a leading `0o`.
+fn parse_file_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        Ok(_) => Err(String::from("expected at most 4 octal digits")),
+        Err(_) => Err(String::from("expected octal digits, e.g. \"0644\"")),
+    }
+}
+
+/// Checks that `address` is a `host:port` or `unix:/path` address that
+/// `fastcgi_pass`, `uwsgi_pass` and the `proxy_pass` of streams accept.
+fn check_address(address: &str) -> Result<(), String> {
+    if address.contains(|c: char| c.is_whitespace() || c == ';' || c == '"') {
+        return Err(String::from("whitespace, ';' and '\"' aren't allowed"));
+    }
+    if let Some(path) = address.strip_prefix("unix:") {
+        return match path.starts_with('/') {
+            true => Ok(()),
+            false => Err(String::from("socket path must be absolute")),
+        };
+    }
+
+    match address.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty() && !host.contains('/') && port.parse::<u16>().is_ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(String::from("expected host:port or unix:/path")),
+    }
+}
+
+/// Records in `definitions` that the `field` of the site of `domain` defines
+/// `definition` in the http context, failing when another field or site
+/// already does, e.g. for domains that only differ by `.` and `-`, whose
+/// names are alike once these are replaced with `_`.
+fn define(
+    definitions: &mut HashMap<String, String>,
+    errors: &mut Vec<ValidationError>,
+    domain: &str,
+    field: &'static str,
+    definition: String,
+) {
+    match definitions.get(&definition) {
+        Some(owner) => errors.push(ValidationError::site(
+            domain,
+            field,
+            format!(
+                "{} defines {}, already defined by {}",
+                field, definition, owner
+            ),
+        )),
+        None => {
+            definitions.insert(definition, format!("the {} of {:?}", field, domain));
+        }
+    }
+}
+
+/// Lists what the top-level directives of `extra` define in the http context,
+/// such as `variable $name` for a `map` or `zone name` for a
+/// `limit_req_zone`, since nginx refuses to define them twice.
+fn http_definitions(extra: &str) -> Vec<String> {
+    let extra: String = extra
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut definitions = Vec::new();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut depth = 0usize;
+    for c in extra.chars() {
+        if c.is_whitespace() || c == ';' || c == '{' || c == '}' {
+            if depth == 0 && !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            word.clear();
+        } else {
+            word.push(c);
+        }
+
+        match c {
+            ';' | '{' if depth == 0 => {
+                definitions.extend(http_definition(&words));
+                words.clear();
+            }
+            _ => {}
+        }
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    definitions
+}
+
+/// What the directive made of `words` defines, if anything.
+fn http_definition(words: &[String]) -> Option<String> {
+    let (name, params) = words.split_first()?;
+    let zone = |prefix: &str| {
+        params
+            .iter()
+            .find_map(|param| param.strip_prefix(prefix))
+            .map(|zone| format!("zone {}", zone.split(':').next().unwrap_or_default()))
+    };
+
+    match name.as_str() {
+        "map" => params
+            .get(1)
+            .map(|variable| format!("variable {}", variable)),
+        "geo" | "split_clients" => params
+            .last()
+            .map(|variable| format!("variable {}", variable)),
+        "upstream" => params
+            .first()
+            .map(|upstream| format!("upstream {}", upstream)),
+        "limit_req_zone" | "limit_conn_zone" => zone("zone="),
+        "proxy_cache_path" | "fastcgi_cache_path" | "uwsgi_cache_path" => zone("keys_zone="),
+        _ => None,
+    }
+}
+
+/// The domain redirecting to the value: the apex of a `www.` domain, or the
+/// `www.` subdomain of an apex. Other subdomains have none, and get an empty
+/// string, unless `always` is set: `www.` is then always prepended, e.g. for
+/// `example.co.uk`.
+fn redirect_domain(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let s = try_get_value!("redirect_domain", "value", String, value);
+    let always = args
+        .get("always")
+        .and_then(Value::as_bool)
+        .unwrap_or_default();
+
+    Ok(to_value(www_counterpart(&s, always).unwrap_or_default()).unwrap())
+}
+
+/// The apex of a `www.` domain, or the `www.` host of an apex domain, or of
+/// any other domain with `always`.
+fn www_counterpart(domain: &str, always: bool) -> Option<String> {
+    match domain.strip_prefix("www.") {
+        Some(apex) => Some(apex.to_string()),
+        None if always || domain.split('.').count() == 2 => Some(format!("www.{}", domain)),
+        None => None,
+    }
+}
+
+fn pad_right(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let s = try_get_value!("pad_right", "value", String, value);
+
+    let width = match args.get("width") {
+        Some(val) => try_get_value!("pad_right", "plural", usize, val),
+        None => 35,
+    };
+
+    Ok(to_value(format!("{:width$}", s, width = width)).unwrap())
+}
+
+/// Turns every line of the value into an nginx comment, so that the value
+/// can't escape the comment whatever it contains.
+fn comment(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let s = try_get_value!("comment", "value", String, value);
+
+    let lines: Vec<String> = s
+        .replace("\r\n", "\n")
+        .split(['\n', '\r'])
+        .map(|line| match line.trim_end() {
+            "" => String::from("#"),
+            line => format!("# {}", line),
+        })
+        .collect();
+
+    Ok(to_value(lines.join("\n")).unwrap())
+}
+
+/// Marks the value as written by the user, e.g. `extra`, so that the
+/// normalization of the rendered config leaves it as is. A missing value
+/// stays missing.
+fn verbatim(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let s = try_get_value!("verbatim", "value", String, value);
+
+    Ok(to_value(format!(
+        "{}{}{}",
+        format::VERBATIM_START,
+        s,
+        format::VERBATIM_END
+    ))
+    .unwrap())
+}
+
+type Filter = fn(&Value, &HashMap<String, Value>) -> tera::Result<Value>;
+
+/// The filters registered on top of the built-in ones of Tera, by name.
+const FILTERS: [(&str, Filter); 4] = [
+    ("redirect_domain", redirect_domain),
+    ("pad_right", pad_right),
+    ("comment", comment),
+    ("verbatim", verbatim),
+];
+
+/// Tera filters registered by the program embedding the library, for the
+/// templates of `Options::template_dir` to use.
+#[derive(Default, Clone)]
+pub struct Filters(Vec<(String, Arc<dyn tera::Filter>)>);
+
+impl Filters {
+    /// Registers `filter` under `name`, replacing the filter of nvhosts or of
+    /// Tera of the same name, e.g.
+    /// `filters.register("upper_first", |value: &Value, _: &HashMap<String, Value>| ...)`.
+    pub fn register<F: tera::Filter + 'static>(&mut self, name: &str, filter: F) {
+        self.0.push((name.to_string(), Arc::new(filter)));
+    }
+}
+
+impl std::fmt::Debug for Filters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+/// The built-in `vhost.template`, preceded by a comment listing the filters
+/// nvhosts adds to Tera, as a starting point for a custom template.
+pub fn builtin_template() -> String {
+    let filters: Vec<&str> = FILTERS.iter().map(|(name, _)| *name).collect();
+    format!(
+        "{{# {}: built-in template of nvhosts {}, with the filters {} #}}\n{}",
+        TEMPLATE_NAME,
+        env!("CARGO_PKG_VERSION"),
+        filters.join(", "),
+        TEMPLATE
+    )
+}
+
+/// Builds a Tera instance with the built-in templates and the filters they
+/// use.
+fn tera() -> Tera {
+    let mut tera = Tera::default();
+    for (name, filter) in FILTERS {
+        tera.register_filter(name, filter);
+    }
+    tera.add_raw_templates(vec![
+        (format!("base/{}", TEMPLATE_NAME), TEMPLATE),
+        (format!("base/{}", HTTP_TEMPLATE_NAME), HTTP_TEMPLATE),
+        (format!("base/{}", MAIN_TEMPLATE_NAME), MAIN_TEMPLATE),
+        (format!("base/{}", STREAM_TEMPLATE_NAME), STREAM_TEMPLATE),
+        (
+            format!("base/{}", CATCH_ALL_TEMPLATE_NAME),
+            CATCH_ALL_TEMPLATE,
+        ),
+        (format!("base/{}", MACROS_TEMPLATE_NAME), MACROS_TEMPLATE),
+        (String::from(TEMPLATE_NAME), TEMPLATE),
+        (String::from(HTTP_TEMPLATE_NAME), HTTP_TEMPLATE),
+        (String::from(MAIN_TEMPLATE_NAME), MAIN_TEMPLATE),
+        (String::from(STREAM_TEMPLATE_NAME), STREAM_TEMPLATE),
+        (String::from(CATCH_ALL_TEMPLATE_NAME), CATCH_ALL_TEMPLATE),
+        (String::from(MACROS_TEMPLATE_NAME), MACROS_TEMPLATE),
+    ])
+    .expect("built-in templates are valid");
+    tera
+}
+
+/// Same as `tera`, with the filters of `Options::filters`, and the templates
+/// of `Options::template_dir` replacing the built-in ones.
+fn templates(options: &Options) -> Result<Tera, NvhostsError> {
+    let mut tera = tera();
+    for (name, filter) in options.filters.0.iter() {
+        let filter = Arc::clone(filter);
+        tera.register_filter(name, move |value: &Value, args: &HashMap<String, Value>| {
+            filter.filter(value, args)
+        });
+    }
+    let dir = match &options.template_dir {
+        Some(dir) => dir,
+        None => return Ok(tera),
+    };
+
+    let mut templates = Vec::new();
+    for entry in fs::read_dir(dir).map_err(NvhostsError::io(dir))? {
+        let path = entry.map_err(NvhostsError::io(dir))?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("template") {
+            continue;
+        }
+        let content = fs::read_to_string(&path).map_err(NvhostsError::io(&path))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        templates.push((name.into_owned(), content));
+    }
+
+    tera.add_raw_templates(templates)
+        .map_err(|source| NvhostsError::TemplateDir {
+            dir: dir.clone(),
+            source,
+        })?;
+    Ok(tera)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    nginx_version: Version,
+    maps: Vec<Map>,
+    geos: Vec<Geo>,
+    proxy_caches: Vec<ProxyCache>,
+    log_formats: Vec<LogFormat>,
+    resolver: Option<Resolver>,
+    real_ip: Option<RealIp>,
+    enabled_dir: Option<PathBuf>,
+    file_mode: Option<u32>,
+    hooks: Vec<Hook>,
+    openresty: bool,
+    main: Option<Main>,
+    catch_all: Option<CatchAll>,
+    streams: Vec<StreamProxy>,
+    sites: Vec<Site>,
+    vars: Vars,
+}
+
+impl Config {
+    /// Writes the generated files, runs the hooks, and tells whether any of
+    /// the files changed.
+    fn generate(self, options: &Options) -> Result<bool, NvhostsError> {
+        let dir = options.output_dir();
+        fs::create_dir_all(dir).map_err(NvhostsError::io(dir))?;
+
+        let progress = if options.progress {
+            ProgressBar::new(self.sites.len() as u64).with_style(
+                ProgressStyle::with_template("{bar:40} {pos}/{len} sites")
+                    .expect("progress template is valid"),
+            )
+        } else {
+            ProgressBar::hidden()
+        };
+        let rendered = self.render_layout(options, &progress);
+        progress.finish_and_clear();
+        let (rendered, failed) = rendered?;
+
+        let mut changed = Vec::new();
+        for (file, content) in rendered.iter() {
+            let path = dir.join(file);
+            if write_file(
+                &path,
+                content.as_bytes(),
+                options.backup,
+                self.file_mode,
+                options.write_retries,
+            )? {
+                changed.push(path);
+            }
+        }
+        // The main and stream contexts can't be included by the http one, so
+        // they are never enabled.
+        let written: Vec<PathBuf> = rendered
+            .iter()
+            .map(|(file, _)| file)
+            .filter(|file| *file != MAIN_FILENAME && *file != STREAM_FILENAME)
+            .map(|file| dir.join(file))
+            .collect();
+
+        let enabled_dir = options.enabled_dir.as_ref().or(self.enabled_dir.as_ref());
+        let mut files: Vec<String> = written
+            .iter()
+            .map(|path| path.strip_prefix(dir).unwrap_or(path).display().to_string())
+            .collect();
+        if self.main.is_some() {
+            files.push(String::from(MAIN_FILENAME));
+        }
+        if !self.streams.is_empty() {
+            files.push(String::from(STREAM_FILENAME));
+        }
+        if options.clean {
+            let previous = manifest::read(dir)?;
+            changed.extend(manifest::clean(
+                dir,
+                &previous,
+                &files,
+                enabled_dir.map(PathBuf::as_path),
+            )?);
+        }
+        manifest::record(dir, &files)?;
+        if !failed.is_empty() {
+            return Err(NvhostsError::Placeholders(failed));
+        }
+
+        if options.verify_clean {
+            let extensions: BTreeSet<&str> = self.sites.iter().map(Site::extension).collect();
+            let paths = unmanaged_files(dir, &extensions)?;
+            if !paths.is_empty() {
+                return Err(NvhostsError::Unmanaged {
+                    dir: dir.to_path_buf(),
+                    paths,
+                });
+            }
+        }
+
+        if let Some(enabled_dir) = enabled_dir {
+            enable(&written, dir, enabled_dir)?;
+        }
+
+        hook::run_hooks(
+            &self.hooks,
+            &changed,
+            options.timeout.unwrap_or(COMMAND_TIMEOUT),
+        )?;
+
+        Ok(!changed.is_empty())
+    }
+
+    /// Renders every site, with its domain, to `sink`, e.g. to write it
+    /// elsewhere than in the output directory. The sites are rendered on a
+    /// pool of `Options::jobs` threads when there are many of them, but
+    /// `sink` is always called on the calling thread, in the order of the
+    /// config; its first error stops the rendering. Like for `render_map`,
+    /// the rendered sites don't start with the banner.
+    pub fn for_each_rendered<F>(&self, options: &Options, mut sink: F) -> Result<(), NvhostsError>
+    where
+        F: FnMut(&str, &str) -> Result<(), NvhostsError>,
+    {
+        let tera = templates(options)?;
+        self.render_sites(tera, options, &ProgressBar::hidden(), |site, content| {
+            sink(&site.domain, content)
+        })
+    }
+
+    /// Renders the sites for `for_each_rendered` and `render_layout`, sequentially
+    /// with `tera` when there are few of them or a single job, else on a pool
+    /// of threads that each render a contiguous run of sites with their own
+    /// copy of the templates.
+    fn render_sites<F>(
+        &self,
+        mut tera: Tera,
+        options: &Options,
+        progress: &ProgressBar,
+        mut sink: F,
+    ) -> Result<(), NvhostsError>
+    where
+        F: FnMut(&Site, &str) -> Result<(), NvhostsError>,
+    {
+        let syntax = Syntax::for_version(&self.nginx_version);
+        let jobs = options
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+        let mut failed = Vec::new();
+        if jobs <= 1 || self.sites.len() < PARALLEL_SITES {
+            for site in self.sites.iter() {
+                let rendered = site.render(&mut tera, syntax, &self.vars);
+                let content = or_placeholder(site, rendered, options, &mut failed)?;
+                progress.suspend(|| sink(site, &content))?;
+                progress.inc(1);
+            }
+        } else {
+            self.render_chunks(tera, jobs, progress, |site, rendered| {
+                let content = or_placeholder(site, rendered, options, &mut failed)?;
+                progress.suspend(|| sink(site, &content))
+            })?;
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(NvhostsError::Placeholders(failed)),
+        }
+    }
+
+    /// Renders the sites on `jobs` threads for `render_sites`, passing them
+    /// to `sink` on the calling thread in order, with their outcome.
+    fn render_chunks<F>(
+        &self,
+        tera: Tera,
+        jobs: usize,
+        progress: &ProgressBar,
+        mut sink: F,
+    ) -> Result<(), NvhostsError>
+    where
+        F: FnMut(&Site, Result<String, NvhostsError>) -> Result<(), NvhostsError>,
+    {
+        let syntax = Syntax::for_version(&self.nginx_version);
+
+        let chunk_size = self.sites.len().div_ceil(jobs);
+        let chunks = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .sites
+                .chunks(chunk_size)
+                .map(|sites| {
+                    let mut tera = tera.clone();
+                    scope.spawn(move || {
+                        sites
+                            .iter()
+                            .map(|site| {
+                                let content = site.render(&mut tera, syntax, &self.vars);
+                                progress.inc(1);
+                                content
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for (site, rendered) in self.sites.iter().zip(chunks.into_iter().flatten()) {
+            sink(site, rendered)?;
+        }
+        Ok(())
+    }
+
+    /// Whether any block of the http context is set.
+    fn has_http(&self) -> bool {
+        !self.maps.is_empty()
+            || !self.geos.is_empty()
+            || !self.proxy_caches.is_empty()
+            || !self.log_formats.is_empty()
+            || self.resolver.is_some()
+            || self.real_ip.is_some()
+            || self.main.is_some()
+    }
+
+    /// Renders the files `generate` would write, by path relative to the
+    /// output directory, in the order it writes them, without writing
+    /// anything nor running the hooks.
+    fn render_files(&self, options: &Options) -> Result<Files, NvhostsError> {
+        match self.render_layout(options, &ProgressBar::hidden())? {
+            (files, failed) if failed.is_empty() => Ok(files),
+            (_, failed) => Err(NvhostsError::Placeholders(failed)),
+        }
+    }
+
+    /// The files of `render_files`, along with the errors of the sites
+    /// rendered as placeholders with `render_errors_inline`, for `generate`
+    /// to write the files before failing. `progress` counts the rendered
+    /// sites.
+    fn render_layout(
+        &self,
+        options: &Options,
+        progress: &ProgressBar,
+    ) -> Result<(Files, Vec<NvhostsError>), NvhostsError> {
+        let mut tera = templates(options)?;
+
+        let mut files = Vec::new();
+        if self.main.is_some() {
+            files.push((String::from(MAIN_FILENAME), self.render_main(&mut tera)?));
+        }
+        if !self.streams.is_empty() {
+            files.push((
+                String::from(STREAM_FILENAME),
+                self.render_stream(&mut tera)?,
+            ));
+        }
+        let http = if self.has_http() {
+            Some(self.render_http(&mut tera)?)
+        } else {
+            None
+        };
+        let catch_all = match self.catch_all {
+            Some(_) => Some(self.render_catch_all(&mut tera)?),
+            None => None,
+        };
+        let mut sites = Vec::new();
+        let rendered = self.render_sites(tera, options, progress, |site, content| {
+            sites.push((site.domain.clone(), site.filename(), content.to_string()));
+            Ok(())
+        });
+        let failed = match rendered {
+            Err(NvhostsError::Placeholders(failed)) => failed,
+            rendered => rendered.map(|_| Vec::new())?,
+        };
+
+        match &options.output {
+            Output::PerSite => {
+                if let Some(http) = http {
+                    files.push((String::from(HTTP_FILENAME), http));
+                }
+                if let Some(catch_all) = catch_all {
+                    files.push((String::from(CATCH_ALL_FILENAME), catch_all));
+                }
+                for (_, filename, content) in sites {
+                    files.push((filename, content));
+                }
+            }
+            Output::Combined(filename) => {
+                let mut sections = Vec::new();
+                if let Some(http) = http {
+                    sections.push(section("http", &http));
+                }
+                if let Some(catch_all) = catch_all {
+                    sections.push(section("catch-all", &catch_all));
+                }
+                for (domain, _, content) in sites {
+                    sections.push(section(&domain, &content));
+                }
+                files.push((filename.clone(), sections.join("\n")));
+            }
+        }
+
+        let files = files
+            .into_iter()
+            .map(|(path, content)| (path, with_banner(&content)))
+            .collect();
+        Ok((files, failed))
+    }
+
+    /// Renders every site in turn with a single Tera instance, by domain,
+    /// without writing anything nor spawning threads. Unlike the generated
+    /// files, the rendered sites don't start with the banner.
+    pub fn render_map(&self, options: &Options) -> Result<HashMap<String, String>, NvhostsError> {
+        let mut tera = templates(options)?;
+        let syntax = Syntax::for_version(&self.nginx_version);
+
+        self.sites
+            .iter()
+            .map(|site| {
+                Ok((
+                    site.domain.clone(),
+                    site.render(&mut tera, syntax, &self.vars)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Renders the blocks that must appear only once in the http context.
+    fn render_http(&self, tera: &mut Tera) -> Result<String, NvhostsError> {
+        let mut context = vars_context(&self.vars);
+        context.insert("maps", &self.maps);
+        context.insert("geos", &self.geos);
+        context.insert("proxy_caches", &self.proxy_caches);
+        context.insert("resolver", &self.resolver);
+        context.insert("real_ip", &self.real_ip);
+        context.insert("log_formats", &self.log_formats);
+        context.insert("main", &self.main);
+
+        match tera.render(HTTP_TEMPLATE_NAME, &context) {
+            Ok(x) => Ok(format::normalize(&x)),
+            Err(source) => Err(NvhostsError::Template {
+                domain: None,
+                name: String::from(HTTP_TEMPLATE_NAME),
+                source,
+            }),
+        }
+    }
+
+    /// Renders the default server of `catch_all`.
+    fn render_catch_all(&self, tera: &mut Tera) -> Result<String, NvhostsError> {
+        let mut context = vars_context(&self.vars);
+        context.insert("catch_all", &self.catch_all);
+
+        match tera.render(CATCH_ALL_TEMPLATE_NAME, &context) {
+            Ok(x) => Ok(format::normalize(&x)),
+            Err(source) => Err(NvhostsError::Template {
+                domain: None,
+                name: String::from(CATCH_ALL_TEMPLATE_NAME),
+                source,
+            }),
+        }
+    }
+
+    /// Renders the `events` block of `main`, for the main context.
+    fn render_main(&self, tera: &mut Tera) -> Result<String, NvhostsError> {
+        let mut context = vars_context(&self.vars);
+        context.insert("main", &self.main);
+
+        match tera.render(MAIN_TEMPLATE_NAME, &context) {
+            Ok(x) => Ok(format::normalize(&x)),
+            Err(source) => Err(NvhostsError::Template {
+                domain: None,
+                name: String::from(MAIN_TEMPLATE_NAME),
+                source,
+            }),
+        }
+    }
+
+    /// Renders the `stream` block of `streams`, for the main context.
+    fn render_stream(&self, tera: &mut Tera) -> Result<String, NvhostsError> {
+        let mut context = vars_context(&self.vars);
+        context.insert("streams", &self.streams);
+
+        match tera.render(STREAM_TEMPLATE_NAME, &context) {
+            Ok(x) => Ok(format::normalize(&x)),
+            Err(source) => Err(NvhostsError::Template {
+                domain: None,
+                name: String::from(STREAM_TEMPLATE_NAME),
+                source,
+            }),
+        }
+    }
+}
+
+/// Symlinks every file of `paths`, in the output directory `dir`, into
+/// `enabled_dir`, replacing the links already there.
+fn enable(paths: &[PathBuf], dir: &Path, enabled_dir: &Path) -> Result<(), NvhostsError> {
+    fs::create_dir_all(enabled_dir).map_err(NvhostsError::io(enabled_dir))?;
+    let enabled_dir = fs::canonicalize(enabled_dir).map_err(NvhostsError::io(enabled_dir))?;
+
+    for path in paths {
+        let file = fs::canonicalize(path).map_err(NvhostsError::io(path))?;
+        // Subdirectories of the output directory are mirrored.
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let link = enabled_dir.join(relative);
+        let dir = link.parent().unwrap_or(&enabled_dir);
+        fs::create_dir_all(dir).map_err(NvhostsError::io(dir))?;
+        let target = link_target(&file, dir);
+
+        match fs::symlink_metadata(&link) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                fs::remove_file(&link).map_err(NvhostsError::io(&link))?
+            }
+            Ok(_) => {
+                return Err(NvhostsError::Io {
+                    source: io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("not a symlink, can't enable {}", path.display()),
+                    ),
+                    path: link,
+                })
+            }
+            Err(_) => {}
+        }
+        symlink(&target, &link).map_err(NvhostsError::io(&link))?;
+
+        info!("linked {} -> {}", link.display(), target.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// The target of a link in `dir` to `file`, both absolute: relative to `dir`
+/// when they share more than the root directory, absolute otherwise.
+fn link_target(file: &Path, dir: &Path) -> PathBuf {
+    let file_components: Vec<Component> = file.components().collect();
+    let dir_components: Vec<Component> = dir.components().collect();
+
+    let common = file_components
+        .iter()
+        .zip(dir_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let shares_root_only = file_components
+        .iter()
+        .take(common)
+        .all(|component| matches!(component, Component::RootDir | Component::Prefix(_)));
+    if shares_root_only {
+        return file.to_path_buf();
+    }
+
+    let mut target = PathBuf::new();
+    for _ in common..dir_components.len() {
+        target.push("..");
+    }
+    for component in file_components.iter().skip(common) {
+        target.push(component);
+    }
+    target
+}
+
+/// The files of `dir` and its subdirectories with one of `extensions`, the
+/// ones of the sites, that don't start with the banner. An empty extension
+/// stands for any file but the hidden ones and the backups.
+fn unmanaged_files(dir: &Path, extensions: &BTreeSet<&str>) -> Result<Vec<PathBuf>, NvhostsError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(NvhostsError::io(dir))? {
+        let path = entry.map_err(NvhostsError::io(dir))?.path();
+        if path.is_dir() {
+            paths.extend(unmanaged_files(&path, extensions)?);
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let generated = extensions.iter().any(|&ext| match ext {
+            "" => !name.starts_with('.') && extension != Some("bak"),
+            ext => extension == Some(ext),
+        });
+        if !path.is_file() || !generated {
+            continue;
+        }
+        let content = fs::read(&path).map_err(NvhostsError::io(&path))?;
+        if !content.starts_with(BANNER.as_bytes()) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// `rendered`, or with `Options::render_errors_inline`, when it failed, a
+/// placeholder for `site` commenting out why, the error added to `failed`.
+fn or_placeholder(
+    site: &Site,
+    rendered: Result<String, NvhostsError>,
+    options: &Options,
+    failed: &mut Vec<NvhostsError>,
+) -> Result<String, NvhostsError> {
+    let err = match rendered {
+        Err(err) if options.render_errors_inline => err,
+        rendered => return rendered,
+    };
+    let errors = match &err {
+        NvhostsError::Invalid(errors) => errors.iter().map(ToString::to_string).collect(),
+        err => vec![err.to_string()],
+    };
+    let reasons = comment(&to_value(errors.join("\n")).unwrap(), &HashMap::new())
+        .expect("a string can be commented out");
+    failed.push(err);
+    Ok(format!(
+        "# {} couldn't be generated, nginx serves nothing of it:\n{}\n",
+        site.domain,
+        reasons.as_str().unwrap_or_default()
+    ))
+}
+
+fn with_banner(content: &str) -> String {
+    format!("{}\n\n{}", BANNER, content)
+}
+
+/// A section of a combined output file: `content` below a banner comment.
+fn section(title: &str, content: &str) -> String {
+    let rule = format!("# {}", "-".repeat(70));
+    format!("{}\n# {}\n{}\n{}\n", rule, title, rule, content.trim())
+}
+
+/// Writes a generated file, logging its path, unless it
+/// already holds `content`. With `backup`, an existing file is first copied
+/// next to it with a `.bak` extension. The file is replaced atomically, with
+/// the permissions of `mode`, if any. Missing parent directories are created.
+/// Transient write errors are retried up to `retries` times. Returns whether
+/// the file changed.
+fn write_file(
+    path: &Path,
+    content: &[u8],
+    backup: bool,
+    mode: Option<u32>,
+    retries: u32,
+) -> Result<bool, NvhostsError> {
+    let unchanged = fs::read(path).is_ok_and(|existing| existing == content);
+    if unchanged {
+        if let Some(mode) = mode {
+            set_mode(path, mode).map_err(NvhostsError::io(path))?;
+        }
+        debug!("{} (unchanged)", path.display());
+        return Ok(false);
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(NvhostsError::io(dir))?;
+    }
+    if backup && path.exists() {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        fs::copy(path, &backup_path).map_err(NvhostsError::io(backup_path))?;
+    }
+
+    with_retries(path, retries, || write_atomically(path, content, mode))
+        .map_err(NvhostsError::io(path))?;
+
+    info!("wrote {}", path.display());
+
+    Ok(true)
+}
+
+/// Writes `content` to a temporary file next to `path`, then renames it to
+/// `path`, so that nginx, reloading meanwhile or after an interrupted run,
+/// reads either the previous file or the new one, never a partial one. The
+/// file gets the permissions of `mode`, or else of the file it replaces. A
+/// symlink at `path` is followed, and its target replaced.
+fn write_atomically(path: &Path, content: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let target = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let mut temp_name = OsString::from(".");
+    temp_name.push(target.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = target.with_file_name(temp_name);
+
+    let written = fs::write(&temp, content).and_then(|()| {
+        match (mode, fs::metadata(&target)) {
+            (Some(mode), _) => set_mode(&temp, mode)?,
+            (None, Ok(existing)) => fs::set_permissions(&temp, existing.permissions())?,
+            (None, Err(_)) => {}
+        }
+        fs::rename(&temp, &target)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Runs `write` on `path`, trying again up to `retries` times, after 50ms then
+/// twice as long each time, while it fails with an error that may be
+/// transient.
+fn with_retries(
+    path: &Path,
+    retries: u32,
+    mut write: impl FnMut() -> io::Result<()>,
+) -> io::Result<()> {
+    let mut delay = Duration::from_millis(50);
+    for _ in 0..retries {
+        match write() {
+            Err(err) if is_transient(&err) => {
+                warn!("couldn't write {}: {}, retrying", path.display(), err);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    write()
+}
+
+/// Whether `err` may go away when trying again, unlike e.g. a permission
+/// denied.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Validates `config`, and returns it along with the warnings of validation,
+/// without printing them. Under `Options::strict`, the warnings are errors
+/// instead, and none are returned.
+pub fn validate(
+    config: UnverifiedConfig,
+    options: &Options,
+) -> Result<(Config, Vec<Warning>), NvhostsError> {
+    config.validate_with_warnings(options)
+}
+
+/// Validates `config` and serializes the result to TOML: what is actually
+/// rendered, once the profile, the file references and the config-level
+/// settings are applied. With `only`, just the site of that domain is kept.
+pub fn resolve(
+    config: UnverifiedConfig,
+    options: &Options,
+    only: Option<&str>,
+) -> Result<String, NvhostsError> {
+    let mut config = config.validate(options)?;
+    if let Some(domain) = only {
+        config.sites.retain(|site| site.domain == domain);
+        if config.sites.is_empty() {
+            return Err(NvhostsError::UnknownSite {
+                domain: domain.to_string(),
+            });
+        }
+    }
+    // The overrides of the profile are already applied.
+    for site in config.sites.iter_mut() {
+        site.overrides = None;
+    }
+
+    let mut value = toml::Value::try_from(&config).expect("a config serializes to TOML");
+    if let (Some(mode), Some(table)) = (config.file_mode, value.as_table_mut()) {
+        table.insert(
+            String::from("file_mode"),
+            toml::Value::String(format!("{:04o}", mode)),
+        );
+    }
+    Ok(toml::to_string_pretty(&value).expect("a config serializes to TOML"))
+}
+
+/// Validates `config` and serializes to JSON the context its template is
+/// rendered with, by domain, to see the variables available to custom
+/// templates. With `only`, just the context of the site of that domain is
+/// printed, not keyed by domain.
+pub fn dump_context(
+    config: UnverifiedConfig,
+    options: &Options,
+    only: Option<&str>,
+) -> Result<String, NvhostsError> {
+    let config = config.validate(options)?;
+    let mut tera = templates(options)?;
+    let syntax = Syntax::for_version(&config.nginx_version);
+
+    let value = match only {
+        Some(domain) => {
+            let site = config
+                .sites
+                .iter()
+                .find(|site| site.domain == domain)
+                .ok_or_else(|| NvhostsError::UnknownSite {
+                    domain: domain.to_string(),
+                })?;
+            site.context(&mut tera, syntax, &config.vars)?.into_json()
+        }
+        None => {
+            let mut contexts = serde_json::Map::new();
+            for site in config.sites.iter() {
+                contexts.insert(
+                    site.domain.clone(),
+                    site.context(&mut tera, syntax, &config.vars)?.into_json(),
+                );
+            }
+            serde_json::Value::Object(contexts)
+        }
+    };
+    let mut json = serde_json::to_string_pretty(&value).expect("a context serializes to JSON");
+    json.push('\n');
+    Ok(json)
+}
+
+/// Validates `config` and renders its sites in memory, by domain; see
+/// `Config::render_map`.
+pub fn render_map(
+    config: UnverifiedConfig,
+    options: &Options,
+) -> Result<HashMap<String, String>, NvhostsError> {
+    config.validate(options)?.render_map(options)
+}
+
+/// Validates `config` and renders the site of `domain` in memory, without
+/// the banner, like `render_map` but for a single site.
+pub fn render_site(
+    config: UnverifiedConfig,
+    options: &Options,
+    domain: &str,
+) -> Result<String, NvhostsError> {
+    let config = config.validate(options)?;
+    let site = config
+        .sites
+        .iter()
+        .find(|site| site.domain == domain)
+        .ok_or_else(|| NvhostsError::UnknownSite {
+            domain: domain.to_string(),
+        })?;
+    site.render(
+        &mut templates(options)?,
+        Syntax::for_version(&config.nginx_version),
+        &config.vars,
+    )
+}
+
+/// Validates `config` and renders its sites to `sink`, by domain; see
+/// `Config::for_each_rendered`.
+pub fn for_each_rendered<F>(
+    config: UnverifiedConfig,
+    options: &Options,
+    sink: F,
+) -> Result<(), NvhostsError>
+where
+    F: FnMut(&str, &str) -> Result<(), NvhostsError>,
+{
+    config.validate(options)?.for_each_rendered(options, sink)
+}
+
+/// Validates `config` and renders the files `run` would write, by path
+/// relative to the output directory, without touching it.
+pub fn dry_run(
+    config: UnverifiedConfig,
+    options: &Options,
+) -> Result<Vec<(String, String)>, NvhostsError> {
+    config.validate(options)?.render_files(options)
+}
+
+/// A file rendered differently by two renders of the same config.
+#[derive(Debug)]
+pub struct Nondeterministic {
+    /// The path of the file, relative to the output directory.
+    pub path: String,
+    /// The unified diff from the first render of the file to the second.
+    pub diff: String,
+}
+
+/// Validates and renders `config` twice, from scratch, and returns the first
+/// file whose renders differ, e.g. from the order of a `HashMap`, if any.
+/// The output of a run only depends on its inputs when none does, which the
+/// diffs and the skipping of unchanged files rely on.
+pub fn check_idempotent(
+    config: UnverifiedConfig,
+    options: &Options,
+) -> Result<Option<Nondeterministic>, NvhostsError> {
+    let first = config.clone().validate(options)?.render_files(options)?;
+    // The warnings were printed by the first validation.
+    let (validated, _) = config.validate_with_warnings(options)?;
+    let second = validated.render_files(options)?;
+
+    let empty = (String::new(), String::new());
+    let count = first.len().max(second.len());
+    Ok((0..count).find_map(|i| {
+        let (first_path, first_content) = first.get(i).unwrap_or(&empty);
+        let (second_path, second_content) = second.get(i).unwrap_or(&empty);
+        if first_path == second_path && first_content == second_content {
+            return None;
+        }
+        let path = match first_path.is_empty() {
+            true => second_path,
+            false => first_path,
+        };
+        let diff = match first_path == second_path {
+            true => diff::unified(path, first_content, second_content),
+            false => format!("rendered as {:?} then as {:?}\n", first_path, second_path),
+        };
+        Some(Nondeterministic {
+            path: path.clone(),
+            diff,
+        })
+    }))
+}
+
+/// The paths of the `files` of a dry run that a run would write, because
+/// their content differs from the one in the output directory of `options`
+/// or they don't exist yet.
+pub fn changed_files<'a>(files: &'a [(String, String)], options: &Options) -> Vec<&'a str> {
+    files
+        .iter()
+        .filter(|(path, content)| {
+            fs::read(options.output_dir().join(path))
+                .map_or(true, |on_disk| on_disk != content.as_bytes())
+        })
+        .map(|(path, _)| path.as_str())
+        .collect()
+}
+
+/// The unified diffs turning the files of the output directory of `options`
+/// into `files`, as rendered by `dry_run`, by path, for the ones that differ.
+/// Missing files are diffed against an empty one.
+pub fn diff_files<'a>(files: &'a [(String, String)], options: &Options) -> Vec<(&'a str, String)> {
+    files
+        .iter()
+        .filter_map(|(path, content)| {
+            let on_disk = fs::read_to_string(options.output_dir().join(path)).unwrap_or_default();
+            let diff = diff::unified(&format!("sites-available/{}", path), &on_disk, content);
+            (!diff.is_empty()).then_some((path.as_str(), diff))
+        })
+        .collect()
+}
+
+/// The patch file of `--patch`, from the files of a dry run.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Patch<'a> {
+    /// The sites whose file is new or changed, one section each, after the
+    /// banner.
+    pub content: String,
+    /// The paths of the files of these sites.
+    pub sites: Vec<&'a str>,
+    /// The other files that changed, which can't be patched since nginx
+    /// rejects some of their blocks when defined twice, e.g. a `log_format`,
+    /// or a site's `upstream`.
+    pub unpatched: Vec<&'a str>,
+}
+
+/// The unified diffs turning the `old` files into the `new` ones, both as
+/// rendered by `dry_run`, by path, for the ones that differ: the files of
+/// `new` in their order, then the ones only `old` has. A file missing on one
+/// side is diffed against an empty one.
+pub fn diff_renders<'a>(
+    old: &'a [(String, String)],
+    new: &'a [(String, String)],
+) -> Vec<(&'a str, String)> {
+    let content = |files: &'a [(String, String)], path: &str| {
+        files
+            .iter()
+            .find(|(file, _)| file == path)
+            .map_or("", |(_, content)| content.as_str())
+    };
+    let removed = old
+        .iter()
+        .filter(|(path, _)| !new.iter().any(|(file, _)| file == path));
+    new.iter()
+        .chain(removed)
+        .filter_map(|(path, _)| {
+            let diff = diff::unified(path, content(old, path), content(new, path));
+            (!diff.is_empty()).then_some((path.as_str(), diff))
+        })
+        .collect()
+}
+
+/// Gathers the sites of `files`, as rendered by `dry_run`, whose file is new
+/// or differs from the one in the output directory of `options`, into a `Patch`.
+pub fn patch<'a>(files: &'a [(String, String)], options: &Options) -> Patch<'a> {
+    let changed = changed_files(files, options);
+    let mut sections = Vec::new();
+    let mut sites = Vec::new();
+    let mut unpatched = Vec::new();
+    for (path, content) in files.iter() {
+        if !changed.contains(&path.as_str()) {
+            continue;
+        }
+        if [
+            HTTP_FILENAME,
+            MAIN_FILENAME,
+            CATCH_ALL_FILENAME,
+            STREAM_FILENAME,
+        ]
+        .contains(&path.as_str())
+            || redefines(content)
+        {
+            unpatched.push(path.as_str());
+            continue;
+        }
+        let content = content.strip_prefix(BANNER).unwrap_or(content);
+        sections.push(section(path, content));
+        sites.push(path.as_str());
+    }
+    Patch {
+        content: with_banner(&sections.join("\n")),
+        sites,
+        unpatched,
+    }
+}
+
+/// Whether the file of a site defines what nginx refuses to start with when
+/// the previous version of the file defines it too: an `upstream`, a
+/// `limit_req_zone`, the map of `block_user_agents` or `cors`, or a
+/// `default_server`.
+fn redefines(content: &str) -> bool {
+    const BLOCKS: [&str; 4] = [
+        "upstream ",
+        "limit_req_zone ",
+        "map $http_user_agent $blocked_user_agent_",
+        "map $http_origin $cors_origin_",
+    ];
+    content.lines().any(|line| {
+        BLOCKS.iter().any(|block| line.starts_with(block))
+            || (line.trim_start().starts_with("listen ") && line.contains(" default_server"))
+    })
+}
+
+/// Validates `config` and writes the generated files. Files whose content
+/// doesn't change are left untouched; returns whether any file changed.
+///
+/// With `Options::incremental`, nothing is done when the last incremental run
+/// had the same inputs, which requires the file references of `config` to be
+/// read already for their changes to be noticed.
+pub fn run(config: UnverifiedConfig, options: &Options) -> Result<bool, NvhostsError> {
+    let dir = options.output_dir();
+    let fingerprint = if options.incremental {
+        Some(state::fingerprint(&config, options)?)
+    } else {
+        None
+    };
+    if let Some(fingerprint) = &fingerprint {
+        if !options.force && state::is_current(dir, fingerprint) {
+            info!("nothing changed since the last run, skipped");
+            return Ok(false);
+        }
+    }
+
+    let changed = config.validate(options)?.generate(options)?;
+    if let Some(fingerprint) = &fingerprint {
+        state::record(dir, fingerprint)?;
+    }
+    Ok(changed)
+}
+
+/// Runs `run` once per profile of `profiles`, each generating its files in a
+/// subdirectory of the output directory named after it, e.g.
+/// `sites-available/prod`, and symlinking them into the same subdirectory of
+/// the enabled directory, if any. Stops at the first profile that fails;
+/// returns whether any file changed.
+pub fn run_profiles(
+    config: UnverifiedConfig,
+    options: &Options,
+    profiles: &[String],
+) -> Result<bool, NvhostsError> {
+    let mut changed = false;
+    for profile in profiles {
+        let mut config = config.clone();
+        config.enabled_dir = config.enabled_dir.map(|dir| dir.join(profile));
+        let options = Options {
+            profile: Some(profile.clone()),
+            output_dir: Some(options.output_dir().join(profile)),
+            enabled_dir: options.enabled_dir.as_ref().map(|dir| dir.join(profile)),
+            ..options.clone()
+        };
+        info!("generating the profile {}", profile);
+        changed |= run(config, &options)?;
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Validates `config` and renders its first site.
+    fn render(config: &str) -> String {
+        let config: UnverifiedConfig = toml::from_str(config).unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let syntax = Syntax::for_version(&config.nginx_version);
+
+        config.sites[0]
+            .render(&mut tera(), syntax, &Vars::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_redirect_domain() {
+        use serde_json::json;
+
+        let value = redirect_domain(&json!("www.mlcdf.fr"), &HashMap::<String, Value>::new());
+        assert_eq!(value.unwrap().to_string(), "\"mlcdf.fr\"");
+
+        let value = redirect_domain(&json!("mlcdf.fr"), &HashMap::<String, Value>::new());
+        assert_eq!(value.unwrap().to_string(), "\"www.mlcdf.fr\"");
+
+        let value = redirect_domain(&json!("dev.www.mlcdf.fr"), &HashMap::<String, Value>::new());
+        assert_eq!(value.unwrap().to_string(), "\"\"");
+
+        let value = redirect_domain(&json!("www.dev.mlcdf.fr"), &HashMap::<String, Value>::new());
+        assert_eq!(value.unwrap().to_string(), "\"dev.mlcdf.fr\"");
+
+        let mut always = HashMap::new();
+        always.insert(String::from("always"), Value::Bool(true));
+        let value = redirect_domain(&json!("example.co.uk"), &always);
+        assert_eq!(value.unwrap().to_string(), "\"www.example.co.uk\"");
+    }
+
+    #[test]
+    fn test_www_redirect() {
+        let site = |fields: &str| render(&format!("[[sites]]\n{}", fields));
+
+        let output = site("domain = \"example.com\"");
+        assert!(output.contains("server_name .www.example.com;"));
+
+        let output = site("domain = \"dev.example.com\"");
+        assert!(!output.contains("server_name ."));
+
+        let output = site("domain = \"example.co.uk\"\nwww_redirect = true");
+        assert!(output.contains("server_name .www.example.co.uk;"));
+
+        let output = site("domain = \"example.com\"\nwww_redirect = false");
+        assert!(!output.contains("server_name ."));
+    }
+
+    #[test]
+    fn test_example_is_valid() {
+        assert!(UnverifiedConfig::example()
+            .validate(&Options::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_strict_promotes_warnings() {
+        let config = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/"
+            [sites.headers.values]
+            Cache-Control = "no-cache"
+            "#;
+
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        assert!(unverified.validate(&Options::default()).is_ok());
+
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        let err = unverified.validate(&strict).err().unwrap();
+        assert!(err.to_string().contains("Cache-Control in headers"));
+    }
+
+    #[test]
+    fn test_validate_warnings() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            dhparam = "/etc/ssl/dhparam.pem"
+
+            [[sites]]
+            domain = "example.com"
+            https_redirect_exclude = ["/health"]
+
+            [[sites.redirects]]
+            from = "old"
+            to = "/new"
+            "#,
+        )
+        .unwrap();
+
+        let (validated, warnings) = validate(config.clone(), &Options::default()).unwrap();
+        assert_eq!(
+            validated.sites[0].redirects.as_ref().unwrap()[0].from_field,
+            "/old"
+        );
+        assert_eq!(
+            warnings,
+            [
+                Warning {
+                    kind: WarningKind::NoEffect,
+                    domain: Some(String::from("example.com")),
+                    message: String::from(
+                        "https_redirect_exclude has no effect without force_https"
+                    ),
+                },
+                Warning {
+                    kind: WarningKind::Corrected,
+                    domain: Some(String::from("example.com")),
+                    message: String::from(
+                        "redirect from \"old\" doesn't start with a slash, using \"/old\""
+                    ),
+                },
+                Warning {
+                    kind: WarningKind::NoEffect,
+                    domain: None,
+                    message: String::from(
+                        "dhparam is only used by the intermediate and old TLS profiles"
+                    ),
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "\"example.com\": https_redirect_exclude has no effect without force_https"
+        );
+
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        match validate(config, &strict).err().unwrap() {
+            NvhostsError::Invalid(errors) => {
+                assert_eq!(errors[2], ValidationError::Warning(warnings[2].clone()))
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_no_validate() {
+        let config = "[[sites]]\ndomain = \"example.com\"\n[[sites]]\ndomain = \"example.org\"\nhttp2 = true\nssl_cert = \"cert.pem\"";
+
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        assert!(unverified.validate(&Options::default()).is_err());
+
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        let no_validate = Options {
+            no_validate: true,
+            ..Default::default()
+        };
+        assert_eq!(unverified.validate(&no_validate).unwrap().sites.len(), 2);
+
+        // Sites written to the same file still fail.
+        let config = "[[sites]]\ndomain = \"example.com\"\n[[sites]]\ndomain = \"example.com\"";
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        match unverified.validate(&no_validate).err().unwrap() {
+            NvhostsError::Invalid(errors) => assert_eq!(
+                errors,
+                vec![ValidationError::DuplicateDomain {
+                    domain: String::from("example.com")
+                }]
+            ),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let config =
+            "[[sites]]\ndomain = \"a\"\n[[sites]]\ndomain = \"b\"\n[[sites]]\ndomain = \"c\"";
+        let options = |max_errors| Options {
+            max_errors,
+            ..Default::default()
+        };
+
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        let err = unverified.validate(&options(Some(1))).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config:\n  \"a\": invalid domain\n  and 2 more"
+        );
+
+        for max_errors in [None, Some(3)] {
+            let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+            let err = unverified.validate(&options(max_errors)).err().unwrap();
+            assert_eq!(err.to_string().lines().count(), 4);
+        }
+    }
+
+    #[test]
+    fn test_no_warn_quiet_errors() {
+        // A warning for the Cache-Control of headers, then an invalid domain.
+        let warning = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/"
+            values = { Cache-Control = "no-cache" }
+            "#;
+        let config = format!("{}\n[[sites]]\ndomain = \"a\"", warning);
+        let validate = |options: Options| {
+            let unverified: UnverifiedConfig = toml::from_str(&config).unwrap();
+            unverified.validate(&options).err().unwrap().to_string()
+        };
+
+        assert_eq!(
+            validate(Options {
+                strict: true,
+                no_warn: true,
+                ..Default::default()
+            }),
+            "invalid config:\n  \"a\": invalid domain\n  1 warning not shown"
+        );
+        assert_eq!(
+            validate(Options {
+                strict: true,
+                quiet_errors: true,
+                ..Default::default()
+            }),
+            "invalid config:\n  1 error and 1 warning not shown"
+        );
+        assert_eq!(
+            validate(Options {
+                strict: true,
+                no_warn: true,
+                quiet_errors: true,
+                ..Default::default()
+            }),
+            "invalid config:\n  1 error and 1 warning not shown"
+        );
+        assert_eq!(
+            validate(Options {
+                quiet_errors: true,
+                ..Default::default()
+            }),
+            "invalid config:\n  1 error not shown"
+        );
+
+        let unverified: UnverifiedConfig = toml::from_str(warning).unwrap();
+        let options = Options {
+            no_warn: true,
+            ..Default::default()
+        };
+        assert!(unverified.validate(&options).is_ok());
+    }
+
+    #[test]
+    fn test_validate_force_https_requires_tls() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            force_https = true
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert!(err.to_string().contains("force_https requires"));
+    }
+
+    #[test]
+    fn test_comment() {
+        use serde_json::json;
+
+        let value = comment(&json!("owner: ops"), &HashMap::<String, Value>::new());
+        assert_eq!(value.unwrap(), json!("# owner: ops"));
+
+        let value = comment(
+            &json!("line one\r\n\n} server {\rlast  "),
+            &HashMap::<String, Value>::new(),
+        );
+        assert_eq!(value.unwrap(), json!("# line one\n#\n# } server {\n# last"));
+    }
+
+    #[test]
+    fn test_description() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            description = "Owner: web team\nTicket: OPS-42"
+            "#,
+        );
+        assert!(output.starts_with("# Owner: web team\n# Ticket: OPS-42\n\nserver {"));
+    }
+
+    #[test]
+    fn test_redirect_to() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "old.example.com"
+            redirect_to = "https://new.example.com$request_uri"
+            "#,
+        );
+        assert_eq!(output.matches("server {").count(), 1);
+        assert!(output.contains("return 301 https://new.example.com$request_uri;"));
+        assert!(!output.contains("proxy_pass"));
+
+        let site = |fields: &str| {
+            format!(
+                "[[sites]]\ndomain = \"old.example.com\"\nredirect_to = \"https://new.example.com/\"\n{}",
+                fields
+            )
+        };
+        let output = render(&site(""));
+        assert!(output.contains("        return 301 https://new.example.com$request_uri;\n"));
+        let output = render(&site("path_mode = \"preserve\""));
+        assert!(output.contains("        return 301 https://new.example.com$request_uri;\n"));
+
+        let output = render(&site("path_mode = \"drop\""));
+        assert!(output
+            .contains("    location / {\n        return 301 https://new.example.com/;\n    }\n"));
+
+        let output = render(&site(
+            r#"path_mode = "rewrite"
+            path_rewrites = [
+                { pattern = "^/blog/(.*)$", replacement = "/posts/$1" },
+                { pattern = "^/about$", replacement = "/company" },
+            ]"#,
+        ));
+        assert!(output.contains(
+            "    location / {
+        rewrite \"^/blog/(.*)$\" \"https://new.example.com/posts/$1\" permanent;
+        rewrite \"^/about$\" \"https://new.example.com/company\" permanent;
+        return 301 https://new.example.com/;
+    }
+"
+        ));
+
+        let invalid = |fields: &str| {
+            let config: UnverifiedConfig = toml::from_str(fields).unwrap();
+            config
+                .validate(&Options::default())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(invalid(&site("path_mode = \"rewrite\""))
+            .contains("path_mode = \"rewrite\" requires path_rewrites"));
+        assert!(invalid(&site(
+            "path_mode = \"drop\"\npath_rewrites = [{ pattern = \"^/a$\", replacement = \"/b\" }]"
+        ))
+        .contains("path_rewrites only apply with path_mode = \"rewrite\""));
+        assert!(invalid(&site(
+            "path_mode = \"rewrite\"\npath_rewrites = [{ pattern = \"^/a$\", replacement = \"b\" }]"
+        ))
+        .contains("path rewrite replacement \"b\" must be a path"));
+        assert!(invalid(
+            "[[sites]]\ndomain = \"old.example.com\"\nredirect_to = \"https://new.example.com$request_uri\"\npath_mode = \"drop\""
+        )
+        .contains("keeps the path with $request_uri"));
+        assert!(
+            invalid("[[sites]]\ndomain = \"example.com\"\npath_mode = \"drop\"")
+                .contains("path_mode and path_rewrites only apply with redirect_to")
+        );
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "old.example.com"
+            redirect_to = "https://new.example.com$request_uri"
+            extra = "gzip on;"
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert!(err.to_string().contains("redirect_to and extra conflict"));
+    }
+
+    /// The conflicts of a site made of `fields`.
+    fn site_conflicts(fields: &str) -> Vec<String> {
+        let site: Site = toml::from_str(&format!("domain = \"example.com\"\n{}", fields)).unwrap();
+        conflicts(&site)
+            .into_iter()
+            .map(|conflict| match conflict {
+                ValidationError::Site { message, .. } => message,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let redirect_to = "redirect_to = \"https://example.org\"\n";
+        for field in [
+            "cache_control = []",
+            "headers = []",
+            "immutable_assets = \"/assets/\"",
+            "redirects = []",
+            "map_redirects = []",
+            "rewrites = []",
+            "early_hints = []",
+            "proxy_buffering = false",
+            "allow_acl = \"office\"",
+            "proxy_cache = { zone = \"static\" }",
+            "upstream = { url = \"http://127.0.0.1\" }",
+            "healthcheck = {}",
+            "https_redirect_exclude = []",
+            "extra = \"\"",
+        ] {
+            let name = field.split(' ').next().unwrap();
+            let conflicts = site_conflicts(&format!("{}{}", redirect_to, field));
+            assert_eq!(conflicts.len(), 1, "{:?}", conflicts);
+            assert!(conflicts[0].starts_with(&format!("redirect_to and {} conflict", name)));
+        }
+
+        let conflicts = site_conflicts(&format!("{}force_https = true", redirect_to));
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("remove force_https"));
+
+        let conflicts = site_conflicts(
+            "proxy_cache = { zone = \"static\" }\nupstream = { url = \"127.0.0.1:9000\", backend_type = \"fastcgi\" }",
+        );
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("proxy_cache only caches proxied upstreams"));
+
+        assert!(site_conflicts(redirect_to).is_empty());
+        assert!(site_conflicts(
+            "proxy_cache = { zone = \"static\" }\nupstream = { url = \"http://127.0.0.1\" }"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_templated_header_values() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/*"
+
+            [sites.headers.values]
+            Link = "<https://{{ site.domain }}/style.css>; rel=preload"
+            X-Literal = "{} {a}"
+            "#,
+        );
+        assert!(
+            output.contains("add_header Link \"<https://example.com/style.css>; rel=preload\";")
+        );
+        assert!(output.contains("add_header X-Literal \"{} {a}\";"));
+    }
+
+    #[test]
+    fn test_server_tokens() {
+        let config = |site: &str| {
+            format!(
+                "server_tokens = false\n[[sites]]\ndomain = \"example.com\"\n{}",
+                site
+            )
+        };
+
+        let output = render(&config(""));
+        assert_eq!(output.matches("server_tokens off;").count(), 2);
+
+        let output = render(&config("server_tokens = true"));
+        assert_eq!(output.matches("server_tokens on;").count(), 2);
+        assert!(!output.contains("server_tokens off;"));
+
+        let output = render("[[sites]]\ndomain = \"example.com\"");
+        assert!(!output.contains("server_tokens"));
+    }
+
+    #[test]
+    fn test_charset() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            charset = "utf-8"
+            "#,
+        );
+        assert!(output.contains("\n    server_name example.com;\n    charset utf-8;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            charset = "utf-8; x"
+
+            [[sites]]
+            domain = "example.org"
+            redirect_to = "https://example.com"
+            charset = "utf-8"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("\"example.com\": charset \"utf-8; x\" must be a charset name"),
+            "{}",
+            err
+        );
+        assert!(err.contains("redirect_to and charset conflict"), "{}", err);
+    }
+
+    #[test]
+    fn test_rewrites() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.rewrites]]
+            pattern = '^/posts/(\d{4})$'
+            replacement = "/posts?year=$1"
+            flag = "last"
+
+            [[sites.rewrites]]
+            pattern = "^/old/(.*)$"
+            replacement = "/new/$1"
+            "#,
+        );
+        assert!(output.contains(
+            "    rewrite \"^/posts/(\\d{4})$\" \"/posts?year=$1\" last;\n    rewrite \"^/old/(.*)$\" \"/new/$1\";\n\n    location / {"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            rewrites = [{ pattern = "(", replacement = "/a b" }]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("rewrite pattern \"(\" is not a valid regex"));
+        assert!(err.contains("rewrite \"/a b\" can't be empty or contain spaces"));
+
+        let invalid = toml::from_str::<UnverifiedConfig>(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            rewrites = [{ pattern = "^/a$", replacement = "/b", flag = "stop" }]
+            "#,
+        );
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_allow_acl() {
+        let output = render(
+            r#"
+            acls.office = ["203.0.113.0/24", "2001:db8::/32"]
+
+            [[sites]]
+            domain = "example.com"
+            allow_acl = "office"
+            healthcheck = {}
+            "#,
+        );
+        assert!(output.contains(
+            "    server_name example.com;\n\n    allow 203.0.113.0/24;\n    allow 2001:db8::/32;\n    deny all;\n"
+        ));
+        assert!(output.contains("location = /healthz {\n        allow all;\n"));
+
+        // The paths served over HTTP are restricted as well.
+        let output = render(
+            r#"
+            acls.office = ["203.0.113.0/24"]
+
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            https_redirect_exclude = ["/webhook"]
+            allow_acl = "office"
+            "#,
+        );
+        assert_eq!(
+            output
+                .matches("\n        allow 203.0.113.0/24;\n        deny all;\n    }")
+                .count(),
+            1
+        );
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            acls.office = ["203.0.113.0/33", "2001:db8::/129"]
+            acls.empty = []
+
+            [[sites]]
+            domain = "example.com"
+            allow_acl = "vpn"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("acl \"office\": invalid address \"203.0.113.0/33\""));
+        assert!(err.contains("acl \"office\": invalid address \"2001:db8::/129\""));
+        assert!(err.contains("acl \"empty\": at least one address is required"));
+        assert!(err.contains("\"example.com\": acl \"vpn\" is not defined"));
+    }
+
+    #[test]
+    fn test_header_buffers() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            header_buffers = { size = "4k", large = "4 32k" }
+            "#,
+        );
+        assert!(output.contains(
+            "    client_header_buffer_size 4k;\n    large_client_header_buffers 4 32k;\n"
+        ));
+
+        let output =
+            render("[[sites]]\ndomain = \"example.com\"\nheader_buffers = { large = \"8 64k\" }");
+        assert!(output.contains("    large_client_header_buffers 8 64k;\n"));
+        assert!(!output.contains("client_header_buffer_size"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            header_buffers = { size = "4 k", large = "32k" }
+
+            [[sites]]
+            domain = "example.org"
+            header_buffers = {}
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("header_buffers size \"4 k\" must be a size"));
+        assert!(err.contains("header_buffers large \"32k\" must be a number and a size"));
+        assert!(err.contains("\"example.org\": header_buffers requires size, large or both"));
+    }
+
+    #[test]
+    fn test_proxy_buffering() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "events.example.com"
+            proxy_buffering = false
+            proxy_buffer_size = "16k"
+            proxy_buffers = "8 16k"
+            "#,
+        );
+        assert!(output.contains(
+            "    proxy_buffering off;\n    proxy_buffer_size 16k;\n    proxy_buffers 8 16k;\n"
+        ));
+
+        let output = render("[[sites]]\ndomain = \"example.com\"");
+        assert!(!output.contains("proxy_buffer"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            proxy_buffer_size = "16 k"
+            proxy_buffers = "16k"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("proxy_buffer_size \"16 k\" must be a size"));
+        assert!(err.contains("proxy_buffers \"16k\" must be a number and a size"));
+
+        let conflicts = site_conflicts(
+            "proxy_buffering = false\nupstream = { url = \"127.0.0.1:9000\", backend_type = \"uwsgi\" }",
+        );
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("proxy_buffering only applies to proxied upstreams"));
+    }
+
+    #[test]
+    fn test_proxy_ssl() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "api.example.com"
+            upstream = { url = "https://api.partner.com" }
+            proxy_ssl = { name = "partner.com", verify = true, trusted_certificate = "/etc/ssl/certs/ca-certificates.crt" }
+            "#,
+        );
+        assert!(output.contains(
+            "\n    proxy_ssl_server_name on;\n    proxy_ssl_name partner.com;\n    proxy_ssl_verify on;\n    proxy_ssl_trusted_certificate /etc/ssl/certs/ca-certificates.crt;\n"
+        ));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "api.example.com"
+            upstream = { url = "https://api.partner.com" }
+            proxy_ssl = {}
+            "#,
+        );
+        assert!(output.contains("    proxy_ssl_server_name on;\n"));
+        assert!(!output.contains("proxy_ssl_verify"));
+
+        // The paths served over HTTP proxy to the same upstream.
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "api.example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            https_redirect_exclude = ["/webhook"]
+            upstream = { url = "https://api.partner.com" }
+            proxy_ssl = { verify = true, trusted_certificate = "/etc/ssl/certs/ca-certificates.crt" }
+            "#,
+        );
+        assert!(output.contains(
+            "location /webhook {\n        proxy_pass https://api.partner.com;\n        include /etc/nginx/proxy.conf;\n\n        proxy_ssl_server_name on;\n        proxy_ssl_verify on;\n"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://127.0.0.1:8080" }
+            proxy_ssl = { server_name = false, name = "bad name", verify = true }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("proxy_ssl requires an https:// upstream"));
+        assert!(err.contains("verify requires a trusted_certificate"));
+        assert!(err.contains("name requires server_name"));
+        assert!(err.contains("name \"bad name\" can't be empty or contain whitespace"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.org"
+            upstream = { url = "https://api.partner.com" }
+            proxy_ssl = { trusted_certificate = "/etc/ssl/certs/ca-certificates.crt" }
+            "#,
+        )
+        .unwrap();
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        let err = config.validate(&strict).err().unwrap().to_string();
+        assert!(err.contains("trusted_certificate has no effect without verify = true"));
+
+        let conflicts = site_conflicts("root = \"/var/www\"\nproxy_ssl = {}");
+        assert!(conflicts[0].contains("root and proxy_ssl conflict"));
+    }
+
+    #[test]
+    fn test_early_hints() {
+        let config = |version: &str| {
+            format!(
+                r#"
+                nginx_version = "{}"
+
+                [[sites]]
+                domain = "example.com"
+                http2 = true
+                early_hints = [
+                    {{ href = "/app.css", as = "style" }},
+                    {{ href = "https://cdn.example.com/font.woff2", as = "font" }},
+                ]
+                "#,
+                version
+            )
+        };
+
+        let output = render(&config("1.28.0"));
+        assert!(output.contains(
+            "        add_header Link \"</app.css>; rel=preload; as=style\" always;\n        add_header Link \"<https://cdn.example.com/font.woff2>; rel=preload; as=font; crossorigin\" always;\n"
+        ));
+        assert!(!output.contains("http2_push_preload"));
+        let output = render(&config("1.24.0"));
+        assert!(output.contains("        http2_push_preload on;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            early_hints = [{ href = "http://cdn.example.com/app.js", as = "script" }]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("early_hints requires http2 = true"));
+        assert!(err.contains("must be a path or an https URL"));
+    }
+
+    #[test]
+    fn test_http3() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            http2 = true
+            http3 = true
+
+            [[sites]]
+            domain = "example.org"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            http3 = true
+            listen = ["10.0.0.5:443", "0.0.0.0:443"]
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        assert_eq!(config.sites[1].quic_ports, [443]);
+        let syntax = Syntax::for_version(&config.nginx_version);
+        let output: Vec<String> = config
+            .sites
+            .iter()
+            .map(|site| site.render(&mut tera(), syntax, &Vars::new()).unwrap())
+            .collect();
+        assert!(output[0].contains(
+            "    listen 443 ssl;\n    listen [::]:443 ssl;\n    listen 443 quic reuseport;\n    listen [::]:443 quic reuseport;\n    http2 on;\n"
+        ));
+        // 0.0.0.0:443 is the address of 443, whose reuseport example.com has.
+        assert!(output[1]
+            .contains("    listen 10.0.0.5:443 quic reuseport;\n    listen 0.0.0.0:443 quic;\n"));
+        for output in output {
+            assert!(output.contains("        add_header Alt-Svc 'h3=\":443\"; ma=86400' always;\n"));
+        }
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            nginx_version = "1.24.0"
+
+            [[sites]]
+            domain = "example.com"
+            http3 = true
+
+            [[sites]]
+            domain = "example.org"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            http3 = true
+
+            [[streams]]
+            listen = 443
+            protocol = "udp"
+            upstream = { url = "10.0.0.1:443" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("http3 requires ssl_cert"));
+        assert!(err.contains("http3 requires nginx 1.25.0 or later, nginx_version is 1.24.0"));
+        assert!(err.contains("the servers of the sites listen on it for http3"));
+    }
+
+    #[test]
+    fn test_root() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            root = "/var/www/example.com"
+            static_optimize = true
+            gzip_static = true
+            brotli_static = true
+
+            [[sites.headers]]
+            for = "/static/"
+            values = { X-Frame-Options = "DENY" }
+            "#,
+        );
+        assert!(output.contains("    root /var/www/example.com;\n    sendfile on;\n    tcp_nopush on;\n    tcp_nodelay on;\n    open_file_cache max=10000 inactive=60s;\n"));
+        assert!(output.contains(
+            "    open_file_cache_errors on;\n    gzip_static on;\n    brotli_static on;\n"
+        ));
+        assert_eq!(output.matches("try_files $uri $uri/ =404;").count(), 2);
+        assert!(!output.contains("$bucket"));
+        assert!(!output.contains("proxy.conf"));
+
+        let output = render("[[sites]]\ndomain = \"example.com\"\nroot = \"/var/www\"");
+        assert!(output.contains("    root /var/www;\n\n"));
+        assert!(!output.contains("sendfile"));
+        assert!(!output.contains("gzip_static"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            root = "var/www"
+            upstream = { url = "http://127.0.0.1:8000" }
+
+            [[sites]]
+            domain = "example.org"
+            static_optimize = true
+            gzip_static = true
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("root \"var/www\" must be an absolute path"));
+        assert!(err.contains("root and upstream conflict"));
+        assert!(
+            err.contains("\"example.org\": static_optimize only applies to sites serving files")
+        );
+        assert!(err.contains("\"example.org\": gzip_static only applies to sites serving files"));
+    }
+
+    #[test]
+    fn test_etag() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            root = "/var/www/example.com"
+            etag = false
+            if_modified_since = "before"
+            "#,
+        );
+        assert!(output.contains(
+            "    root /var/www/example.com;\n    etag off;\n    if_modified_since before;\n"
+        ));
+
+        let output = render("[[sites]]\ndomain = \"example.com\"\nroot = \"/var/www\"");
+        assert!(!output.contains("etag"));
+        assert!(!output.contains("if_modified_since"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.org"
+            etag = true
+            if_modified_since = "off"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("\"example.org\": etag only applies to sites serving files"));
+        assert!(
+            err.contains("\"example.org\": if_modified_since only applies to sites serving files")
+        );
+    }
+
+    #[test]
+    fn test_listen() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            listen = ["10.0.0.5:443", "[2001:db8::5]:443"]
+            "#,
+        );
+        assert!(output.contains("    listen 8080;\n    listen [::]:8080;\n    listen 10.0.0.5:443 ssl;\n    listen [2001:db8::5]:443 ssl;\n"));
+        assert!(!output.contains("listen 443"));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            redirect_to = "https://example.org"
+            listen = ["10.0.0.5:8080"]
+            "#,
+        );
+        assert!(output.contains("server {\n    listen 10.0.0.5:8080;\n\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            listen = ["10.0.0.5", "10.0.0.5:443", "10.0.0.5:80", "10.0.0.5:8080", "10.0.0.5:8080"]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("\"10.0.0.5\" isn't an IP address and port"));
+        assert!(err.contains("\"10.0.0.5:443\" listens for HTTPS without ssl_cert"));
+        assert!(err.contains(
+            "port 80 of \"10.0.0.5:80\" isn't served, use 8080 for HTTP or 443 for HTTPS"
+        ));
+        assert!(err.contains("\"10.0.0.5:8080\" is listed twice"));
+    }
+
+    #[test]
+    fn test_lean_redirects() {
+        let site = |lean_redirects: bool, upstream: &str| {
+            render(&format!(
+                r#"
+                lean_redirects = {}
+
+                [[sites]]
+                domain = "old.example.com"
+                {}
+
+                [[sites.redirects]]
+                from = "/blog"
+                to = "https://blog.example.com"
+                status_code = 301
+                "#,
+                lean_redirects, upstream
+            ))
+        };
+
+        let output = site(true, "");
+        assert!(output.contains(
+            "    server_name old.example.com;
+
+    location = /blog {
+        return 301 https://blog.example.com;
+    }
+
+    location / {
+        return 404;
+    }
+}"
+        ));
+        assert!(!output.contains("$cacheable_types_old_example_com"));
+
+        // A site serving an upstream keeps its redirects in its server.
+        let output = site(true, "upstream = { url = \"http://127.0.0.1:3000\" }");
+        assert!(output.contains(
+            "    location / {
+        location = /blog {
+            return 301 https://blog.example.com;
+        }
+
+        proxy_pass http://127.0.0.1:3000;"
+        ));
+
+        let output = site(false, "");
+        assert!(!output.contains("return 404;"));
+        assert!(output.contains("proxy_pass https://$bucket;"));
+    }
+
+    #[test]
+    fn test_server_names() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            server_names = [
+                { name = "*.example.org" },
+                { name = '^(?<sub>[a-z]+)\.example\.net$', regex = true },
+            ]
+            upstream = { url = "http://$sub.internal:3000", dynamic = true }
+
+            [resolver]
+            addresses = ["127.0.0.11"]
+            "#,
+        );
+        assert!(output.contains(
+            "    server_name example.com *.example.org \"~^(?<sub>[a-z]+)\\.example\\.net$\";\n"
+        ));
+        assert!(output.contains("set $upstream \"http://$sub.internal:3000\";"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            server_names = [
+                { name = "exa mple.org" },
+                { name = "^(?<sub>.+\\.example\\.net$", regex = true },
+                { name = "~^www\\..+$", regex = true },
+            ]
+
+            [[sites]]
+            domain = "example.org"
+            server_names = [{ name = '^(?<sub>[a-z]+)\.example\.net$', regex = true }]
+            upstream = { url = "http://${sub}.internal:3000" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("\"example.com\": server name \"exa mple.org\" isn't a domain, a wildcard name, nor a regex"),
+            "{}",
+            err
+        );
+        assert!(err.contains("\"example.com\": server name \"^(?<sub>.+\\\\.example\\\\.net$\" isn't a valid regex: "));
+        assert!(err.contains("\"example.com\": server name \"~^www\\\\..+$\" starts with ~, which regex = true already adds"));
+        assert!(err.contains("\"example.org\": upstream \"http://${sub}.internal:3000\" uses the capture $sub of a server name, which requires dynamic = true"));
+
+        assert!(references("$sub.internal", "sub"));
+        assert!(!references("$subdomain.internal", "sub"));
+
+        // PCRE lookarounds are left to nginx, and a name is only served by
+        // one site.
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            server_names = [{ name = '^(?!www\.)(?<sub>[a-z]+)\.example\.net$', regex = true }]
+            upstream = { url = "http://$sub.internal:3000" }
+
+            [[sites]]
+            domain = "example.org"
+            server_names = [{ name = "example.com" }, { name = "www.example.org" }]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            err,
+            "invalid config:
+  \"example.com\": upstream \"http://$sub.internal:3000\" uses the capture $sub of a server name, which requires dynamic = true
+  \"example.org\": server name \"example.com\" is also one of \"example.com\", nginx would only serve it from the first"
+        );
+
+        assert!(pcre_only("^(?<=a)b$"));
+        assert!(pcre_only("^a++$"));
+        assert!(pcre_only("^(a)\\1$"));
+        assert!(!pcre_only("^(?<sub>[a-z]+)\\.example\\.net$"));
+    }
+
+    #[test]
+    fn test_default_server() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            default_server = true
+            www_redirect = false
+            "#,
+        );
+        assert!(output.contains("server {\n    listen 8080 default_server;\n    listen [::]:8080 default_server;\n\n    server_name example.com;"));
+        assert!(output.contains(
+            "    listen 443 ssl default_server;\n    listen [::]:443 ssl default_server;\n"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [catch_all]
+            ssl_cert = "/etc/ssl/default.pem"
+            ssl_key = "/etc/ssl/default.key"
+
+            [[sites]]
+            domain = "example.com"
+            default_server = true
+
+            [[sites]]
+            domain = "example.org"
+            default_server = true
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+
+            [[sites]]
+            domain = "example.net"
+            default_server = true
+            listen = ["10.0.0.5:8080"]
+
+            [[sites]]
+            domain = "example.io"
+            default_server = true
+            listen = ["0.0.0.0:8080"]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            err,
+            "invalid config:
+  \"example.org\": default_server on 8080, [::]:8080 is also set by \"example.com\", nginx would refuse to start
+  \"example.org\": default_server on 443, [::]:443 is also set by catch_all, nginx would refuse to start
+  \"example.io\": default_server on 0.0.0.0:8080 is also set by \"example.com\", nginx would refuse to start"
+        );
+    }
+
+    #[test]
+    fn test_tls_profile() {
+        let site = |fields: &str| {
+            render(&format!(
+                r#"
+                tls_profile = "intermediate"
+                dhparam = "/etc/ssl/dhparam.pem"
+
+                [[sites]]
+                domain = "example.com"
+                ssl_cert = "/etc/ssl/cert.pem"
+                ssl_key = "/etc/ssl/key.pem"
+                {}
+                "#,
+                fields
+            ))
+        };
+
+        let output = site("");
+        assert!(output.contains(&format!(
+            "    ssl_protocols TLSv1.2 TLSv1.3;\n    ssl_ciphers {};\n    ssl_prefer_server_ciphers off;\n    ssl_session_timeout 1d;\n    ssl_session_cache shared:MozSSL:10m;\n    ssl_session_tickets off;\n    ssl_dhparam /etc/ssl/dhparam.pem;\n",
+            INTERMEDIATE_CIPHERS
+        )));
+
+        let output = site("tls_profile = \"modern\"");
+        assert!(output.contains("    ssl_protocols TLSv1.3;\n    ssl_prefer_server_ciphers off;\n"));
+        assert!(!output.contains("ssl_ciphers"));
+        assert!(!output.contains("ssl_dhparam"));
+
+        let output = site("ssl_protocols = \"TLSv1.3\"\nssl_ciphers = \"HIGH:!aNULL\"");
+        assert!(output.contains("    ssl_protocols TLSv1.3;\n    ssl_ciphers HIGH:!aNULL;\n"));
+
+        // The server redirecting the aliases presents the same profile.
+        let output = site("tls_profile = \"modern\"\naliases = [\"example.net\"]");
+        assert_eq!(output.matches("    ssl_protocols TLSv1.3;\n").count(), 2);
+
+        let output = render(
+            "[[sites]]\ndomain = \"example.com\"\nssl_cert = \"/a.pem\"\nssl_key = \"/b.pem\"",
+        );
+        assert!(!output.contains("ssl_protocols"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            ssl_protocols = "TLSv1.2 TLSv1.4"
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("unknown TLS protocol \"TLSv1.4\" in ssl_protocols"));
+    }
+
+    #[test]
+    fn test_healthcheck() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            healthcheck = {}
+            "#,
+        );
+        let location = "location = /healthz {\n        access_log off;\n        auth_basic off;\n        default_type text/plain;\n        return 200 \"ok\";\n    }";
+        assert_eq!(output.matches(location).count(), 2);
+
+        let plain = &output[..output.find("listen 443").unwrap()];
+        assert!(plain.find(location).unwrap() < plain.find("return 301 https://$host").unwrap());
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            healthcheck = { path = "status", status = 42, body = 'say "ok"' }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("must start with a slash"));
+        assert!(err.contains("is not an HTTP status"));
+        assert!(err.contains("can't contain quotes"));
+    }
+
+    #[test]
+    fn test_https_redirect_exclude() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            acme_webroot = "/var/www/acme"
+            https_redirect_exclude = ["/healthz"]
+            "#,
+        );
+
+        let plain =
+            &output[output.find("listen 8080;").unwrap()..output.find("listen 443").unwrap()];
+        let redirect = plain.find("return 301 https://$host$request_uri;").unwrap();
+        assert!(
+            plain
+                .find("location ^~ /.well-known/acme-challenge/")
+                .unwrap()
+                < redirect
+        );
+        assert!(plain.find("location /healthz {").unwrap() < redirect);
+        assert!(!output[output.find("listen 443").unwrap()..].contains("listen 8080"));
+    }
+
+    /// Validates a site with a single redirect from `from`.
+    fn validate_redirect(
+        from: &str,
+        regex: bool,
+        options: &Options,
+    ) -> Result<Config, NvhostsError> {
+        let config: UnverifiedConfig = toml::from_str(&format!(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.redirects]]
+            from = "{}"
+            to = "/new"
+            regex = {}
+            "#,
+            from, regex
+        ))
+        .unwrap();
+        config.validate(options)
+    }
+
+    #[test]
+    fn test_validate_redirect_from() {
+        let redirect = |config: Config| config.sites[0].redirects.clone().unwrap().remove(0);
+
+        let config = validate_redirect("/ok", false, &Options::default()).unwrap();
+        assert_eq!(redirect(config).from_field, "/ok");
+
+        let config = validate_redirect("bad", false, &Options::default()).unwrap();
+        assert_eq!(redirect(config).from_field, "/bad");
+
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        let err = validate_redirect("bad", false, &strict).err().unwrap();
+        assert!(err.to_string().contains("doesn't start with a slash"));
+
+        let config = validate_redirect("\\\\.php$", true, &strict).unwrap();
+        assert_eq!(redirect(config).from_field, "\\.php$");
+
+        let err = validate_redirect("(", true, &strict).err().unwrap();
+        assert!(err.to_string().contains("is not a valid regex"));
+    }
+
+    #[test]
+    fn test_validate_redirect_to() {
+        let validate = |to: &str| {
+            let config: UnverifiedConfig = toml::from_str(&format!(
+                r#"
+                [[sites]]
+                domain = "example.com"
+
+                [[sites.redirects]]
+                from = "/old"
+                to = "{}"
+                "#,
+                to
+            ))
+            .unwrap();
+            config.validate(&Options::default())
+        };
+
+        assert!(validate("https://example.org/new?from=old").is_ok());
+
+        let err = validate("").err().unwrap().to_string();
+        assert!(
+            err.contains("redirect from \"/old\" has an invalid target \"\": it is empty"),
+            "{}",
+            err
+        );
+        let err = validate("  ").err().unwrap().to_string();
+        assert!(err.contains("it is empty"), "{}", err);
+        let err = validate("/new page").err().unwrap().to_string();
+        assert!(
+            err.contains("invalid target \"/new page\": whitespace and control characters"),
+            "{}",
+            err
+        );
+        let err = validate("https://example.org/a\\tb")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("whitespace and control characters"), "{}", err);
+    }
+
+    #[test]
+    fn test_letsencrypt() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            letsencrypt = true
+            force_https = true
+            "#,
+        );
+        assert!(output
+            .contains("    ssl_certificate /etc/letsencrypt/live/example.com/fullchain.pem;\n"));
+        assert!(output
+            .contains("    ssl_certificate_key /etc/letsencrypt/live/example.com/privkey.pem;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            letsencrypt = true
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("letsencrypt conflicts with ssl_cert and ssl_key"));
+    }
+
+    #[test]
+    fn test_methods() {
+        let site = |fields: &str| {
+            format!(
+                "[[sites]]\ndomain = \"example.com\"\nextra_location = \"client_max_body_size 1m;\"\n{}",
+                fields
+            )
+        };
+
+        let output = render(&site(r#"method_allow = ["POST", "GET", "HEAD"]"#));
+        assert!(output.contains("        limit_except GET HEAD POST {\n            deny all;\n        }\n        client_max_body_size 1m;\n"));
+
+        let output = render(&site(r#"method_deny = ["DELETE", "PUT", "PATCH"]"#));
+        assert!(output.contains("        limit_except GET HEAD POST MKCOL COPY MOVE OPTIONS PROPFIND PROPPATCH LOCK UNLOCK {\n"));
+
+        assert!(!render(&site("")).contains("limit_except"));
+
+        let invalid = |fields: &str| {
+            let config: UnverifiedConfig = toml::from_str(&site(fields)).unwrap();
+            config
+                .validate(&Options::default())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(invalid(r#"method_allow = ["GET", "delete"]"#)
+            .contains("unknown methods [\"delete\"] in method_allow"));
+        assert!(invalid(r#"method_deny = ["HEAD"]"#)
+            .contains("HEAD can't be denied while GET is allowed"));
+        assert!(invalid(r#"method_allow = []"#).contains("method_allow leaves no method to serve"));
+        assert!(invalid("method_allow = [\"GET\"]\nmethod_deny = [\"PUT\"]")
+            .contains("method_allow and method_deny conflict"));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            aliases = ["example.net", "example-shop.com", "www.example-shop.com"]
+            "#,
+        );
+        assert!(
+            output.contains("    server_name example.net example-shop.com www.example-shop.com;\n")
+        );
+        assert!(output.contains("        return 301 https://example.com$request_uri;\n"));
+        assert_eq!(output.matches("server {").count(), 3);
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            aliases = ["example.net", "example.org"]
+            canonical = "www.example.com"
+            "#,
+        );
+        assert!(output.contains("        return 301 http://www.example.com$request_uri;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            aliases = ["example.net", "example.com", "example.org", "example.net", "nope"]
+
+            [[sites]]
+            domain = "example.org"
+            canonical = "example.com"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("alias \"example.com\" is the canonical host it redirects to"));
+        assert!(err.contains("alias \"example.org\" is the domain of a site"));
+        assert!(err.contains("alias \"example.net\" is listed more than once"));
+        assert!(err.contains("alias \"nope\" isn't a domain"));
+        assert!(err.contains("\"example.org\": canonical has no effect without aliases"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            canonical = "b.com; return 200 x"
+            aliases = ["a.com; return 200 x"]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("alias \"a.com; return 200 x\" isn't a domain"));
+        assert!(err.contains("canonical \"b.com; return 200 x\" isn't a domain"));
+    }
+
+    #[test]
+    fn test_header_order() {
+        let config = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/*"
+            values = { X-Frame-Options = "DENY", Content-Security-Policy = "default-src 'self'", Referrer-Policy = "no-referrer", X-Served-By = "{{ site.domain }}" }
+            "#;
+        let output = render(config);
+        let names: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("add_header "))
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|name| *name != "Cache-Control")
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "Content-Security-Policy",
+                "Referrer-Policy",
+                "X-Frame-Options",
+                "X-Served-By"
+            ]
+        );
+        for _ in 0..5 {
+            assert_eq!(render(config), output);
+        }
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            cache_control_default = ""
+
+            [[sites.cache_control]]
+            mime = "image/*"
+            value = "public, max-age=86400"
+
+            [[sites.cache_control]]
+            mime = "text/html"
+            value = "no-cache"
+            "#,
+        );
+        assert!(output.contains(
+            r#"map $sent_http_content_type $cacheable_types_example_com {
+    "~^image/"                           "public, max-age=86400";
+    "text/html; charset=utf-8"           "no-cache";
+    "text/html"                          "no-cache";
+    default                              "";
+}"#
+        ));
+        assert!(output.contains("add_header Cache-Control $cacheable_types_example_com;"));
+
+        let output = render("[[sites]]\ndomain = \"example.com\"");
+        assert!(output.contains("    default                              \"public\";\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            cache_control_default = 'no-cache="set-cookie"'
+            cache_control = [
+                { mime = "text/html", value = "no-cache" },
+                { mime = "TEXT/HTML", value = "no-store" },
+                { mime = "html", value = "no-cache" },
+                { mime = "*/*", value = "no-cache" },
+                { mime = "image/png", value = "" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config:
+  \"example.com\": cache_control for \"TEXT/HTML\" is listed more than once
+  \"example.com\": cache_control for \"html\": not a MIME type like text/html or image/*
+  \"example.com\": cache_control for \"*/*\": not a MIME type like text/html or image/*
+  \"example.com\": cache_control for \"image/png\": the value must be non-empty, without double quotes
+  \"example.com\": cache_control_default can't contain double quotes"
+        );
+    }
+
+    #[test]
+    fn test_immutable_assets() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            root = "/var/www/example.com"
+            immutable_assets = "/assets/"
+
+            [[sites.headers]]
+            for = "/*"
+            values = { Cache-Control = "no-cache", X-Frame-Options = "DENY" }
+            "#,
+        );
+        assert!(output.contains(
+            "        location /assets/ {
+            try_files $uri =404;
+
+            add_header Cache-Control \"public, max-age=31536000, immutable\";
+            add_header X-Frame-Options \"DENY\";
+
+            include /etc/nginx/security_headers.conf;
+        }
+"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            immutable_assets = "/"
+
+            [[sites]]
+            domain = "example.org"
+            immutable_assets = "assets"
+
+            [[sites]]
+            domain = "example.net"
+            immutable_assets = "~* \\.js$"
+
+            [[sites.headers]]
+            for = "~* \\.js$"
+            values = { X-Content-Type-Options = "nosniff" }
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config:
+  \"example.com\": immutable_assets \"/\" would cache the whole site forever, use the location of the hashed assets
+  \"example.org\": immutable_assets \"assets\": a location starts with /, @, or a modifier among =, ~, ~* and ^~
+  \"example.net\": immutable_assets and headers both have a location \"~* \\\\.js$\", move the headers into a less specific one"
+        );
+    }
+
+    #[test]
+    fn test_server_headers() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/*"
+            values = { Strict-Transport-Security = "max-age=31536000", X-Frame-Options = "DENY" }
+
+            [[sites.headers]]
+            for = "/embed/"
+            values = { X-Frame-Options = "SAMEORIGIN" }
+            "#,
+        );
+        assert!(!output.contains("location /* {"));
+        assert!(output.contains(
+            "        location /embed/ {
+            set $bucket \"cellar-c2.services.clever-cloud.com\";
+            proxy_pass https://$bucket;
+            include /etc/nginx/proxy.conf;
+
+            add_header Cache-Control $cacheable_types_example_com;
+            add_header Strict-Transport-Security \"max-age=31536000\";
+            add_header X-Frame-Options \"SAMEORIGIN\";
+"
+        ));
+        assert!(output.contains(
+            "        add_header Cache-Control $cacheable_types_example_com;
+        add_header Strict-Transport-Security \"max-age=31536000\";
+        add_header X-Frame-Options \"DENY\";
+"
+        ));
+        assert_eq!(output.matches("add_header X-Frame-Options").count(), 2);
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            openresty = true
+
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/"
+            values = {}
+            lua = [{ phase = "access", code = "" }]
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config:\n  \"example.com\": headers for \"/\" apply to the whole site, their lua goes in the lua of the site"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_headers() {
+        let config = |allow: &str| {
+            format!(
+                r#"
+                [[sites]]
+                domain = "example.com"
+
+                [[sites.headers]]
+                for = "/"
+                values = {{ Set-Cookie = "a=1", X-Frame-Options = "DENY" }}
+                {allow}
+
+                [[sites.headers]]
+                for = "/*"
+                values = {{ set-cookie = "b=2" }}
+                {allow}
+
+                [[sites.headers]]
+                for = "/api/"
+                values = {{ X-A = "1" }}
+
+                [[sites.headers]]
+                for = "/api/"
+                values = {{ X-B = "2" }}
+                "#,
+                allow = allow
+            )
+        };
+
+        let err = toml::from_str::<UnverifiedConfig>(&config(""))
+            .unwrap()
+            .validate(&Options::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config:\n  \"example.com\": headers for \"/*\" set Set-Cookie in more than one entry, set allow_duplicates = true on each of them to send every value, e.g. for Set-Cookie"
+        );
+
+        let output = render(&config("allow_duplicates = true"));
+        assert!(output.contains(
+            "        add_header Set-Cookie \"a=1\";\n        add_header X-Frame-Options \"DENY\";\n        add_header set-cookie \"b=2\";\n"
+        ));
+        assert_eq!(output.matches("location /api/ {").count(), 1);
+        assert!(output.contains(
+            "            add_header set-cookie \"b=2\";\n            add_header X-A \"1\";\n            add_header X-B \"2\";\n"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            openresty = true
+
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/api/"
+            values = { X-A = "1" }
+            lua = [{ phase = "access", code = "" }]
+
+            [[sites.headers]]
+            for = "/api/"
+            values = { X-A = "2" }
+            allow_duplicates = true
+            lua = [{ phase = "access", code = "" }]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("headers for \"/api/\" set X-A in more than one entry"));
+        assert!(err.contains("headers for \"/api/\" have lua in more than one entry"));
+
+        // A location setting a header of the whole site replaces all of its
+        // values, whatever the case of their names.
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/"
+            values = { Set-Cookie = "a=1" }
+            allow_duplicates = true
+
+            [[sites.headers]]
+            for = "/*"
+            values = { set-cookie = "b=2" }
+            allow_duplicates = true
+
+            [[sites.headers]]
+            for = "/api/"
+            values = { Set-Cookie = "c=3" }
+            "#,
+        );
+        let location = &output[output.find("location /api/ {").unwrap()..];
+        let location = &location[..location.find('}').unwrap()];
+        assert!(location.contains("add_header Set-Cookie \"c=3\";"));
+        assert!(!location.contains("a=1"));
+        assert!(!location.contains("b=2"));
+    }
+
+    #[test]
+    fn test_validate_location() {
+        for pattern in [
+            "/*",
+            "/api/",
+            "= /exact",
+            "~* \\.css$",
+            "~*\\.js$",
+            "^~ /static/",
+            "@fallback",
+        ] {
+            assert_eq!(validate_location(pattern), Ok(()), "{:?}", pattern);
+        }
+        for pattern in ["", "  ", "static", "/a b", "~ a b", "/a;", "/{x}"] {
+            assert!(validate_location(pattern).is_err(), "{:?}", pattern);
+        }
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "~* \\.css$"
+            values = { X-Content-Type-Options = "nosniff" }
+
+            [[sites.headers]]
+            for = "static"
+            values = { X-Frame-Options = "DENY" }
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid config:\n  \"example.com\": headers for \"static\": a location starts with /, @, or a modifier among =, ~, ~* and ^~"
+        );
+    }
+
+    #[test]
+    fn test_builtin_template() {
+        let template = builtin_template();
+        assert!(template.starts_with("{# vhost.template: built-in template of nvhosts "));
+        assert!(template
+            .contains(", with the filters redirect_domain, pad_right, comment, verbatim #}\n"));
+        assert!(template.ends_with(TEMPLATE));
+
+        // The comment keeps it a valid template, rendering the same.
+        let mut tera = tera();
+        tera.add_raw_template(TEMPLATE_NAME, &template).unwrap();
+        let config: UnverifiedConfig =
+            toml::from_str("[[sites]]\ndomain = \"example.com\"").unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let syntax = Syntax::for_version(&config.nginx_version);
+        assert_eq!(
+            config.sites[0]
+                .render(&mut tera, syntax, &Vars::new())
+                .unwrap(),
+            config.sites[0]
+                .render(&mut self::tera(), syntax, &Vars::new())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let path = Path::new("example.com.conf");
+        let mut calls = 0;
+        let result = with_retries(path, 2, || {
+            calls += 1;
+            match calls {
+                1 => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                2 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                _ => Ok(()),
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = with_retries(path, 2, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result = with_retries(path, 0, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_write_file_backup() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-backup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.conf");
+        let backup = dir.join("example.com.conf.bak");
+
+        write_file(&path, b"first", true, None, 0).unwrap();
+        assert!(!backup.exists());
+
+        write_file(&path, b"second", true, None, 0).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "first");
+
+        write_file(&path, b"third", false, None, 0).unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "first");
+
+        assert!(!write_file(&path, b"third", true, None, 0).unwrap());
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "first");
+        assert!(write_file(&path, b"fourth", false, None, 0).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.conf");
+
+        write_atomically(&path, b"first", None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            set_mode(&path, 0o600).unwrap();
+            write_atomically(&path, b"second", None).unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            write_atomically(&path, b"third", Some(0o644)).unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o644);
+
+            let link = dir.join("link.conf");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            write_atomically(&link, b"fourth", None).unwrap();
+            assert!(fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "fourth");
+        }
+
+        let err = write_atomically(&dir.join("missing").join("a.conf"), b"", None);
+        assert!(err.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_link_target() {
+        assert_eq!(
+            link_target(
+                Path::new("/etc/nginx/sites-available/example.com.conf"),
+                Path::new("/etc/nginx/sites-enabled")
+            ),
+            Path::new("../sites-available/example.com.conf")
+        );
+        assert_eq!(
+            link_target(
+                Path::new("/srv/nvhosts/sites-available/example.com.conf"),
+                Path::new("/etc/nginx/sites-enabled")
+            ),
+            Path::new("/srv/nvhosts/sites-available/example.com.conf")
+        );
+        assert_eq!(
+            link_target(
+                Path::new("/etc/nginx/example.com.conf"),
+                Path::new("/etc/nginx")
+            ),
+            Path::new("example.com.conf")
+        );
+    }
+
+    #[test]
+    fn test_select_profile() {
+        let config = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "dev.example.com"
+            profiles = ["dev"]
+
+            [[sites]]
+            domain = "api.example.com"
+            profiles = ["dev", "prod"]
+            http2 = false
+
+            [sites.overrides.prod]
+            http2 = true
+            healthcheck = {}
+            "#;
+        let select = |profile: Option<&str>| {
+            let mut config: UnverifiedConfig = toml::from_str(config).unwrap();
+            config.select_profile(profile).unwrap();
+            config.sites
+        };
+        let domains = |sites: &[Site]| sites.iter().map(|s| s.domain.clone()).collect::<Vec<_>>();
+
+        assert_eq!(domains(&select(None)), ["example.com"]);
+
+        let sites = select(Some("dev"));
+        assert_eq!(
+            domains(&sites),
+            ["example.com", "dev.example.com", "api.example.com"]
+        );
+        assert!(!sites[2].http2);
+
+        let sites = select(Some("prod"));
+        assert_eq!(domains(&sites), ["example.com", "api.example.com"]);
+        assert!(sites[1].http2);
+        assert!(sites[1].healthcheck.is_some());
+
+        let mut config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            overrides.prod = { http2 = "yes" }
+            "#,
+        )
+        .unwrap();
+        let err = config.select_profile(Some("prod")).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("invalid overrides for profile \"prod\""));
+    }
+
+    #[test]
+    fn test_include() {
+        let mut config: UnverifiedConfig = r#"
+            [fragments.baseline]
+            http2 = true
+            healthcheck = { path = "/ping" }
+            extra = "gzip on;"
+
+            [fragments.spa]
+            include = ["baseline"]
+            extra = "try_files $uri /index.html;"
+            healthcheck = { status = 204 }
+
+            [[sites]]
+            domain = "example.com"
+            include = ["spa"]
+            extra = "gzip off;"
+            "#
+        .parse()
+        .unwrap();
+        config.apply_includes().unwrap();
+        let site = &config.sites[0];
+        assert!(site.http2);
+        assert!(site.include.is_none());
+        assert_eq!(site.extra.as_deref(), Some("gzip off;"));
+        let healthcheck = site.healthcheck.as_ref().unwrap();
+        assert_eq!(
+            (healthcheck.path.as_str(), healthcheck.status),
+            ("/ping", 204)
+        );
+
+        // A field the site sets wins even set to its default value, and a
+        // nested table only replaces the keys it sets.
+        let mut config: UnverifiedConfig = r#"
+            [fragments.secure]
+            force_https = true
+            server_tokens = false
+            cors = { origins = ["https://example.org"], methods = ["GET"] }
+
+            [[sites]]
+            domain = "example.com"
+            include = ["secure"]
+            force_https = false
+            cors = { origins = ["https://example.net"] }
+            "#
+        .parse()
+        .unwrap();
+        config.apply_includes().unwrap();
+        let site = &config.sites[0];
+        assert!(!site.force_https);
+        assert_eq!(site.server_tokens, Some(false));
+        let cors = site.cors.as_ref().unwrap();
+        assert_eq!(cors.origins, ["https://example.net"]);
+        assert_eq!(cors.methods, ["GET"]);
+
+        let include = |fragments: &str| {
+            let mut config: UnverifiedConfig = format!(
+                "{}\n[[sites]]\ndomain = \"example.com\"\ninclude = [\"a\"]",
+                fragments
+            )
+            .parse()
+            .unwrap();
+            config.apply_includes().err().unwrap().to_string()
+        };
+        assert_eq!(
+            include("[fragments.a]\ninclude = [\"b\"]\n[fragments.b]\ninclude = [\"a\"]"),
+            "\"example.com\": invalid include: fragments include each other: a -> b -> a"
+        );
+        assert!(include("").contains("fragment \"a\" is not defined"));
+        assert!(include("[fragments.a]\ndomain = \"example.org\"").contains("can't set domain"));
+    }
+
+    #[test]
+    fn test_merge_strategy() {
+        let site = |strategy: &str| {
+            let mut config: UnverifiedConfig = format!(
+                r#"
+                [fragments.baseline]
+                redirects = [{{ from = "/old", to = "/new" }}, {{ from = "/legacy", to = "/" }}]
+
+                [[fragments.baseline.headers]]
+                for = "/*"
+                values = {{ X-Frame-Options = "DENY", X-Content-Type-Options = "nosniff" }}
+
+                [[sites]]
+                domain = "example.com"
+                include = ["baseline"]
+                {}
+                redirects = [{{ from = "/old", to = "/newer" }}, {{ from = "/blog", to = "/posts" }}]
+
+                [[sites.headers]]
+                for = "/api/*"
+                values = {{ Access-Control-Allow-Origin = "*" }}
+
+                [[sites.headers]]
+                for = "/*"
+                values = {{ X-Frame-Options = "SAMEORIGIN" }}
+                "#,
+                strategy
+            )
+            .parse()
+            .unwrap();
+            config.apply_includes().unwrap();
+            config.sites.remove(0)
+        };
+        let redirects = |site: &Site| -> Vec<(String, String)> {
+            site.redirects
+                .iter()
+                .flatten()
+                .map(|redirect| (redirect.from_field.clone(), redirect.to.clone()))
+                .collect()
+        };
+        let headers = |site: &Site| -> Vec<(String, Vec<(String, String)>)> {
+            site.headers
+                .iter()
+                .flatten()
+                .map(|header| {
+                    let mut values: Vec<(String, String)> = header
+                        .values
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.clone()))
+                        .collect();
+                    values.sort();
+                    (header.for_field.clone(), values)
+                })
+                .collect()
+        };
+        let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+
+        let appended = site("");
+        assert_eq!(
+            redirects(&appended),
+            [
+                pair("/legacy", "/"),
+                pair("/old", "/newer"),
+                pair("/blog", "/posts")
+            ]
+        );
+        assert_eq!(
+            headers(&appended),
+            [
+                (
+                    String::from("/api/*"),
+                    vec![pair("Access-Control-Allow-Origin", "*")]
+                ),
+                (
+                    String::from("/*"),
+                    vec![
+                        pair("X-Content-Type-Options", "nosniff"),
+                        pair("X-Frame-Options", "SAMEORIGIN")
+                    ]
+                ),
+            ]
+        );
+
+        let prepended = site("merge_strategy = \"prepend\"");
+        assert_eq!(
+            redirects(&prepended),
+            [
+                pair("/old", "/newer"),
+                pair("/blog", "/posts"),
+                pair("/legacy", "/")
+            ]
+        );
+        assert_eq!(headers(&prepended), headers(&appended));
+
+        let replaced = site("merge_strategy = \"replace\"");
+        assert_eq!(
+            redirects(&replaced),
+            [pair("/old", "/newer"), pair("/blog", "/posts")]
+        );
+        assert_eq!(
+            headers(&replaced)[1],
+            (
+                String::from("/*"),
+                vec![pair("X-Frame-Options", "SAMEORIGIN")]
+            )
+        );
+
+        // Other lists are replaced, not to widen what the site allows.
+        let mut config: UnverifiedConfig = r#"
+            [fragments.baseline]
+            method_allow = ["GET", "HEAD", "POST", "PUT", "DELETE"]
+            listen = ["8080"]
+
+            [[sites]]
+            domain = "example.com"
+            include = ["baseline"]
+            method_allow = ["GET", "HEAD"]
+            listen = ["8081"]
+            "#
+        .parse()
+        .unwrap();
+        config.apply_includes().unwrap();
+        let site = &config.sites[0];
+        assert_eq!(
+            site.method_allow.as_deref(),
+            Some(&[String::from("GET"), String::from("HEAD")][..])
+        );
+        assert_eq!(site.listen.as_deref(), Some(&[String::from("8081")][..]));
+
+        // `/` and `/*` are the same location, and the site's value of a
+        // header wins whatever the case of its name.
+        let mut config: UnverifiedConfig = r#"
+            [[fragments.baseline.headers]]
+            for = "/*"
+            values = { X-Frame-Options = "DENY" }
+
+            [[sites]]
+            domain = "example.com"
+            include = ["baseline"]
+
+            [[sites.headers]]
+            for = "/"
+            values = { x-frame-options = "SAMEORIGIN" }
+            "#
+        .parse()
+        .unwrap();
+        config.apply_includes().unwrap();
+        assert_eq!(
+            headers(&config.sites[0]),
+            [(
+                String::from("/"),
+                vec![pair("x-frame-options", "SAMEORIGIN")]
+            )]
+        );
+
+        let mut config: UnverifiedConfig = "[fragments.a]\nmerge_strategy = \"replace\"\n[[sites]]\ndomain = \"example.com\"\ninclude = [\"a\"]"
+        .parse()
+        .unwrap();
+        let err = config.apply_includes().err().unwrap().to_string();
+        assert!(err.contains("fragment \"a\" can't set merge_strategy"));
+    }
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("/new").is_ok());
+        assert!(check_url("https://example.com$request_uri").is_ok());
+        assert!(check_url("$scheme://example.com").is_ok());
+        assert!(check_url("htp://example.com").is_err());
+        assert!(check_url("example.com/new").is_err());
+        assert!(check_url("https://b.com/x;y").is_err());
+        assert!(check_url("$scheme://b.com/x;return").is_err());
+    }
+
+    #[test]
+    fn test_syntax_for_version() {
+        assert!(!Syntax::for_version(&Version::new(1, 24, 0)).http2_directive);
+        assert!(Syntax::for_version(&Version::new(1, 25, 1)).http2_directive);
+        assert!(Syntax::for_version(&DEFAULT_NGINX_VERSION).http2_directive);
+        assert!(Syntax::for_version(&Version::new(1, 24, 0)).http2_push);
+        assert!(!Syntax::for_version(&DEFAULT_NGINX_VERSION).http2_push);
+        assert!(!Syntax::for_version(&Version::new(1, 24, 0)).quic);
+        assert!(Syntax::for_version(&Version::new(1, 25, 0)).quic);
+    }
+
+    #[test]
+    fn test_validate_map_redirects() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[maps]]
+            variable = "mobile_redirect"
+            source = "$http_user_agent"
+
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.map_redirects]]
+            map = "mobile_redirect"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate(&Options::default()).is_ok());
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.map_redirects]]
+            map = "mobile_redirect"
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert!(err.to_string().contains("is not defined"));
+    }
+
+    #[test]
+    fn test_geos() {
+        let config = r#"
+            [[maps]]
+            variable = "mobile"
+            source = "$http_user_agent"
+
+            [[geos]]
+            variable = "blocked"
+            default = "0"
+            ranges = [{ cidr = "203.0.113.0/24", value = "1" }, { cidr = "2001:db8::/32", value = "1" }]
+
+            [[geos]]
+            variable = "office"
+            source = "$http_x_real_ip"
+            ranges = [{ cidr = "192.0.2.10", value = "1" }]
+
+            [[sites]]
+            domain = "example.com"
+            geo_deny = ["blocked"]
+            "#;
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        let http = unverified
+            .validate(&Options::default())
+            .unwrap()
+            .render_http(&mut tera())
+            .unwrap();
+        assert!(http.ends_with(
+            r#"}
+
+geo $blocked {
+    203.0.113.0/24                      "1";
+    2001:db8::/32                       "1";
+    default                             "0";
+}
+
+geo $http_x_real_ip $office {
+    192.0.2.10                          "1";
+}
+"#
+        ));
+
+        let output = render(config);
+        assert!(output.contains("\n    if ($blocked) {\n        return 403;\n    }\n"));
+
+        // Including the paths served over HTTP.
+        let output = render(&format!(
+            "{}ssl_cert = \"/etc/ssl/cert.pem\"\nssl_key = \"/etc/ssl/key.pem\"\nforce_https = true\nhttps_redirect_exclude = [\"/webhook\"]\n",
+            config
+        ));
+        assert!(output
+            .contains("\n        if ($blocked) {\n            return 403;\n        }\n    }\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[maps]]
+            variable = "blocked"
+            source = "$http_user_agent"
+
+            [[geos]]
+            variable = "blocked"
+            source = "remote_addr"
+            default = "\"0"
+            ranges = [{ cidr = "10.0.0.0/33", value = "1" }, { cidr = "10.0.0.1", value = "1" }, { cidr = "10.0.0.1", value = "0" }]
+
+            [[sites]]
+            domain = "example.com"
+            geo_deny = ["missing"]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("geo \"blocked\": defined more than once, in maps or geos"));
+        assert!(err.contains("geo \"blocked\": source \"remote_addr\" must be a variable"));
+        assert!(err.contains("invalid address range \"10.0.0.0/33\""));
+        assert!(err.contains("range \"10.0.0.1\" listed more than once"));
+        assert!(err.contains("value \"\\\"0\" can't contain double quotes"));
+        assert!(err.contains("\"example.com\": geo \"missing\" is not defined"));
+    }
+
+    #[test]
+    fn test_validate_dynamic_upstream() {
+        let config = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [sites.upstream]
+            url = "http://api:8080"
+            dynamic = true
+            "#;
+
+        let unverified: UnverifiedConfig = toml::from_str(config).unwrap();
+        let err = unverified.validate(&Options::default()).err().unwrap();
+        assert!(err.to_string().contains("requires a resolver"));
+
+        let with_resolver = format!("resolver = {{ addresses = [\"127.0.0.11\"] }}\n{}", config);
+        let unverified: UnverifiedConfig = toml::from_str(&with_resolver).unwrap();
+        assert!(unverified.validate(&Options::default()).is_ok());
+
+        let output = render(&with_resolver);
+        assert!(
+            output.contains("set $upstream \"http://api:8080\";\n        proxy_pass $upstream;")
+        );
+        assert!(!output.contains("$bucket"));
+
+        let ipv4_only = format!(
+            "resolver = {{ addresses = [\"127.0.0.11\", \"[2001:db8::53]:5353\"], valid = \"30s\", ipv6 = false }}\n{}",
+            config
+        );
+        let unverified: UnverifiedConfig = toml::from_str(&ipv4_only).unwrap();
+        let config_ok = unverified.validate(&Options::default()).unwrap();
+        let http = config_ok.render_http(&mut tera()).unwrap();
+        assert!(http.contains("resolver 127.0.0.11 [2001:db8::53]:5353 valid=30s ipv6=off;\n"));
+
+        let invalid = format!(
+            "resolver = {{ addresses = [\"[2001:db8::53]:5353\", \"dns.local\"] }}\n{}",
+            config
+        );
+        let unverified: UnverifiedConfig = toml::from_str(&invalid).unwrap();
+        let err = unverified.validate(&Options::default()).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("resolver: \"dns.local\" isn't an IP address, with an optional port"));
+        assert!(!err.to_string().contains("2001:db8::53"));
+
+        for url in [
+            "http://api:8080/",
+            "http://api:8080/v1",
+            "http://unix:/run/api.sock:/",
+        ] {
+            let with_path = with_resolver.replace("http://api:8080", url);
+            let unverified: UnverifiedConfig = toml::from_str(&with_path).unwrap();
+            let err = unverified.validate(&Options::default()).err().unwrap();
+            assert!(
+                err.to_string().contains(&format!(
+                    "dynamic upstream {:?} can't have a path, which nginx would send in place of the URI of the requests",
+                    url
+                )),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_upstream_servers() {
+        let config = |upstream: &str| {
+            format!(
+                r#"
+                [[sites]]
+                domain = "example.com"
+
+                [sites.upstream]
+                url = "http://app/api/"
+                servers = [
+                    {{ address = "10.0.0.1:3000", max_fails = 3, fail_timeout = "30s" }},
+                    {{ address = "10.0.0.2:3000", max_fails = 0 }},
+                ]
+                {}
+                "#,
+                upstream
+            )
+        };
+
+        let output = render(&config(""));
+        assert!(output.contains(
+            "upstream app {\n    server 10.0.0.1:3000 max_fails=3 fail_timeout=30s;\n    server 10.0.0.2:3000 max_fails=0;\n}\n"
+        ));
+        assert!(output.contains("        proxy_pass http://app/api/;\n"));
+        assert!(!output.contains("health_check"));
+
+        let checked = config(r#"health_check = { uri = "/healthz", interval = "5s", fails = 2 }"#);
+        let err = toml::from_str::<UnverifiedConfig>(&checked)
+            .unwrap()
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("health_check requires active_health_checks = true"),
+            "{}",
+            err
+        );
+        let output = render(&format!("active_health_checks = true\n{}", checked));
+        assert!(output.contains("upstream app {\n    zone app 64k;\n"));
+        assert!(output.contains(
+            "        proxy_pass http://app/api/;\n        health_check uri=/healthz interval=5s fails=2;\n"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.io"
+            upstream = { url = "http://php" }
+
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://app:8080", servers = [{ address = "app1", fail_timeout = "soon" }] }
+
+            [[sites]]
+            domain = "example.org"
+            upstream = { url = "php", backend_type = "fastcgi", servers = [{ address = "unix:/run/php.sock" }] }
+
+            [[sites]]
+            domain = "example.net"
+            upstream = { url = "php", backend_type = "fastcgi", servers = [] }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        for message in [
+            "\"example.com\": with servers, upstream \"http://app:8080\" must name their group",
+            "\"example.com\": server \"app1\": expected host:port or unix:/path",
+            "\"example.com\": server \"app1\": fail_timeout \"soon\" must be a duration",
+            "\"example.net\": the servers of upstream \"php\" are already those of the upstream of \"example.org\"",
+            "\"example.net\": servers can't be empty",
+            "\"example.io\": upstream \"http://php\" would pass to the servers of the upstream of \"example.org\", named alike",
+        ] {
+            assert!(err.contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_check_upstream() {
+        assert!(check_upstream("http://127.0.0.1:8080").is_ok());
+        assert!(check_upstream("https://api.example.com/v1").is_ok());
+        assert!(check_upstream("unix:/run/app.sock").is_err());
+        assert!(check_upstream("http://unix:/run/app.sock").is_ok());
+        assert!(check_upstream("http://unix:/run/app.sock:/api/").is_ok());
+        assert!(check_upstream("http://unix:run/app.sock").is_err());
+        assert!(check_upstream("http://unix:/run/app.sock:api").is_err());
+        assert!(check_upstream("http://unix:/run/my app.sock").is_err());
+        assert!(check_upstream("http://127.0.0.1:3000/ x").is_err());
+        assert!(check_upstream("http://127.0.0.1:3000/;x").is_err());
+        assert!(check_upstream("http://127.0.0.1:3000/\"x").is_err());
+        assert!(check_upstream("api:8080").is_err());
+        assert!(check_upstream("/api").is_err());
+    }
+
+    #[test]
+    fn test_unix_socket_upstream() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://unix:/run/app.sock:/" }
+            "#,
+        );
+        assert!(output.contains("        proxy_pass http://unix:/run/app.sock:/;\n"));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://unix:/run/app.sock", dynamic = true }
+            "#,
+        );
+        assert!(output.contains("        set $upstream \"http://unix:/run/app.sock\";\n"));
+    }
+
+    #[test]
+    fn test_check_address() {
+        assert!(check_address("127.0.0.1:9000").is_ok());
+        assert!(check_address("[::1]:9000").is_ok());
+        assert!(check_address("unix:/run/app.sock").is_ok());
+        assert!(check_address("unix:run/app.sock").is_err());
+        assert!(check_address("http://127.0.0.1:9000").is_err());
+        assert!(check_address("app").is_err());
+    }
+
+    #[test]
+    fn test_backend_type() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [sites.upstream]
+            url = "unix:/run/uwsgi/app.sock"
+            backend_type = "uwsgi"
+            "#,
+        );
+        assert!(
+            output.contains("uwsgi_pass unix:/run/uwsgi/app.sock;\n        include uwsgi_params;")
+        );
+        assert!(!output.contains("proxy_pass"));
+        assert!(!output.contains("proxy.conf"));
+    }
+
+    #[test]
+    fn test_validate_proxy_cache_zone() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[proxy_caches]]
+            path = "/var/cache/nginx"
+            keys_zone = "static:10m"
+
+            [[sites]]
+            domain = "example.com"
+
+            [sites.proxy_cache]
+            zone = "dynamic"
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert!(err.to_string().contains("zone \"dynamic\" is not defined"));
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "not a domain"
+            "#,
+        )
+        .unwrap();
+
+        match config.validate(&Options::default()) {
+            Err(NvhostsError::Invalid(errors)) => assert_eq!(
+                errors,
+                vec![
+                    ValidationError::DuplicateDomain {
+                        domain: String::from("example.com")
+                    },
+                    ValidationError::InvalidDomain {
+                        domain: String::from("not a domain")
+                    },
+                ]
+            ),
+            _ => panic!("expected validation errors"),
+        }
+    }
+
+    #[test]
+    fn test_http_definitions() {
+        let definitions = http_definitions(
+            "
+            # map $ignored $commented {}
+            limit_req_zone $binary_remote_addr zone=api:10m rate=10r/s;
+            map $http_upgrade $connection_upgrade {
+                default upgrade;
+                '' close;
+            }
+            geo $remote_addr $internal { default 0; 10.0.0.0/8 1; }
+            upstream backend { server 127.0.0.1:8000; }
+            proxy_cache_path /var/cache/nginx keys_zone=static:10m;
+            gzip on;
+            ",
+        );
+        assert_eq!(
+            definitions,
+            vec![
+                "zone api",
+                "variable $connection_upgrade",
+                "variable $internal",
+                "upstream backend",
+                "zone static",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extra_contexts() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            extra_http = "limit_req_zone $binary_remote_addr zone=api:10m rate=10r/s;"
+            extra_location = "limit_req zone=api burst=20;"
+
+            [[sites.headers]]
+            for = "/api"
+
+            [sites.headers.values]
+            X-Frame-Options = "DENY"
+            "#,
+        );
+        assert!(output.starts_with("limit_req_zone $binary_remote_addr zone=api:10m rate=10r/s;\n"));
+        assert_eq!(output.matches("limit_req zone=api burst=20;").count(), 2);
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[maps]]
+            variable = "internal"
+            source = "$remote_addr"
+
+            [[sites]]
+            domain = "example.com"
+            extra_http = "limit_req_zone $binary_remote_addr zone=api:10m rate=10r/s;"
+
+            [[sites]]
+            domain = "example.org"
+            extra_http = """
+            limit_req_zone $binary_remote_addr zone=api:10m rate=10r/s;
+            geo $internal { default 0; }
+            """
+            "#,
+        )
+        .unwrap();
+        let err = config.validate(&Options::default()).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("extra_http defines zone api, already defined by \"example.com\""));
+        assert!(err
+            .to_string()
+            .contains("extra_http defines variable $internal, already defined by maps"));
+    }
+
+    #[test]
+    fn test_template_dir() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("vhost.template"),
+            r#"{% extends "base/vhost.template" %}
+{% block tls %}
+    # {{ backend }} behind a TLS terminating proxy
+{% endblock tls %}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "{% not a template").unwrap();
+
+        let options = Options {
+            template_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&options).unwrap();
+        let syntax = Syntax::for_version(&config.nginx_version);
+        let output = config.sites[0]
+            .render(&mut templates(&options).unwrap(), syntax, &Vars::new())
+            .unwrap();
+
+        assert!(output.contains("    # proxy behind a TLS terminating proxy\n"));
+        assert!(!output.contains("ssl_certificate"));
+        assert!(output.contains("listen 443 ssl;"));
+
+        fs::write(dir.join("vhost.template"), "{% extends \"base/missing\" %}").unwrap();
+        assert!(matches!(
+            templates(&options),
+            Err(NvhostsError::TemplateDir { .. })
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut options = Options::default();
+        options
+            .filters
+            .register("shout", |value: &Value, _: &HashMap<String, Value>| {
+                let s = try_get_value!("shout", "value", String, value);
+                Ok(to_value(format!("{}!", s.to_uppercase())).unwrap())
+            });
+        options
+            .filters
+            .register("comment", |_: &Value, _: &HashMap<String, Value>| {
+                Ok(to_value("# replaced").unwrap())
+            });
+        assert_eq!(format!("{:?}", options.filters), r#"["shout", "comment"]"#);
+
+        let mut tera = templates(&options).unwrap();
+        let output = tera
+            .render_str(
+                "{{ 'hello' | shout }} {{ 'example.com' | redirect_domain }} {{ 'a' | comment }}",
+                &Context::new(),
+            )
+            .unwrap();
+        assert_eq!(output, "HELLO! www.example.com # replaced");
+    }
+
+    #[test]
+    fn test_check_idempotent() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "api.example.com"
+
+            [[sites.headers]]
+            for = "/"
+            values = { X-Render = "{{ 'render' | tick }}" }
+            "#,
+        )
+        .unwrap();
+
+        let mut options = Options::default();
+        options
+            .filters
+            .register("tick", |value: &Value, _: &HashMap<String, Value>| {
+                Ok(value.clone())
+            });
+        assert!(check_idempotent(config.clone(), &options)
+            .unwrap()
+            .is_none());
+
+        static TICKS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        options
+            .filters
+            .register("tick", |_: &Value, _: &HashMap<String, Value>| {
+                let tick = TICKS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(to_value(format!("render {}", tick)).unwrap())
+            });
+        let nondeterministic = check_idempotent(config, &options).unwrap().unwrap();
+        assert_eq!(nondeterministic.path, "api.example.com.conf");
+        let diff = &nondeterministic.diff;
+        assert!(diff.starts_with("--- a/api.example.com.conf\n"), "{}", diff);
+        assert!(diff.contains("\n-        add_header X-Render \"render "));
+        assert!(diff.contains("\n+        add_header X-Render \"render "));
+    }
+
+    #[test]
+    fn test_read_vars() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-vars-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let toml = dir.join("vars.toml");
+        fs::write(
+            &toml,
+            "environment = \"prod\"\nreplicas = 3\ncanary = false\n",
+        )
+        .unwrap();
+        let vars = read_vars(&toml).unwrap();
+        assert_eq!(vars["environment"], "prod");
+        assert_eq!(vars["replicas"], "3");
+        assert_eq!(vars["canary"], "false");
+
+        let json = dir.join("vars.json");
+        fs::write(&json, r#"{"region": "eu", "zones": ["a", "b"]}"#).unwrap();
+        assert_eq!(
+            read_vars(&json).err().unwrap().to_string(),
+            format!(
+                "{}: variable \"zones\" must be a string, a number or a boolean",
+                json.display()
+            )
+        );
+        fs::write(&json, r#"{"region": "eu"}"#).unwrap();
+        assert_eq!(read_vars(&json).unwrap()["region"], "eu");
+
+        assert!(matches!(
+            read_vars(&dir.join("missing.toml")),
+            Err(NvhostsError::VarsFile { .. })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_vars() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/*"
+            values = { X-Environment = "{{ environment }}" }
+            "#,
+        )
+        .unwrap();
+        let options = Options {
+            vars: Vars::from([(String::from("environment"), String::from("prod"))]),
+            ..Default::default()
+        };
+        let validated = config.clone().validate(&options).unwrap();
+        let syntax = Syntax::for_version(&validated.nginx_version);
+        let context = validated.sites[0]
+            .context(&mut tera(), syntax, &validated.vars)
+            .unwrap()
+            .into_json();
+        assert_eq!(context["environment"], "prod");
+        assert_eq!(
+            context["site"]["headers"][0]["values"]["X-Environment"],
+            "prod"
+        );
+
+        let err = |name: &str| {
+            let options = Options {
+                vars: Vars::from([(name.to_string(), String::new())]),
+                ..Default::default()
+            };
+            config.clone().validate(&options).err().unwrap().to_string()
+        };
+        assert!(err("site").contains("variable \"site\": already given to the templates"));
+        assert!(err("a-b").contains("variable \"a-b\": invalid variable name"));
+    }
+
+    #[test]
+    fn test_render_errors_inline() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-inline-{}", std::process::id()));
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            hooks = [{ command = "false" }]
+
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "bad.example.com"
+            charset = "utf 8"
+
+            [[sites]]
+            domain = "template.example.com"
+
+            [[sites.headers]]
+            for = "/"
+            values = { X-Test = "{{ missing }}" }
+            "#,
+        )
+        .unwrap();
+        let options = Options {
+            output_dir: Some(dir.clone()),
+            render_errors_inline: true,
+            ..Default::default()
+        };
+        let err = run(config.clone(), &options).err().unwrap();
+        let message = err.to_string();
+        assert!(
+            message.starts_with("2 sites written as placeholders:\n  \"bad.example.com\": charset"),
+            "{}",
+            message
+        );
+        assert!(message.contains("\n  \"template.example.com\": couldn't render header X-Test"));
+
+        assert!(fs::read_to_string(dir.join("example.com.conf"))
+            .unwrap()
+            .contains("server_name example.com;"));
+        let placeholder = fs::read_to_string(dir.join("bad.example.com.conf")).unwrap();
+        assert!(placeholder
+            .lines()
+            .all(|line| line.is_empty() || line.starts_with('#')));
+        assert!(placeholder.contains(
+            "# bad.example.com couldn't be generated, nginx serves nothing of it:\n# \"bad.example.com\": charset \"utf 8\""
+        ));
+        assert!(dir.join("template.example.com.conf").exists());
+
+        // Without the option, nothing is written.
+        fs::remove_dir_all(&dir).unwrap();
+        let err = run(
+            config,
+            &Options {
+                render_errors_inline: false,
+                ..options
+            },
+        );
+        assert!(matches!(err, Err(NvhostsError::Invalid(_))));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_local_access() {
+        let config: UnverifiedConfig = "[[sites]]\ndomain = \"example.com\"".parse().unwrap();
+        assert!(config.local_access().is_empty());
+
+        let config: UnverifiedConfig = r#"
+            sites_dir = "sites"
+            hooks = [{ command = "systemctl reload nginx" }]
+
+            [[sites]]
+            domain = "example.com"
+            extra = "${file:/etc/passwd}"
+            "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.local_access(),
+            ["hooks", "sites_dir", "sites[0].extra"]
+        );
+    }
+
+    #[test]
+    fn test_run_profiles() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-profiles-{}", std::process::id()));
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "dev.example.com"
+            profiles = ["dev"]
+
+            [[sites]]
+            domain = "api.example.com"
+            profiles = ["dev", "prod"]
+
+            [sites.overrides.prod]
+            server_tokens = false
+            "#,
+        )
+        .unwrap();
+        let options = Options {
+            output_dir: Some(dir.join("sites-available")),
+            enabled_dir: Some(dir.join("sites-enabled")),
+            ..Default::default()
+        };
+        let profiles = [String::from("dev"), String::from("prod")];
+        assert!(run_profiles(config.clone(), &options, &profiles).unwrap());
+
+        let files = |profile: &str| {
+            let mut files: Vec<String> = fs::read_dir(dir.join("sites-available").join(profile))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| !name.starts_with('.'))
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            files("dev"),
+            [
+                "api.example.com.conf",
+                "dev.example.com.conf",
+                "example.com.conf"
+            ]
+        );
+        assert_eq!(files("prod"), ["api.example.com.conf", "example.com.conf"]);
+        let prod =
+            fs::read_to_string(dir.join("sites-available/prod/api.example.com.conf")).unwrap();
+        assert!(prod.contains("server_tokens off;"));
+        assert!(dir.join("sites-enabled/dev/dev.example.com.conf").exists());
+
+        assert!(!run_profiles(config, &options, &profiles).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unmanaged_files() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-unmanaged-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_file(
+            &dir.join("a.conf"),
+            with_banner("").as_bytes(),
+            false,
+            None,
+            0,
+        )
+        .unwrap();
+        fs::write(dir.join("b.conf"), "server {}").unwrap();
+        fs::write(dir.join("b.conf.bak"), "server {}").unwrap();
+        write_file(&dir.join("c/vhost.conf"), b"server {}", false, None, 0).unwrap();
+
+        fs::write(dir.join("d.vhost"), "server {}").unwrap();
+        fs::write(dir.join("example.org"), "server {}").unwrap();
+
+        assert_eq!(
+            unmanaged_files(&dir, &BTreeSet::from(["conf"])).unwrap(),
+            vec![dir.join("b.conf"), dir.join("c").join("vhost.conf")]
+        );
+        assert_eq!(
+            unmanaged_files(&dir, &BTreeSet::from(["vhost"])).unwrap(),
+            vec![dir.join("d.vhost")]
+        );
+        assert_eq!(
+            unmanaged_files(&dir, &BTreeSet::from([""])).unwrap(),
+            vec![
+                dir.join("b.conf"),
+                dir.join("c").join("vhost.conf"),
+                dir.join("d.vhost"),
+                dir.join("example.org")
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filename() {
+        let validate = |filename: &str| {
+            let config: UnverifiedConfig = toml::from_str(&format!(
+                "filename = {:?}\n[[sites]]\ndomain = \"example.com\"\n[[sites]]\ndomain = \"example.org\"",
+                filename
+            ))
+            .unwrap();
+            config.validate(&Options::default())
+        };
+
+        let config = validate("{{ site.domain }}/vhost.conf").unwrap();
+        assert_eq!(config.sites[0].filename(), "example.com/vhost.conf");
+        let config = validate("10-{{ site.domain }}.conf").unwrap();
+        assert_eq!(config.sites[1].filename(), "10-example.org.conf");
+
+        let err = |filename: &str| validate(filename).err().unwrap().to_string();
+        assert!(err("vhost.conf").contains("\"vhost.conf\" is also the one of \"example.com\""));
+        assert!(err("../{{ site.domain }}.conf").contains("must be a relative path"));
+        assert!(err("{{ site.domain }}").contains("must end with .conf"));
+        assert!(err("{{ site.nope }}.conf").contains("couldn't render filename"));
+    }
+
+    #[test]
+    fn test_priority() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            filename = "{{ site.priority }}-{{ site.domain }}.conf"
+
+            [[sites]]
+            domain = "_"
+            priority = 90
+
+            [[sites]]
+            domain = "example.org"
+
+            [[sites]]
+            domain = "api.example.com"
+            priority = 10
+
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+
+        let files = dry_run(config.clone(), &Options::default()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "0-example.com.conf",
+                "0-example.org.conf",
+                "10-api.example.com.conf",
+                "90-_.conf"
+            ]
+        );
+
+        let combined = Options {
+            output: Output::Combined(String::from("all.conf")),
+            ..Default::default()
+        };
+        let files = dry_run(config, &combined).unwrap();
+        let sections: Vec<&str> = files[0]
+            .1
+            .lines()
+            .filter(|line| line.starts_with("# ") && !line.starts_with("# -") && *line != BANNER)
+            .collect();
+        assert_eq!(
+            sections,
+            ["# example.com", "# example.org", "# api.example.com", "# _"]
+        );
+
+        let config: UnverifiedConfig = toml::from_str(
+            "[[sites]]\ndomain = \"example.org\"\n[[sites]]\ndomain = \"example.com\"",
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        assert_eq!(config.sites[0].domain, "example.org");
+    }
+
+    #[test]
+    fn test_extension() {
+        let validate = |config: &str| {
+            let config: UnverifiedConfig = toml::from_str(config).unwrap();
+            config.validate(&Options::default())
+        };
+
+        let config = validate(
+            r#"
+            extension = "vhost"
+
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "example.org"
+            extension = ""
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.sites[0].filename(), "example.com.vhost");
+        assert_eq!(config.sites[1].filename(), "example.org");
+
+        let config = validate(
+            "extension = \"vhost\"\nfilename = \"10-{{ site.domain }}.vhost\"\n[[sites]]\ndomain = \"example.com\"",
+        )
+        .unwrap();
+        assert_eq!(config.sites[0].filename(), "10-example.com.vhost");
+
+        let err = |config: &str| validate(config).err().unwrap().to_string();
+        assert!(err("extension = \".vhost\"").contains("must not start with a dot, e.g. \"vhost\""));
+        assert!(
+            err("[[sites]]\ndomain = \"example.com\"\nextension = \"v host\"")
+                .contains("must only have letters, digits, - and _")
+        );
+        assert!(err(
+            "extension = \"vhost\"\nfilename = \"{{ site.domain }}.conf\"\n[[sites]]\ndomain = \"example.com\""
+        )
+        .contains("must end with .vhost"));
+    }
+
+    #[test]
+    fn test_file_mode() {
+        assert_eq!(parse_file_mode("0644"), Ok(0o644));
+        assert_eq!(parse_file_mode("0o640"), Ok(0o640));
+        assert!(parse_file_mode("0999").is_err());
+        assert!(parse_file_mode("17777").is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = std::env::temp_dir().join(format!("nvhosts-mode-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("example.com.conf");
+            write_file(&path, b"", false, Some(0o640), 0).unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o640);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_log_formats() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[log_formats]]
+            name = "json"
+
+            [[log_formats]]
+            name = "short"
+            format = "$remote_addr $status"
+
+            [[sites]]
+            domain = "example.com"
+            access_log = { path = "/var/log/nginx/example.com.log", format = "json" }
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let http = config.render_http(&mut tera()).unwrap();
+        assert!(http.contains(&format!(
+            "log_format json escape=json '{}';\n",
+            JSON_LOG_FORMAT
+        )));
+        assert!(http.contains("log_format short '$remote_addr $status';\n"));
+
+        let output = config.sites[0]
+            .render(
+                &mut tera(),
+                Syntax::for_version(&config.nginx_version),
+                &config.vars,
+            )
+            .unwrap();
+        assert!(output.contains("    access_log /var/log/nginx/example.com.log json;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[log_formats]]
+            name = "main"
+
+            [[sites]]
+            domain = "example.com"
+            access_log = { path = "/var/log/nginx/example.com.log", format = "missing" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("log format \"main\": format is required"));
+        assert!(err.contains("log format \"missing\" is not defined"));
+    }
+
+    #[test]
+    fn test_error_log() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            error_log_level = "warn"
+            "#,
+        );
+        assert!(output.contains("    error_log /var/log/nginx/error.log warn;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            error_log = "/var/log/nginx/example.com.error.log"
+
+            [[sites]]
+            domain = "example.org"
+            "#,
+        )
+        .unwrap();
+        let options = Options {
+            debug_site: Some(String::from("example.com")),
+            ..Default::default()
+        };
+        let rendered = config
+            .clone()
+            .validate(&options)
+            .unwrap()
+            .render_map(&options)
+            .unwrap();
+        assert!(rendered["example.com"]
+            .contains("    error_log /var/log/nginx/example.com.error.log debug;\n"));
+        assert!(!rendered["example.org"].contains("error_log"));
+
+        let options = Options {
+            debug_site: Some(String::from("example.net")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(&options),
+            Err(NvhostsError::UnknownSite { .. })
+        ));
+
+        let err = toml::from_str::<UnverifiedConfig>(
+            "[[sites]]\ndomain = \"example.com\"\nerror_log_level = \"verbose\"",
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("unknown variant `verbose`"), "{}", err);
+    }
+
+    #[test]
+    fn test_access_log_conditions() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "my-app.example.com"
+            access_log = { path = "/var/log/nginx/app.log", sample = "10%", skip_paths = ["/metrics"], skip_healthchecks = true }
+            "#,
+        );
+        assert!(output.starts_with(
+            r#"split_clients $request_id $log_sample_my_app_example_com {
+    10% 1;
+    * 0;
+}
+
+map $uri $loggable_my_app_example_com {
+    default $log_sample_my_app_example_com;
+    "/metrics" 0;
+    "/health" 0;
+    "/healthz" 0;
+    "/livez" 0;
+    "/readyz" 0;
+    "/ping" 0;
+}
+"#
+        ));
+        assert!(output
+            .contains("    access_log /var/log/nginx/app.log if=$loggable_my_app_example_com;\n"));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            access_log = { path = "/var/log/nginx/example.com.log", sample = "0.5%" }
+            "#,
+        );
+        assert!(!output.contains("map $uri"));
+        assert!(output.contains(
+            "    access_log /var/log/nginx/example.com.log if=$log_sample_example_com;\n"
+        ));
+
+        let output = render(
+            r#"
+            [[maps]]
+            variable = "loggable"
+            source = "$status"
+            default = "1"
+            entries = [{ match = "~^[23]", value = "0" }]
+
+            [[sites]]
+            domain = "example.com"
+            access_log = { path = "/var/log/nginx/example.com.log", condition = "$loggable" }
+            "#,
+        );
+        assert!(!output.contains("split_clients"));
+        assert!(output.contains("    access_log /var/log/nginx/example.com.log if=$loggable;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            access_log = { path = "/var/log/nginx/example.com.log", sample = "100%", skip_paths = ["health"] }
+
+            [[sites]]
+            domain = "example.org"
+            access_log = { path = "/var/log/nginx/example.org.log", condition = "$missing", skip_healthchecks = true }
+
+            [[sites]]
+            domain = "example.net"
+            access_log = { path = "/var/log/nginx/example.net.log", sample = "0.125%" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("sample \"100%\" must be a percentage between 0 and 100"));
+        assert!(err.contains(
+            "sample \"0.125%\" must be a percentage between 0 and 100 with at most two decimals"
+        ));
+        assert!(err.contains("skip path \"health\" must start with /"));
+        assert!(err.contains("condition \"$missing\" must be the variable of one of maps"));
+        assert!(err.contains("condition can't be combined with sample"));
+    }
+
+    #[test]
+    fn test_split() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "app.example.com"
+            upstream = { url = "http://10.0.0.1:8080" }
+            split = { targets = [{ percent = "10%", url = "http://10.0.0.2:8080" }, { percent = "0.5%", url = "http://unix:/run/canary.sock" }] }
+            "#,
+        );
+        assert!(output.starts_with(
+            r#"split_clients "$remote_addr" $split_upstream_app_example_com {
+    10% http://10.0.0.2:8080;
+    0.5% http://unix:/run/canary.sock;
+    * http://10.0.0.1:8080;
+}
+"#
+        ));
+        assert!(output.contains("\n        proxy_pass $split_upstream_app_example_com;\n"));
+        assert!(!output.contains("proxy_pass http://10.0.0.1:8080"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://stable.internal:8080/app" }
+            split = { key = "remote_addr", targets = [{ percent = "60%", url = "http://10.0.0.2:8080" }, { percent = "40%", url = "http://10.0.0.3:8080/" }] }
+
+            [[sites]]
+            domain = "example.org"
+            root = "/var/www/example.org"
+            split = { targets = [{ percent = "-5%", url = "ftp://10.0.0.2" }] }
+
+            [[sites]]
+            domain = "example.net"
+            upstream = { url = "http://stable.internal:8080" }
+            split = { targets = [{ percent = "5%", url = "http://10.0.0.2:8080" }] }
+
+            [[sites]]
+            domain = "example.io"
+            upstream = { url = "http://10.0.0.1:8080" }
+            split = { targets = [{ percent = "0.125%", url = "http://10.0.0.2:8080" }, { percent = "1e1%", url = "http://10.0.0.3:8080" }] }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("key \"remote_addr\" must contain a variable"));
+        assert!(err.contains("the targets get 100% of the traffic"));
+        assert!(err.contains(
+            "invalid upstream \"http://stable.internal:8080/app\": a split upstream can't have a path"
+        ));
+        assert!(err.contains(
+            "invalid upstream \"http://10.0.0.3:8080/\": a split upstream can't have a path"
+        ));
+        assert!(err.contains("requires a proxied upstream"));
+        assert!(err.contains("percent \"-5%\" of \"ftp://10.0.0.2\" must be a percentage above 0"));
+        assert!(err.contains("percent \"0.125%\" of \"http://10.0.0.2:8080\" must be a percentage above 0 with at most two decimals"));
+        assert!(err.contains("percent \"1e1%\" of \"http://10.0.0.3:8080\" must be a percentage"));
+        assert!(err.contains("unsupported scheme \"ftp\""));
+        assert!(err.contains("upstream host \"stable.internal\" requires a resolver"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "api.example.com"
+            upstream = { url = "http://10.0.0.1:8080" }
+            rate_limit = { rate = "10r/s", burst = 20, nodelay = true, status = 429, dry_run = true }
+            "#,
+        );
+        assert!(output.starts_with(
+            "limit_req_zone $binary_remote_addr zone=rate_limit_api_example_com:10m rate=10r/s;\n"
+        ));
+        assert!(output.contains(
+            "\n    limit_req zone=rate_limit_api_example_com burst=20 nodelay;\n    limit_req_status 429;\n    limit_req_dry_run on;\n"
+        ));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            rate_limit = { rate = "30r/m" }
+            "#,
+        );
+        assert!(output.contains("\n    limit_req zone=rate_limit_example_com;\n"));
+        assert!(!output.contains("limit_req_dry_run"));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            https_redirect_exclude = ["/webhook"]
+            rate_limit = { rate = "30r/m" }
+            "#,
+        );
+        assert!(output.contains(
+            "        include /etc/nginx/proxy.conf;\n\n        limit_req zone=rate_limit_example_com;\n    }\n\n    location / {\n        return 301"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            nginx_version = "1.16.1"
+
+            [[sites]]
+            domain = "example.com"
+            rate_limit = { rate = "10/s", key = "remote_addr", zone_size = "lots", status = 200, dry_run = true }
+            extra_http = "limit_req_zone $binary_remote_addr zone=rate_limit_example_com:1m rate=1r/s;"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("rate \"10/s\" must be requests per second or minute"));
+        assert!(err.contains("key \"remote_addr\" must contain a variable"));
+        assert!(err.contains("zone_size \"lots\" must be a size"));
+        assert!(err.contains("status 200 must be between 400 and 599"));
+        assert!(err.contains("dry_run requires nginx 1.17.1 or later, nginx_version is 1.16.1"));
+        assert!(err.contains(
+            "extra_http defines zone rate_limit_example_com, already defined by the rate_limit of \"example.com\""
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "a-b.example.com"
+            rate_limit = { rate = "10r/s" }
+
+            [[sites]]
+            domain = "a.b.example.com"
+            rate_limit = { rate = "10r/s" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains(
+            "\"a.b.example.com\": rate_limit defines zone rate_limit_a_b_example_com, already defined by the rate_limit of \"a-b.example.com\""
+        ));
+    }
+
+    #[test]
+    fn test_block_user_agents() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            block_user_agents = ["AhrefsBot", "^curl/", "python-requests"]
+            "#,
+        );
+        assert!(output.starts_with(
+            "map $http_user_agent $blocked_user_agent_example_com {
+    default 0;
+    \"~*AhrefsBot\" 1;
+    \"~*^curl/\" 1;
+    \"~*python-requests\" 1;
+}
+"
+        ));
+        assert!(output.contains(
+            "\n    if ($blocked_user_agent_example_com) {\n        return 444;\n    }\n"
+        ));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            block_user_agents = ["SemrushBot"]
+            block_user_agents_status = 403
+            "#,
+        );
+        assert!(output.contains("\n        return 403;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            block_user_agents = ["", "Bot\"; return 200", "(unclosed"]
+            block_user_agents_status = 200
+            extra_http = "map $http_user_agent $blocked_user_agent_example_com { default 0; }"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("user agent pattern \"\" can't be empty"));
+        assert!(err.contains(
+            "user agent pattern \"Bot\\\"; return 200\" can't contain double quotes or control characters"
+        ));
+        assert!(err.contains("user agent pattern \"(unclosed\" is not a valid regex"));
+        assert!(err.contains("block_user_agents_status 200 must be between 400 and 599"));
+        assert!(err.contains(
+            "extra_http defines variable $blocked_user_agent_example_com, already defined by the block_user_agents of \"example.com\""
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "a-b.example.com"
+            block_user_agents = ["AhrefsBot"]
+
+            [[sites]]
+            domain = "a.b.example.com"
+            block_user_agents = ["AhrefsBot"]
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            https_redirect_exclude = ["/webhook"]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains(
+            "\"a.b.example.com\": block_user_agents defines variable $blocked_user_agent_a_b_example_com, already defined by the block_user_agents of \"a-b.example.com\""
+        ));
+
+        // The paths served over HTTP deny them as well.
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            block_user_agents = ["AhrefsBot"]
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            https_redirect_exclude = ["/webhook"]
+            "#,
+        );
+        assert!(output.contains(
+            "\n        if ($blocked_user_agent_example_com) {\n            return 444;\n        }\n    }\n"
+        ));
+    }
+
+    #[test]
+    fn test_cors() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "api.example.com"
+            upstream = { url = "http://127.0.0.1:3000" }
+            cors = { origins = ["https://app.example.com", "http://localhost:8080"], methods = ["GET", "POST", "PUT"], headers = ["Authorization", "Content-Type"], expose_headers = ["X-Request-Id"], max_age = 86400, credentials = true }
+            "#,
+        );
+        assert!(output.starts_with(
+            "map $http_origin $cors_origin_api_example_com {
+    default \"\";
+    \"https://app.example.com\" $http_origin;
+    \"http://localhost:8080\" $http_origin;
+}
+"
+        ));
+        // The preflight gets the headers of the responses, which add_header in
+        // the if doesn't inherit, and its own.
+        assert!(output.contains(
+            "
+        if ($request_method = OPTIONS) {
+            add_header Access-Control-Allow-Credentials \"true\" always;
+            add_header Access-Control-Allow-Headers \"Authorization, Content-Type\" always;
+            add_header Access-Control-Allow-Methods \"GET, POST, PUT\" always;
+            add_header Access-Control-Allow-Origin \"$cors_origin_api_example_com\" always;
+            add_header Access-Control-Max-Age \"86400\" always;
+            add_header Vary \"Origin\" always;
+            return 204;
+        }
+        add_header Access-Control-Allow-Credentials \"true\" always;
+        add_header Access-Control-Allow-Origin \"$cors_origin_api_example_com\" always;
+        add_header Access-Control-Expose-Headers \"X-Request-Id\" always;
+        add_header Vary \"Origin\" always;
+"
+        ));
+        assert!(!output.contains("# add_header"));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            root = "/var/www/example.com"
+            cors = { origins = ["*"] }
+
+            [[sites.headers]]
+            for = "/fonts/"
+            values = { X-Content-Type-Options = "nosniff" }
+            "#,
+        );
+        assert!(!output.contains("map $http_origin"));
+        assert!(!output.contains("Vary"));
+        assert_eq!(
+            output
+                .matches("add_header Access-Control-Allow-Methods \"GET, HEAD, POST\" always;")
+                .count(),
+            2
+        );
+        assert_eq!(
+            output
+                .matches("add_header Access-Control-Allow-Origin \"*\" always;")
+                .count(),
+            4
+        );
+        assert!(!output.contains("Access-Control-Max-Age"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            cors = { origins = ["*", "https://app.example.com/", "ftp://example.org"], methods = ["FETCH"], headers = ["X Bad"], credentials = true }
+            extra_http = "map $http_origin $cors_origin_example_com { default \"\"; }"
+
+            [[sites.headers]]
+            for = "/*"
+            values = { access-control-allow-origin = "*" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("origins can't list \"*\" along with other origins"));
+        assert!(err.contains("origin \"https://app.example.com/\" must be a scheme and a host"));
+        assert!(err.contains("origin \"ftp://example.org\" must be a scheme and a host"));
+        assert!(err.contains("credentials require listed origins"));
+        assert!(err.contains("unknown methods [\"FETCH\"]"));
+        assert!(err.contains("header \"X Bad\" must be a header name"));
+        assert!(err.contains("headers for \"/*\" set access-control-allow-origin, which cors sets"));
+        assert!(!err.contains("extra_http defines"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            cors = { origins = ["https://app.example.com"] }
+            extra_http = "map $http_origin $cors_origin_example_com { default \"\"; }"
+
+            [[sites.headers]]
+            for = "/api/"
+            values = { Vary = "Accept-Encoding" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains(
+            "extra_http defines variable $cors_origin_example_com, already defined by the cors of \"example.com\""
+        ));
+        assert!(err.contains("headers for \"/api/\" set Vary, which cors sets"));
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "127.0.0.1:9000", backend_type = "fastcgi" }
+            proxy_intercept_errors = true
+
+            [[sites.error_pages]]
+            codes = [502, 503, 504]
+            page = "/var/www/errors/50x.html"
+
+            [[sites.error_pages]]
+            codes = [404]
+            page = "/404.html"
+            "#,
+        );
+        assert!(output.contains(
+            "
+    fastcgi_intercept_errors on;
+    error_page 502 503 504 @error_page_1;
+    error_page 404 @error_page_2;
+
+    location @error_page_1 {
+        root /var/www/errors;
+        try_files /50x.html =502;
+    }
+
+    location @error_page_2 {
+        root /;
+        try_files /404.html =404;
+    }
+"
+        ));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            error_pages = [{ codes = [404], page = "/var/www/404.html" }]
+            "#,
+        );
+        assert!(output.contains("\n    error_page 404 @error_page_1;\n"));
+        assert!(!output.contains("intercept_errors"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            maintenance_page = "/var/www/maintenance.html"
+            error_pages = [
+                { codes = [], page = "/var/www/empty.html" },
+                { codes = [200, 404, 503], page = "errors/404.html" },
+                { codes = [404], page = "/var/www/404.html" },
+            ]
+
+            [[sites]]
+            domain = "api.example.com"
+            proxy_intercept_errors = true
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("error page \"/var/www/empty.html\" must have codes"));
+        assert!(err.contains("error page code 200 must be between 300 and 599"));
+        assert!(err.contains("error page code 503 conflicts with maintenance_page"));
+        assert!(err.contains("error page \"errors/404.html\" must be the absolute path of a file"));
+        assert!(err.contains("error page code 404 is given more than once"));
+        assert!(err.contains("\"api.example.com\": proxy_intercept_errors requires error_pages"));
+    }
+
+    #[test]
+    fn test_template_errors() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/*"
+
+            [sites.headers.values]
+            X-Served-By = "{{ site.nope }}"
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let err = config.sites[0]
+            .render(
+                &mut tera(),
+                Syntax::for_version(&config.nginx_version),
+                &config.vars,
+            )
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            &err,
+            NvhostsError::Template { domain: Some(domain), .. } if domain == "example.com"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "\"example.com\": couldn't render header X-Served-By for \"/*\": Variable `site.nope` not found in context"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            server_tokens = false
+            file_mode = "0o640"
+
+            [[sites]]
+            domain = "example.com"
+            profiles = ["prod"]
+
+            [sites.overrides.prod]
+            http2 = true
+
+            [[sites]]
+            domain = "example.org"
+            "#,
+        )
+        .unwrap();
+        let options = Options {
+            profile: Some(String::from("prod")),
+            ..Default::default()
+        };
+
+        let resolved = resolve(config.clone(), &options, Some("example.com")).unwrap();
+        let value: toml::Value = toml::from_str(&resolved).unwrap();
+        assert_eq!(value["file_mode"].as_str(), Some("0640"));
+        let sites = value["sites"].as_array().unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0]["http2"].as_bool(), Some(true));
+        assert_eq!(sites[0]["server_tokens"].as_bool(), Some(false));
+        assert!(sites[0].get("overrides").is_none());
+
+        assert!(matches!(
+            resolve(config, &options, Some("example.net")),
+            Err(NvhostsError::UnknownSite { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mtls() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "internal.example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            mtls = { client_ca = "/etc/ssl/clients.pem", verify = "optional", verify_depth = 2 }
+            "#,
+        );
+        assert!(output.contains(
+            "    ssl_client_certificate /etc/ssl/clients.pem;
+    ssl_verify_client optional;
+    ssl_verify_depth 2;
+"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "internal.example.com"
+            mtls = { client_ca = "" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("mtls requires ssl_cert and ssl_key"));
+        assert!(err.contains("mtls requires a client_ca"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "internal.example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            mtls = { client_ca = "/etc/ssl/clients.pem" }
+
+            [[sites]]
+            domain = "admin.example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            force_https = true
+            https_redirect_exclude = ["/webhook"]
+            mtls = { client_ca = "/etc/ssl/clients.pem" }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("\"internal.example.com\": mtls requires force_https"));
+        assert!(err.contains("\"admin.example.com\": mtls conflicts with https_redirect_exclude"));
+    }
+    #[test]
+    fn test_maintenance() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "my-app.example.com"
+            maintenance = true
+            maintenance_page = "/var/www/maintenance.html"
+            maintenance_allow = ["203.0.113.7", "10.0.0.0/8"]
+            "#,
+        );
+        assert!(output.contains(
+            "geo $maintenance_my_app_example_com {
+    default 1;
+    203.0.113.7 0;
+    10.0.0.0/8 0;
+}"
+        ));
+        assert!(output.contains(
+            "    error_page 503 @maintenance;
+    if ($maintenance_my_app_example_com) {
+        return 503;
+    }
+
+    location @maintenance {
+        root /var/www;
+        try_files /maintenance.html =503;
+    }
+"
+        ));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            maintenance = true
+            "#,
+        );
+        assert!(output.contains("    include /etc/nginx/general.conf;\n\n    return 503;\n"));
+        assert!(!output.contains("geo "));
+        assert!(!output.contains("error_page"));
+
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            maintenance = true
+            maintenance_allow = ["10.0.0.0/8"]
+            acme_webroot = "/var/www/acme"
+            healthcheck = { path = "/healthz" }
+            "#,
+        );
+        assert!(output.contains("geo $maintenance_example_com_allow {\n"));
+        assert!(output.contains(
+            "map $uri $maintenance_example_com {
+    default $maintenance_example_com_allow;
+    ~^/\\.well-known/acme-challenge/ 0;
+    /healthz 0;
+}"
+        ));
+        assert!(
+            output.contains("    if ($maintenance_example_com) {\n        return 503;\n    }\n")
+        );
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            maintenance = true
+            maintenance_page = "maintenance.html"
+            maintenance_allow = ["10.0.0.0/33", "localhost"]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("must be the absolute path of a file"));
+        assert!(err.contains("invalid maintenance_allow address \"10.0.0.0/33\""));
+        assert!(err.contains("invalid maintenance_allow address \"localhost\""));
+    }
+    #[test]
+    fn test_special_server_names() {
+        for domain in ["_", "localhost", "192.168.1.10"] {
+            let output = render(&format!("[[sites]]\ndomain = {:?}", domain));
+            assert!(output.contains(&format!("server_name {};", domain)));
+            assert!(!output.contains("www."), "{}", output);
+        }
+
+        for domain in ["localhost.", "192.168.1", "::1", "__"] {
+            let config: UnverifiedConfig =
+                toml::from_str(&format!("[[sites]]\ndomain = {:?}", domain)).unwrap();
+            assert!(config.validate(&Options::default()).is_err(), "{}", domain);
+        }
+    }
+    #[test]
+    fn test_nameless_default_server() {
+        let output = render(
+            r#"
+            [[sites]]
+            domain = "_"
+            default_server = true
+            maintenance = true
+            "#,
+        );
+        assert!(output.contains("    listen 8080 default_server;\n"));
+        assert!(output.contains("    server_name _;\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "_"
+            "#,
+        )
+        .unwrap();
+        let (_, warnings) = validate(config, &Options::default()).unwrap();
+        assert_eq!(
+            warnings,
+            [Warning::site(
+                WarningKind::NoEffect,
+                "_",
+                String::from(
+                    "the domain \"_\" matches no host, set default_server to answer the hosts no other site serves"
+                ),
+            )]
+        );
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "_"
+            default_server = true
+            letsencrypt = true
+            www_redirect = true
+
+            [[sites]]
+            domain = "example.com"
+            default_server = true
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("letsencrypt can't issue a certificate for \"_\""));
+        assert!(err.contains("www_redirect needs a domain"));
+        assert!(
+            err.contains("\"example.com\": default_server on 8080, [::]:8080 is also set by \"_\"")
+        );
+    }
+
+    #[test]
+    fn test_lua() {
+        let output = render(
+            r#"
+            openresty = true
+
+            [[sites]]
+            domain = "example.com"
+            upstream = { url = "http://127.0.0.1:8000" }
+
+            [[sites.lua]]
+            phase = "content"
+            code = """local name = ngx.var.arg_name or "{{ world }}"
+            if name == "" then
+
+              return ngx.exit(400)
+            end
+            ngx.say("hello, ", name)"""
+
+            [[sites.headers]]
+            for = "/api/"
+            values = {}
+
+            [[sites.headers.lua]]
+            phase = "access"
+            code = "ngx.exit(403)"
+            "#,
+        );
+        assert!(output.contains(
+            "    location / {
+        location /api/ {
+            proxy_pass http://127.0.0.1:8000;
+            include /etc/nginx/proxy.conf;
+
+            access_by_lua_block {
+                ngx.exit(403)
+            }
+"
+        ));
+        assert!(output.contains(
+            "
+        content_by_lua_block {
+            local name = ngx.var.arg_name or \"{{ world }}\"
+            if name == \"\" then
+
+              return ngx.exit(400)
+            end
+            ngx.say(\"hello, \", name)
+        }
+"
+        ));
+        assert_eq!(output.matches("proxy_pass").count(), 1);
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            lua = [
+                { phase = "log", code = "" },
+                { phase = "log", code = "" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("lua of location \"/\" requires openresty = true"));
+        assert!(err.contains("location \"/\" has more than one lua block for phase \"log\""));
+    }
+    #[test]
+    fn test_real_ip() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            real_ip = { preset = "cloudflare" }
+
+            [[sites]]
+            domain = "example.com"
+            real_ip = { from = ["10.0.0.0/8", "unix:"], header = "X-Forwarded-For", recursive = true }
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let http = config.render_http(&mut tera()).unwrap();
+        assert!(http.starts_with("set_real_ip_from 173.245.48.0/20;\n"));
+        assert!(
+            http.contains("set_real_ip_from 2c0f:f248::/32;\nreal_ip_header CF-Connecting-IP;\n")
+        );
+        assert_eq!(
+            http.matches("set_real_ip_from").count(),
+            CLOUDFLARE_RANGES.len()
+        );
+
+        let output = config.sites[0]
+            .render(
+                &mut tera(),
+                Syntax::for_version(&config.nginx_version),
+                &config.vars,
+            )
+            .unwrap();
+        assert!(output.contains(
+            "    server_name example.com;
+
+    set_real_ip_from 10.0.0.0/8;
+    set_real_ip_from unix:;
+    real_ip_header X-Forwarded-For;
+    real_ip_recursive on;
+"
+        ));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            real_ip = { header = "X-Real-IP" }
+
+            [[sites]]
+            domain = "example.com"
+            real_ip = { from = ["cdn.example.com"] }
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("real_ip: at least one trusted address is required in from"));
+        assert!(err.contains("\"example.com\": real_ip: invalid address \"cdn.example.com\""));
+    }
+
+    #[test]
+    fn test_render_map() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "example.org"
+            redirect_to = "https://example.com$request_uri"
+            "#,
+        )
+        .unwrap();
+        let rendered = render_map(config, &Options::default()).unwrap();
+        assert_eq!(rendered.len(), 2);
+        assert!(rendered["example.com"].contains("server_name example.com;"));
+        assert!(rendered["example.org"].contains("return 301 https://example.com$request_uri;"));
+        assert!(!Path::new(OUTPUT_DIR).join("example.org.conf").exists());
+    }
+
+    #[test]
+    fn test_dump_context() {
+        let config = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/"
+            values = { X-Site = "{{ site.domain }}" }
+
+            [[sites]]
+            domain = "example.org"
+            redirect_to = "https://example.com"
+            "#;
+        let context: serde_json::Value = serde_json::from_str(
+            &dump_context(
+                toml::from_str(config).unwrap(),
+                &Options::default(),
+                Some("example.com"),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(context["site"]["domain"], "example.com");
+        assert_eq!(context["site"]["server_headers"]["X-Site"], "example.com");
+        assert_eq!(context["nginx"]["http2_directive"], true);
+
+        let contexts: serde_json::Value = serde_json::from_str(
+            &dump_context(toml::from_str(config).unwrap(), &Options::default(), None).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            contexts["example.org"]["site"]["redirect_to"],
+            "https://example.com"
+        );
+
+        let err = dump_context(
+            toml::from_str(config).unwrap(),
+            &Options::default(),
+            Some("example.net"),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "\"example.net\": no such site");
+    }
+
+    #[test]
+    fn test_for_each_rendered() {
+        let mut toml = String::new();
+        for i in 0..PARALLEL_SITES + 2 {
+            toml.push_str(&format!("[[sites]]\ndomain = \"site{}.example.com\"\n", i));
+        }
+        let config: UnverifiedConfig = toml::from_str(&toml).unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+
+        for jobs in [1, 4] {
+            let options = Options {
+                jobs: Some(jobs),
+                ..Default::default()
+            };
+            let mut rendered = Vec::new();
+            config
+                .for_each_rendered(&options, |domain, content| {
+                    rendered.push((domain.to_string(), content.to_string()));
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(rendered.len(), PARALLEL_SITES + 2);
+            assert_eq!(rendered[1].0, "site1.example.com");
+            assert!(rendered[1].1.contains("server_name site1.example.com;"));
+            assert!(!rendered[1].1.starts_with(BANNER));
+        }
+
+        let mut calls = 0;
+        let err = config
+            .for_each_rendered(&Options::default(), |domain, _| {
+                calls += 1;
+                Err(NvhostsError::UnknownSite {
+                    domain: domain.to_string(),
+                })
+            })
+            .err()
+            .unwrap();
+        assert_eq!(calls, 1);
+        assert!(err.to_string().contains("site0.example.com"), "{}", err);
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let unchanged = String::from("unchanged.changed-files.example.com.conf");
+        let files = [
+            (unchanged.clone(), String::from("server {}\n")),
+            (
+                String::from("new.changed-files.example.com.conf"),
+                String::from("server {}\n"),
+            ),
+        ];
+        fs::create_dir_all(OUTPUT_DIR).unwrap();
+        let path = Path::new(OUTPUT_DIR).join(&unchanged);
+        fs::write(&path, "server {}\n").unwrap();
+
+        assert_eq!(
+            changed_files(&files, &Options::default()),
+            ["new.changed-files.example.com.conf"]
+        );
+        let elsewhere = Options {
+            output_dir: Some(std::env::temp_dir().join("nvhosts-changed-files-elsewhere")),
+            ..Default::default()
+        };
+        assert_eq!(changed_files(&files, &elsewhere).len(), 2);
+        fs::write(&path, "server { listen 8080; }\n").unwrap();
+        assert_eq!(changed_files(&files, &Options::default()).len(), 2);
+
+        let diffs = diff_files(&files, &Options::default());
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[0].0, unchanged);
+        assert!(diffs[0]
+            .1
+            .ends_with("@@ -1,1 +1,1 @@\n-server { listen 8080; }\n+server {}\n"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_diff_renders() {
+        let render = |config: &str| {
+            let config: UnverifiedConfig = toml::from_str(config).unwrap();
+            dry_run(config, &Options::default()).unwrap()
+        };
+        let old = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "old.example.com"
+            "#,
+        );
+        let new = render(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            server_tokens = false
+
+            [[sites]]
+            domain = "new.example.com"
+            "#,
+        );
+
+        let diffs = diff_renders(&old, &new);
+        let paths: Vec<&str> = diffs.iter().map(|(path, _)| *path).collect();
+        assert_eq!(
+            paths,
+            [
+                "example.com.conf",
+                "new.example.com.conf",
+                "old.example.com.conf"
+            ]
+        );
+        assert!(diffs[0].1.contains("\n+    server_tokens off;\n"));
+        assert!(diffs[1].1.contains("\n+    server_name new.example.com;\n"));
+        assert!(diffs[2].1.contains("\n-    server_name old.example.com;\n"));
+        assert!(diff_renders(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_patch() {
+        let unchanged = String::from("unchanged.patch.example.com.conf");
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            resolver = { addresses = ["127.0.0.1"] }
+
+            [[sites]]
+            domain = "unchanged.patch.example.com"
+
+            [[sites]]
+            domain = "new.patch.example.com"
+
+            [[sites]]
+            domain = "limited.patch.example.com"
+            rate_limit = { rate = "10r/s" }
+            "#,
+        )
+        .unwrap();
+        let files = dry_run(config, &Options::default()).unwrap();
+        fs::create_dir_all(OUTPUT_DIR).unwrap();
+        let path = Path::new(OUTPUT_DIR).join(&unchanged);
+        let (_, content) = files.iter().find(|(file, _)| *file == unchanged).unwrap();
+        fs::write(&path, content).unwrap();
+
+        let patched = patch(&files, &Options::default());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(patched.sites, ["new.patch.example.com.conf"]);
+        assert_eq!(
+            patched.unpatched,
+            [HTTP_FILENAME, "limited.patch.example.com.conf"]
+        );
+        let content = patched.content;
+        assert!(content.starts_with(&format!("{}\n\n# ---", BANNER)));
+        assert!(content.contains("# new.patch.example.com.conf\n"));
+        assert!(content.contains("server_name new.patch.example.com;"));
+        assert!(!content.contains("unchanged.patch.example.com"));
+        assert_eq!(content.matches(BANNER).count(), 1);
+
+        assert_eq!(
+            patch(&[], &Options::default()).content,
+            format!("{}\n\n", BANNER)
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            main = { worker_connections = 4096 }
+
+            [[sites]]
+            domain = "dry-run.example.com"
+            "#,
+        )
+        .unwrap();
+
+        let files = dry_run(config.clone(), &Options::default()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [MAIN_FILENAME, HTTP_FILENAME, "dry-run.example.com.conf"]
+        );
+        assert!(files[2].1.starts_with(BANNER));
+        assert!(!Path::new(OUTPUT_DIR)
+            .join("dry-run.example.com.conf")
+            .exists());
+
+        let options = Options {
+            output: Output::Combined(String::from("all.conf")),
+            ..Default::default()
+        };
+        let files = dry_run(config, &options).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].0, "all.conf");
+        assert!(files[1].1.contains("# dry-run.example.com\n"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let err = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites]]
+            domain = "example.org"
+            http2 = "yes"
+            "#
+        .parse::<UnverifiedConfig>()
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "sites[1] \"example.org\": invalid type: string \"yes\", expected a boolean for key `sites[1].http2` at line 7 column 21"
+        );
+
+        let err = "nginx_version = 1\n[[sites]]\ndomain = \"example.com\""
+            .parse::<UnverifiedConfig>()
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("invalid type: integer `1`"));
+        assert!("[[sites]]\ndomain = \"example.com\""
+            .parse::<UnverifiedConfig>()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_catch_all() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            catch_all = { ssl_cert = "/etc/ssl/certs/ssl-cert-snakeoil.pem", ssl_key = "/etc/ssl/private/ssl-cert-snakeoil.key" }
+
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let catch_all = config.render_catch_all(&mut tera()).unwrap();
+        assert!(catch_all.starts_with("server {\n    listen 443 ssl default_server;\n"));
+        assert!(catch_all.contains("    ssl_certificate /etc/ssl/certs/ssl-cert-snakeoil.pem;\n"));
+        assert!(catch_all.ends_with("    return 444;\n}\n"));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            catch_all = { ssl_cert = "", ssl_key = "/etc/ssl/private/key.pem" }
+
+            [[sites]]
+            domain = "_"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("catch_all: ssl_cert and ssl_key are required"));
+        assert!(err.contains("catch_all and the site \"_\" conflict"));
+    }
+
+    #[test]
+    fn test_main() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [main]
+            worker_connections = 4096
+            multi_accept = true
+            sendfile = true
+            tcp_nopush = true
+
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        let main = config.render_main(&mut tera()).unwrap();
+        assert_eq!(
+            main,
+            "events {\n    worker_connections 4096;\n    multi_accept on;\n}\n"
+        );
+        let http = config.render_http(&mut tera()).unwrap();
+        assert_eq!(http, "sendfile on;\ntcp_nopush on;\n");
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            main = { worker_connections = 0, tcp_nopush = true }
+
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        let err = config.validate(&strict).err().unwrap().to_string();
+        assert!(err.contains("worker_connections must be positive"));
+        assert!(err.contains("tcp_nopush has no effect without sendfile = true"));
+    }
+
+    #[test]
+    fn test_streams() {
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[streams]]
+            listen = 5432
+            upstream = { url = "db", servers = [{ address = "db1.internal:5432" }, { address = "db2.internal:5432", max_fails = 3 }] }
+            proxy_timeout = "1h"
+
+            [[streams]]
+            listen = 53
+            protocol = "udp"
+            upstream = { url = "127.0.0.1:5353" }
+
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+        let config = config.validate(&Options::default()).unwrap();
+        assert_eq!(
+            config.render_stream(&mut tera()).unwrap(),
+            "stream {
+    upstream db {
+        server db1.internal:5432;
+        server db2.internal:5432 max_fails=3;
+    }
+
+    server {
+        listen 5432;
+        listen [::]:5432;
+        proxy_pass db;
+        proxy_timeout 1h;
+    }
+
+    server {
+        listen 53 udp;
+        listen [::]:53 udp;
+        proxy_pass 127.0.0.1:5353;
+    }
+}
+"
+        );
+        let files = config.render_files(&Options::default()).unwrap();
+        assert_eq!(files[0].0, STREAM_FILENAME);
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[streams]]
+            listen = 0
+            upstream = { url = "db.internal" }
+
+            [[streams]]
+            listen = 8080
+            upstream = { url = "unix:/run/app.sock" }
+            proxy_timeout = "soon"
+
+            [[streams]]
+            listen = 8080
+            protocol = "udp"
+            upstream = { url = "db", servers = [{ address = "db1" }] }
+
+            [[streams]]
+            listen = 8080
+            protocol = "udp"
+            upstream = { url = "db", servers = [], dynamic = true }
+
+            [[streams]]
+            listen = 443
+            upstream = { url = "127.0.0.1:8443", backend_type = "fastcgi" }
+
+            [catch_all]
+            ssl_cert = "/etc/ssl/certs/ssl-cert-snakeoil.pem"
+            ssl_key = "/etc/ssl/private/ssl-cert-snakeoil.key"
+
+            [[sites]]
+            domain = "example.com"
+            "#,
+        )
+        .unwrap();
+        let err = config
+            .validate(&Options::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("streams: port 0/tcp: listen must be a port between 1 and 65535"),
+            "{}",
+            err
+        );
+        assert!(err.contains(
+            "streams: port 0/tcp: invalid upstream \"db.internal\": expected host:port or unix:/path"
+        ));
+        assert!(err.contains(
+            "streams: port 8080/tcp: the servers of the sites listen on it, nginx would refuse to start"
+        ));
+        assert!(err.contains("streams: port 8080/tcp: proxy_timeout \"soon\" must be a duration"));
+        assert!(err.contains("streams: port 8080/udp: another stream listens on it"));
+        assert!(err.contains("streams: port 8080/udp: server \"db1\": expected host:port"));
+        assert!(err.contains(
+            "streams: port 8080/udp: the servers of upstream \"db\" are already those of the stream on port 8080"
+        ));
+        assert!(err.contains("streams: port 8080/udp: servers can't be empty"));
+        assert!(err.contains("streams: port 8080/udp: dynamic doesn't apply to streams"));
+        assert!(err.contains(
+            "streams: port 443/tcp: the servers of the sites listen on it, nginx would refuse to start"
+        ));
+        assert!(err.contains("streams: port 443/tcp: backend_type doesn't apply to streams"));
     }
 }
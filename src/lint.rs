@@ -0,0 +1,926 @@
+//! Advisory checks of a valid config. Unlike validation, lints flag configs
+//! that work but are likely to be risky or redundant.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+
+use regex::Regex;
+
+use crate::{
+    is_server_location, BackendType, Config, NvhostsError, Options, Redirect, Site, TlsProfile,
+    UnverifiedConfig,
+};
+
+/// Headers telling the upstream about the client, which `proxy_pass` doesn't
+/// send by itself.
+const FORWARDED_HEADERS: &[&str] = &["X-Forwarded-For", "X-Forwarded-Proto"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Lint {
+    pub severity: Severity,
+    pub domain: String,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.domain, self.message)
+    }
+}
+
+/// The location serving the ACME challenges of `acme_webroot`.
+const ACME_LOCATION: &str = "^~ /.well-known/acme-challenge/";
+
+/// Protocols with known weaknesses, deprecated by RFC 8996.
+const OUTDATED_PROTOCOLS: &[&str] = &["SSLv2", "SSLv3", "TLSv1", "TLSv1.1"];
+
+/// Names of the files of a certificate that don't tell its domain, e.g. the
+/// ones of a Let's Encrypt lineage.
+const CERTIFICATE_FILES: &[&str] = &["fullchain", "cert", "chain", "privkey", "server"];
+
+/// Prefixes of the files of a certificate that stand for the `*.` of a
+/// wildcard one, e.g. `wildcard.example.com.pem`.
+const WILDCARD_PREFIXES: &[&str] = &["*.", "_.", "wildcard.", "star."];
+
+/// Parts of OpenSSL cipher names that denote broken ciphers or no
+/// encryption or authentication at all.
+const WEAK_CIPHERS: &[&str] = &[
+    "NULL", "EXPORT", "RC4", "DES", "MD5", "aNULL", "ADH", "AECDH",
+];
+
+/// Validates `config` and lints every site, most severe lints first.
+pub fn lint(config: UnverifiedConfig, options: &Options) -> Result<Vec<Lint>, NvhostsError> {
+    let config = config.validate(options)?;
+
+    let mut lints = lint_config(&config, options);
+    lints.sort_by_key(|lint| Reverse(lint.severity));
+    Ok(lints)
+}
+
+/// Validates `config` and checks the TLS settings of every HTTPS site against
+/// the intermediate profile. The lints are errors under `--strict`.
+pub fn lint_tls(config: UnverifiedConfig, options: &Options) -> Result<Vec<Lint>, NvhostsError> {
+    let config = config.validate(options)?;
+
+    let severity = if options.strict {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    let mut lints = Vec::new();
+    for site in config.sites.iter().filter(|site| site.ssl_cert.is_some()) {
+        let mut push = |message: String| {
+            lints.push(Lint {
+                severity,
+                domain: site.domain.clone(),
+                message,
+            })
+        };
+        let baseline = match site.tls_profile {
+            Some(TlsProfile::Modern) => "tls_profile \"modern\"",
+            Some(TlsProfile::Intermediate) => "tls_profile \"intermediate\"",
+            Some(TlsProfile::Old) => "tls_profile \"old\"",
+            None => "no tls_profile",
+        };
+        let tls = site.tls.as_ref();
+
+        match tls.and_then(|tls| tls.protocols.as_deref()) {
+            None => push(format!(
+                "{} and no ssl_protocols, the protocols depend on the nginx version, set tls_profile = \"intermediate\"",
+                baseline
+            )),
+            Some(protocols) => {
+                let outdated: Vec<&str> = protocols
+                    .split_whitespace()
+                    .filter(|protocol| OUTDATED_PROTOCOLS.contains(protocol))
+                    .collect();
+                if !outdated.is_empty() {
+                    push(format!(
+                        "{} enables {}, set tls_profile = \"intermediate\" or ssl_protocols = \"TLSv1.2 TLSv1.3\" unless very old clients must be served",
+                        baseline,
+                        outdated.join(" and ")
+                    ));
+                }
+            }
+        }
+
+        if let Some(ciphers) = tls.and_then(|tls| tls.ciphers.as_deref()) {
+            let weak: Vec<&str> = ciphers
+                .split(':')
+                .filter(|cipher| !cipher.starts_with('!') && !cipher.starts_with('-'))
+                .filter(|cipher| {
+                    cipher
+                        .trim_start_matches('+')
+                        .split('-')
+                        .any(|part| WEAK_CIPHERS.iter().any(|weak| part.starts_with(weak)))
+                })
+                .collect();
+            if !weak.is_empty() {
+                push(format!(
+                    "{} allows the weak ciphers {}, remove them or drop ssl_ciphers to use the ciphers of tls_profile = \"intermediate\"",
+                    baseline,
+                    weak.join(", ")
+                ));
+            }
+        }
+
+        if site.redirect_to.is_none() && !has_header(site, "Strict-Transport-Security") {
+            push(String::from(
+                "no Strict-Transport-Security header, add one for \"/*\" such as \"max-age=63072000\" so that browsers stick to HTTPS",
+            ));
+        }
+    }
+
+    Ok(lints)
+}
+
+fn lint_config(config: &Config, options: &Options) -> Vec<Lint> {
+    let mut lints = Vec::new();
+
+    for site in config.sites.iter() {
+        let mut push = |severity, message: String| {
+            lints.push(Lint {
+                severity,
+                domain: site.domain.clone(),
+                message,
+            })
+        };
+
+        for header in site.headers.iter().flatten() {
+            let mut names = HashMap::new();
+            for name in header.values.keys() {
+                if let Some(other) = names.insert(name.to_lowercase(), name) {
+                    push(
+                        Severity::Warning,
+                        format!(
+                            "headers for {:?} set both {:?} and {:?}, which is the same header",
+                            header.for_field, other, name
+                        ),
+                    );
+                }
+            }
+
+            let value = |name: &str| {
+                header
+                    .values
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.trim())
+            };
+            if value("Access-Control-Allow-Origin") == Some("*")
+                && value("Access-Control-Allow-Credentials") == Some("true")
+            {
+                push(
+                    Severity::Error,
+                    format!(
+                        "headers for {:?} allow credentials from any origin",
+                        header.for_field
+                    ),
+                );
+            }
+        }
+
+        if site.ssl_cert.is_some()
+            && site.redirect_to.is_none()
+            && !has_header(site, "Strict-Transport-Security")
+        {
+            push(
+                Severity::Warning,
+                String::from("HTTPS site without a Strict-Transport-Security header"),
+            );
+        }
+
+        if site.server_tokens != Some(false) {
+            push(
+                Severity::Info,
+                String::from("server_tokens isn't turned off, responses show the nginx version"),
+            );
+        }
+
+        let extra = site.extra.as_deref().unwrap_or_default();
+        for line in extra.lines().map(str::trim) {
+            let always = line.trim_end_matches(';').ends_with(" always");
+            if line.starts_with("add_header ") && !always {
+                push(
+                    Severity::Info,
+                    format!(
+                        "{:?} in extra isn't sent on error responses, add `always`",
+                        line
+                    ),
+                );
+            }
+        }
+
+        // Nested locations inherit the proxy_set_header of `location /`, from
+        // the proxy params of the upstream, unless they set their own.
+        let inherited = site.root.is_none()
+            && site
+                .upstream
+                .as_ref()
+                .map_or(BackendType::Proxy, |u| u.backend_type)
+                == BackendType::Proxy;
+        let fields = [
+            ("extra", &site.extra),
+            ("extra_location", &site.extra_location),
+        ];
+        for (field, directives) in fields {
+            for location in locations(directives.as_deref().unwrap_or_default()) {
+                let has = |name: &str| location.directives.iter().any(|d| d[0] == name);
+                if !has("proxy_pass") || has("include") || (inherited && !has("proxy_set_header")) {
+                    continue;
+                }
+                let missing: Vec<&str> = FORWARDED_HEADERS
+                    .iter()
+                    .copied()
+                    .filter(|header| {
+                        !location.directives.iter().any(|d| {
+                            d[0] == "proxy_set_header"
+                                && d.get(1)
+                                    .is_some_and(|name| name.eq_ignore_ascii_case(header))
+                        })
+                    })
+                    .collect();
+                if missing.is_empty() {
+                    continue;
+                }
+                push(
+                    if options.strict {
+                        Severity::Error
+                    } else {
+                        Severity::Warning
+                    },
+                    format!(
+                        "{} in {} proxies without setting {}, the upstream sees the wrong client",
+                        location.name,
+                        field,
+                        missing.join(" nor ")
+                    ),
+                );
+            }
+        }
+
+        // The other locations of the site, with where they are defined.
+        let mut defined = Vec::new();
+        for header in site.headers.iter().flatten() {
+            if !is_server_location(&header.for_field) {
+                defined.push((
+                    header.for_field.clone(),
+                    format!("headers for {:?}", header.for_field),
+                ));
+            }
+        }
+        if let Some(healthcheck) = &site.healthcheck {
+            defined.push((
+                format!("= {}", healthcheck.path),
+                format!("healthcheck {:?}", healthcheck.path),
+            ));
+        }
+        if site.acme_webroot.is_some() {
+            defined.push((String::from(ACME_LOCATION), String::from("acme_webroot")));
+        }
+        for (field, directives) in fields {
+            for location in locations(directives.as_deref().unwrap_or_default()) {
+                let pattern = location.name.trim_start_matches("location").trim();
+                defined.push((
+                    pattern.to_string(),
+                    format!("{} in {}", location.name, field),
+                ));
+            }
+        }
+        for redirect in site.redirects.iter().flatten() {
+            for (pattern, definition) in defined.iter() {
+                if overlaps(redirect, pattern) {
+                    push(
+                        if options.strict {
+                            Severity::Error
+                        } else {
+                            Severity::Warning
+                        },
+                        format!(
+                            "redirect from {:?} overlaps {}, which of them answers depends on the precedence of nginx locations, not on the order of the config",
+                            redirect.from_field, definition
+                        ),
+                    );
+                }
+            }
+        }
+
+        for mismatch in certificate_mismatches(site) {
+            push(Severity::Warning, mismatch);
+        }
+
+        let paths = literal_paths(site);
+        for (i, (path, definition)) in paths.iter().enumerate() {
+            for (other, other_definition) in paths[i + 1..].iter() {
+                if path != other && path.trim_end_matches('/') == other.trim_end_matches('/') {
+                    push(
+                        Severity::Warning,
+                        format!(
+                            "{} and {} only differ by a trailing slash, requests for one of them don't match the other",
+                            definition, other_definition
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    lints
+}
+
+/// The paths of `site` matched or redirected to literally, apart from `/`,
+/// with where they are defined.
+fn literal_paths(site: &Site) -> Vec<(String, String)> {
+    let mut paths = Vec::new();
+    for redirect in site.redirects.iter().flatten() {
+        if !redirect.regex {
+            paths.push((
+                redirect.from_field.clone(),
+                format!("redirect from {:?}", redirect.from_field),
+            ));
+            if redirect.to.starts_with('/') && !redirect.to.contains(['?', '$']) {
+                paths.push((
+                    redirect.to.clone(),
+                    format!("redirect to {:?}", redirect.to),
+                ));
+            }
+        }
+    }
+    for header in site.headers.iter().flatten() {
+        if let PathMatch::Exact(path) | PathMatch::Prefix(path) =
+            PathMatch::parse(&header.for_field)
+        {
+            if !is_server_location(&header.for_field) {
+                paths.push((
+                    path.to_string(),
+                    format!("headers for {:?}", header.for_field),
+                ));
+            }
+        }
+    }
+    if let Some(healthcheck) = &site.healthcheck {
+        paths.push((
+            healthcheck.path.clone(),
+            format!("healthcheck {:?}", healthcheck.path),
+        ));
+    }
+    for path in site.https_redirect_exclude.iter().flatten() {
+        paths.push((path.clone(), format!("https_redirect_exclude {:?}", path)));
+    }
+    paths.retain(|(path, _)| path.starts_with('/') && path != "/");
+    paths
+}
+
+/// A `location` block of free-form directives, with the directives of its own
+/// level, each split in words.
+struct Location {
+    /// The location and its modifier, e.g. `location ~ \.php$`.
+    name: String,
+    directives: Vec<Vec<String>>,
+}
+
+/// The `location` blocks of `directives`, nested ones included.
+fn locations(directives: &str) -> Vec<Location> {
+    let directives: String = directives
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut locations = Vec::new();
+    // The blocks being read, `None` for the ones other than locations.
+    let mut blocks: Vec<Option<Location>> = Vec::new();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in directives.chars() {
+        if !(c.is_whitespace() || c == ';' || c == '{' || c == '}') {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+
+        match c {
+            ';' if !words.is_empty() => {
+                if let Some(Some(location)) = blocks.last_mut() {
+                    location.directives.push(std::mem::take(&mut words));
+                }
+                words.clear();
+            }
+            '{' => {
+                blocks.push(match words.first().map(String::as_str) {
+                    Some("location") => Some(Location {
+                        name: words.join(" "),
+                        directives: Vec::new(),
+                    }),
+                    _ => None,
+                });
+                words.clear();
+            }
+            '}' => {
+                if let Some(Some(location)) = blocks.pop() {
+                    locations.push(location);
+                }
+                words.clear();
+            }
+            _ => {}
+        }
+    }
+
+    locations
+}
+
+/// How a `location` matches request paths.
+enum PathMatch<'a> {
+    Exact(&'a str),
+    Prefix(&'a str),
+    Regex(&'a str, bool),
+}
+
+impl<'a> PathMatch<'a> {
+    /// `pattern`, with its modifier if any, as written after `location`.
+    fn parse(pattern: &'a str) -> Self {
+        let (modifier, path) = pattern
+            .split_once(char::is_whitespace)
+            .unwrap_or(("", pattern));
+        let path = path.trim();
+        match modifier {
+            "=" => Self::Exact(path),
+            "^~" => Self::Prefix(path),
+            "~" => Self::Regex(path, false),
+            "~*" => Self::Regex(path, true),
+            _ => Self::Prefix(pattern),
+        }
+    }
+}
+
+fn regex(pattern: &str, case_insensitive: bool) -> Option<Regex> {
+    let pattern = if case_insensitive {
+        format!("(?i){}", pattern)
+    } else {
+        pattern.to_string()
+    };
+    Regex::new(&pattern).ok()
+}
+
+/// Whether some request path can match both the location of `redirect` and
+/// the location `pattern`, apart from `location /` that holds them all.
+/// Regular expressions nginx accepts but the `regex` crate doesn't are
+/// assumed not to overlap.
+fn overlaps(redirect: &Redirect, pattern: &str) -> bool {
+    let location = PathMatch::parse(pattern);
+    if !redirect.regex {
+        let from = redirect.from_field.as_str();
+        return match location {
+            PathMatch::Exact(path) => from == path,
+            PathMatch::Prefix(path) => path != "/" && from.starts_with(path),
+            PathMatch::Regex(pattern, case_insensitive) => {
+                regex(pattern, case_insensitive).is_some_and(|re| re.is_match(from))
+            }
+        };
+    }
+
+    let from = match regex(&redirect.from_field, false) {
+        Some(from) => from,
+        None => return false,
+    };
+    match location {
+        PathMatch::Exact(path) => from.is_match(path),
+        PathMatch::Prefix(path) => {
+            // The literal start of an anchored expression, e.g. `/app/` for
+            // `^/app/(.*)$`.
+            let literal: String = redirect
+                .from_field
+                .strip_prefix('^')
+                .unwrap_or_default()
+                .chars()
+                .take_while(|c| !".*+?()[]{}|\\$".contains(*c))
+                .collect();
+            path != "/"
+                && (from.is_match(path)
+                    || literal.starts_with(path)
+                    || (!literal.is_empty() && path.starts_with(&literal)))
+        }
+        PathMatch::Regex(pattern, _) => pattern == redirect.from_field,
+    }
+}
+
+/// The names of `site` that its certificate, going by the domain in the path
+/// of `ssl_cert`, doesn't cover, with wildcards: a wildcard name with a
+/// certificate for a single domain, or a name beyond the wildcard of the
+/// certificate, such as the bare domain. The certificate may list other
+/// names, so this is only a hint.
+pub(crate) fn certificate_mismatches(site: &Site) -> Vec<String> {
+    let Some(path) = &site.ssl_cert else {
+        return Vec::new();
+    };
+    let Some(certificate) = certificate_name(path) else {
+        return Vec::new();
+    };
+
+    let mut names = vec![site.domain.clone()];
+    for name in site
+        .server_names
+        .iter()
+        .flatten()
+        .filter(|name| !name.regex)
+    {
+        match name.name.strip_prefix('.') {
+            // `.example.com` also matches `example.com`.
+            Some(domain) => names.extend([domain.to_string(), format!("*.{}", domain)]),
+            None if name.name.ends_with(".*") => {}
+            None => names.push(name.name.clone()),
+        }
+    }
+
+    let mut mismatches = Vec::new();
+    for name in names {
+        let covered = match certificate.strip_prefix("*.") {
+            Some(parent) => name
+                .split_once('.')
+                .is_some_and(|(label, rest)| rest == parent && !label.is_empty()),
+            None => !name.starts_with("*."),
+        };
+        let mismatch = format!(
+            "ssl_cert {:?} looks like a certificate for {:?}, which doesn't cover {:?} unless it lists it too",
+            path, certificate, name
+        );
+        if !covered && !mismatches.contains(&mismatch) {
+            mismatches.push(mismatch);
+        }
+    }
+    mismatches
+}
+
+/// The domain a certificate is for, going by its path: the name of its file
+/// or of a directory, such as the one of a Let's Encrypt lineage, with `*.`
+/// for a wildcard one. None when no part of the path is a domain.
+fn certificate_name(path: &str) -> Option<String> {
+    for component in path.rsplit('/') {
+        let mut name = component;
+        for extension in [".pem", ".crt", ".cer"] {
+            name = name.strip_suffix(extension).unwrap_or(name);
+        }
+        if CERTIFICATE_FILES.contains(&name) {
+            continue;
+        }
+        // Let's Encrypt numbers the lineages of a name it already has.
+        if let Some((lineage, number)) = name.rsplit_once('-') {
+            if number.len() == 4 && number.chars().all(|c| c.is_ascii_digit()) {
+                name = lineage;
+            }
+        }
+        let (wildcard, domain) = match WILDCARD_PREFIXES
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix))
+        {
+            Some(domain) => (true, domain),
+            None => (false, name),
+        };
+        if is_domain(domain) {
+            return Some(match wildcard {
+                true => format!("*.{}", domain),
+                false => domain.to_string(),
+            });
+        }
+    }
+    None
+}
+
+/// Whether `name` has at least two labels of letters, digits and `-`, the
+/// last one of letters only.
+fn is_domain(name: &str) -> bool {
+    let labels: Vec<&str> = name.split('.').collect();
+    labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+pub(crate) fn has_header(site: &Site, name: &str) -> bool {
+    site.headers.iter().flatten().any(|header| {
+        header
+            .values
+            .keys()
+            .any(|key| key.eq_ignore_ascii_case(name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lints(config: &str) -> Vec<Lint> {
+        let config: UnverifiedConfig = toml::from_str(config).unwrap();
+        lint(config, &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn test_lint() {
+        let lints = lints(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+            extra = """
+            add_header X-Served-By "edge";
+            add_header X-Cache $upstream_cache_status always;
+            location /api {
+                proxy_set_header Host api.internal;
+                proxy_set_header x-forwarded-for $proxy_add_x_forwarded_for;
+                proxy_pass http://127.0.0.1:8000;
+            }
+            """
+
+            [[sites.headers]]
+            for = "/api/*"
+
+            [sites.headers.values]
+            Access-Control-Allow-Origin = "*"
+            Access-Control-Allow-Credentials = "true"
+            X-Frame-Options = "DENY"
+            x-frame-options = "DENY"
+            "#,
+        );
+
+        let found: Vec<(Severity, &str)> = lints
+            .iter()
+            .map(|lint| (lint.severity, lint.message.as_str()))
+            .collect();
+        assert_eq!(found.len(), 6, "{:?}", found);
+        assert_eq!(found[0].0, Severity::Error);
+        assert!(found[0].1.contains("allow credentials from any origin"));
+        assert!(found
+            .iter()
+            .any(|(_, m)| m.contains("which is the same header")));
+        assert!(found
+            .iter()
+            .any(|(_, m)| m.contains("Strict-Transport-Security")));
+        assert!(found.iter().any(|(_, m)| m.contains("X-Served-By")));
+        assert!(found.iter().any(|(_, m)| m
+            == &"location /api in extra proxies without setting X-Forwarded-Proto, the upstream sees the wrong client"));
+        assert!(found.iter().any(|(_, m)| m.contains("server_tokens")));
+    }
+
+    #[test]
+    fn test_lint_forwarded_headers() {
+        let site = |upstream: &str, extra_location: &str| {
+            format!(
+                "server_tokens = false\n[[sites]]\ndomain = \"example.com\"\n{}\nextra_location = {:?}",
+                upstream, extra_location
+            )
+        };
+        let proxied = "location /ws { proxy_pass http://127.0.0.1:9000; }";
+
+        assert!(lints(&site("", proxied)).is_empty());
+        let fastcgi = r#"upstream = { url = "127.0.0.1:9000", backend_type = "fastcgi" }"#;
+        let found = lints(&site(fastcgi, proxied));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Warning);
+        assert!(found[0]
+            .message
+            .contains("without setting X-Forwarded-For nor X-Forwarded-Proto"));
+        assert!(lints(&site(
+            fastcgi,
+            "location /ws { include proxy_params; proxy_pass http://127.0.0.1:9000; }"
+        ))
+        .is_empty());
+
+        let config: UnverifiedConfig = toml::from_str(&site(fastcgi, proxied)).unwrap();
+        let strict = Options {
+            strict: true,
+            ..Default::default()
+        };
+        let found = lint(config, &strict).unwrap();
+        assert_eq!(found[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_lint_redirect_overlaps() {
+        let lints = |fields: &str| {
+            lints(&format!(
+                "server_tokens = false\n[[sites]]\ndomain = \"example.com\"\n{}",
+                fields
+            ))
+        };
+        let redirect = |from: &str, regex: bool| {
+            format!(
+                "[[sites.redirects]]\nfrom = {:?}\nto = \"/new\"\nregex = {}\n",
+                from, regex
+            )
+        };
+        let headers =
+            "[[sites.headers]]\nfor = \"/app\"\nvalues = { X-Frame-Options = \"DENY\" }\n";
+
+        let found = lints(&format!("{}{}", headers, redirect("/app", false)));
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert_eq!(found[0].severity, Severity::Warning);
+        assert!(found[0]
+            .message
+            .starts_with("redirect from \"/app\" overlaps headers for \"/app\", which of them"));
+
+        assert_eq!(
+            lints(&format!("{}{}", headers, redirect("^/app/(.*)$", true))).len(),
+            1
+        );
+        assert_eq!(
+            lints(&format!("{}{}", headers, redirect("/other", false))).len(),
+            0
+        );
+        assert_eq!(
+            lints(&format!("{}{}", headers, redirect("^/other/", true))).len(),
+            0
+        );
+
+        let found = lints(&format!(
+            "healthcheck = {{ path = \"/health\" }}\nextra = \"location ~ \\\\.php$ {{ deny all; }}\"\n{}{}",
+            redirect("/health", false),
+            redirect("/index.php", false)
+        ));
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0]
+            .message
+            .contains("overlaps healthcheck \"/health\""));
+        assert!(found[1]
+            .message
+            .contains("overlaps location ~ \\.php$ in extra"));
+    }
+
+    #[test]
+    fn test_lint_trailing_slashes() {
+        let lints = |fields: &str| {
+            lints(&format!(
+                "server_tokens = false\n[[sites]]\ndomain = \"example.com\"\n{}",
+                fields
+            ))
+        };
+
+        let found = lints(
+            "[[sites.redirects]]\nfrom = \"/old/\"\nto = \"/new\"\n[[sites.headers]]\nfor = \"/new/\"\nvalues = { X-Frame-Options = \"DENY\" }\n",
+        );
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert_eq!(
+            found[0].message,
+            "redirect to \"/new\" and headers for \"/new/\" only differ by a trailing slash, requests for one of them don't match the other"
+        );
+
+        let found = lints(
+            "https_redirect_exclude = [\"/status\"]\n[[sites.redirects]]\nfrom = \"/status/\"\nto = \"/status\"\n",
+        );
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0]
+            .message
+            .starts_with("redirect from \"/status/\" and redirect to \"/status\" only differ"));
+
+        assert!(lints(
+            "[[sites.redirects]]\nfrom = \"/old\"\nto = \"/new\"\n[[sites.redirects]]\nfrom = \"^/old/(.*)$\"\nto = \"/new/$1\"\nregex = true\n"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_certificate_mismatches() {
+        assert_eq!(
+            certificate_name("/etc/letsencrypt/live/example.com-0001/fullchain.pem").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            certificate_name("/etc/ssl/wildcard.example.com.crt").as_deref(),
+            Some("*.example.com")
+        );
+        assert_eq!(certificate_name("/etc/nginx/ssl/server.crt"), None);
+
+        let lints = |fields: &str| {
+            lints(&format!(
+                "server_tokens = false
+[[sites]]
+{}
+[[sites.headers]]
+for = \"/*\"
+values = {{ Strict-Transport-Security = \"max-age=63072000\" }}",
+                fields
+            ))
+        };
+
+        let found = lints(
+            "domain = \"example.com\"\nletsencrypt = true\nserver_names = [{ name = \"*.example.com\" }]",
+        );
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert_eq!(found[0].severity, Severity::Warning);
+        assert_eq!(
+            found[0].message,
+            "ssl_cert \"/etc/letsencrypt/live/example.com/fullchain.pem\" looks like a certificate for \"example.com\", which doesn't cover \"*.example.com\" unless it lists it too"
+        );
+
+        let found = lints(
+            "domain = \"example.com\"\nssl_cert = \"/etc/ssl/_.example.com.pem\"\nssl_key = \"/etc/ssl/_.example.com.key\"\nserver_names = [{ name = \"api.example.com\" }, { name = \"v2.api.example.com\" }]",
+        );
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0]
+            .message
+            .contains("for \"*.example.com\", which doesn't cover \"example.com\""));
+        assert!(found[1]
+            .message
+            .contains("doesn't cover \"v2.api.example.com\""));
+
+        assert!(lints(
+            "domain = \"api.example.com\"\nssl_cert = \"/etc/ssl/wildcard.example.com/fullchain.pem\"\nssl_key = \"/etc/ssl/wildcard.example.com/privkey.pem\"\nserver_names = [{ name = \"*.example.com\" }]"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_lint_tls() {
+        let lints = |fields: &str, strict: bool| {
+            let config: UnverifiedConfig = toml::from_str(&format!(
+                r#"
+                tls_profile = "intermediate"
+
+                [[sites]]
+                domain = "example.com"
+                ssl_cert = "/etc/ssl/cert.pem"
+                ssl_key = "/etc/ssl/key.pem"
+                {}
+                "#,
+                fields
+            ))
+            .unwrap();
+            let options = Options {
+                strict,
+                ..Default::default()
+            };
+            lint_tls(config, &options).unwrap()
+        };
+        let hsts = "[[sites.headers]]\nfor = \"/*\"\nvalues = { Strict-Transport-Security = \"max-age=63072000\" }";
+
+        assert!(lints(hsts, false).is_empty());
+
+        let found = lints("", false);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Warning);
+        assert!(found[0]
+            .message
+            .starts_with("no Strict-Transport-Security header"));
+
+        let found = lints(&format!("tls_profile = \"old\"\n{}", hsts), true);
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert_eq!(found[0].severity, Severity::Error);
+        assert!(found[0]
+            .message
+            .starts_with("tls_profile \"old\" enables TLSv1 and TLSv1.1,"));
+        assert!(found[1]
+            .message
+            .starts_with("tls_profile \"old\" allows the weak ciphers DES-CBC3-SHA,"));
+
+        let found = lints(
+            &format!("ssl_ciphers = \"HIGH:!aNULL:!MD5:RC4-SHA\"\n{}", hsts),
+            false,
+        );
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(found[0].message.contains("the weak ciphers RC4-SHA,"));
+    }
+
+    #[test]
+    fn test_lint_clean() {
+        let lints = lints(
+            r#"
+            server_tokens = false
+
+            [[sites]]
+            domain = "example.com"
+            ssl_cert = "/etc/ssl/cert.pem"
+            ssl_key = "/etc/ssl/key.pem"
+
+            [[sites.headers]]
+            for = "/*"
+
+            [sites.headers.values]
+            Strict-Transport-Security = "max-age=31536000"
+            "#,
+        );
+        assert!(lints.is_empty(), "{:?}", lints);
+    }
+}
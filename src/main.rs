@@ -1,60 +1,1575 @@
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 use argh::FromArgs;
-use confy;
-use toml;
+use log::{error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use nvhosts::lint::Severity;
+use nvhosts::NvhostsError;
+use owo_colors::{OwoColorize, Stream};
 
 pub const DEFAULT_PATH: &str = "./nvhosts.toml";
+/// Name of the config file looked for in the config directories.
+const CONFIG_FILENAME: &str = "nvhosts.toml";
+/// Directory of the system-wide config file.
+const SYSTEM_CONFIG_DIR: &str = "/etc/nvhosts";
+pub const DEFAULT_OUTPUT_FILE: &str = "generated.conf";
+
+/// Layout of the generated files, given to `--output-format`.
+enum OutputFormat {
+    PerSite,
+    Combined,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-site" => Ok(Self::PerSite),
+            "combined" => Ok(Self::Combined),
+            _ => Err(format!("expected per-site or combined, got {:?}", s)),
+        }
+    }
+}
+
+/// What is generated from the config, given to `--format`.
+enum Format {
+    Nginx,
+    K8sIngress,
+    Haproxy,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nginx" => Ok(Self::Nginx),
+            "k8s-ingress" => Ok(Self::K8sIngress),
+            "haproxy" => Ok(Self::Haproxy),
+            _ => Err(format!(
+                "expected nginx, k8s-ingress or haproxy, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// When to color the output, given to `--color`.
+enum Color {
+    /// When the stream is a terminal, and `NO_COLOR` isn't set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("expected auto, always or never, got {:?}", s)),
+        }
+    }
+}
+
+/// A variable given to the templates, given to `--var` as `name=value`.
+struct Var(String, String);
+
+impl FromStr for Var {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, value)) => Ok(Self(name.to_string(), value.to_string())),
+            None => Err(format!("expected name=value, got {:?}", s)),
+        }
+    }
+}
 
 /// Generate nginx vhosts from a configuration file
 #[derive(FromArgs)]
 struct Args {
-    /// path to config file to use; defaults to nvhosts.toml
-    #[argh(option, short = 'c', default = "DEFAULT_PATH.to_string()")]
-    config: String,
+    /// path to config file to use, or http(s) URL to fetch it from; defaults
+    /// to the first found of $XDG_CONFIG_HOME/nvhosts/nvhosts.toml,
+    /// /etc/nvhosts/nvhosts.toml and ./nvhosts.toml
+    #[argh(option, short = 'c')]
+    config: Option<String>,
+
+    /// let a config fetched from a URL run hooks and read local files, with
+    /// sites_dir and ${file:} references, for a server that is trusted
+    #[argh(switch)]
+    trust_remote_config: bool,
+
+    /// print the path of the config file that would be used, and exit
+    #[argh(switch)]
+    print_config_path: bool,
 
     /// show an example config
     #[argh(switch)]
     example: bool,
 
-    /// print verbose output
+    /// print the built-in vhost.template, to start a custom one for
+    /// --template-dir
+    #[argh(switch)]
+    print_template: bool,
+
+    /// nginx (config files), k8s-ingress (Kubernetes Ingress manifests
+    /// printed on stdout) or haproxy (an HAProxy config printed on stdout);
+    /// defaults to nginx
+    #[argh(option, default = "Format::Nginx")]
+    format: Format,
+
+    /// per-site (one file per domain) or combined (a single file); defaults
+    /// to per-site
+    #[argh(option, default = "OutputFormat::PerSite")]
+    output_format: OutputFormat,
+
+    /// name of the file written by --output-format combined; defaults to
+    /// generated.conf
+    #[argh(option, default = "DEFAULT_OUTPUT_FILE.to_string()")]
+    output_file: String,
+
+    /// directory the files are generated in, instead of sites-available;
+    /// ${VAR} is replaced by the environment variable VAR, e.g.
+    /// /srv/nginx/${HOSTNAME}
+    #[argh(option)]
+    output_dir: Option<String>,
+
+    /// directory where the generated files are symlinked to enable them;
+    /// overrides enabled_dir from the config
+    #[argh(option)]
+    enabled_dir: Option<String>,
+
+    /// only generate the sites of this profile, with its overrides; when
+    /// repeated, each profile is generated in its own subdirectory, e.g.
+    /// sites-available/prod
+    #[argh(option)]
+    profile: Vec<String>,
+
+    /// directory of templates replacing the built-in ones of the same name
+    #[argh(option)]
+    template_dir: Option<String>,
+
+    /// log the errors of the site of this domain from the debug level, for
+    /// this run only
+    #[argh(option)]
+    debug_site: Option<String>,
+
+    /// variable given to the templates as name=value, e.g. --var
+    /// environment=prod for {{ environment }}; can be repeated
+    #[argh(option)]
+    var: Vec<Var>,
+
+    /// TOML or JSON file of variables given to the templates, by .json
+    /// extension; --var overrides them
+    #[argh(option)]
+    template_var_file: Option<String>,
+
+    /// fail when the output directory holds files with the extension of the
+    /// sites, .conf by default, not generated by nvhosts
+    #[argh(switch)]
+    verify_clean: bool,
+
+    /// remove the files the previous run generated and this one doesn't, with
+    /// their links in the enabled directory
+    #[argh(switch)]
+    clean: bool,
+
+    /// exit with status 2 when files changed, 0 when nothing changed; with
+    /// --diff, when files would change, only listing them unless -v
+    #[argh(switch)]
+    exit_code: bool,
+
+    /// skip generation when the config, its file references, the options and
+    /// the templates didn't change since the last --incremental run
+    #[argh(switch)]
+    incremental: bool,
+
+    /// generate even when --incremental would skip it
+    #[argh(switch)]
+    force: bool,
+
+    /// copy files about to be overwritten to <file>.bak first
+    #[argh(switch)]
+    backup: bool,
+
+    /// try writing a file again up to this many times on transient errors,
+    /// such as EAGAIN on a network filesystem; defaults to 0
+    #[argh(option, default = "0")]
+    write_retries: u32,
+
+    /// number of threads generating the sites, for configs of 64 sites or
+    /// more, and probing them with --skip-unreachable; defaults to the number
+    /// of CPUs
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+
+    /// fail when validation emits warnings, or lint finds anything
+    #[argh(switch)]
+    strict: bool,
+
+    /// generate even when validation finds errors, printing them as warnings;
+    /// unsafe, nginx may reject the result
+    #[argh(switch)]
+    no_validate: bool,
+
+    /// report at most this many validation errors, followed by the number of
+    /// the others; defaults to all of them
+    #[argh(option)]
+    max_errors: Option<usize>,
+
+    /// don't print validation warnings; with --strict, they fail the run
+    /// counted rather than listed
+    #[argh(switch)]
+    no_warn: bool,
+
+    /// print the number of validation errors rather than each of them
+    #[argh(switch)]
+    quiet_errors: bool,
+
+    /// write the sites that fail as placeholders commenting out their
+    /// errors, rather than writing nothing; the run still fails
+    #[argh(switch)]
+    render_errors_inline: bool,
+
+    /// render the files without writing them nor running the hooks, and
+    /// list them
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// render the files without writing them, and print how they differ from
+    /// the ones in sites-available
+    #[argh(switch)]
+    diff: bool,
+
+    /// render the files into this gzipped tarball, under sites-available/,
+    /// instead of writing them, without running the hooks
+    #[argh(option)]
+    archive: Option<PathBuf>,
+
+    /// write the sites that are new or changed since the files in
+    /// sites-available to this single file, to include before them, instead
+    /// of writing them, without running the hooks
+    #[argh(option)]
+    patch: Option<PathBuf>,
+
+    /// with --dry-run, test the rendered files with nginx -t in a temporary
+    /// directory
+    #[argh(switch)]
+    check: bool,
+
+    /// render the files twice without writing them, and fail with the first
+    /// one that differs between the renders
+    #[argh(switch)]
+    check_idempotent: bool,
+
+    /// generate again whenever the config, its site files, the files it
+    /// references or the templates change, until interrupted
+    #[argh(switch)]
+    watch: bool,
+
+    /// with --watch, reload nginx after generating files that nginx -t
+    /// accepts; the files aren't written when it rejects them
+    #[argh(switch)]
+    reload: bool,
+
+    /// with --reload, try reloading nginx again up to this many times when
+    /// it fails for another reason than an invalid config, e.g. while nginx
+    /// restarts; defaults to 0
+    #[argh(option, default = "0")]
+    reload_retries: u32,
+
+    /// milliseconds to wait before the first retry of --reload-retries,
+    /// twice as long before each next one; defaults to 500
+    #[argh(option, default = "500")]
+    reload_retry_delay: u64,
+
+    /// with --check or --reload, skip nginx -t when no file would change
+    #[argh(switch)]
+    check_only_changed: bool,
+
+    /// warn about the features of the config needing a module that nginx -V
+    /// doesn't list, failing with --strict
+    #[argh(switch)]
+    check_modules: bool,
+
+    /// leave out the proxied sites whose upstream doesn't accept a TCP
+    /// connection within 2s, with a warning each, failing with --strict
+    #[argh(switch)]
+    skip_unreachable: bool,
+
+    /// nginx binary run by --check, --reload, --check-modules and selftest
+    /// --check; defaults to nginx
+    #[argh(option, default = "String::from(\"nginx\")")]
+    nginx: String,
+
+    /// seconds nginx and the hooks may run before they are killed and the
+    /// run fails; defaults to 30
+    #[argh(option)]
+    timeout: Option<u64>,
+
+    /// color the output: auto, the default, when printing to a terminal and
+    /// NO_COLOR isn't set, always or never
+    #[argh(option, default = "Color::Auto")]
+    color: Color,
+
+    /// print verbose output: written files and hooks, repeat for debug
+    /// messages
     #[argh(switch, short = 'v')]
-    verbose: bool,
+    verbose: u8,
 
     /// show the version
     #[argh(switch, short = 'V')]
     version: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Import(Import),
+    Lint(Lint),
+    Audit(Audit),
+    Test(Test),
+    Resolve(Resolve),
+    DumpContext(DumpContext),
+    Explain(Explain),
+    Show(Show),
+    Minimize(Minimize),
+    DiffConfigs(DiffConfigs),
+    Fmt(Fmt),
+    Split(Split),
+    Completions(Completions),
+    Selftest(Selftest),
+}
+
+/// Print a config converted from existing nginx vhost files
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+struct Import {
+    /// directory of the vhost files to import
+    #[argh(positional)]
+    dir: String,
+}
+
+/// Print advice on risky or redundant parts of the config
+#[derive(FromArgs)]
+#[argh(subcommand, name = "lint")]
+struct Lint {
+    #[argh(subcommand)]
+    check: Option<LintCheck>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum LintCheck {
+    Tls(LintTls),
+}
+
+/// Print outdated or unsafe TLS settings of the HTTPS sites
+#[derive(FromArgs)]
+#[argh(subcommand, name = "tls")]
+struct LintTls {}
+
+/// Print a report of the security posture of every site
+#[derive(FromArgs)]
+#[argh(subcommand, name = "audit")]
+struct Audit {
+    #[argh(subcommand)]
+    check: AuditCheck,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum AuditCheck {
+    Tls(AuditTls),
+}
+
+/// Print the sites served over plain HTTP, without HSTS or without
+/// force_https, failing with --strict when there are any
+#[derive(FromArgs)]
+#[argh(subcommand, name = "tls")]
+struct AuditTls {
+    /// print the report as JSON rather than as a table
+    #[argh(switch)]
+    json: bool,
+}
+
+/// Print the config as it is generated, once profiles and file references
+/// are applied
+#[derive(FromArgs)]
+#[argh(subcommand, name = "resolve")]
+struct Resolve {
+    /// only print the site of this domain
+    #[argh(option)]
+    only: Option<String>,
+}
+
+/// Print as JSON the context the template of each site is rendered with, to
+/// write custom templates
+#[derive(FromArgs)]
+#[argh(subcommand, name = "dump-context")]
+struct DumpContext {
+    /// only print the context of the site of this domain
+    #[argh(option)]
+    only: Option<String>,
+}
+
+/// Print in plain English what the generated config of a site does
+#[derive(FromArgs)]
+#[argh(subcommand, name = "explain")]
+struct Explain {
+    /// domain of the site to explain
+    #[argh(positional)]
+    domain: String,
+}
+
+/// Print the generated config of a site, with syntax highlighting
+#[derive(FromArgs)]
+#[argh(subcommand, name = "show")]
+struct Show {
+    /// domain of the site to show
+    #[argh(positional)]
+    domain: String,
+}
+
+/// Print the config reduced to a site and the fields that reproduce a
+/// problem with it, to attach to bug reports
+#[derive(FromArgs)]
+#[argh(subcommand, name = "minimize")]
+struct Minimize {
+    /// domain of the site to reduce
+    #[argh(option)]
+    only: String,
+
+    /// keep the fields needed for the render, or the error, of the site to
+    /// contain this text; by default, the fields needed for its first error,
+    /// or every field when it renders
+    #[argh(option)]
+    contains: Option<String>,
+}
+
+/// Print how the files generated from a config differ from the ones of
+/// another, e.g. before merging a change
+#[derive(FromArgs)]
+#[argh(subcommand, name = "diff-configs")]
+struct DiffConfigs {
+    /// config file, or http(s) URL, before the change
+    #[argh(positional)]
+    old: String,
+
+    /// config file, or http(s) URL, after the change
+    #[argh(positional)]
+    new: String,
+}
+
+/// Rewrite a config file in canonical form, sorting its sites and keys
+#[derive(FromArgs)]
+#[argh(subcommand, name = "fmt")]
+struct Fmt {
+    /// config file to format; defaults to the one of -c
+    #[argh(positional)]
+    path: Option<String>,
+
+    /// print the canonical form on stdout instead of writing it, and fail
+    /// when the file isn't in that form
+    #[argh(switch)]
+    check: bool,
+
+    /// strip the trailing slash of the from paths of the redirects that
+    /// aren't regular expressions and of the for paths of the headers, e.g.
+    /// from = "/old/" becomes "/old"
+    #[argh(switch)]
+    strip_trailing_slashes: bool,
+}
+
+/// Move the sites of the config to one file per domain in a directory, read
+/// through sites_dir
+#[derive(FromArgs)]
+#[argh(subcommand, name = "split")]
+struct Split {
+    /// directory of the site files, relative to the config file
+    #[argh(option)]
+    out_dir: String,
+}
+
+/// Print the completion script of a shell: bash, zsh or fish
+#[derive(FromArgs)]
+#[argh(subcommand, name = "completions")]
+struct Completions {
+    /// shell to complete in: bash, zsh or fish
+    #[argh(positional)]
+    shell: nvhosts::completions::Shell,
+}
+
+/// Check the install: validate and render the example config in memory, and
+/// optionally test the result with nginx -t
+#[derive(FromArgs)]
+#[argh(subcommand, name = "selftest")]
+struct Selftest {
+    /// also test the rendered files with nginx -t
+    #[argh(switch)]
+    check: bool,
+}
+
+/// Check the rendered config of a single site with nginx -t
+#[derive(FromArgs)]
+#[argh(subcommand, name = "test")]
+struct Test {
+    /// domain of the site to check
+    #[argh(positional)]
+    domain: String,
+
+    /// nginx binary to run; defaults to nginx
+    #[argh(option, default = "String::from(\"nginx\")")]
+    nginx: String,
+}
+
+/// Exit statuses, distinct by class of failure so that scripts can tell a
+/// wrong config from a full disk.
+#[derive(Clone, Copy)]
+enum Exit {
+    Success = 0,
+    /// Findings of `lint --strict`, `audit --strict` or `fmt --check`, or an
+    /// unexpected argument.
+    Failure = 1,
+    /// Files changed, with `--exit-code`.
+    Changed = 2,
+    /// The config, or a file it references, can't be read or parsed.
+    Load = 3,
+    /// The config is invalid.
+    Invalid = 4,
+    /// Rendering or writing the files, or running the hooks, failed.
+    Generate = 5,
+    /// nginx rejected the rendered config, or couldn't be run.
+    NginxCheck = 6,
+}
+
+impl Exit {
+    /// The class of failure of `err`.
+    fn of(err: &NvhostsError) -> Self {
+        match err {
+            NvhostsError::Parse { .. }
+            | NvhostsError::FileReference { .. }
+            | NvhostsError::Fetch { .. }
+            | NvhostsError::SitesFile { .. }
+            | NvhostsError::VarsFile { .. }
+            | NvhostsError::EnvVar { .. } => Self::Load,
+            NvhostsError::Invalid(_)
+            | NvhostsError::Profile { .. }
+            | NvhostsError::Include { .. }
+            | NvhostsError::UnknownSite { .. } => Self::Invalid,
+            NvhostsError::Nginx { .. } | NvhostsError::NginxTimeout { .. } => Self::NginxCheck,
+            NvhostsError::Template { .. }
+            | NvhostsError::TemplateDir { .. }
+            | NvhostsError::Unmanaged { .. }
+            | NvhostsError::Hook { .. }
+            | NvhostsError::HookFailed { .. }
+            | NvhostsError::HookTimeout { .. }
+            | NvhostsError::Io { .. } => Self::Generate,
+            NvhostsError::Placeholders(errors) => errors.first().map_or(Self::Generate, Self::of),
+        }
+    }
+}
+
+/// Prints the messages logged by the library and the binary on stderr,
+/// warnings after a colored `warning:`, errors in color, debug messages
+/// dimmed.
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let message = match record.level() {
+            Level::Error => message
+                .if_supports_color(Stream::Stderr, |t| t.red())
+                .to_string(),
+            Level::Warn => format!(
+                "{} {}",
+                "warning:".if_supports_color(Stream::Stderr, |t| t.yellow()),
+                message
+            ),
+            Level::Info => message,
+            Level::Debug | Level::Trace => message
+                .if_supports_color(Stream::Stderr, |t| t.dimmed())
+                .to_string(),
+        };
+        eprintln!("{}", message);
+    }
+
+    fn flush(&self) {}
+}
+
+/// The options and subcommands of the subcommand at `path`, read from its
+/// `--help` as argh prints it.
+fn command_spec(path: &[&str], description: String) -> nvhosts::completions::CommandSpec {
+    let name = env!("CARGO_PKG_NAME");
+    let mut args = path.to_vec();
+    args.push("--help");
+    let help = match Args::from_args(&[name], &args) {
+        Err(early_exit) => early_exit.output,
+        Ok(_) => String::new(),
+    };
+    let (flags, commands) = nvhosts::completions::parse_help(&help);
+    let commands = commands
+        .into_iter()
+        .map(|(command, description)| {
+            let mut path = path.to_vec();
+            path.push(&command);
+            command_spec(&path, description)
+        })
+        .collect();
+    nvhosts::completions::CommandSpec {
+        name: path.last().copied().unwrap_or(name).to_string(),
+        description,
+        flags,
+        commands,
+    }
+}
+
+fn exit(code: Exit) -> ! {
+    process::exit(code as i32);
+}
+
+/// Prints `message` in red on stderr and exits with the status of `code`.
+fn fail(code: Exit, message: String) -> ! {
+    eprintln!("{}", message.if_supports_color(Stream::Stderr, |t| t.red()));
+    exit(code);
+}
+
+/// The config file used without `--config`: the first found of the one of
+/// the user, in `$XDG_CONFIG_HOME/nvhosts` or `~/.config/nvhosts`, the
+/// system-wide one, and the one of the current directory, which is used
+/// when none is found.
+fn discover_config() -> String {
+    let user_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    user_dir
+        .map(|dir| dir.join("nvhosts").join(CONFIG_FILENAME))
+        .into_iter()
+        .chain([Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILENAME)])
+        .find(|path| path.is_file())
+        .map_or_else(
+            || DEFAULT_PATH.to_string(),
+            |path| path.display().to_string(),
+        )
+}
+
+/// The options of the flags shared by generation and the commands, which
+/// override the fields they need.
+fn options(args: &Args) -> nvhosts::Options {
+    // The variables of --template-var-file, overridden by the ones of --var.
+    let mut vars = match &args.template_var_file {
+        Some(path) => nvhosts::read_vars(Path::new(path)).unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to load template variables: {}", err),
+            )
+        }),
+        None => nvhosts::Vars::new(),
+    };
+    vars.extend(
+        args.var
+            .iter()
+            .map(|Var(name, value)| (name.clone(), value.clone())),
+    );
+
+    nvhosts::Options {
+        strict: args.strict,
+        output: match args.output_format {
+            OutputFormat::PerSite => nvhosts::Output::PerSite,
+            OutputFormat::Combined => nvhosts::Output::Combined(args.output_file.clone()),
+        },
+        backup: args.backup,
+        enabled_dir: args.enabled_dir.as_ref().map(PathBuf::from),
+        profile: args.profile.first().cloned(),
+        template_dir: args.template_dir.as_ref().map(PathBuf::from),
+        verify_clean: args.verify_clean,
+        clean: args.clean,
+        incremental: args.incremental,
+        force: args.force,
+        no_validate: args.no_validate,
+        max_errors: args.max_errors,
+        no_warn: args.no_warn,
+        quiet_errors: args.quiet_errors,
+        write_retries: args.write_retries,
+        jobs: args.jobs,
+        timeout: args.timeout.map(Duration::from_secs),
+        vars,
+        debug_site: args.debug_site.clone(),
+        skip_unreachable: args.skip_unreachable.then_some(nvhosts::PROBE_TIMEOUT),
+        render_errors_inline: args.render_errors_inline,
+        ..Default::default()
+    }
+}
+
+/// Reads the config at `path`, with its site files and file references. A
+/// URL is fetched instead, its site files and file references being relative
+/// to the working directory; unless `trusted`, it can't have any, nor hooks,
+/// so that its server can't run commands or read files on this machine.
+fn load(path: &str, trusted: bool) -> Result<nvhosts::UnverifiedConfig, String> {
+    if nvhosts::remote::is_url(path) {
+        let cfg: nvhosts::UnverifiedConfig = nvhosts::remote::fetch(path)
+            .map_err(|err| err.to_string())?
+            .parse()
+            .map_err(|err| format!("failed to load {}: {}", path, err))?;
+        let local_access = cfg.local_access();
+        if !trusted && !local_access.is_empty() {
+            return Err(format!(
+                "failed to load {}: {} would run commands or read local files, pass --trust-remote-config if its server is trusted",
+                path,
+                local_access.join(", ")
+            ));
+        }
+        let dir = Path::new(".");
+        return cfg
+            .read_sites_dir(dir)
+            .and_then(|cfg| cfg.read_files(dir))
+            .map_err(|err| format!("failed to load {}: {}", path, err));
+    }
+
+    let cfg: nvhosts::UnverifiedConfig = match fs::read_to_string(path) {
+        Ok(content) => content
+            .parse()
+            .map_err(|err| format!("failed to load file {}: {}", path, err))?,
+        // confy writes the default config when the file is missing.
+        Err(_) => confy::load_path(path)
+            .map_err(|err| format!("failed to load file {}: {}", path, err))?,
+    };
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    cfg.read_sites_dir(dir)
+        .and_then(|cfg| cfg.read_files(dir))
+        .map_err(|err| format!("failed to load file {}: {}", path, err))
+}
+
+/// Generates the files of `cfg` for `--watch`, logging failures rather than
+/// exiting. With `reload`, the files are only written once `nginx -t` accepts
+/// them, and nginx is reloaded when they changed, so that a broken config
+/// leaves nginx serving the last good one; a reload failing transiently is
+/// tried again `retries` times. With `only_changed`, the check is skipped
+/// when no file would change.
+fn regenerate(
+    cfg: nvhosts::UnverifiedConfig,
+    options: &nvhosts::Options,
+    reload: bool,
+    retries: (u32, Duration),
+    only_changed: bool,
+    nginx: &str,
+) {
+    if reload {
+        let check = nvhosts::dry_run(cfg.clone(), options).and_then(|files| {
+            if only_changed && nvhosts::changed_files(&files, options).is_empty() {
+                return Ok(None);
+            }
+            let timeout = options.timeout.unwrap_or(nvhosts::COMMAND_TIMEOUT);
+            nvhosts::check::check_files(&files, nginx, timeout).map(Some)
+        });
+        match check {
+            Ok(None) => {
+                info!("nothing changed, nginx -t skipped");
+                return;
+            }
+            Ok(Some(check)) if check.passed => {}
+            Ok(Some(check)) => {
+                eprint!("{}", check.output);
+                error!("nginx -t failed, nothing written nor reloaded");
+                return;
+            }
+            Err(err) => {
+                error!("failed to run: {}", err);
+                return;
+            }
+        }
+    }
+
+    match nvhosts::run(cfg, options) {
+        Ok(true) if reload => {
+            let timeout = options.timeout.unwrap_or(nvhosts::COMMAND_TIMEOUT);
+            let (retries, delay) = retries;
+            match nvhosts::check::reload(nginx, timeout, retries, delay) {
+                Ok(reload) if reload.reloaded => println!(
+                    "{}",
+                    attempts("nginx reloaded", reload.attempts)
+                        .if_supports_color(Stream::Stdout, |t| t.green())
+                ),
+                Ok(reload) => {
+                    eprint!("{}", reload.output);
+                    error!("{}", attempts("failed to reload nginx", reload.attempts));
+                }
+                Err(err) => error!("failed to reload nginx: {}", err),
+            }
+        }
+        Ok(true) => info!("files generated"),
+        Ok(false) => info!("nothing changed"),
+        Err(err) => error!("failed to run: {}", err),
+    }
+}
+
+/// Tells after `message` how many attempts it took, when more than one.
+fn attempts(message: &str, attempts: u32) -> String {
+    match attempts {
+        1 => message.to_string(),
+        _ => format!("{} after {} attempts", message, attempts),
+    }
 }
 
 fn main() {
     let args: Args = argh::from_env();
 
+    match args.color {
+        Color::Auto => {}
+        Color::Always => owo_colors::set_override(true),
+        Color::Never => owo_colors::set_override(false),
+    }
+
     if args.version {
-        println!(std::env!("CARGO_PKG_VERSION"));
-        process::exit(0);
+        println!(
+            "{} ({} {})",
+            env!("CARGO_PKG_VERSION"),
+            env!("NVHOSTS_GIT_COMMIT"),
+            env!("NVHOSTS_BUILD_DATE")
+        );
+        exit(Exit::Success);
+    }
+
+    let config_path = args.config.clone().unwrap_or_else(discover_config);
+    if args.print_config_path {
+        println!("{}", config_path);
+        exit(Exit::Success);
+    }
+
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(match args.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        });
     }
 
-    if args.verbose {
-        nvhosts::verbose::enable();
+    if args.print_template {
+        print!("{}", nvhosts::builtin_template());
+        exit(Exit::Success);
     }
 
     if args.example {
         let config = nvhosts::UnverifiedConfig::example();
-        let example: String = toml::to_string_pretty(&config).unwrap_or_else(|err| {
-            eprintln!("failed to print an example file {}: {}", args.config, err);
-            process::exit(1);
+        let example: String = config.to_toml().unwrap_or_else(|err| {
+            fail(
+                Exit::Failure,
+                format!("failed to print an example file {}: {}", config_path, err),
+            );
         });
         print!("{}", example);
-        process::exit(0);
+        exit(Exit::Success);
     }
 
-    let cfg: nvhosts::UnverifiedConfig = confy::load_path(&args.config).unwrap_or_else(|err| {
-        eprintln!("failed to load file {}: {}", args.config, err);
-        process::exit(1);
+    if let Some(Command::Completions(completions)) = args.command {
+        let command = command_spec(&[], String::new());
+        print!(
+            "{}",
+            nvhosts::completions::script(completions.shell, &command)
+        );
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Import(import)) = args.command {
+        let imported = nvhosts::import::import_dir(Path::new(&import.dir)).unwrap_or_else(|err| {
+            fail(
+                Exit::Load,
+                format!("failed to import {}: {:#}", import.dir, err),
+            );
+        });
+        for warning in imported.warnings.iter() {
+            warn!("{}", warning);
+        }
+        let config = imported.config.to_toml().unwrap_or_else(|err| {
+            fail(
+                Exit::Failure,
+                format!("failed to print the imported config: {}", err),
+            );
+        });
+        print!("{}", config);
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Fmt(fmt)) = args.command {
+        let path = fmt.path.unwrap_or(config_path.clone());
+        let content = fs::read_to_string(&path).unwrap_or_else(|err| {
+            fail(Exit::Load, format!("failed to read {}: {}", path, err));
+        });
+        let formatted = nvhosts::canonical::format(&content, fmt.strip_trailing_slashes)
+            .unwrap_or_else(|err| {
+                fail(Exit::Load, format!("failed to format {}: {}", path, err));
+            });
+        if fmt.check {
+            print!("{}", formatted);
+            if formatted != content {
+                fail(Exit::Failure, format!("{} isn't formatted", path));
+            }
+        } else if formatted != content {
+            fs::write(&path, formatted).unwrap_or_else(|err| {
+                fail(Exit::Generate, format!("failed to write {}: {}", path, err));
+            });
+        }
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Split(split)) = args.command {
+        let content = fs::read_to_string(&config_path).unwrap_or_else(|err| {
+            fail(
+                Exit::Load,
+                format!("failed to read {}: {}", config_path, err),
+            );
+        });
+        let result = nvhosts::split::split(&content, &split.out_dir).unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to split {}: {}", config_path, err),
+            );
+        });
+        let dir = Path::new(&config_path)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(&split.out_dir);
+        fs::create_dir_all(&dir).unwrap_or_else(|err| {
+            fail(
+                Exit::Generate,
+                format!("failed to create {}: {}", dir.display(), err),
+            );
+        });
+        for (name, _) in result.files.iter() {
+            let path = dir.join(name);
+            if path.exists() {
+                fail(
+                    Exit::Failure,
+                    format!("{} already exists, not overwriting it", path.display()),
+                );
+            }
+        }
+        for (name, content) in result.files.iter() {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap_or_else(|err| {
+                fail(
+                    Exit::Generate,
+                    format!("failed to write {}: {}", path.display(), err),
+                );
+            });
+            println!("{}", path.display());
+        }
+        fs::write(&config_path, result.config).unwrap_or_else(|err| {
+            fail(
+                Exit::Generate,
+                format!("failed to write {}: {}", config_path, err),
+            );
+        });
+        exit(Exit::Success);
+    }
+
+    if args.quiet_errors && args.max_errors.is_some() {
+        fail(
+            Exit::Failure,
+            String::from("--quiet-errors and --max-errors conflict, remove one of them"),
+        );
+    }
+    if args.max_errors == Some(0) {
+        fail(
+            Exit::Failure,
+            String::from("--max-errors must be at least 1"),
+        );
+    }
+    if args.timeout == Some(0) {
+        fail(Exit::Failure, String::from("--timeout must be at least 1"));
+    }
+    let timeout = args.timeout.map(Duration::from_secs);
+    if args.jobs == Some(0) {
+        fail(Exit::Failure, String::from("--jobs must be at least 1"));
+    }
+
+    if let Some(Command::Selftest(selftest)) = args.command {
+        let options = nvhosts::Options {
+            timeout,
+            ..Default::default()
+        };
+        let files = nvhosts::dry_run(nvhosts::UnverifiedConfig::example(), &options)
+            .unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("selftest failed on the example config: {}", err),
+                );
+            });
+        let paths: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+        info!("the example config rendered to {}", paths.join(", "));
+        if selftest.check {
+            let check = nvhosts::check::check_files(
+                &files,
+                &args.nginx,
+                timeout.unwrap_or(nvhosts::COMMAND_TIMEOUT),
+            )
+            .unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("selftest failed to run nginx -t: {}", err),
+                );
+            });
+            if !check.passed {
+                eprint!("{}", check.output);
+                fail(
+                    Exit::NginxCheck,
+                    String::from("selftest failed: nginx -t rejected the example config"),
+                );
+            }
+            if args.verbose > 0 {
+                eprint!("{}", check.output);
+            }
+        }
+        println!(
+            "{}",
+            "selftest passed".if_supports_color(Stream::Stdout, |t| t.green())
+        );
+        exit(Exit::Success);
+    }
+
+    if args.profile.len() > 1 && args.command.is_some() {
+        fail(
+            Exit::Failure,
+            String::from("several --profile only work when generating, give one to commands"),
+        );
+    }
+    let options = options(&args);
+
+    if let Some(Command::DiffConfigs(diff)) = args.command {
+        let render = |path: &str| {
+            // load writes a default config in place of a missing file.
+            if !nvhosts::remote::is_url(path) && !Path::new(path).exists() {
+                fail(
+                    Exit::Load,
+                    format!("failed to load file {}: not found", path),
+                );
+            }
+            let cfg =
+                load(path, args.trust_remote_config).unwrap_or_else(|err| fail(Exit::Load, err));
+            nvhosts::dry_run(cfg, &options).unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("failed to render {}: {}", path, err),
+                );
+            })
+        };
+        let (old, new) = (render(&diff.old), render(&diff.new));
+        let diffs = nvhosts::diff_renders(&old, &new);
+        for (path, diff) in diffs.iter() {
+            if args.exit_code && args.verbose == 0 {
+                println!("sites-available/{} would change", path);
+            } else {
+                print!("{}", diff);
+            }
+        }
+        if args.exit_code && !diffs.is_empty() {
+            exit(Exit::Changed);
+        }
+        exit(Exit::Success);
+    }
+
+    let cfg =
+        load(&config_path, args.trust_remote_config).unwrap_or_else(|err| fail(Exit::Load, err));
+
+    if let Some(Command::Lint(lint)) = args.command {
+        let lints = match lint.check {
+            None => nvhosts::lint::lint(cfg, &options),
+            Some(LintCheck::Tls(_)) => nvhosts::lint::lint_tls(cfg, &options),
+        };
+        let lints = lints.unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to lint: {}", err));
+        });
+        for lint in lints.iter() {
+            let severity = format!("{}:", lint.severity);
+            let severity = match lint.severity {
+                Severity::Error => severity
+                    .if_supports_color(Stream::Stdout, |t| t.red())
+                    .to_string(),
+                Severity::Warning => severity
+                    .if_supports_color(Stream::Stdout, |t| t.yellow())
+                    .to_string(),
+                Severity::Info => severity
+                    .if_supports_color(Stream::Stdout, |t| t.blue())
+                    .to_string(),
+            };
+            println!("{} {}", severity, lint);
+        }
+        if args.strict && !lints.is_empty() {
+            exit(Exit::Failure);
+        }
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Audit(audit)) = args.command {
+        let AuditCheck::Tls(tls) = audit.check;
+        let audits = nvhosts::audit::tls(cfg, &options).unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to audit: {}", err));
+        });
+        if tls.json {
+            print!("{}", nvhosts::audit::json(&audits));
+        } else {
+            print!("{}", nvhosts::audit::table(&audits));
+        }
+        if args.strict && audits.iter().any(|audit| !audit.issues.is_empty()) {
+            exit(Exit::Failure);
+        }
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Resolve(resolve)) = args.command {
+        let resolved =
+            nvhosts::resolve(cfg, &options, resolve.only.as_deref()).unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("failed to resolve {}: {}", config_path, err),
+                );
+            });
+        print!("{}", resolved);
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::DumpContext(dump)) = args.command {
+        let context =
+            nvhosts::dump_context(cfg, &options, dump.only.as_deref()).unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("failed to dump the context of {}: {}", config_path, err),
+                );
+            });
+        print!("{}", context);
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Explain(explain)) = args.command {
+        let explanation =
+            nvhosts::explain::explain(cfg, &options, &explain.domain).unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("failed to explain {}: {}", explain.domain, err),
+                );
+            });
+        print!("{}", explanation);
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Show(show)) = args.command {
+        let rendered = nvhosts::render_site(cfg, &options, &show.domain).unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to render {}: {}", show.domain, err),
+            );
+        });
+        print!("{}", nvhosts::highlight::nginx(&rendered));
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Minimize(minimize)) = args.command {
+        let minimized = nvhosts::minimize::minimize(
+            cfg,
+            &options,
+            &minimize.only,
+            minimize.contains.as_deref(),
+        )
+        .unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to minimize {}: {}", minimize.only, err),
+            );
+        });
+        match minimized {
+            Some(minimized) => print!("{}", minimized),
+            None => fail(
+                Exit::Failure,
+                format!(
+                    "the render of {} doesn't contain {:?}, nothing to reproduce",
+                    minimize.only,
+                    minimize.contains.unwrap_or_default()
+                ),
+            ),
+        }
+        exit(Exit::Success);
+    }
+
+    if let Format::K8sIngress = args.format {
+        let manifest = nvhosts::ingress::ingress(cfg, &options).unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to generate the Ingress manifests: {}", err),
+            );
+        });
+        for warning in manifest.warnings.iter() {
+            warn!("{}", warning);
+        }
+        print!("{}", manifest.yaml);
+        exit(Exit::Success);
+    }
+
+    if let Format::Haproxy = args.format {
+        let haproxy = nvhosts::haproxy::haproxy(cfg, &options).unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to generate the HAProxy config: {}", err),
+            );
+        });
+        for warning in haproxy.warnings.iter() {
+            warn!("{}", warning);
+        }
+        print!("{}", haproxy.config);
+        exit(Exit::Success);
+    }
+
+    if let Some(Command::Test(test)) = args.command {
+        let check = nvhosts::check::check_site(cfg, &options, &test.domain, &test.nginx)
+            .unwrap_or_else(|err| {
+                fail(
+                    Exit::of(&err),
+                    format!("failed to test {}: {}", test.domain, err),
+                );
+            });
+        if !check.passed {
+            print!("{}", check.rendered);
+            eprint!("{}", check.output);
+            fail(
+                Exit::NginxCheck,
+                format!("{}: nginx -t failed", test.domain),
+            );
+        }
+        if args.verbose > 0 {
+            eprint!("{}", check.output);
+        }
+        println!(
+            "{}",
+            format!("{}: nginx -t passed", test.domain)
+                .if_supports_color(Stream::Stdout, |t| t.green())
+        );
+        exit(Exit::Success);
+    }
+
+    let output_dir = args.output_dir.map(|dir| {
+        nvhosts::expand_env(&dir, "--output-dir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|err| fail(Exit::of(&err), err.to_string()))
+    });
+
+    // Queried once, the watch mode validating every change against them.
+    let modules = args.check_modules.then(|| {
+        let timeout = timeout.unwrap_or(nvhosts::COMMAND_TIMEOUT);
+        nvhosts::modules::Modules::query(&args.nginx, timeout).unwrap_or_else(|err| {
+            fail(
+                Exit::of(&err),
+                format!("failed to list the modules of nginx: {}", err),
+            );
+        })
     });
+    let options = nvhosts::Options {
+        progress: true,
+        modules,
+        output_dir,
+        ..options
+    };
+
+    if args.no_validate {
+        if args.strict {
+            fail(
+                Exit::Failure,
+                String::from("--no-validate and --strict conflict, remove one of them"),
+            );
+        }
+        if args.render_errors_inline {
+            fail(
+                Exit::Failure,
+                String::from(
+                    "--no-validate and --render-errors-inline conflict: the sites are rendered whatever their errors",
+                ),
+            );
+        }
+        eprintln!(
+            "{} --no-validate is set: validation errors don't stop generation, check the result with nginx -t before reloading",
+            "WARNING:".if_supports_color(Stream::Stderr, |t| t.yellow().bold().to_string())
+        );
+    }
+
+    if args.skip_unreachable && args.incremental {
+        fail(
+            Exit::Failure,
+            String::from(
+                "--skip-unreachable and --incremental conflict: an upstream coming back doesn't change the config",
+            ),
+        );
+    }
+
+    if args.check && !args.dry_run {
+        fail(
+            Exit::Failure,
+            String::from("--check only works with --dry-run"),
+        );
+    }
+    if args.check_only_changed && !args.check && !args.reload {
+        fail(
+            Exit::Failure,
+            String::from("--check-only-changed only works with --check or --reload"),
+        );
+    }
+    if args.diff && (args.dry_run || args.watch) {
+        fail(
+            Exit::Failure,
+            String::from("--diff conflicts with --dry-run and --watch"),
+        );
+    }
+    if args.archive.is_some() && (args.dry_run || args.diff || args.watch) {
+        fail(
+            Exit::Failure,
+            String::from("--archive conflicts with --dry-run, --diff and --watch"),
+        );
+    }
+    if args.patch.is_some() {
+        if args.dry_run || args.diff || args.archive.is_some() || args.watch {
+            fail(
+                Exit::Failure,
+                String::from("--patch conflicts with --dry-run, --diff, --archive and --watch"),
+            );
+        }
+        if let nvhosts::Output::Combined(_) = options.output {
+            fail(
+                Exit::Failure,
+                String::from("--patch only works with --output-format per-site"),
+            );
+        }
+    }
+    if args.check_idempotent
+        && (args.dry_run
+            || args.diff
+            || args.archive.is_some()
+            || args.patch.is_some()
+            || args.watch)
+    {
+        fail(
+            Exit::Failure,
+            String::from(
+                "--check-idempotent conflicts with --dry-run, --diff, --archive, --patch and --watch",
+            ),
+        );
+    }
+    if args.profile.len() > 1
+        && (args.check_idempotent
+            || args.dry_run
+            || args.diff
+            || args.archive.is_some()
+            || args.patch.is_some()
+            || args.watch)
+    {
+        fail(
+            Exit::Failure,
+            String::from(
+                "several --profile conflict with --check-idempotent, --dry-run, --diff, --archive, --patch and --watch",
+            ),
+        );
+    }
+    if args.reload && !args.watch {
+        fail(
+            Exit::Failure,
+            String::from("--reload only works with --watch"),
+        );
+    }
+    if args.reload_retries > 0 && !args.reload {
+        fail(
+            Exit::Failure,
+            String::from("--reload-retries only works with --reload"),
+        );
+    }
+    if args.watch {
+        if args.dry_run {
+            fail(
+                Exit::Failure,
+                String::from("--watch and --dry-run conflict, remove one of them"),
+            );
+        }
+        let interval = if nvhosts::remote::is_url(&config_path) {
+            nvhosts::watch::REMOTE_INTERVAL
+        } else {
+            nvhosts::watch::INTERVAL
+        };
+        let mut watcher =
+            nvhosts::watch::Watcher::new(|| load(&config_path, args.trust_remote_config), &options);
+        loop {
+            match watcher.poll() {
+                Some(Ok(cfg)) => regenerate(
+                    cfg,
+                    &options,
+                    args.reload,
+                    (
+                        args.reload_retries,
+                        Duration::from_millis(args.reload_retry_delay),
+                    ),
+                    args.check_only_changed,
+                    &args.nginx,
+                ),
+                Some(Err(err)) => error!("{}", err),
+                None => {}
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    if args.diff {
+        let files = nvhosts::dry_run(cfg, &options).unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to run: {}", err));
+        });
+        let diffs = nvhosts::diff_files(&files, &options);
+        for (path, diff) in diffs.iter() {
+            if args.exit_code && args.verbose == 0 {
+                println!("sites-available/{} would change", path);
+            } else {
+                print!("{}", diff);
+            }
+        }
+        if args.exit_code && !diffs.is_empty() {
+            exit(Exit::Changed);
+        }
+        exit(Exit::Success);
+    }
+
+    if let Some(path) = &args.archive {
+        let count = nvhosts::archive::write(cfg, &options, path).unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to archive: {}", err));
+        });
+        info!("archived {} files to {}", count, path.display());
+        exit(Exit::Success);
+    }
+
+    if let Some(path) = &args.patch {
+        let files = nvhosts::dry_run(cfg, &options).unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to run: {}", err));
+        });
+        let patch = nvhosts::patch(&files, &options);
+        for file in patch.unpatched.iter() {
+            warn!(
+                "sites-available/{} changed but can't be patched, generate it instead",
+                file
+            );
+        }
+        fs::write(path, &patch.content).unwrap_or_else(|err| {
+            fail(
+                Exit::Generate,
+                format!("failed to write {}: {}", path.display(), err),
+            );
+        });
+        match patch.sites.is_empty() {
+            true => info!("no site changed, {} is empty", path.display()),
+            false => info!("wrote {} to {}", patch.sites.join(", "), path.display()),
+        }
+        if args.exit_code && !patch.sites.is_empty() {
+            exit(Exit::Changed);
+        }
+        exit(Exit::Success);
+    }
+
+    if args.check_idempotent {
+        let nondeterministic = nvhosts::check_idempotent(cfg, &options).unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to run: {}", err));
+        });
+        if let Some(nondeterministic) = nondeterministic {
+            eprint!("{}", nondeterministic.diff);
+            fail(
+                Exit::Failure,
+                format!(
+                    "sites-available/{} differs between two renders of the same config",
+                    nondeterministic.path
+                ),
+            );
+        }
+        println!(
+            "{}",
+            "the files are identical between two renders"
+                .if_supports_color(Stream::Stdout, |t| t.green())
+        );
+        exit(Exit::Success);
+    }
 
-    nvhosts::run(cfg).unwrap_or_else(|err| {
-        eprintln!("failed to run: {}", err);
-        process::exit(1);
+    if args.dry_run {
+        let files = nvhosts::dry_run(cfg, &options).unwrap_or_else(|err| {
+            fail(Exit::of(&err), format!("failed to run: {}", err));
+        });
+        if !args.check {
+            for (path, _) in files.iter() {
+                println!("would write sites-available/{}", path);
+            }
+            exit(Exit::Success);
+        }
+        if args.check_only_changed && nvhosts::changed_files(&files, &options).is_empty() {
+            println!(
+                "{}",
+                "nothing changed, nginx -t skipped"
+                    .if_supports_color(Stream::Stdout, |t| t.green())
+            );
+            exit(Exit::Success);
+        }
+
+        let timeout = timeout.unwrap_or(nvhosts::COMMAND_TIMEOUT);
+        let check =
+            nvhosts::check::check_files(&files, &args.nginx, timeout).unwrap_or_else(|err| {
+                fail(Exit::of(&err), format!("failed to check: {}", err));
+            });
+        if !check.passed {
+            eprint!("{}", check.output);
+            fail(
+                Exit::NginxCheck,
+                match check.file {
+                    Some(file) => format!("nginx -t failed on sites-available/{}", file),
+                    None => String::from("nginx -t failed"),
+                },
+            );
+        }
+        if args.verbose > 0 {
+            eprint!("{}", check.output);
+        }
+        println!(
+            "{}",
+            format!("nginx -t passed on {} files", files.len())
+                .if_supports_color(Stream::Stdout, |t| t.green())
+        );
+        exit(Exit::Success);
+    }
+
+    let changed = match args.profile.len() {
+        0 | 1 => nvhosts::run(cfg, &options),
+        _ => nvhosts::run_profiles(cfg, &options, &args.profile),
+    }
+    .unwrap_or_else(|err| {
+        if args.backup {
+            fail(Exit::of(&err), format!(
+                "failed to run: {}\nthe previous version of each overwritten file is kept as <file>.bak",
+                err
+            ));
+        }
+        fail(Exit::of(&err), format!("failed to run: {}", err));
     });
+
+    if args.exit_code && changed {
+        exit(Exit::Changed);
+    }
 }
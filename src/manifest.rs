@@ -0,0 +1,129 @@
+//! The list of the files written by the last run, kept in the output
+//! directory so that `Options::clean` only removes files that nvhosts
+//! generated and no longer generates.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{NvhostsError, BANNER};
+
+/// Name of the file holding the manifest, in the output directory.
+const MANIFEST_FILENAME: &str = ".nvhosts-manifest.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    /// Paths relative to the output directory.
+    files: Vec<String>,
+}
+
+/// The files of the last run in `dir`, none when it left no manifest.
+pub(crate) fn read(dir: &Path) -> Result<Vec<String>, NvhostsError> {
+    let path = dir.join(MANIFEST_FILENAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let manifest: Manifest = serde_json::from_str(&content).map_err(|err| NvhostsError::Io {
+        source: std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        path: path.clone(),
+    })?;
+    Ok(manifest.files)
+}
+
+/// Records `files` as the ones of the last run in `dir`.
+pub(crate) fn record(dir: &Path, files: &[String]) -> Result<(), NvhostsError> {
+    let path = dir.join(MANIFEST_FILENAME);
+    let manifest = Manifest {
+        files: files.to_vec(),
+    };
+    let content = serde_json::to_string_pretty(&manifest).expect("a manifest serializes to JSON");
+    fs::write(&path, format!("{}\n", content)).map_err(NvhostsError::io(path))
+}
+
+/// Removes the files of `previous` that `files` no longer has from `dir`,
+/// along with their links in `enabled_dir`, and returns their paths. Files
+/// that don't start with the banner anymore were taken over by hand, and are
+/// kept.
+pub(crate) fn clean(
+    dir: &Path,
+    previous: &[String],
+    files: &[String],
+    enabled_dir: Option<&Path>,
+) -> Result<Vec<PathBuf>, NvhostsError> {
+    let mut removed = Vec::new();
+    for name in previous.iter().filter(|name| !files.contains(name)) {
+        let path = dir.join(name);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        if !content.starts_with(BANNER.as_bytes()) {
+            warn!(
+                "{} no longer starts with the banner of nvhosts, not removed",
+                path.display()
+            );
+            continue;
+        }
+
+        if let Some(enabled_dir) = enabled_dir {
+            let link = enabled_dir.join(name);
+            if fs::symlink_metadata(&link).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                fs::remove_file(&link).map_err(NvhostsError::io(&link))?;
+                info!("unlinked {}", link.display());
+            }
+        }
+        fs::remove_file(&path).map_err(NvhostsError::io(&path))?;
+        info!("removed {}", path.display());
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-manifest-{}", std::process::id()));
+        let enabled_dir = dir.join("enabled");
+        fs::create_dir_all(&enabled_dir).unwrap();
+
+        assert!(read(&dir).unwrap().is_empty());
+        let previous = vec![
+            String::from("a.conf"),
+            String::from("b.conf"),
+            String::from("c.conf"),
+            String::from("d.conf"),
+        ];
+        record(&dir, &previous).unwrap();
+        assert_eq!(read(&dir).unwrap(), previous);
+
+        let generated = format!("{}\n\nserver {{}}\n", BANNER);
+        fs::write(dir.join("a.conf"), &generated).unwrap();
+        fs::write(dir.join("b.conf"), &generated).unwrap();
+        fs::write(dir.join("c.conf"), "server {}\n").unwrap();
+        fs::write(dir.join("unlisted.conf"), &generated).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("b.conf"), enabled_dir.join("b.conf")).unwrap();
+
+        let removed = clean(
+            &dir,
+            &previous,
+            &[String::from("a.conf")],
+            Some(&enabled_dir),
+        )
+        .unwrap();
+        assert_eq!(removed, [dir.join("b.conf")]);
+        assert!(dir.join("a.conf").exists());
+        assert!(!dir.join("b.conf").exists());
+        assert!(fs::symlink_metadata(enabled_dir.join("b.conf")).is_err());
+        assert!(dir.join("c.conf").exists());
+        assert!(dir.join("unlisted.conf").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+//! Reduces a config to a single site and the fields it needs to reproduce a
+//! problem, for `nvhosts minimize`, to attach a small config to bug reports.
+
+use std::collections::BTreeMap;
+
+use tera::Tera;
+
+use crate::{templates, NvhostsError, Options, Syntax, UnverifiedConfig, ValidationError};
+
+/// Reduces `config` to the site of `domain` alone, then leaves out every field of
+/// the site and of the config, and every element of the lists of the site,
+/// whose removal still reproduces the problem: a render containing
+/// `contains`, or when it isn't given and the site fails, the same first
+/// error. Without either, the site is only extracted. Returns the reduced
+/// config as TOML, or `None` when `contains` isn't in the render of the site
+/// to start with.
+pub fn minimize(
+    config: UnverifiedConfig,
+    options: &Options,
+    domain: &str,
+    contains: Option<&str>,
+) -> Result<Option<String>, NvhostsError> {
+    let mut value = toml::Value::try_from(&config).expect("a config serializes to TOML");
+    let site = config
+        .sites
+        .iter()
+        .position(|site| site.domain == domain)
+        .ok_or_else(|| NvhostsError::UnknownSite {
+            domain: domain.to_string(),
+        })?;
+    let sites = value["sites"]
+        .as_array_mut()
+        .expect("the sites serialize to an array");
+    let site = sites.swap_remove(site);
+    *sites = vec![site];
+    // Fields left to their default value change nothing.
+    strip_defaults(&mut value, domain);
+
+    let extracted = value
+        .clone()
+        .try_into()
+        .map(|config: UnverifiedConfig| config.keep_written(&value))
+        .map_err(|err: toml::de::Error| NvhostsError::Parse {
+            site: None,
+            message: err.to_string(),
+        })?;
+    let mut tera = templates(options)?;
+    let needle = match (contains, render(extracted, options, &mut tera, domain)) {
+        (Some(contains), outcome) => {
+            if !text(outcome).contains(contains) {
+                return Ok(None);
+            }
+            Some(contains.to_string())
+        }
+        (None, Err(NvhostsError::Invalid(errors))) => {
+            errors.first().map(ValidationError::to_string)
+        }
+        (None, Err(err)) => Some(err.to_string()),
+        (None, Ok(_)) => None,
+    };
+
+    if let Some(needle) = &needle {
+        let mut reproduces = |value: &toml::Value| {
+            value
+                .clone()
+                .try_into()
+                .is_ok_and(|config: UnverifiedConfig| {
+                    let config = config.keep_written(value);
+                    text(render(config, options, &mut tera, domain)).contains(needle)
+                })
+        };
+        loop {
+            let mut reduced = false;
+            for removal in removals(&value) {
+                let mut candidate = value.clone();
+                if removal.apply(&mut candidate) && reproduces(&candidate) {
+                    value = candidate;
+                    reduced = true;
+                }
+            }
+            if !reduced {
+                break;
+            }
+        }
+    }
+
+    let mut minimized = match &needle {
+        Some(needle) => format!(
+            "# The site {:?} reduced by nvhosts {} to reproduce {:?}\n",
+            domain,
+            env!("CARGO_PKG_VERSION"),
+            needle
+        ),
+        None => format!(
+            "# The site {:?} extracted by nvhosts {}\n",
+            domain,
+            env!("CARGO_PKG_VERSION")
+        ),
+    };
+    minimized.push_str(&toml::to_string_pretty(&value).expect("a config serializes to TOML"));
+    Ok(Some(minimized))
+}
+
+/// Renders the site of `domain` like `render_site`, without printing the
+/// warnings of every attempt.
+fn render(
+    config: UnverifiedConfig,
+    options: &Options,
+    tera: &mut Tera,
+    domain: &str,
+) -> Result<String, NvhostsError> {
+    let (config, _) = config.validate_with_warnings(options)?;
+    let site = config
+        .sites
+        .iter()
+        .find(|site| site.domain == domain)
+        .ok_or_else(|| NvhostsError::UnknownSite {
+            domain: domain.to_string(),
+        })?;
+    site.render(
+        tera,
+        Syntax::for_version(&config.nginx_version),
+        &config.vars,
+    )
+}
+
+/// The render of a site, or why it failed.
+fn text(outcome: Result<String, NvhostsError>) -> String {
+    outcome.unwrap_or_else(|err| err.to_string())
+}
+
+/// Leaves out of `value` the fields of the config and of its single site
+/// that have their default value.
+fn strip_defaults(value: &mut toml::Value, domain: &str) {
+    let mut site = toml::value::Table::new();
+    site.insert(String::from("domain"), toml::Value::from(domain));
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("sites"),
+        toml::Value::Array(vec![toml::Value::Table(site)]),
+    );
+    let config: UnverifiedConfig = toml::Value::Table(config)
+        .try_into()
+        .expect("a config of a domain is valid");
+    let mut defaults = toml::Value::try_from(&config).expect("a config serializes to TOML");
+
+    let strip = |table: &mut toml::value::Table, defaults: &toml::value::Table| {
+        let stripped: Vec<String> = table
+            .iter()
+            .filter(|(key, field)| *key != "domain" && defaults.get(*key) == Some(*field))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stripped {
+            table.remove(&key);
+        }
+    };
+    let default_site = site_mut(defaults.as_table_mut().expect("a config is a table")).clone();
+    let config = value.as_table_mut().expect("a config is a table");
+    strip(site_mut(config), &default_site);
+    let sites = config.remove("sites");
+    strip(config, defaults.as_table().expect("a config is a table"));
+    if let Some(sites) = sites {
+        config.insert(String::from("sites"), sites);
+    }
+}
+
+/// A part of a config to try leaving out.
+#[derive(Debug)]
+enum Removal {
+    /// A field of the config, other than `sites`.
+    Config(String),
+    /// A field of the site, other than `domain`.
+    Site(String),
+    /// The fields of the site named alike, e.g. `ssl_cert` and `ssl_key`,
+    /// which often only work together.
+    Fields(Vec<String>),
+    /// A field of a table of the site, e.g. `rate` of `rate_limit`.
+    Nested(String, String),
+    /// An element of a list of the site.
+    Element(String, usize),
+}
+
+impl Removal {
+    /// Leaves the part out of `value`, if it is still there.
+    fn apply(&self, value: &mut toml::Value) -> bool {
+        let config = value.as_table_mut().expect("a config is a table");
+        match self {
+            Removal::Config(key) => config.remove(key).is_some(),
+            Removal::Site(key) => site_mut(config).remove(key).is_some(),
+            Removal::Fields(keys) => {
+                let site = site_mut(config);
+                let removed: Vec<toml::Value> =
+                    keys.iter().filter_map(|key| site.remove(key)).collect();
+                !removed.is_empty()
+            }
+            Removal::Nested(key, nested) => match site_mut(config).get_mut(key) {
+                Some(toml::Value::Table(table)) => table.remove(nested).is_some(),
+                _ => false,
+            },
+            Removal::Element(key, index) => match site_mut(config).get_mut(key) {
+                Some(toml::Value::Array(elements)) if *index < elements.len() => {
+                    elements.remove(*index);
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+fn site_mut(config: &mut toml::value::Table) -> &mut toml::value::Table {
+    config["sites"][0]
+        .as_table_mut()
+        .expect("a site is a table")
+}
+
+/// What can be left out of `value`: whole fields first, the ones of the site
+/// named alike together then alone, then the fields of the tables of the site
+/// and the elements of its lists, last ones first so that the indexes of the
+/// others still hold once one is removed.
+fn removals(value: &toml::Value) -> Vec<Removal> {
+    let mut removals: Vec<Removal> = value
+        .as_table()
+        .into_iter()
+        .flat_map(|config| config.keys())
+        .filter(|key| *key != "sites")
+        .map(|key| Removal::Config(key.clone()))
+        .collect();
+    if let Some(site) = value["sites"][0].as_table() {
+        let mut alike: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for key in site.keys().filter(|key| *key != "domain") {
+            let prefix = key.split('_').next().unwrap_or_default();
+            alike.entry(prefix).or_default().push(key.clone());
+        }
+        removals.extend(
+            alike
+                .into_values()
+                .filter(|keys| keys.len() > 1)
+                .map(Removal::Fields),
+        );
+        removals.extend(
+            site.keys()
+                .filter(|key| *key != "domain")
+                .map(|key| Removal::Site(key.clone())),
+        );
+        for (key, field) in site.iter() {
+            match field {
+                toml::Value::Table(table) => removals.extend(
+                    table
+                        .keys()
+                        .map(|nested| Removal::Nested(key.clone(), nested.clone())),
+                ),
+                toml::Value::Array(elements) => removals.extend(
+                    (0..elements.len())
+                        .rev()
+                        .map(|index| Removal::Element(key.clone(), index)),
+                ),
+                _ => {}
+            }
+        }
+    }
+    removals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        nginx_version = "1.25.0"
+
+        [[sites]]
+        domain = "example.com"
+        http2 = true
+        server_tokens = false
+        ssl_cert = "/etc/ssl/example.pem"
+        ssl_key = "/etc/ssl/example.key"
+
+        [[sites.headers]]
+        for = "/*"
+        values = { X-Frame-Options = "DENY" }
+
+        [[sites.headers]]
+        for = "/api"
+        values = { Access-Control-Allow-Origin = "*" }
+
+        [[sites]]
+        domain = "api.example.com"
+        upstream = { url = "http://127.0.0.1:3000" }
+        rate_limit = { rate = "10/s" }
+        "#;
+
+    #[test]
+    fn test_minimize() {
+        let config: UnverifiedConfig = toml::from_str(CONFIG).unwrap();
+        let options = Options::default();
+
+        let minimized = minimize(
+            config.clone(),
+            &options,
+            "example.com",
+            Some("X-Frame-Options"),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(minimized.starts_with("# The site \"example.com\" reduced by nvhosts"));
+        let reduced: UnverifiedConfig = minimized.parse().unwrap();
+        assert_eq!(reduced.sites.len(), 1);
+        let toml = reduced.to_toml().unwrap();
+        assert!(toml.contains("X-Frame-Options"));
+        assert!(!toml.contains("Access-Control-Allow-Origin"));
+        assert!(!toml.contains("ssl_cert"));
+        assert!(!toml.contains("api.example.com"));
+
+        let minimized = minimize(config.clone(), &options, "api.example.com", None)
+            .unwrap()
+            .unwrap();
+        assert!(minimized.contains("reduced by nvhosts"));
+        assert!(minimized.contains("rate_limit"));
+        assert!(!minimized.contains("upstream"));
+        let reduced: UnverifiedConfig = minimized.parse().unwrap();
+        let err = reduced.validate(&options).err().unwrap().to_string();
+        assert!(err.contains("rate \"10/s\" must be requests per second or minute"));
+
+        let extracted = minimize(config.clone(), &options, "example.com", None)
+            .unwrap()
+            .unwrap();
+        assert!(extracted.starts_with("# The site \"example.com\" extracted by nvhosts"));
+        assert!(extracted.contains("Access-Control-Allow-Origin"));
+        assert!(!extracted.contains("api.example.com"));
+
+        assert!(
+            minimize(config.clone(), &options, "example.com", Some("X-Missing"))
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            minimize(config, &options, "missing.example.com", None),
+            Err(NvhostsError::UnknownSite { .. })
+        ));
+    }
+}
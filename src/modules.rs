@@ -0,0 +1,237 @@
+//! The modules compiled into nginx, read from `nginx -V`, to flag the
+//! features of a config that need a module the binary lacks before `nginx -t`
+//! fails on an unknown directive.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{
+    command, BackendType, ErrorLogLevel, NvhostsError, Site, UnverifiedConfig, Warning, WarningKind,
+};
+
+/// A module a feature needs.
+#[derive(Debug, Clone, Copy)]
+enum Module {
+    /// Built unless nginx is configured `--without-<name>`.
+    Standard(&'static str),
+    /// Only built when nginx is configured `--with-<name>`.
+    Optional(&'static str),
+    /// A third-party module, added by an `--add-module` or
+    /// `--add-dynamic-module` whose directory has this name in it.
+    ThirdParty(&'static str),
+}
+
+impl Module {
+    fn describe(&self) -> String {
+        match self {
+            Module::Standard(name) | Module::Optional(name) => name.to_string(),
+            Module::ThirdParty(name) => format!("a third-party {} module", name),
+        }
+    }
+}
+
+/// The configure arguments of an nginx binary, which tell its modules.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Modules {
+    with: BTreeSet<String>,
+    without: BTreeSet<String>,
+    added: BTreeSet<String>,
+}
+
+impl Modules {
+    /// Runs `nginx -V` with the `nginx` binary, killed after `timeout`. Its
+    /// output is read once, and reused for every validation of the run.
+    pub fn query(nginx: &str, timeout: Duration) -> Result<Self, NvhostsError> {
+        // nginx prints its version and configure arguments on stderr.
+        let (_, printed) = command::nginx(nginx, &["-V"], timeout)?;
+        Ok(Self::parse(&printed))
+    }
+
+    /// Reads the `configure arguments:` line of the output of `nginx -V`.
+    pub fn parse(version: &str) -> Self {
+        let mut modules = Self::default();
+        let arguments = version
+            .lines()
+            .find_map(|line| line.strip_prefix("configure arguments:"))
+            .unwrap_or_default();
+        for argument in arguments.split_whitespace() {
+            if let Some(name) = argument.strip_prefix("--with-") {
+                // Dynamic modules are built as `--with-<name>=dynamic`.
+                let name = name.split('=').next().unwrap_or_default();
+                modules.with.insert(name.to_string());
+            } else if let Some(name) = argument.strip_prefix("--without-") {
+                modules.without.insert(name.to_string());
+            } else if let Some(path) = argument
+                .strip_prefix("--add-module=")
+                .or_else(|| argument.strip_prefix("--add-dynamic-module="))
+            {
+                let name = Path::new(path.trim_end_matches('/'))
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                modules.added.insert(name);
+            }
+        }
+        modules
+    }
+
+    fn has(&self, module: Module) -> bool {
+        match module {
+            Module::Standard(name) => !self.without.contains(name),
+            Module::Optional(name) => self.with.contains(name),
+            Module::ThirdParty(name) => self.added.iter().any(|added| added.contains(name)),
+        }
+    }
+}
+
+/// Explains the features of `config` that need a module missing from
+/// `modules`.
+pub(crate) fn missing(config: &UnverifiedConfig, modules: &Modules) -> Vec<Warning> {
+    let mut missing = Vec::new();
+    let mut need = |domain: Option<&str>, feature: &str, module: Module| {
+        if modules.has(module) {
+            return;
+        }
+        let message = format!(
+            "{} requires {}, which nginx wasn't built with",
+            feature,
+            module.describe()
+        );
+        missing.push(Warning {
+            kind: WarningKind::MissingModule,
+            domain: domain.map(String::from),
+            message,
+        });
+    };
+
+    need(None, "cache_control", Module::Standard("http_map_module"));
+    if config.real_ip.is_some() {
+        need(None, "real_ip", Module::Optional("http_realip_module"));
+    }
+    if !config.geos.is_empty() {
+        need(None, "geos", Module::Standard("http_geo_module"));
+    }
+    if !config.streams.is_empty() {
+        need(None, "streams", Module::Optional("stream"));
+    }
+    for site in config.sites.iter() {
+        for (feature, module) in site_modules(site) {
+            need(Some(&site.domain), feature, module);
+        }
+    }
+    missing
+}
+
+/// The features of `site` that need a module, with that module.
+fn site_modules(site: &Site) -> Vec<(&'static str, Module)> {
+    let mut modules = Vec::new();
+    if site.ssl_cert.is_some() {
+        modules.push(("ssl_cert", Module::Optional("http_ssl_module")));
+    }
+    if site.http2 {
+        modules.push(("http2", Module::Optional("http_v2_module")));
+    }
+    if site.http3 {
+        modules.push(("http3", Module::Optional("http_v3_module")));
+    }
+    if site.redirect_to.is_none() && site.root.is_none() {
+        let backend = site
+            .upstream
+            .as_ref()
+            .map_or(BackendType::Proxy, |upstream| upstream.backend_type);
+        modules.push(match backend {
+            BackendType::Proxy => ("upstream", Module::Standard("http_proxy_module")),
+            BackendType::Fastcgi => ("upstream", Module::Standard("http_fastcgi_module")),
+            BackendType::Uwsgi => ("upstream", Module::Standard("http_uwsgi_module")),
+        });
+    }
+    if site.gzip_static {
+        modules.push(("gzip_static", Module::Optional("http_gzip_static_module")));
+    }
+    if site.brotli_static {
+        modules.push(("brotli_static", Module::ThirdParty("brotli")));
+    }
+    if site.real_ip.is_some() {
+        modules.push(("real_ip", Module::Optional("http_realip_module")));
+    }
+    if site.maintenance_allow.is_some() {
+        modules.push(("maintenance_allow", Module::Standard("http_geo_module")));
+    }
+    if site.error_log_level == Some(ErrorLogLevel::Debug) {
+        modules.push(("error_log_level debug", Module::Optional("debug")));
+    }
+    if site.allow_acl.is_some() {
+        modules.push(("allow_acl", Module::Standard("http_access_module")));
+    }
+    if site.proxy_cache.is_some() {
+        modules.push(("proxy_cache", Module::Standard("http_proxy_module")));
+    }
+    if site.lua.is_some() || site.headers.iter().flatten().any(|h| h.lua.is_some()) {
+        modules.push(("lua", Module::ThirdParty("lua")));
+    }
+    modules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing() {
+        let modules = Modules::parse(
+            "nginx version: nginx/1.26.2
+built by gcc 12.2.0 (Debian 12.2.0-14)
+configure arguments: --prefix=/etc/nginx --with-http_ssl_module --with-http_v2_module --with-http_realip_module=dynamic --without-http_uwsgi_module --add-dynamic-module=/build/ngx_brotli/",
+        );
+        assert!(modules.has(Module::Optional("http_realip_module")));
+        assert!(modules.has(Module::ThirdParty("brotli")));
+        assert!(!modules.has(Module::Standard("http_uwsgi_module")));
+
+        let config: UnverifiedConfig = toml::from_str(
+            r#"
+            [[sites]]
+            domain = "example.com"
+            http2 = true
+            ssl_cert = "/etc/ssl/example.pem"
+            ssl_key = "/etc/ssl/example.key"
+            root = "/var/www/example.com"
+            gzip_static = true
+            brotli_static = true
+
+            [[sites]]
+            domain = "app.example.com"
+            upstream = { url = "127.0.0.1:3031", backend_type = "uwsgi" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            missing(&config, &modules)
+                .iter()
+                .map(Warning::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "\"example.com\": gzip_static requires http_gzip_static_module, which nginx wasn't built with",
+                "\"app.example.com\": upstream requires http_uwsgi_module, which nginx wasn't built with",
+            ]
+        );
+
+        let stock = Modules::parse("configure arguments: --prefix=/etc/nginx");
+        assert_eq!(
+            missing(&config, &stock)[3].to_string(),
+            "\"example.com\": brotli_static requires a third-party brotli module, which nginx wasn't built with"
+        );
+
+        let strict = crate::Options {
+            strict: true,
+            modules: Some(modules),
+            ..Default::default()
+        };
+        let err = config.validate(&strict).err().unwrap().to_string();
+        assert!(
+            err.contains("upstream requires http_uwsgi_module"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Probes of the upstreams of the sites, for `--skip-unreachable`: a site
+//! whose backend doesn't accept a connection is left out of the generation,
+//! rather than served errors or failing the reload of every other site.
+
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use url::Url;
+
+use crate::{unix_socket, BackendType, Options, Site, Upstream};
+
+/// Why the upstream of each of `sites` is unreachable, in the same order,
+/// `None` for the sites that are reachable or don't proxy. The upstreams are
+/// probed on `Options::jobs` threads, each probing a contiguous run of the
+/// sites, and each resolution and connection gives up after `timeout`.
+pub(crate) fn unreachable(
+    sites: &[Site],
+    options: &Options,
+    timeout: Duration,
+) -> Vec<Option<String>> {
+    if sites.is_empty() {
+        return Vec::new();
+    }
+    let jobs = options
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+    let chunk_size = sites.len().div_ceil(jobs);
+    thread::scope(|scope| {
+        let chunks: Vec<_> = sites
+            .chunks(chunk_size)
+            .map(|sites| {
+                scope.spawn(move || {
+                    sites
+                        .iter()
+                        .map(|site| {
+                            let upstream = site
+                                .upstream
+                                .as_ref()
+                                .filter(|_| site.redirect_to.is_none() && site.root.is_none())?;
+                            probe(upstream, timeout).err().map(|reason| {
+                                format!("upstream {:?} is unreachable: {}", upstream.url, reason)
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        chunks
+            .into_iter()
+            .zip(sites.chunks(chunk_size))
+            .flat_map(|(chunk, sites)| chunk.join().unwrap_or_else(|_| vec![None; sites.len()]))
+            .collect()
+    })
+}
+
+/// Connects to `upstream`, or checks that its Unix socket exists. Upstreams
+/// with a variable, only known at request time, are taken as reachable, and
+/// the ones with `servers` when any of them is.
+fn probe(upstream: &Upstream, timeout: Duration) -> Result<(), String> {
+    let url = upstream.url.as_str();
+    if url.contains('$') {
+        return Ok(());
+    }
+    if let Some(servers) = &upstream.servers {
+        let mut reason = String::from("no server");
+        for server in servers {
+            // A host:port or unix:/path, like the address of fastcgi.
+            let server = Upstream {
+                url: server.address.clone(),
+                backend_type: BackendType::Fastcgi,
+                ..Default::default()
+            };
+            match probe(&server, timeout) {
+                Ok(()) => return Ok(()),
+                Err(err) => reason = err,
+            }
+        }
+        return Err(reason);
+    }
+    let socket = match upstream.backend_type {
+        BackendType::Proxy => unix_socket(url).map(|socket| socket.split(':').next()),
+        BackendType::Fastcgi | BackendType::Uwsgi => url.strip_prefix("unix:").map(Some),
+    };
+    if let Some(path) = socket.flatten() {
+        return match Path::new(path).exists() {
+            true => Ok(()),
+            false => Err(String::from("no such socket")),
+        };
+    }
+
+    let address = match upstream.backend_type {
+        BackendType::Proxy => {
+            let url = Url::parse(url).map_err(|err| err.to_string())?;
+            match (url.host_str(), url.port_or_known_default()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                _ => return Err(String::from("no host and port")),
+            }
+        }
+        BackendType::Fastcgi | BackendType::Uwsgi => url.to_string(),
+    };
+    let addresses = resolve(address, timeout)?;
+    let mut reason = String::from("no address");
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(_) => return Ok(()),
+            Err(err) => reason = format!("{}: {}", address, err),
+        }
+    }
+    Err(reason)
+}
+
+/// Resolves the `host:port` of `address` on a thread of its own, since the
+/// resolver of the system has no timeout, giving up after `timeout`. A
+/// resolution given up on ends in the background.
+fn resolve(address: String, timeout: Duration) -> Result<Vec<SocketAddr>, String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let resolved = address
+            .to_socket_addrs()
+            .map(Iterator::collect)
+            .map_err(|err| err.to_string());
+        let _ = sender.send(resolved);
+    });
+    receiver
+        .recv_timeout(timeout)
+        .unwrap_or_else(|_| Err(String::from("name resolution timed out")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::{Options, UnverifiedConfig, Warning, WarningKind, PROBE_TIMEOUT};
+
+    #[test]
+    fn test_skip_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = listener.local_addr().unwrap();
+        let down = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let config: UnverifiedConfig = toml::from_str(&format!(
+            r#"
+            [[sites]]
+            domain = "up.example.com"
+            upstream = {{ url = "http://{}" }}
+
+            [[sites]]
+            domain = "down.example.com"
+            upstream = {{ url = "http://{}" }}
+
+            [[sites]]
+            domain = "php.example.com"
+            upstream = {{ url = "unix:/nonexistent/php-fpm.sock", backend_type = "fastcgi" }}
+
+            [[sites]]
+            domain = "static.example.com"
+            root = "/var/www/static"
+            "#,
+            up, down
+        ))
+        .unwrap();
+
+        let options = Options {
+            skip_unreachable: Some(PROBE_TIMEOUT),
+            ..Default::default()
+        };
+        let (validated, warnings) = crate::validate(config.clone(), &options).unwrap();
+        let domains: Vec<&str> = validated
+            .sites
+            .iter()
+            .map(|site| site.domain.as_str())
+            .collect();
+        assert_eq!(domains, ["up.example.com", "static.example.com"]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings
+            .iter()
+            .all(|warning| warning.kind == WarningKind::Unreachable));
+        assert!(
+            warnings[0].to_string().starts_with(&format!(
+                "\"down.example.com\": skipped, upstream \"http://{}\" is unreachable: ",
+                down
+            )),
+            "{}",
+            warnings[0]
+        );
+        assert_eq!(
+            warnings[1],
+            Warning::site(
+                WarningKind::Unreachable,
+                "php.example.com",
+                String::from(
+                    "skipped, upstream \"unix:/nonexistent/php-fpm.sock\" is unreachable: no such socket"
+                ),
+            )
+        );
+
+        // A single job probes the sites in turn, in the same order.
+        let single = Options {
+            jobs: Some(1),
+            ..options
+        };
+        let (validated, _) = crate::validate(config.clone(), &single).unwrap();
+        assert_eq!(validated.sites.len(), 2);
+        assert_eq!(validated.sites[1].domain, "static.example.com");
+
+        let (validated, warnings) = crate::validate(config, &Options::default()).unwrap();
+        assert_eq!(validated.sites.len(), 4);
+        assert!(warnings.is_empty());
+        drop(listener);
+    }
+}
@@ -0,0 +1,145 @@
+//! Configs fetched over HTTP(S), e.g. from a config service, rather than read
+//! from a file.
+
+use std::env;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::NvhostsError;
+
+/// Environment variable holding the value of the `Authorization` header sent
+/// with the request, e.g. `Bearer <token>`.
+pub const AUTHORIZATION_ENV: &str = "NVHOSTS_CONFIG_AUTHORIZATION";
+
+/// How long fetching a config may take, from connecting to reading the body.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `path` is the URL of a remote config rather than a file.
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Fetches the config at `url`, failing on network errors and on statuses
+/// other than 2xx. The credentials of `AUTHORIZATION_ENV` are only sent over
+/// HTTPS: with an `http://` URL, they fail the fetch rather than going out in
+/// clear text. Without them, an `http://` URL is fetched with a warning, as
+/// anyone on the way can change the config.
+pub fn fetch(url: &str) -> Result<String, NvhostsError> {
+    let error = |message: String| NvhostsError::Fetch {
+        url: url.to_string(),
+        message,
+    };
+
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    let mut request = agent.get(url);
+    if let Ok(authorization) = env::var(AUTHORIZATION_ENV) {
+        if !url.starts_with("https://") {
+            return Err(error(format!(
+                "{} is set, refusing to send it over plain HTTP, use an https:// URL",
+                AUTHORIZATION_ENV
+            )));
+        }
+        request = request.set("Authorization", &authorization);
+    } else if !url.starts_with("https://") {
+        warn!(
+            "fetching {} over plain HTTP, anyone on the way can change the config",
+            url
+        );
+    }
+    let response = request.call().map_err(|err| match err {
+        ureq::Error::Status(status, response) => {
+            error(format!("status {} {}", status, response.status_text()))
+        }
+        // Unlike its Display, the kind and cause of the error don't repeat
+        // the URL.
+        ureq::Error::Transport(transport) => match std::error::Error::source(&transport) {
+            Some(source) => error(format!("{}: {}", transport.kind(), source)),
+            None => error(transport.kind().to_string()),
+        },
+    })?;
+    response
+        .into_string()
+        .map_err(|err| error(format!("couldn't read the body: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    /// Serves a single request with `status` and `body`, returning the URL
+    /// and the request headers it got, once answered.
+    fn serve(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/nvhosts.toml", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut headers = Vec::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_string());
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+            headers
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_fetch() {
+        assert!(is_url("https://config.internal/nvhosts.toml"));
+        assert!(!is_url("./nvhosts.toml"));
+
+        let (url, handle) = serve("200 OK", "[[sites]]\ndomain = \"example.com\"\n");
+        assert_eq!(
+            fetch(&url).unwrap(),
+            "[[sites]]\ndomain = \"example.com\"\n"
+        );
+        assert!(handle.join().unwrap()[0].starts_with("GET /nvhosts.toml "));
+
+        let (url, handle) = serve("404 Not Found", "");
+        let err = fetch(&url).err().unwrap().to_string();
+        assert_eq!(err, format!("couldn't fetch {}: status 404 Not Found", url));
+        handle.join().unwrap();
+
+        env::set_var(AUTHORIZATION_ENV, "Bearer secret");
+        let err = fetch("http://config.internal/nvhosts.toml")
+            .err()
+            .unwrap()
+            .to_string();
+        env::remove_var(AUTHORIZATION_ENV);
+        assert_eq!(
+            err,
+            "couldn't fetch http://config.internal/nvhosts.toml: NVHOSTS_CONFIG_AUTHORIZATION is set, refusing to send it over plain HTTP, use an https:// URL"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let err = fetch(&url).err().unwrap().to_string();
+        assert!(
+            err.starts_with(&format!("couldn't fetch {}: ", url)),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Splitting the sites of a config file into one file per domain under its
+//! `sites_dir`, printed by `nvhosts split`, to move from a single config file
+//! to a directory of sites.
+
+use toml::Value;
+
+use crate::NvhostsError;
+
+/// A config file split by `split`.
+pub struct Split {
+    /// The config file without its sites, reading them from `sites_dir`.
+    pub config: String,
+    /// The name and content of each file of `sites_dir`, `<domain>.toml`,
+    /// holding the sites of that domain in the order of the config.
+    pub files: Vec<(String, String)>,
+}
+
+/// Splits the config file `content` so that every domain has its own file in
+/// `sites_dir`, read back by `UnverifiedConfig::read_sites_dir`. Values are
+/// kept as written, e.g. `${file:<path>}` references aren't read; comments
+/// are lost.
+pub fn split(content: &str, sites_dir: &str) -> Result<Split, NvhostsError> {
+    let parse = |message: String| NvhostsError::Parse {
+        site: None,
+        message,
+    };
+    let mut config: Value = content
+        .parse()
+        .map_err(|err: toml::de::Error| parse(err.to_string()))?;
+    let table = config
+        .as_table_mut()
+        .ok_or_else(|| parse(String::from("expected a table")))?;
+    if table.contains_key("sites_dir") {
+        return Err(parse(String::from("sites_dir is already set")));
+    }
+    let sites = match table.remove("sites") {
+        Some(Value::Array(sites)) => sites,
+        Some(_) => return Err(parse(String::from("sites isn't an array of tables"))),
+        None => Vec::new(),
+    };
+
+    let mut files: Vec<(String, Vec<Value>)> = Vec::new();
+    for (i, site) in sites.into_iter().enumerate() {
+        let domain = match site.get("domain").and_then(Value::as_str) {
+            Some(domain) if !domain.is_empty() && !domain.contains(['/', '\\']) => domain,
+            _ => {
+                return Err(NvhostsError::Parse {
+                    site: Some(format!("sites[{}]", i)),
+                    message: String::from("no domain to name its file after"),
+                })
+            }
+        };
+        let name = format!("{}.toml", domain);
+        match files.iter_mut().find(|(file, _)| *file == name) {
+            Some((_, sites)) => sites.push(site),
+            None => files.push((name, vec![site])),
+        }
+    }
+
+    table.insert(String::from("sites_dir"), Value::from(sites_dir));
+    let config = toml::to_string_pretty(&config).expect("a config serializes to TOML");
+    let files = files
+        .into_iter()
+        .map(|(name, sites)| {
+            let mut file = toml::value::Table::new();
+            file.insert(String::from("sites"), Value::Array(sites));
+            let content = toml::to_string_pretty(&file).expect("sites serialize to TOML");
+            (name, content)
+        })
+        .collect();
+
+    Ok(Split { config, files })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::UnverifiedConfig;
+
+    #[test]
+    fn test_split() {
+        let content = r#"
+            server_tokens = false
+
+            [[sites]]
+            domain = "example.com"
+            profiles = ["prod"]
+
+            [[sites]]
+            domain = "example.org"
+            upstream = { url = "http://127.0.0.1:8000" }
+
+            [[sites.headers]]
+            for = "/*"
+            values = { X-Frame-Options = "DENY" }
+
+            [[sites]]
+            domain = "example.com"
+            profiles = ["staging"]
+        "#;
+        let split = split(content, "conf.d").unwrap();
+
+        assert_eq!(
+            split.config,
+            "server_tokens = false\nsites_dir = 'conf.d'\n"
+        );
+        let names: Vec<&str> = split.files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["example.com.toml", "example.org.toml"]);
+        assert_eq!(split.files[0].1.matches("[[sites]]").count(), 2);
+
+        let dir = std::env::temp_dir().join(format!("nvhosts-split-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        for (name, content) in split.files.iter() {
+            fs::write(dir.join("conf.d").join(name), content).unwrap();
+        }
+        let merged: UnverifiedConfig = split.config.parse().unwrap();
+        let merged = merged.read_sites_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Sites come back grouped by domain, in the order of the file names.
+        let mut original: UnverifiedConfig = content.parse().unwrap();
+        original.sites.sort_by(|a, b| a.domain.cmp(&b.domain));
+        assert_eq!(merged.to_toml().unwrap(), original.to_toml().unwrap());
+
+        let err = super::split("[[sites]]\ndescription = \"no domain\"", "conf.d")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "sites[0]: no domain to name its file after"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! The fingerprint of the last incremental run, kept in the output directory
+//! so that a run with the same inputs can skip generation altogether.
+
+use std::fs;
+use std::path::Path;
+
+use crate::{NvhostsError, Options, UnverifiedConfig};
+
+/// Name of the file holding the fingerprint, in the output directory.
+const STATE_FILENAME: &str = ".nvhosts-state";
+
+/// A digest of everything the generated files depend on: the config, with
+/// its file references already read, the options, the templates of
+/// `Options::template_dir` and the version of nvhosts.
+pub(crate) fn fingerprint(
+    config: &UnverifiedConfig,
+    options: &Options,
+) -> Result<String, NvhostsError> {
+    let mut value = toml::Value::try_from(config).expect("a config serializes to TOML");
+    sort_tables(&mut value);
+
+    // The switches below change how a run goes, not what it generates.
+    let options = Options {
+        progress: false,
+        incremental: false,
+        force: false,
+        write_retries: 0,
+        ..options.clone()
+    };
+
+    let mut input = format!(
+        "{} {}\n{:?}\n{}\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("NVHOSTS_GIT_COMMIT"),
+        options,
+        value
+    );
+    if let Some(dir) = &options.template_dir {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).map_err(NvhostsError::io(dir))? {
+            paths.push(entry.map_err(NvhostsError::io(dir))?.path());
+        }
+        paths.sort();
+        for path in paths.iter().filter(|path| path.is_file()) {
+            let content = fs::read_to_string(path).map_err(NvhostsError::io(path))?;
+            input.push_str(&format!("{}\n{}\n", path.display(), content));
+        }
+    }
+
+    Ok(format!("{:016x}", fnv1a(input.as_bytes())))
+}
+
+/// Whether the last incremental run in `dir` had this `fingerprint`.
+pub(crate) fn is_current(dir: &Path, fingerprint: &str) -> bool {
+    fs::read_to_string(dir.join(STATE_FILENAME))
+        .is_ok_and(|recorded| recorded.trim() == fingerprint)
+}
+
+/// Records `fingerprint` as the one of the last run in `dir`.
+pub(crate) fn record(dir: &Path, fingerprint: &str) -> Result<(), NvhostsError> {
+    let path = dir.join(STATE_FILENAME);
+    fs::write(&path, format!("{}\n", fingerprint)).map_err(NvhostsError::io(path))
+}
+
+/// Orders the keys of every table: some of them come from hash maps, whose
+/// order changes from one run to the next.
+fn sort_tables(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            let mut entries: Vec<_> = std::mem::take(table).into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, mut value) in entries {
+                sort_tables(&mut value);
+                table.insert(key, value);
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(sort_tables),
+        _ => {}
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`, which unlike the hasher of the
+/// standard library is the same across Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(content: &str) -> UnverifiedConfig {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let site = r#"
+            [[sites]]
+            domain = "example.com"
+
+            [[sites.headers]]
+            for = "/*"
+            values = { A = "1", B = "2", C = "3", D = "4" }
+            "#;
+        let options = Options::default();
+        let expected = fingerprint(&config(site), &options).unwrap();
+
+        assert_eq!(expected.len(), 16);
+        for _ in 0..10 {
+            assert_eq!(fingerprint(&config(site), &options).unwrap(), expected);
+        }
+        let progress = Options {
+            progress: true,
+            force: true,
+            ..Default::default()
+        };
+        assert_eq!(fingerprint(&config(site), &progress).unwrap(), expected);
+
+        let changed = site.replace("\"4\"", "\"5\"");
+        assert_ne!(fingerprint(&config(&changed), &options).unwrap(), expected);
+        let profile = Options {
+            profile: Some(String::from("prod")),
+            ..Default::default()
+        };
+        assert_ne!(fingerprint(&config(site), &profile).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = std::env::temp_dir().join(format!("nvhosts-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(!is_current(&dir, "0123456789abcdef"));
+        record(&dir, "0123456789abcdef").unwrap();
+        assert!(is_current(&dir, "0123456789abcdef"));
+        assert!(!is_current(&dir, "fedcba9876543210"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
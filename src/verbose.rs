@@ -1,18 +1,19 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+//! Compatibility shim of the former verbose switch. Messages now go through
+//! the `log` facade: embedders install their own logger, and verbose output
+//! is the `info` level.
 
-static LEVEL: AtomicUsize = AtomicUsize::new(0);
+use log::{Level, LevelFilter};
 
+/// Lets `info` messages through, unless a more verbose level is set.
+#[deprecated(note = "install a `log` logger and call `log::set_max_level` instead")]
 pub fn enable() {
-    if LEVEL.load(Ordering::SeqCst) != 0 {
-        return;
+    if log::max_level() < LevelFilter::Info {
+        log::set_max_level(LevelFilter::Info);
     }
-    LEVEL.fetch_add(1, Ordering::SeqCst);
 }
 
+/// Whether `info` messages are logged.
+#[deprecated(note = "use `log::log_enabled!(log::Level::Info)` instead")]
 pub fn is_enabled() -> bool {
-    if LEVEL.load(Ordering::SeqCst) != 0 {
-        true
-    } else {
-        false
-    }
+    log::log_enabled!(Level::Info)
 }
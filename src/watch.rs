@@ -0,0 +1,113 @@
+//! Polling of the inputs of a run, to generate the files again whenever they
+//! change. Saves in quick succession are coalesced into a single change.
+
+use std::time::Duration;
+
+use crate::{state, Options, UnverifiedConfig};
+
+/// How often the inputs are polled. A change is only reported once the inputs
+/// stayed the same for a whole interval.
+pub const INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the inputs are polled when the config is fetched over HTTP, so
+/// as not to request the config service twice a second.
+pub const REMOTE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What a poll found: the fingerprint of the loaded config, or why it
+/// couldn't be loaded.
+type Snapshot = Result<(String, UnverifiedConfig), String>;
+
+/// Watches the config returned by `load`, with its file references, site
+/// files and the templates of `Options::template_dir`.
+pub struct Watcher<L> {
+    load: L,
+    options: Options,
+    /// What the last poll found.
+    seen: Snapshot,
+    /// The key of the last snapshot reported.
+    reported: Option<Result<String, String>>,
+}
+
+impl<L> Watcher<L>
+where
+    L: Fn() -> Result<UnverifiedConfig, String>,
+{
+    /// Loads the config a first time, which the first poll reports.
+    pub fn new(load: L, options: &Options) -> Self {
+        let options = options.clone();
+        let seen = snapshot(&load, &options);
+        Self {
+            load,
+            options,
+            seen,
+            reported: None,
+        }
+    }
+
+    /// Loads the config again, returning it, or why it couldn't be loaded,
+    /// when it changed since the last report and didn't since the last poll.
+    pub fn poll(&mut self) -> Option<Result<UnverifiedConfig, String>> {
+        let current = snapshot(&self.load, &self.options);
+        if key(&current) != key(&self.seen) {
+            self.seen = current;
+            return None;
+        }
+        if self.reported.as_ref() == Some(&key(&current)) {
+            return None;
+        }
+        self.reported = Some(key(&current));
+        Some(current.map(|(_, config)| config))
+    }
+}
+
+fn snapshot<L>(load: &L, options: &Options) -> Snapshot
+where
+    L: Fn() -> Result<UnverifiedConfig, String>,
+{
+    let config = load()?;
+    let fingerprint = state::fingerprint(&config, options).map_err(|err| err.to_string())?;
+    Ok((fingerprint, config))
+}
+
+fn key(snapshot: &Snapshot) -> Result<String, String> {
+    snapshot
+        .as_ref()
+        .map(|(fingerprint, _)| fingerprint.clone())
+        .map_err(Clone::clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn test_poll() {
+        let content = RefCell::new(String::from("[[sites]]\ndomain = \"example.com\""));
+        let load = || toml::from_str(&content.borrow()).map_err(|err| err.to_string());
+        let mut watcher = Watcher::new(load, &Options::default());
+
+        assert!(matches!(watcher.poll(), Some(Ok(_))));
+        assert!(watcher.poll().is_none());
+
+        // Two saves in a row are reported once, after a quiet poll.
+        *content.borrow_mut() = String::from("[[sites]]\ndomain = \"example.org\"");
+        assert!(watcher.poll().is_none());
+        *content.borrow_mut() = String::from("[[sites]]\ndomain = \"example.net\"");
+        assert!(watcher.poll().is_none());
+        let config = watcher.poll().unwrap().unwrap();
+        assert_eq!(config.sites[0].domain, "example.net");
+        assert!(watcher.poll().is_none());
+
+        *content.borrow_mut() = String::from("[[sites]\n");
+        assert!(watcher.poll().is_none());
+        assert!(matches!(watcher.poll(), Some(Err(_))));
+        assert!(watcher.poll().is_none());
+
+        // Going back to the config last reported is still a change.
+        *content.borrow_mut() = String::from("[[sites]]\ndomain = \"example.net\"");
+        assert!(watcher.poll().is_none());
+        assert!(matches!(watcher.poll(), Some(Ok(_))));
+    }
+}
@@ -3,6 +3,7 @@ use std::process::{Command, Output};
 pub const IMAGE_TAG: &str = "nvhosts-test";
 
 pub struct RunOutput {
+    #[allow(dead_code)]
     pub output: Output,
     pub container_id: String,
 }
@@ -16,9 +16,11 @@ static START: Once = Once::new();
 static STOP: Once = Once::new();
 static NB_TESTS: AtomicUsize = AtomicUsize::new(0);
 
+type TestResult = Result<(), Box<dyn Any + Send>>;
+
 lazy_static! {
-    static ref RESULTS: Arc<Mutex<Vec<Result<(), Box<dyn Any + Send>>>>> =
-        Arc::new(Mutex::new(Vec::<Result<(), Box<dyn Any + Send>>>::new()));
+    static ref RESULTS: Arc<Mutex<Vec<TestResult>>> =
+        Arc::new(Mutex::new(Vec::<TestResult>::new()));
 }
 
 fn setup() {
@@ -66,7 +68,7 @@ fn teardown(is_success: bool) {
 
 pub fn run_test<T>(test: T)
 where
-    T: FnOnce() -> () + panic::UnwindSafe,
+    T: FnOnce() + panic::UnwindSafe,
 {
     START.call_once(|| {
         setup();
@@ -80,14 +82,14 @@ where
     if NB_TESTS.load(Ordering::SeqCst) == RESULTS.lock().unwrap().len() {
         let ok_tests = RESULTS.lock().unwrap();
 
-        let ok_tests: Vec<&Result<(), Box<dyn Any + Send>>> = ok_tests
+        let ok_tests: Vec<&TestResult> = ok_tests
             .deref()
-            .into_iter()
+            .iter()
             .filter(|result| result.is_err())
             .collect();
 
         STOP.call_once(|| {
-            teardown(ok_tests.len() > 0);
+            teardown(!ok_tests.is_empty());
         });
     }
 
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use nvhosts::builder::SiteBuilder;
+
+const GOLDEN_DIR: &str = "tests/golden";
+
+/// Set to regenerate the golden files instead of comparing against them.
+const UPDATE_ENV: &str = "UPDATE_GOLDEN";
+
+/// Reads every file of a directory into a name -> content map, leaving out
+/// the hidden ones nvhosts keeps its state in.
+fn read_dir(dir: &Path) -> BTreeMap<String, String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !path.file_name().unwrap().to_string_lossy().starts_with('.'))
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(&path).unwrap())
+        })
+        .collect()
+}
+
+/// Runs nvhosts on `config` in a temporary directory named after `case`, and
+/// returns the files it generated.
+fn generate(case: &str, config: &Path, args: &[&str]) -> BTreeMap<String, String> {
+    let work_dir: PathBuf =
+        env::temp_dir().join(format!("nvhosts-golden-{}-{}", case, std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nvhosts"))
+        .arg("-c")
+        .arg(config)
+        .args(args)
+        .current_dir(&work_dir)
+        .output()
+        .expect("failed to execute nvhosts");
+    assert!(
+        output.status.success(),
+        "nvhosts failed on {}: {}",
+        config.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let files = read_dir(&work_dir.join("sites-available"));
+    fs::remove_dir_all(&work_dir).unwrap();
+    files
+}
+
+/// Runs nvhosts on `tests/golden/<case>.toml` and compares the generated
+/// files with the ones checked in under `tests/golden/<case>/`.
+fn assert_golden(case: &str) {
+    assert_golden_with_args(case, &[]);
+}
+
+/// Same as `assert_golden`, passing extra command line arguments to nvhosts.
+fn assert_golden_with_args(case: &str, args: &[&str]) {
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN_DIR);
+    let config = golden_dir.join(format!("{}.toml", case));
+    let expected_dir = golden_dir.join(case);
+
+    let actual = generate(case, &config, args);
+
+    if env::var_os(UPDATE_ENV).is_some() {
+        if expected_dir.exists() {
+            fs::remove_dir_all(&expected_dir).unwrap();
+        }
+        fs::create_dir_all(&expected_dir).unwrap();
+        for (name, content) in actual.iter() {
+            fs::write(expected_dir.join(name), content).unwrap();
+        }
+        return;
+    }
+
+    let expected = read_dir(&expected_dir);
+    assert_eq!(
+        actual.keys().collect::<Vec<_>>(),
+        expected.keys().collect::<Vec<_>>(),
+        "generated files differ for {} (rerun with {}=1 to update)",
+        case,
+        UPDATE_ENV
+    );
+    for (name, content) in expected.iter() {
+        assert_eq!(
+            &actual[name], content,
+            "{}/{} differs (rerun with {}=1 to update)",
+            case, name, UPDATE_ENV
+        );
+    }
+}
+
+#[test]
+fn golden_redirects() {
+    assert_golden("redirects");
+}
+
+#[test]
+fn golden_headers() {
+    assert_golden("headers");
+}
+
+#[test]
+fn golden_http() {
+    assert_golden("http");
+}
+
+#[test]
+fn golden_http2() {
+    assert_golden("http2");
+}
+
+#[test]
+fn golden_tls() {
+    assert_golden("tls");
+}
+
+#[test]
+fn golden_upstream() {
+    assert_golden("upstream");
+}
+
+#[test]
+fn golden_combined() {
+    assert_golden_with_args("combined", &["--output-format", "combined"]);
+}
+
+/// Runs nvhosts with `--check-idempotent` on `config`, which renders it
+/// twice, and fails with the first file that differs between the renders.
+fn assert_idempotent(config: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_nvhosts"))
+        .arg("-c")
+        .arg(config)
+        .arg("--check-idempotent")
+        .args(args)
+        .current_dir(env::temp_dir())
+        .output()
+        .expect("failed to execute nvhosts");
+    assert!(
+        output.status.success(),
+        "nvhosts renders {} differently twice: {}",
+        config.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// The golden configs render to the same files every time.
+#[test]
+fn idempotent_generation() {
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN_DIR);
+    for entry in fs::read_dir(&golden_dir).unwrap() {
+        let config = entry.unwrap().path();
+        if config.extension().is_some_and(|ext| ext == "toml") {
+            assert_idempotent(&config, &[]);
+            assert_idempotent(&config, &["--output-format", "combined"]);
+        }
+    }
+}
+
+/// Sites built in code render to the same files every time too.
+#[test]
+fn idempotent_built_sites() {
+    let config = nvhosts::UnverifiedConfig::default()
+        .with_site(
+            SiteBuilder::new("example.com")
+                .proxy("http://127.0.0.1:3000")
+                .ssl("/etc/ssl/example.pem", "/etc/ssl/example.key")
+                .force_https()
+                .header("/*", "X-Frame-Options", "DENY")
+                .header("/api", "Cache-Control", "no-store")
+                .redirect("/old", "/new"),
+        )
+        .with_site(SiteBuilder::new("example.org").redirect_to("https://example.com"));
+    for output in [
+        nvhosts::Output::PerSite,
+        nvhosts::Output::Combined(String::from("generated.conf")),
+    ] {
+        let options = nvhosts::Options {
+            output,
+            ..Default::default()
+        };
+        let nondeterministic = nvhosts::check_idempotent(config.clone(), &options).unwrap();
+        assert!(nondeterministic.is_none(), "{:?}", nondeterministic);
+    }
+}
+
+/// Configs large enough to be generated by several threads give the same
+/// files, in the same order, as when generated on a single one.
+#[test]
+fn parallel_generation() {
+    let config = env::temp_dir().join(format!("nvhosts-parallel-{}.toml", std::process::id()));
+    let sites: String = (0..100)
+        .map(|i| format!("[[sites]]\ndomain = \"site{}.example.com\"\n", i))
+        .collect();
+    fs::write(&config, sites).unwrap();
+
+    for output_format in ["per-site", "combined"] {
+        let args = |jobs| ["--output-format", output_format, "--jobs", jobs];
+        let sequential = generate("sequential", &config, &args("1"));
+        assert_eq!(generate("parallel", &config, &args("4")), sequential);
+    }
+    fs::remove_file(&config).unwrap();
+}
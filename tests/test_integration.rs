@@ -1,5 +1,3 @@
-use ureq;
-
 mod common;
 use common::{docker, runner};
 